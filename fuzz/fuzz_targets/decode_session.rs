@@ -0,0 +1,61 @@
+//! Feeds arbitrary bytes through the decoding of a session, which may fail with an error but must
+//! never panic.
+//!
+//! The first byte picks the options the rest is decoded with: bit 0 sets `checksum`, bit 1 uses
+//! Europe/Berlin as `pico_timezone` and bit 2 enables the spike filter and the derived quantities.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use pico_humidity_temp_read::{
+    config::{PerceivedTemp, PicoTimezone, SpikeFilterConfig},
+    pico::{decode_session, DownloadInfo, CHUNKS_IN_FLIGHT},
+    Config,
+};
+use rust_decimal::Decimal;
+use tokio::sync::mpsc;
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&options, mut session)) = data.split_first() else {
+        return;
+    };
+
+    let mut config = Config::default();
+    if options & 0b10 != 0 {
+        config.pico_timezone = PicoTimezone::Named(chrono_tz::Europe::Berlin);
+    }
+    if options & 0b100 != 0 {
+        config.compute_dew_point = true;
+        config.perceived_temp = Some(PerceivedTemp::HeatIndex);
+        config.spike_filter = Some(SpikeFilterConfig {
+            max_temp_delta: Some(Decimal::new(50, 1)),
+            max_humidity_delta: Some(Decimal::new(200, 1)),
+            action: Default::default(),
+        });
+    }
+
+    let mut station = config.stations[0].clone();
+    station.checksum = options & 0b1 != 0;
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .unwrap();
+
+    runtime.block_on(async {
+        let (sender, mut receiver) = mpsc::channel(CHUNKS_IN_FLIGHT);
+        let len = session.len() as u64;
+
+        let decoding = async move {
+            let mut info = DownloadInfo::default();
+            decode_session(&config, &station, &mut session, &sender, &mut info).await
+        };
+        let draining = async { while receiver.recv().await.is_some() {} };
+        let (result, ()) = tokio::join!(decoding, draining);
+
+        // A session decoded in full was read up to its end.
+        if let Ok(received) = result {
+            assert_eq!(received, len);
+        }
+    });
+});
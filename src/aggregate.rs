@@ -0,0 +1,409 @@
+//! Aggregating the measurements into buckets of a fixed length, stored in the
+//! `measurement_hourly` table to keep less than every single measurement.
+//!
+//! A bucket stores the time of its latest measurement, so the measurements arriving for it on a
+//! later run (like the rest of the bucket still in progress) are added to it without counting the
+//! ones already aggregated again.
+
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, FixedOffset, TimeDelta, Timelike, Utc};
+use rust_decimal::{Decimal, RoundingStrategy};
+
+use crate::{
+    config::{AggregateConfig, AggregateFunction, Config},
+    Measurement,
+};
+
+/// The table the aggregates are stored in.
+pub const AGGREGATE_TABLE: &str = "measurement_hourly";
+
+/// The aggregates of the measurements of a station in a bucket.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bucket {
+    pub start: DateTime<FixedOffset>,
+    pub samples: u32,
+    /// The time of the latest measurement in the bucket.
+    pub last_at: DateTime<FixedOffset>,
+    pub temp: Aggregates,
+    pub humidity: Aggregates,
+}
+
+/// The aggregates of a quantity in a bucket.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aggregates {
+    pub sum: Decimal,
+    pub min: Decimal,
+    pub max: Decimal,
+}
+
+impl Aggregates {
+    fn new(value: Decimal) -> Aggregates {
+        Aggregates {
+            sum: value,
+            min: value,
+            max: value,
+        }
+    }
+
+    fn add(&mut self, value: Decimal) {
+        self.sum += value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+}
+
+/// The start of the bucket `time` falls into: the latest multiple of `minutes` since midnight on
+/// the wall clock of `pico_timezone`.
+pub fn bucket_start(
+    config: &Config,
+    minutes: u32,
+    time: &DateTime<FixedOffset>,
+) -> DateTime<FixedOffset> {
+    let local = config.pico_timezone.local(time);
+    let into_bucket = local.num_seconds_from_midnight() % (minutes * 60);
+
+    local.with_nanosecond(0).unwrap_or(local) - TimeDelta::seconds(into_bucket.into())
+}
+
+/// Aggregates the `measurements` taken after `after` (all of them if it's `None`) into the
+/// buckets they fall into, in the order of the buckets.
+pub fn aggregate(
+    config: &Config,
+    aggregate: &AggregateConfig,
+    measurements: &[Measurement],
+    after: Option<DateTime<Utc>>,
+) -> Vec<Bucket> {
+    let mut buckets: BTreeMap<DateTime<FixedOffset>, Bucket> = BTreeMap::new();
+
+    for measurement in measurements {
+        if after.is_some_and(|after| measurement.time <= after) {
+            continue;
+        }
+
+        let start = bucket_start(config, aggregate.interval.minutes(), &measurement.time);
+
+        buckets
+            .entry(start)
+            .and_modify(|bucket| {
+                bucket.samples += 1;
+                bucket.last_at = bucket.last_at.max(measurement.time);
+                bucket.temp.add(measurement.temp);
+                bucket.humidity.add(measurement.humidity);
+            })
+            .or_insert_with(|| Bucket {
+                start,
+                samples: 1,
+                last_at: measurement.time,
+                temp: Aggregates::new(measurement.temp),
+                humidity: Aggregates::new(measurement.humidity),
+            });
+    }
+
+    buckets.into_values().collect()
+}
+
+/// A quantity which is aggregated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quantity {
+    Temp,
+    Humidity,
+}
+
+/// A column of the aggregate table holding an aggregate of a quantity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Column {
+    /// The sum of the values, which the average is computed from.
+    Sum(Quantity),
+    /// The average of the values, rounded to two decimal places.
+    Avg(Quantity),
+    Min(Quantity),
+    Max(Quantity),
+}
+
+impl Column {
+    pub fn name(self) -> &'static str {
+        match self {
+            Column::Sum(Quantity::Temp) => "temp_sum",
+            Column::Sum(Quantity::Humidity) => "humidity_sum",
+            Column::Avg(Quantity::Temp) => "temp_avg",
+            Column::Avg(Quantity::Humidity) => "humidity_avg",
+            Column::Min(Quantity::Temp) => "temp_min",
+            Column::Min(Quantity::Humidity) => "humidity_min",
+            Column::Max(Quantity::Temp) => "temp_max",
+            Column::Max(Quantity::Humidity) => "humidity_max",
+        }
+    }
+
+    /// The value of this column for `bucket`.
+    pub fn value(self, bucket: &Bucket) -> Decimal {
+        let aggregates = |quantity| match quantity {
+            Quantity::Temp => &bucket.temp,
+            Quantity::Humidity => &bucket.humidity,
+        };
+
+        match self {
+            Column::Sum(quantity) => aggregates(quantity).sum,
+            Column::Avg(quantity) => average(aggregates(quantity).sum, bucket.samples.into()),
+            Column::Min(quantity) => aggregates(quantity).min,
+            Column::Max(quantity) => aggregates(quantity).max,
+        }
+    }
+
+    /// The expression of an upsert merging the stored value of this column with the `excluded`
+    /// one, given the names of the SQL functions for the smaller and greater of two values.
+    pub fn merged(self, least: &str, greatest: &str) -> String {
+        let stored = format!("{AGGREGATE_TABLE}.{}", self.name());
+        let excluded = format!("excluded.{}", self.name());
+
+        match self {
+            Column::Sum(_) => format!("{stored} + {excluded}"),
+            Column::Avg(quantity) => {
+                let sum = Column::Sum(quantity).name();
+                format!(
+                    "round(({AGGREGATE_TABLE}.{sum} + excluded.{sum}) / ({AGGREGATE_TABLE}.samples + excluded.samples), 2)"
+                )
+            }
+            Column::Min(_) => format!("{least}({stored}, {excluded})"),
+            Column::Max(_) => format!("{greatest}({stored}, {excluded})"),
+        }
+    }
+}
+
+/// The value columns the configured aggregate `functions` need.
+pub fn columns(functions: &[AggregateFunction]) -> Vec<Column> {
+    let mut columns = Vec::new();
+
+    for quantity in [Quantity::Temp, Quantity::Humidity] {
+        for function in [
+            AggregateFunction::Avg,
+            AggregateFunction::Min,
+            AggregateFunction::Max,
+        ] {
+            if !functions.contains(&function) {
+                continue;
+            }
+
+            match function {
+                AggregateFunction::Avg => {
+                    columns.extend([Column::Sum(quantity), Column::Avg(quantity)])
+                }
+                AggregateFunction::Min => columns.push(Column::Min(quantity)),
+                AggregateFunction::Max => columns.push(Column::Max(quantity)),
+            }
+        }
+    }
+
+    columns
+}
+
+/// `sum / samples`, rounded to two decimal places like the databases do.
+fn average(sum: Decimal, samples: Decimal) -> Decimal {
+    let mut average =
+        (sum / samples).round_dp_with_strategy(2, RoundingStrategy::MidpointAwayFromZero);
+    average.rescale(2);
+    average
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{PicoTimezone, Storage};
+
+    use super::*;
+
+    fn measurement(time: &str, temp: i64, humidity: i64) -> Measurement {
+        Measurement {
+            time: DateTime::parse_from_rfc3339(time).unwrap(),
+            temp: Decimal::new(temp, 1),
+            humidity: Decimal::new(humidity, 1),
+            uncalibrated: None,
+            out_of_range: false,
+            dew_point: None,
+            perceived_temp: None,
+            absolute_humidity: None,
+            packed: None,
+        }
+    }
+
+    fn config(timezone: &str, interval: &str) -> (Config, AggregateConfig) {
+        let aggregate = AggregateConfig {
+            interval: interval.to_string().try_into().unwrap(),
+            functions: vec![AggregateFunction::Avg, AggregateFunction::Min],
+            keep_measurements: true,
+        };
+        let config = Config {
+            pico_timezone: PicoTimezone::try_from(timezone.to_string()).unwrap(),
+            aggregate: Some(aggregate.clone()),
+            ..Config::default()
+        };
+
+        (config, aggregate)
+    }
+
+    #[test]
+    fn aligns_buckets_to_the_local_wall_clock() {
+        let (config, _) = config("Asia/Kolkata", "1h");
+        let time = DateTime::parse_from_rfc3339("2024-06-01T05:10:07Z").unwrap();
+
+        // 10:40:07 in India, half an hour off the hours of UTC.
+        assert_eq!(
+            bucket_start(&config, 60, &time).to_rfc3339(),
+            "2024-06-01T10:00:00+05:30"
+        );
+        assert_eq!(
+            bucket_start(&config, 15, &time).to_rfc3339(),
+            "2024-06-01T10:30:00+05:30"
+        );
+        assert_eq!(
+            bucket_start(&config, 1440, &time).to_rfc3339(),
+            "2024-06-01T00:00:00+05:30"
+        );
+    }
+
+    #[test]
+    fn keeps_the_repeated_hour_apart() {
+        let (config, _) = config("Europe/Berlin", "1h");
+
+        // 02:30 on daylight saving time and an hour later on standard time.
+        let summer = DateTime::parse_from_rfc3339("2024-10-27T00:30:00Z").unwrap();
+        let winter = DateTime::parse_from_rfc3339("2024-10-27T01:30:00Z").unwrap();
+
+        assert_eq!(
+            bucket_start(&config, 60, &summer).to_rfc3339(),
+            "2024-10-27T02:00:00+02:00"
+        );
+        assert_eq!(
+            bucket_start(&config, 60, &winter).to_rfc3339(),
+            "2024-10-27T02:00:00+01:00"
+        );
+    }
+
+    #[test]
+    fn aggregates_the_measurements_of_every_bucket() {
+        let (config, options) = config("utc", "1h");
+        let measurements = [
+            measurement("2024-06-01T12:59:30Z", 200, 500),
+            measurement("2024-06-01T12:00:00Z", 215, 480),
+            measurement("2024-06-01T12:30:00Z", 210, 490),
+            measurement("2024-06-01T13:00:00Z", 190, 550),
+        ];
+
+        let buckets = aggregate(&config, &options, &measurements, None);
+
+        assert_eq!(buckets.len(), 2);
+        assert_eq!(buckets[0].start.to_rfc3339(), "2024-06-01T12:00:00+00:00");
+        assert_eq!(buckets[0].samples, 3);
+        assert_eq!(buckets[0].last_at, measurements[0].time);
+        assert_eq!(
+            Column::Avg(Quantity::Temp).value(&buckets[0]).to_string(),
+            "20.83"
+        );
+        assert_eq!(
+            Column::Sum(Quantity::Temp).value(&buckets[0]).to_string(),
+            "62.5"
+        );
+        assert_eq!(
+            Column::Min(Quantity::Humidity)
+                .value(&buckets[0])
+                .to_string(),
+            "48.0"
+        );
+        assert_eq!(
+            Column::Max(Quantity::Temp).value(&buckets[0]).to_string(),
+            "21.5"
+        );
+        assert_eq!(buckets[1].samples, 1);
+        assert_eq!(
+            Column::Avg(Quantity::Humidity)
+                .value(&buckets[1])
+                .to_string(),
+            "55.00"
+        );
+    }
+
+    #[test]
+    fn leaves_out_the_measurements_already_aggregated() {
+        let (config, options) = config("utc", "1h");
+        let measurements = [
+            measurement("2024-06-01T12:00:00Z", 215, 480),
+            measurement("2024-06-01T12:30:00Z", 210, 490),
+        ];
+        let after = Some(measurements[0].time.to_utc());
+
+        let buckets = aggregate(&config, &options, &measurements, after);
+
+        assert_eq!(buckets.len(), 1);
+        assert_eq!(buckets[0].samples, 1);
+        assert_eq!(buckets[0].temp.sum, Decimal::new(210, 1));
+    }
+
+    #[test]
+    fn stores_only_the_configured_functions() {
+        let names: Vec<_> = columns(&[AggregateFunction::Max, AggregateFunction::Avg])
+            .into_iter()
+            .map(Column::name)
+            .collect();
+
+        assert_eq!(
+            names,
+            [
+                "temp_sum",
+                "temp_avg",
+                "temp_max",
+                "humidity_sum",
+                "humidity_avg",
+                "humidity_max"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn extends_the_stored_buckets() {
+        let dir = std::env::temp_dir().join("pico_humidity_temp_read_aggregate");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let (config, _) = config("utc", "1h");
+        let config = Config {
+            storage: Storage::Sqlite {
+                path: dir.join("measurements.db"),
+            },
+            ..config
+        };
+        let station = config.stations[0].clone();
+        let mut db = crate::sink::connect_database(&config).await.unwrap();
+
+        let first = [
+            measurement("2024-06-01T12:00:00Z", 200, 500),
+            measurement("2024-06-01T12:10:00Z", 210, 480),
+        ];
+        // The next run gets the measurements of the first again, along with a newer one.
+        let second = [
+            first[0].clone(),
+            first[1].clone(),
+            measurement("2024-06-01T12:20:00Z", 250, 460),
+        ];
+
+        for measurements in [&first[..], &second[..]] {
+            crate::sink::insert_measurements(&config, &mut db, &station, measurements, true)
+                .await
+                .unwrap();
+        }
+
+        let crate::sink::Database::Sqlite(connection) = db else {
+            unreachable!()
+        };
+        let row: (u32, f64, f64, f64) = connection
+            .query_row(
+                "select samples, temp_sum, temp_avg, humidity_min from measurement_hourly",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .unwrap();
+
+        assert_eq!(row, (3, 66.0, 22.0, 46.0));
+
+        drop(connection);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,253 @@
+//! Notifying webhooks when the measurements cross the thresholds of the alert rules.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use chrono::{DateTime, FixedOffset};
+use rust_decimal::Decimal;
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn, Instrument, Span};
+
+use crate::{
+    config::{AlertField, AlertRule, Config, Station, TemperatureUnit},
+    serialize_decimal, serialize_optional_decimal, Measurement,
+};
+
+/// How long notifying a webhook may take before it's given up.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What's POSTed to the webhook of a rule which fired.
+#[derive(Debug, serde::Serialize)]
+struct Alert {
+    station_id: i32,
+    timestamp: DateTime<FixedOffset>,
+    #[serde(serialize_with = "serialize_decimal")]
+    value: Decimal,
+    /// The unit of the value and the thresholds, `°C`, `°F` or `%`.
+    unit: &'static str,
+    rule: FiredRule,
+}
+
+/// The rule which fired, without the webhook which may well contain a token.
+#[derive(Debug, serde::Serialize)]
+struct FiredRule {
+    index: usize,
+    field: AlertField,
+    #[serde(serialize_with = "serialize_optional_decimal")]
+    below: Option<Decimal>,
+    #[serde(serialize_with = "serialize_optional_decimal")]
+    above: Option<Decimal>,
+}
+
+/// The alert rules of a run, each of which notifies its webhook at most once.
+pub struct Alerts {
+    client: reqwest::Client,
+    fired: Vec<AtomicBool>,
+    notifying: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl Alerts {
+    pub fn new(config: &Config) -> Alerts {
+        Alerts {
+            client: reqwest::Client::builder()
+                .timeout(WEBHOOK_TIMEOUT)
+                .build()
+                .expect("the webhook client should build"),
+            fired: config
+                .alerts
+                .iter()
+                .map(|_| AtomicBool::new(false))
+                .collect(),
+            notifying: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Checks the `measurements` of `station` against the rules which haven't fired yet in this
+    /// run, and notifies the webhooks of those crossed in the background, so neither a slow nor a
+    /// failing one holds up the inserts. The temperature thresholds are in `output_units`.
+    pub fn check(&self, config: &Config, station: &Station, measurements: &[Measurement]) {
+        let temp_unit = config.output_units.temperature;
+
+        for (index, rule) in config.alerts.iter().enumerate() {
+            if self.fired[index].load(Ordering::Relaxed) {
+                continue;
+            }
+
+            let Some((measurement, value)) = measurements.iter().find_map(|measurement| {
+                Some((measurement, crossed(rule, measurement, temp_unit)?))
+            }) else {
+                continue;
+            };
+
+            if self.fired[index].swap(true, Ordering::Relaxed) {
+                continue;
+            }
+
+            let unit = match rule.field {
+                AlertField::Temp => temp_unit.symbol(),
+                AlertField::Humidity => "%",
+            };
+
+            warn!(
+                "{} of {value} {unit} at {} crossed the threshold of alerts[{index}], notifying its \
+                 webhook",
+                field_name(rule.field),
+                measurement.time.to_rfc3339()
+            );
+
+            let alert = Alert {
+                station_id: station.station_id,
+                timestamp: measurement.time,
+                value,
+                unit,
+                rule: FiredRule {
+                    index,
+                    field: rule.field,
+                    below: rule.below,
+                    above: rule.above,
+                },
+            };
+            let body = match serde_json::to_vec(&alert) {
+                Ok(body) => body,
+                Err(err) => {
+                    error!("serializing the alert of alerts[{index}] failed: {err}");
+                    continue;
+                }
+            };
+            let request = self
+                .client
+                .post(&rule.webhook)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body);
+
+            let notifying = tokio::spawn(
+                async move {
+                    match request
+                        .send()
+                        .await
+                        .and_then(|response| response.error_for_status())
+                    {
+                        Ok(_) => info!("notified the webhook of alerts[{index}]"),
+                        Err(err) => {
+                            error!("notifying the webhook of alerts[{index}] failed: {err}")
+                        }
+                    }
+                }
+                .instrument(Span::current()),
+            );
+
+            self.notifying.lock().unwrap().push(notifying);
+        }
+    }
+
+    /// Waits for the webhooks still being notified.
+    pub async fn finish(self) {
+        for notifying in self.notifying.into_inner().unwrap() {
+            let _ = notifying.await;
+        }
+    }
+}
+
+/// The value of `measurement` if it crosses a threshold of `rule`, the temperature in `temp_unit`.
+/// Measurements outside of the plausible ranges don't, since they are more likely a glitch of the
+/// sensor than an emergency.
+fn crossed(
+    rule: &AlertRule,
+    measurement: &Measurement,
+    temp_unit: TemperatureUnit,
+) -> Option<Decimal> {
+    if measurement.out_of_range {
+        return None;
+    }
+
+    let value = match rule.field {
+        AlertField::Temp => temp_unit.convert(measurement.temp),
+        AlertField::Humidity => measurement.humidity,
+    };
+
+    let below = rule.below.is_some_and(|below| value < below);
+    let above = rule.above.is_some_and(|above| value > above);
+
+    (below || above).then_some(value)
+}
+
+fn field_name(field: AlertField) -> &'static str {
+    match field {
+        AlertField::Temp => "temp",
+        AlertField::Humidity => "humidity",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn measurement(temp: i64, humidity: i64) -> Measurement {
+        Measurement {
+            time: DateTime::parse_from_rfc3339("2024-06-01T12:00:00Z").unwrap(),
+            temp: Decimal::new(temp, 1),
+            humidity: Decimal::new(humidity, 1),
+            uncalibrated: None,
+            out_of_range: false,
+            dew_point: None,
+            perceived_temp: None,
+            absolute_humidity: None,
+            packed: None,
+        }
+    }
+
+    fn rule(field: AlertField, below: Option<i64>, above: Option<i64>) -> AlertRule {
+        AlertRule {
+            field,
+            below: below.map(|below| Decimal::new(below, 1)),
+            above: above.map(|above| Decimal::new(above, 1)),
+            webhook: "http://localhost/".to_string(),
+        }
+    }
+
+    #[test]
+    fn fires_below_and_above_the_thresholds() {
+        let humidity = rule(AlertField::Humidity, Some(400), None);
+        let temp = rule(AlertField::Temp, Some(50), Some(350));
+
+        assert_eq!(
+            crossed(&humidity, &measurement(215, 399), TemperatureUnit::Celsius),
+            Some(Decimal::new(399, 1))
+        );
+        assert_eq!(
+            crossed(&humidity, &measurement(215, 400), TemperatureUnit::Celsius),
+            None
+        );
+        assert_eq!(
+            crossed(&temp, &measurement(351, 480), TemperatureUnit::Celsius),
+            Some(Decimal::new(351, 1))
+        );
+        assert_eq!(
+            crossed(&temp, &measurement(-10, 480), TemperatureUnit::Celsius),
+            Some(Decimal::new(-10, 1))
+        );
+        assert_eq!(
+            crossed(&temp, &measurement(350, 100), TemperatureUnit::Celsius),
+            None
+        );
+    }
+
+    #[test]
+    fn ignores_measurements_out_of_range() {
+        let humidity = rule(AlertField::Humidity, Some(400), None);
+        let measurement = Measurement {
+            out_of_range: true,
+            ..measurement(215, 0)
+        };
+
+        assert_eq!(
+            crossed(&humidity, &measurement, TemperatureUnit::Celsius),
+            None
+        );
+    }
+}
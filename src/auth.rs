@@ -0,0 +1,88 @@
+//! The optional mutual authentication of the host and a Pico with a pre-shared key.
+//!
+//! Right after connecting, before the packed date time:
+//!
+//! 1. The Pico sends a random challenge of [`CHALLENGE_LEN`] bytes.
+//! 2. The host answers with its [`proof`] of that challenge, followed by a challenge of its own.
+//! 3. The Pico replies [`REJECTED`] and closes the connection if the proof is wrong, or else
+//!    [`ACCEPTED`] followed by its proof of both challenges.
+//!
+//! The byte protocol continues as usual afterwards, unencrypted.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+pub const CHALLENGE_LEN: usize = 16;
+pub const PROOF_LEN: usize = 32;
+
+/// The Pico's reply to a wrong proof of the host.
+pub const REJECTED: u8 = 0;
+/// The Pico's reply to the right proof of the host, followed by its own.
+pub const ACCEPTED: u8 = 1;
+
+/// Who proves to know the key, so neither proof can be replayed as the other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Host,
+    Pico,
+}
+
+/// The HMAC-SHA256 with `psk` over `side` and `challenges`.
+fn mac(psk: &[u8], side: Side, challenges: &[&[u8]]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(psk).expect("HMAC takes keys of any length");
+
+    mac.update(match side {
+        Side::Host => b"host",
+        Side::Pico => b"pico",
+    });
+
+    for challenge in challenges {
+        mac.update(challenge);
+    }
+
+    mac
+}
+
+/// The proof of `side` knowing `psk`, over the Pico's challenge for the host and over the host's
+/// and then the Pico's challenge for the Pico.
+pub fn proof(psk: &[u8], side: Side, challenges: &[&[u8]]) -> [u8; PROOF_LEN] {
+    mac(psk, side, challenges).finalize().into_bytes().into()
+}
+
+/// Whether `proof` is the one of `side` knowing `psk`, compared in constant time.
+pub fn verify(psk: &[u8], side: Side, challenges: &[&[u8]], proof: &[u8]) -> bool {
+    mac(psk, side, challenges).verify_slice(proof).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verifies_only_the_right_proof() {
+        let challenge = [7; CHALLENGE_LEN];
+        let host = proof(b"secret", Side::Host, &[&challenge]);
+
+        assert!(verify(b"secret", Side::Host, &[&challenge], &host));
+        assert!(!verify(b"guessed", Side::Host, &[&challenge], &host));
+        assert!(!verify(
+            b"secret",
+            Side::Host,
+            &[&[8; CHALLENGE_LEN]],
+            &host
+        ));
+        assert!(!verify(b"secret", Side::Host, &[&challenge], &host[..16]));
+    }
+
+    #[test]
+    fn tells_the_sides_apart() {
+        let challenge = [7; CHALLENGE_LEN];
+
+        assert!(!verify(
+            b"secret",
+            Side::Pico,
+            &[&challenge],
+            &proof(b"secret", Side::Host, &[&challenge])
+        ));
+    }
+}
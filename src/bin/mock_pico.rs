@@ -0,0 +1,332 @@
+//! A stand-in for the Pico that serves synthetic measurements, for testing the program without
+//! the hardware.
+
+use std::{f64::consts::TAU, io::ErrorKind};
+
+use anyhow::anyhow;
+use chrono::{Duration, NaiveDateTime, Timelike};
+use clap::Parser;
+use pico_humidity_temp_read::{
+    auth::{self, Side, ACCEPTED, CHALLENGE_LEN, PROOF_LEN, REJECTED},
+    protocol::{
+        encode_measurement, unpack_datetime, unpack_precise_datetime, PROTOCOL_VERSION,
+        TIME_SYNC_PROBE, TIME_SYNC_SKIP,
+    },
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+/// Listens like the Pico does and sends synthetic measurements to every connecting client.
+#[derive(Debug, Clone, Parser)]
+#[command(version)]
+struct Args {
+    /// Port to listen on, or 0 for any free port
+    #[arg(long, default_value_t = 60438)]
+    port: u16,
+    /// Number of measurements to announce and send
+    #[arg(long, default_value_t = 96)]
+    count: u32,
+    /// Seconds between the times of consecutive measurements, the last one being taken at the
+    /// time received from the client
+    #[arg(long, default_value_t = 900)]
+    interval_secs: u32,
+    /// Take the last measurement at this time, as `YYYY-MM-DDTHH:MM:SS`, instead of the one
+    /// received from the client, so every connection gets the same measurements
+    #[arg(long, value_name = "TIME")]
+    last_time: Option<NaiveDateTime>,
+    /// Don't answer the version handshake, like firmware before it
+    #[arg(long)]
+    legacy_protocol: bool,
+    /// Expect the time in whole seconds without the probe of the millisecond time sync, like
+    /// firmware before it
+    #[arg(long)]
+    legacy_time_sync: bool,
+    /// Send the CRC32 of the measurements after them
+    #[arg(long)]
+    checksum: bool,
+    /// Wait for the acknowledgement after the measurements and print it
+    #[arg(long)]
+    ack: bool,
+    /// Close the connection after sending this many of the measurements
+    #[arg(long, value_name = "N")]
+    truncate_after: Option<u32>,
+    /// Send an invalid date as the time of the measurement at this index
+    #[arg(long, value_name = "INDEX")]
+    invalid_date_at: Option<u32>,
+    /// Wait this many seconds before sending the measurements
+    #[arg(long, value_name = "M")]
+    stall_secs: Option<u64>,
+    /// Authenticate the client with this pre-shared key before the time
+    #[arg(long, value_name = "KEY")]
+    psk: Option<String>,
+    /// Serve a single connection, then exit
+    #[arg(long)]
+    once: bool,
+    /// Connect to the host listening at this address, like the Pico does in its listen mode,
+    /// instead of listening; serves that single connection, retrying for 5s while the host isn't
+    /// listening yet
+    #[arg(long, value_name = "ADDRESS")]
+    connect: Option<String>,
+    /// Send this station id as a byte first after connecting with --connect
+    #[arg(long, value_name = "ID", requires = "connect")]
+    station_byte: Option<u8>,
+}
+
+/// Returns a plausible temperature and humidity in tenths for `time`, peaking respectively
+/// bottoming out in the afternoon.
+fn synthetic_weather(time: &NaiveDateTime) -> (i32, i32) {
+    let day = f64::from(time.num_seconds_from_midnight()) / 86_400.0;
+    let wave = (TAU * (day - 0.375)).sin();
+
+    (
+        (200.0 + 40.0 * wave).round() as i32,
+        (550.0 - 150.0 * wave).round() as i32,
+    )
+}
+
+async fn serve(args: &Args, stream: &mut TcpStream) -> anyhow::Result<()> {
+    if let Some(psk) = &args.psk {
+        if !authenticate(stream, psk.as_bytes()).await? {
+            println!("rejected the key of the client");
+            return stream
+                .shutdown()
+                .await
+                .map_err(|err| anyhow!("Error closing the connection: {err}"));
+        }
+        println!("authenticated the client");
+    }
+
+    let now = match receive_time(args, stream).await? {
+        Some(now) => {
+            println!("received time {now}");
+            now
+        }
+        None => {
+            println!("client left the clock alone");
+            chrono::Local::now().naive_local()
+        }
+    };
+    let now = args.last_time.unwrap_or(now);
+
+    if !args.legacy_protocol {
+        let version = match stream.read_u8().await {
+            Ok(version) => version,
+            // Only setting the clock.
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
+                println!("client closed the connection after the time");
+                return Ok(());
+            }
+            Err(err) => return Err(anyhow!("Error reading the protocol version: {err}")),
+        };
+        println!("client speaks protocol version {version}");
+
+        stream
+            .write_u8(PROTOCOL_VERSION)
+            .await
+            .map_err(|err| anyhow!("Error sending the protocol version: {err}"))?;
+    }
+
+    let mut bytes = args.count.to_le_bytes().to_vec();
+    let mut checksum = crc32fast::Hasher::new();
+
+    for index in 0..args.truncate_after.unwrap_or(args.count).min(args.count) {
+        let time = now
+            - Duration::seconds(i64::from(args.interval_secs) * i64::from(args.count - 1 - index));
+        let (temp, humidity) = synthetic_weather(&time);
+        let mut packed = encode_measurement(&time, temp, humidity);
+
+        if args.invalid_date_at == Some(index) {
+            // Month 13.
+            packed |= 0b1100 << 22;
+        }
+
+        checksum.update(&packed.to_le_bytes());
+        bytes.extend_from_slice(&packed.to_le_bytes());
+    }
+
+    if args.checksum && args.truncate_after.is_none() {
+        bytes.extend_from_slice(&checksum.finalize().to_le_bytes());
+    }
+
+    if let Some(stall_secs) = args.stall_secs {
+        stream
+            .write_all(&bytes[..4])
+            .await
+            .map_err(|err| anyhow!("Error sending the measurement count: {err}"))?;
+        bytes.drain(..4);
+
+        println!("stalling for {stall_secs} seconds");
+        tokio::time::sleep(std::time::Duration::from_secs(stall_secs)).await;
+    }
+
+    stream
+        .write_all(&bytes)
+        .await
+        .map_err(|err| anyhow!("Error sending the measurements: {err}"))?;
+    println!("sent {} measurement bytes", bytes.len());
+
+    if args.ack && args.truncate_after.is_none() {
+        let mut ack = [0; 4];
+
+        match stream.read_exact(&mut ack).await {
+            Ok(_) => println!(
+                "acknowledged {} measurements ({ack:02x?})",
+                u32::from_le_bytes(ack)
+            ),
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                println!("received no acknowledgement");
+            }
+            Err(err) => return Err(anyhow!("Error reading the acknowledgement: {err}")),
+        }
+    }
+
+    stream
+        .shutdown()
+        .await
+        .map_err(|err| anyhow!("Error closing the connection: {err}"))
+}
+
+/// Reads the time the client sets the clock to, echoing the probe of the millisecond time sync
+/// first unless `--legacy-time-sync` is given. Returns `None` if the client skips setting it.
+async fn receive_time(
+    args: &Args,
+    stream: &mut TcpStream,
+) -> anyhow::Result<Option<NaiveDateTime>> {
+    if args.legacy_time_sync {
+        let mut packed_time = [0; 6];
+        stream
+            .read_exact(&mut packed_time)
+            .await
+            .map_err(|err| anyhow!("Error reading the time: {err}"))?;
+
+        return unpack_datetime(packed_time)
+            .map(Some)
+            .ok_or_else(|| anyhow!("Received invalid time {packed_time:02x?}"));
+    }
+
+    let probe = stream
+        .read_u8()
+        .await
+        .map_err(|err| anyhow!("Error reading the time sync probe: {err}"))?;
+    if probe != TIME_SYNC_PROBE && probe != TIME_SYNC_SKIP {
+        return Err(anyhow!(
+            "Received {probe:#04x} instead of the time sync probe"
+        ));
+    }
+    stream
+        .write_u8(probe)
+        .await
+        .map_err(|err| anyhow!("Error echoing the time sync probe: {err}"))?;
+
+    if probe == TIME_SYNC_SKIP {
+        return Ok(None);
+    }
+
+    let mut packed_time = [0; 8];
+    stream
+        .read_exact(&mut packed_time)
+        .await
+        .map_err(|err| anyhow!("Error reading the time: {err}"))?;
+
+    unpack_precise_datetime(packed_time)
+        .map(Some)
+        .ok_or_else(|| anyhow!("Received invalid time {packed_time:02x?}"))
+}
+
+/// Challenges the client to prove it knows `psk`, proving the same in return if it does. Returns
+/// whether it did.
+async fn authenticate(stream: &mut TcpStream, psk: &[u8]) -> anyhow::Result<bool> {
+    let pico_challenge: [u8; CHALLENGE_LEN] = rand::random();
+    stream
+        .write_all(&pico_challenge)
+        .await
+        .map_err(|err| anyhow!("Error sending the challenge: {err}"))?;
+
+    let mut answer = [0; PROOF_LEN + CHALLENGE_LEN];
+    stream
+        .read_exact(&mut answer)
+        .await
+        .map_err(|err| anyhow!("Error reading the proof: {err}"))?;
+    let (host_proof, host_challenge) = answer.split_at(PROOF_LEN);
+
+    if !auth::verify(psk, Side::Host, &[&pico_challenge], host_proof) {
+        stream
+            .write_u8(REJECTED)
+            .await
+            .map_err(|err| anyhow!("Error sending the verdict: {err}"))?;
+        return Ok(false);
+    }
+
+    let mut reply = vec![ACCEPTED];
+    reply.extend_from_slice(&auth::proof(
+        psk,
+        Side::Pico,
+        &[host_challenge, &pico_challenge],
+    ));
+    stream
+        .write_all(&reply)
+        .await
+        .map_err(|err| anyhow!("Error sending the proof: {err}"))?;
+
+    Ok(true)
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+
+    if let Some(address) = &args.connect {
+        let mut attempts = 0;
+        let mut stream = loop {
+            match TcpStream::connect(address).await {
+                Ok(stream) => break stream,
+                Err(err) if err.kind() == ErrorKind::ConnectionRefused && attempts < 50 => {
+                    attempts += 1;
+                    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                }
+                Err(err) => return Err(anyhow!("Error connecting to {address}: {err}")),
+            }
+        };
+        println!("connected to {address}");
+
+        if let Some(station_id) = args.station_byte {
+            stream
+                .write_u8(station_id)
+                .await
+                .map_err(|err| anyhow!("Error writing the station id: {err}"))?;
+        }
+
+        return serve(&args, &mut stream).await;
+    }
+
+    let listener = TcpListener::bind(("127.0.0.1", args.port))
+        .await
+        .map_err(|err| anyhow!("Error binding to port {}: {err}", args.port))?;
+    println!(
+        "listening on {}",
+        listener
+            .local_addr()
+            .map_err(|err| anyhow!("Error getting the listening address: {err}"))?
+    );
+
+    loop {
+        let (mut stream, peer) = listener
+            .accept()
+            .await
+            .map_err(|err| anyhow!("Error accepting a connection: {err}"))?;
+        println!("accepted connection from {peer}");
+
+        if args.once {
+            return serve(&args, &mut stream).await;
+        }
+
+        let args = args.clone();
+        tokio::spawn(async move {
+            if let Err(err) = serve(&args, &mut stream).await {
+                eprintln!("{peer}: {err}");
+            }
+        });
+    }
+}
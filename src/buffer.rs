@@ -0,0 +1,401 @@
+//! Inserting the measurements into a local SQLite database while PostgreSQL can't be reached, and
+//! from there into PostgreSQL once it's back.
+
+use std::path::Path;
+
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
+use rust_decimal::{prelude::FromPrimitive, Decimal};
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+use crate::{
+    config::{Config, DerivedMetric, Station, Storage},
+    pico::MEASUREMENT_CHUNK,
+    sink::{connect_database, insert_measurements, quote_identifier, Database, QuotedTable},
+    Measurement,
+};
+
+/// Held while the buffer is inserted into or drained, since the sessions of listen mode may do
+/// either at the same time.
+static BUFFER: Mutex<()> = Mutex::const_new(());
+
+/// A measurement read from the buffer.
+struct Buffered {
+    rowid: i64,
+    station_id: i32,
+    measurement: Measurement,
+}
+
+/// The config inserting into the buffer at `path`: its measurement table is the one of `config`,
+/// without a station table to reference or aggregates, since the measurements are only kept there
+/// until they're inserted into PostgreSQL.
+fn buffer_config(config: &Config, path: &Path) -> Config {
+    Config {
+        storage: Storage::Sqlite {
+            path: path.to_owned(),
+        },
+        station_table: false,
+        skip_known: false,
+        aggregate: None,
+        ..config.clone()
+    }
+}
+
+/// Opens the buffer at `path`, creating it if it doesn't exist.
+async fn open_buffer(config: &Config, path: &Path) -> anyhow::Result<rusqlite::Connection> {
+    match connect_database(config).await {
+        Ok(Database::Sqlite(connection)) => Ok(connection),
+        Ok(_) => Err(anyhow!(
+            "Error opening the buffer {}: not SQLite",
+            path.display()
+        )),
+        Err(err) => Err(anyhow!(
+            "Error opening the buffer {}: {err}",
+            path.display()
+        )),
+    }
+}
+
+/// Inserts `measurements` of `station` into the buffer, deleting the oldest measurements in it
+/// beyond `buffer_max_rows`.
+pub async fn buffer_measurements(
+    config: &Config,
+    station: &Station,
+    measurements: &[Measurement],
+) -> anyhow::Result<()> {
+    let Some(path) = &config.buffer_path else {
+        return Err(anyhow!(
+            "Error buffering the measurements: no buffer_path configured"
+        ));
+    };
+    let _buffer = BUFFER.lock().await;
+
+    let config = buffer_config(config, path);
+    let mut db = Database::Sqlite(open_buffer(&config, path).await?);
+
+    let summary = insert_measurements(&config, &mut db, station, measurements, false)
+        .await
+        .map_err(|err| anyhow!("Error inserting into the buffer {}: {err}", path.display()))?;
+
+    if let Some(failure) = summary.failures.first() {
+        return Err(anyhow!(
+            "Error inserting the measurement at {} into the buffer {}: {}",
+            failure.time.to_rfc3339(),
+            path.display(),
+            failure.error
+        ));
+    }
+
+    let Database::Sqlite(connection) = db else {
+        return Ok(());
+    };
+
+    let QuotedTable { name, at, .. } = QuotedTable::new(&config.table);
+    let evicted = connection
+        .execute(
+            &format!(
+                "delete from {name} where rowid in (
+                    select rowid from {name} order by {at}
+                        limit max(0, (select count(*) from {name}) - ?1)
+                )"
+            ),
+            [i64::try_from(config.buffer_max_rows).unwrap_or(i64::MAX)],
+        )
+        .map_err(|err| anyhow!("Error deleting from the buffer {}: {err}", path.display()))?;
+
+    if evicted > 0 {
+        warn!(
+            "deleted the oldest {evicted} measurements in the buffer {}, which holds no more than \
+             buffer_max_rows ({})",
+            path.display(),
+            config.buffer_max_rows
+        );
+    }
+
+    Ok(())
+}
+
+/// Inserts the buffered measurements into `db`, deleting them from the buffer. If inserting fails,
+/// the measurements which weren't inserted yet are left in it.
+pub async fn drain_buffer(
+    config: &Config,
+    stations: &[Station],
+    db: &mut Database,
+) -> anyhow::Result<()> {
+    let Some(path) = &config.buffer_path else {
+        return Ok(());
+    };
+
+    if !path.exists() {
+        return Ok(());
+    }
+
+    let _buffer = BUFFER.lock().await;
+
+    let buffer_config = buffer_config(config, path);
+    let mut buffer = open_buffer(&buffer_config, path).await?;
+
+    // The buffered measurements may well be older than the ones stored since.
+    let config = Config {
+        skip_known: false,
+        ..config.clone()
+    };
+
+    let mut drained = 0;
+    let mut inserted = 0;
+
+    loop {
+        let mut group = buffered_measurements(&buffer_config, &buffer)
+            .map_err(|err| anyhow!("Error reading the buffer {}: {err}", path.display()))?;
+
+        let Some(station_id) = group.first().map(|buffered| buffered.station_id) else {
+            break;
+        };
+        group.retain(|buffered| buffered.station_id == station_id);
+
+        let station = stations
+            .iter()
+            .find(|station| station.station_id == station_id)
+            .cloned()
+            .unwrap_or_else(|| Station {
+                pico: String::new(),
+                pico_port: 0,
+                station_id,
+                ..Config::default().stations[0].clone()
+            });
+
+        let measurements: Vec<_> = group
+            .iter()
+            .map(|buffered| buffered.measurement.clone())
+            .collect();
+
+        match insert_measurements(&config, db, &station, &measurements, false).await {
+            Ok(summary) => {
+                inserted += summary.inserted;
+
+                for failure in &summary.failures {
+                    warn!(
+                        station_id,
+                        "dropping the buffered measurement at {}, which was rejected: {}",
+                        failure.time.to_rfc3339(),
+                        failure.error
+                    );
+                }
+            }
+            Err(err) => {
+                return Err(anyhow!(
+                    "Error draining the buffer {}, the measurements not inserted yet are left in \
+                     it: {err}",
+                    path.display()
+                ))
+            }
+        }
+
+        delete_buffered(&buffer_config, &mut buffer, &group)
+            .map_err(|err| anyhow!("Error deleting from the buffer {}: {err}", path.display()))?;
+        drained += group.len();
+    }
+
+    if drained > 0 {
+        info!("drained {drained} buffered measurements, inserted {inserted} new");
+    }
+
+    Ok(())
+}
+
+/// The next [`MEASUREMENT_CHUNK`] measurements in the buffer, by station and time. The optional
+/// columns the config doesn't need are read as absent.
+fn buffered_measurements(
+    config: &Config,
+    connection: &rusqlite::Connection,
+) -> rusqlite::Result<Vec<Buffered>> {
+    let QuotedTable {
+        name,
+        at,
+        station_id,
+        temp,
+        humidity,
+    } = QuotedTable::new(&config.table);
+    let optional = |present: bool, column: &str, absent: &str| match present {
+        true => quote_identifier(column),
+        false => absent.to_string(),
+    };
+
+    let query = format!(
+        "select rowid, {at}, {station_id}, {temp}, {humidity}, {}, {}, {}, {}, {}, {}, {} from {name}
+            order by {station_id}, {at} limit {MEASUREMENT_CHUNK}",
+        optional(config.stores_raw(), "temp_raw", "null"),
+        optional(config.stores_raw(), "humidity_raw", "null"),
+        optional(config.flags_out_of_range(), "out_of_range", "0"),
+        optional(config.compute_dew_point, "dew_point", "null"),
+        match config.perceived_temp {
+            Some(perceived_temp) => quote_identifier(perceived_temp.column()),
+            None => "null".to_string(),
+        },
+        optional(
+            config.derives(DerivedMetric::AbsoluteHumidity),
+            "absolute_humidity",
+            "null"
+        ),
+        optional(config.store_raw_packed, "raw", "null"),
+    );
+
+    let mut statement = connection.prepare(&query)?;
+    let rows = statement.query_map([], |row| {
+        let temp = decimal(row.get(3)?);
+        let humidity = decimal(row.get(4)?);
+        let raw: (Option<f64>, Option<f64>) = (row.get(5)?, row.get(6)?);
+
+        Ok(Buffered {
+            rowid: row.get(0)?,
+            station_id: row.get(2)?,
+            measurement: Measurement {
+                time: row.get::<_, DateTime<Utc>>(1)?.fixed_offset(),
+                temp,
+                humidity,
+                uncalibrated: match raw {
+                    (Some(raw_temp), Some(raw_humidity)) => {
+                        Some((decimal(raw_temp), decimal(raw_humidity)))
+                            .filter(|raw| *raw != (temp, humidity))
+                    }
+                    _ => None,
+                },
+                out_of_range: row.get(7)?,
+                dew_point: row.get::<_, Option<f64>>(8)?.map(decimal),
+                perceived_temp: row.get::<_, Option<f64>>(9)?.map(decimal),
+                absolute_humidity: row.get::<_, Option<f64>>(10)?.map(decimal),
+                packed: row.get(11)?,
+            },
+        })
+    })?;
+
+    rows.collect()
+}
+
+fn decimal(value: f64) -> Decimal {
+    Decimal::from_f64(value).unwrap_or_default()
+}
+
+/// Deletes the `buffered` measurements from the buffer in one transaction.
+fn delete_buffered(
+    config: &Config,
+    connection: &mut rusqlite::Connection,
+    buffered: &[Buffered],
+) -> rusqlite::Result<()> {
+    let transaction = connection.transaction()?;
+
+    {
+        let mut statement = transaction.prepare(&format!(
+            "delete from {} where rowid = ?1",
+            quote_identifier(&config.table.name)
+        ))?;
+
+        for buffered in buffered {
+            statement.execute([buffered.rowid])?;
+        }
+    }
+
+    transaction.commit()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn measurements(count: i64) -> Vec<Measurement> {
+        (0..count)
+            .map(|minute| Measurement {
+                time: DateTime::parse_from_rfc3339("2024-06-01T12:00:00Z").unwrap()
+                    + chrono::Duration::minutes(minute),
+                temp: Decimal::new(207, 1),
+                humidity: Decimal::new(520, 1),
+                uncalibrated: Some((Decimal::new(215, 1), Decimal::new(480, 1))),
+                out_of_range: false,
+                dew_point: Some(Decimal::new(102, 1)),
+                perceived_temp: None,
+                absolute_humidity: Some(Decimal::new(912, 2)),
+                packed: Some(0x0123_4567_89ab_cdef),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn keeps_the_latest_measurements_up_to_the_cap() {
+        let dir = std::env::temp_dir().join("pico_humidity_temp_read_buffer_cap");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join("buffer.db");
+        let mut config = Config {
+            buffer_path: Some(path.clone()),
+            buffer_max_rows: 2,
+            compute_dew_point: true,
+            derived_metrics: vec![DerivedMetric::AbsoluteHumidity],
+            store_raw_packed: true,
+            ..Config::default()
+        };
+        config.stations[0].store_raw = true;
+        let station = config.stations[0].clone();
+        let measurements = measurements(3);
+
+        buffer_measurements(&config, &station, &measurements)
+            .await
+            .unwrap();
+
+        let buffer_config = buffer_config(&config, &path);
+        let buffer = open_buffer(&buffer_config, &path).await.unwrap();
+        let buffered: Vec<_> = buffered_measurements(&buffer_config, &buffer)
+            .unwrap()
+            .into_iter()
+            .map(|buffered| buffered.measurement)
+            .collect();
+
+        assert_eq!(buffered, measurements[1..]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn drains_the_buffer_into_the_database() {
+        let dir = std::env::temp_dir().join("pico_humidity_temp_read_buffer_drain");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let config = Config {
+            storage: Storage::Sqlite {
+                path: dir.join("measurements.db"),
+            },
+            buffer_path: Some(dir.join("buffer.db")),
+            ..Config::default()
+        };
+        let station = config.stations[0].clone();
+        let measurements = measurements(3);
+
+        buffer_measurements(&config, &station, &measurements)
+            .await
+            .unwrap();
+
+        let mut db = connect_database(&config).await.unwrap();
+        drain_buffer(&config, std::slice::from_ref(&station), &mut db)
+            .await
+            .unwrap();
+
+        let buffer_config = buffer_config(&config, &dir.join("buffer.db"));
+        let buffer = open_buffer(&buffer_config, &dir.join("buffer.db"))
+            .await
+            .unwrap();
+
+        assert!(buffered_measurements(&buffer_config, &buffer)
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            db.latest_time(&config, &station).await.unwrap(),
+            Some(measurements[2].time.to_utc())
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
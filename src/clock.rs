@@ -0,0 +1,95 @@
+//! Checking the host's clock before setting the ones of the Picos with it, so a host booting with
+//! a wrong clock, like a Raspberry Pi without a real-time clock before NTP synchronized it, doesn't
+//! corrupt the times of the measurements.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::{anyhow, bail};
+use chrono::SubsecRound;
+use tracing::warn;
+
+use crate::config::Config;
+
+/// Whether `--force-time-sync` is given.
+static FORCED: AtomicBool = AtomicBool::new(false);
+
+/// Sets the clocks of the Picos even if the host's looks wrong, for `--force-time-sync`.
+pub fn force_time_sync() {
+    FORCED.store(true, Ordering::Relaxed);
+}
+
+/// Why the host's clock looks wrong according to `min_plausible_time` and `require_ntp_sync`, if
+/// it does and `--force-time-sync` isn't given.
+pub async fn host_clock_problem(config: &Config) -> Option<String> {
+    if FORCED.load(Ordering::Relaxed) {
+        return None;
+    }
+
+    let now = config.pico_timezone.now();
+
+    if let Some(min_plausible_time) = config.min_plausible_time {
+        if now.date() < min_plausible_time {
+            return Some(format!(
+                "the host's clock shows {}, before min_plausible_time ({min_plausible_time})",
+                now.trunc_subsecs(0)
+            ));
+        }
+    }
+
+    if config.require_ntp_sync {
+        match ntp_synchronized().await {
+            Ok(true) => {}
+            Ok(false) => {
+                return Some("timedated reports the host's clock as not synchronized".to_string())
+            }
+            Err(err) => warn!("not checking whether the host's clock is synchronized: {err}"),
+        }
+    }
+
+    None
+}
+
+/// Whether timedated reports the host's clock as synchronized by chronyd, systemd-timesyncd or
+/// another NTP client, asked over D-Bus with `timedatectl`.
+async fn ntp_synchronized() -> anyhow::Result<bool> {
+    let output = tokio::process::Command::new("timedatectl")
+        .args(["show", "--property=NTPSynchronized", "--value"])
+        .output()
+        .await
+        .map_err(|err| anyhow!("Error running timedatectl: {err}"))?;
+
+    if !output.status.success() {
+        bail!(
+            "timedatectl failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    match String::from_utf8_lossy(&output.stdout).trim() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        other => bail!("timedatectl reported NTPSynchronized={other:?}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn rejects_clocks_before_the_min_plausible_time() {
+        let config = |year| Config {
+            min_plausible_time: NaiveDate::from_ymd_opt(year, 1, 1),
+            ..Config::default()
+        };
+
+        let problem = host_clock_problem(&config(9999)).await.unwrap();
+        assert!(
+            problem.ends_with("before min_plausible_time (9999-01-01)"),
+            "{problem}"
+        );
+        assert_eq!(host_clock_problem(&config(2024)).await, None);
+    }
+}
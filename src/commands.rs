@@ -0,0 +1,218 @@
+//! The subcommands which don't download the measurements of the Picos.
+
+use std::{future::Future, process::ExitCode, time::Duration};
+
+use anyhow::anyhow;
+use chrono::{DateTime, FixedOffset, LocalResult, NaiveDate, NaiveDateTime, Utc};
+use futures::{stream, FutureExt, StreamExt};
+use tracing::{error, info_span, Instrument};
+
+use crate::{
+    config::{Config, PicoTimezone, Station},
+    error::Error,
+    failure::{Categorize, Failure},
+    pico::{measurement_count, ping, sync_time},
+    protocol::{decode_measurement, PROTOCOL_VERSION},
+    shutdown::{INTERRUPTED_EXIT_CODE, SHUTDOWN},
+};
+
+/// Sets the clock of the Pico of every station, printing the time each was set to.
+pub async fn sync_clocks(config: &Config, stations: &[Station]) -> ExitCode {
+    for_each_station(config, stations, sync_time, |station, time| {
+        println!("station {}: clock set to {time}", station.station_id);
+    })
+    .await
+}
+
+/// Prints how many measurements the Pico of every station has stored.
+pub async fn print_status(config: &Config, stations: &[Station]) -> ExitCode {
+    for_each_station(config, stations, measurement_count, |station, count| {
+        println!(
+            "station {}: {count} measurements stored, {:.1}% of the flash",
+            station.station_id,
+            station.flash_usage(count)
+        );
+    })
+    .await
+}
+
+/// Checks that the Pico of every station answers within `timeout`, printing how many measurements
+/// it has pending and how long it took to announce them.
+pub async fn ping_stations(config: &Config, stations: &[Station], timeout: Duration) -> ExitCode {
+    let ping = |config, station| ping(config, station, timeout);
+
+    for_each_station(config, stations, ping, |station, ping| {
+        println!(
+            "station {}: reachable, {} measurements pending, rtt {:.0?}",
+            station.station_id, ping.pending, ping.round_trip
+        );
+    })
+    .await
+}
+
+/// Parses a packed measurement given in decimal or as hex with `0x`, like the `raw` column and the
+/// debug log show it.
+pub fn parse_packed(text: &str) -> Result<u64, String> {
+    let text = text.trim().replace('_', "");
+
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => text.parse(),
+    }
+    .map_err(|err| format!("not a packed measurement in decimal or hex: {err}"))
+}
+
+/// A time given on the command line: an instant, or a wall clock time in `pico_timezone`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeArg {
+    Instant(DateTime<FixedOffset>),
+    Local(NaiveDateTime),
+}
+
+impl TimeArg {
+    /// The instant this time refers to in `timezone`, the earlier one if it's ambiguous.
+    pub fn instant(self, timezone: PicoTimezone) -> anyhow::Result<DateTime<Utc>> {
+        match self {
+            TimeArg::Instant(time) => Ok(time.to_utc()),
+            TimeArg::Local(time) => match timezone.resolve(&time) {
+                LocalResult::Single(time) => Ok(time.to_utc()),
+                LocalResult::Ambiguous(first, second) => Ok(first.min(second).to_utc()),
+                LocalResult::None => Err(anyhow!("{time} doesn't exist in pico_timezone")),
+            },
+        }
+    }
+}
+
+/// Parses a time in RFC 3339, or as `YYYY-MM-DD HH:MM:SS` or a date (meaning its midnight) in
+/// `pico_timezone`.
+pub fn parse_time(text: &str) -> Result<TimeArg, String> {
+    let text = text.trim();
+
+    if let Ok(time) = DateTime::parse_from_rfc3339(text) {
+        return Ok(TimeArg::Instant(time));
+    }
+
+    NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M:%S")
+        .or_else(|_| NaiveDate::parse_from_str(text, "%Y-%m-%d").map(|date| date.into()))
+        .map(TimeArg::Local)
+        .map_err(|_| {
+            format!("{text:?} isn't a time in RFC 3339, YYYY-MM-DD HH:MM:SS or YYYY-MM-DD")
+        })
+}
+
+/// Prints the measurement `packed` decodes to, before any calibration, as a JSON line.
+pub fn print_decoded(config: &Config, packed: u64) -> anyhow::Result<()> {
+    let measurement = decode_measurement(config, PROTOCOL_VERSION, packed)
+        .map_err(|err| anyhow!("Error decoding {packed:#x}: {err}"))
+        .category(Failure::Decode)?
+        .ok_or_else(|| {
+            Failure::Decode.tag(anyhow!(
+                "Error decoding {packed:#x}: its time doesn't map to a single instant in pico_timezone, and such measurements are skipped"
+            ))
+        })?;
+
+    println!(
+        "{}",
+        serde_json::to_string(&measurement)
+            .map_err(|err| anyhow!("Error serializing the measurement: {err}"))?
+    );
+
+    Ok(())
+}
+
+/// Runs `operation` for up to `max_concurrent_fetches` stations at once, handing the results to
+/// `report` in the order of the stations. Returns the exit code of the first failure, if any.
+async fn for_each_station<'a, T, F: Future<Output = Result<T, Error>> + 'a>(
+    config: &'a Config,
+    stations: &'a [Station],
+    operation: impl Fn(&'a Config, &'a Station) -> F,
+    report: impl Fn(&Station, T),
+) -> ExitCode {
+    let results: Vec<_> = stream::iter(stations)
+        .map(|station| {
+            operation(config, station)
+                .instrument(info_span!(
+                    "station",
+                    station_id = station.station_id,
+                    pico = %station.pico,
+                    pico_port = station.pico_port
+                ))
+                .map(move |result| (station, result))
+        })
+        .buffered(config.max_concurrent_fetches.get())
+        .collect()
+        .await;
+
+    let mut exit_code = ExitCode::SUCCESS;
+    let mut failed = false;
+
+    for (station, result) in results {
+        match result {
+            Ok(value) => report(station, value),
+            Err(err) => {
+                error!(
+                    station_id = station.station_id,
+                    pico = %station.pico,
+                    pico_port = station.pico_port,
+                    "failed: {err}"
+                );
+
+                if !failed {
+                    exit_code = err.failure().map_or(ExitCode::FAILURE, Failure::exit_code);
+                    failed = true;
+                }
+            }
+        }
+    }
+
+    if SHUTDOWN.is_requested() {
+        ExitCode::from(INTERRUPTED_EXIT_CODE)
+    } else {
+        exit_code
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_decimal_and_hex() {
+        assert_eq!(
+            parse_packed("1081809626418167808"),
+            Ok(0xf03_5c1f_a140_c000)
+        );
+        assert_eq!(parse_packed("0xf035c1fa140c000"), Ok(0xf03_5c1f_a140_c000));
+        assert_eq!(
+            parse_packed(" 0XF03_5C1F_A140_C000 "),
+            Ok(0xf03_5c1f_a140_c000)
+        );
+        assert!(parse_packed("0x").is_err());
+        assert!(parse_packed("-1").is_err());
+    }
+
+    #[test]
+    fn parses_instants_and_local_times() {
+        let local = NaiveDate::from_ymd_opt(2024, 1, 1).unwrap();
+
+        assert_eq!(
+            parse_time("2024-01-01T12:00:00+01:00"),
+            Ok(TimeArg::Instant(
+                DateTime::parse_from_rfc3339("2024-01-01T11:00:00Z").unwrap()
+            ))
+        );
+        assert_eq!(
+            parse_time("2024-01-01 12:30:00"),
+            Ok(TimeArg::Local(local.and_hms_opt(12, 30, 0).unwrap()))
+        );
+        assert_eq!(parse_time("2024-01-01"), Ok(TimeArg::Local(local.into())));
+        assert!(parse_time("01.01.2024").is_err());
+        assert_eq!(
+            TimeArg::Local(local.into())
+                .instant(PicoTimezone::Named(chrono_tz::Europe::Berlin))
+                .unwrap()
+                .to_rfc3339(),
+            "2023-12-31T23:00:00+00:00"
+        );
+    }
+}
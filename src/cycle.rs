@@ -0,0 +1,648 @@
+//! A single fetch of the measurements of every station.
+
+use std::{
+    fmt,
+    path::PathBuf,
+    process::ExitCode,
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use futures::{stream, FutureExt, StreamExt};
+use tokio::{
+    net::TcpStream,
+    sync::{mpsc, oneshot, Mutex},
+};
+use tracing::{debug, error, info, info_span, warn, Instrument, Span};
+
+use crate::{
+    alerts::Alerts,
+    buffer::{buffer_measurements, drain_buffer},
+    config::{Config, Station},
+    failure::Failure,
+    gaps::GapDetector,
+    metrics::{Phase, METRICS},
+    mqtt::publish_mqtt,
+    output::{print_measurements, print_table, Outputs},
+    pico::{fetch_measurements, finish_session, DownloadInfo, Source, CHUNKS_IN_FLIGHT},
+    recording::recording_path,
+    report::StationReport,
+    shutdown::{INTERRUPTED_EXIT_CODE, SHUTDOWN},
+    sink::{connect_database, Database, InsertSummary, StationInsert, REPORTED_ROW_FAILURES},
+    spool::{replay_spool, spool_measurements},
+    telemetry::{failed, traced},
+    Measurement,
+};
+
+/// The target of the summary logged at the end of every cycle, which is logged by default unlike
+/// the other info lines.
+pub const SUMMARY_TARGET: &str = "pico_humidity_temp_read::summary";
+
+/// How the measurements are handled in a cycle.
+#[derive(Debug, Clone, Default)]
+pub struct CycleOptions {
+    /// Print the measurements instead of storing them.
+    pub dry_run: bool,
+    /// Fail the insert of a station at the first measurement the database rejects.
+    pub fail_fast: bool,
+    /// Print the measurements as a table, in addition to storing them unless in a dry run.
+    pub print_table: bool,
+    /// Print only the latest this many measurements in the table.
+    pub last: Option<usize>,
+    /// The run summary is written to stdout, so nothing else may be.
+    pub summary_to_stdout: bool,
+    /// Record the measurements the Picos send here, see [`recording_path`].
+    pub record: Option<PathBuf>,
+}
+
+/// What a cycle achieved.
+#[derive(Debug, Clone)]
+pub struct CycleSummary {
+    pub fetched: usize,
+    pub inserted: u64,
+    /// How many measurements were buffered to be inserted later instead.
+    pub buffered: usize,
+    /// How many stations the measurements were fetched (and stored) from.
+    pub succeeded: usize,
+    pub stations: usize,
+    /// What the program should exit with if this was its only cycle.
+    pub exit_code: ExitCode,
+    /// What happened at every station fetched from.
+    pub reports: Vec<StationReport>,
+    /// The errors which didn't occur at a single station.
+    pub errors: Vec<String>,
+    /// How long connecting to the database and preparing the statements took, if it wasn't
+    /// still connected.
+    pub db_connect: Option<Duration>,
+}
+
+impl fmt::Display for CycleSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} measurements fetched from {} of {} stations, {} inserted",
+            self.fetched, self.succeeded, self.stations, self.inserted
+        )?;
+
+        if self.buffered > 0 {
+            write!(f, ", {} buffered", self.buffered)?;
+        }
+
+        let clocks_not_set = self
+            .reports
+            .iter()
+            .filter(|report| report.clock_not_set.is_some())
+            .count();
+        if clocks_not_set > 0 {
+            write!(
+                f,
+                ", {clocks_not_set} of the Picos' clocks not set since the host's looks wrong"
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Fetches and inserts the measurements of every station once.
+///
+/// The database connection in `database` is reused if it's still open and (re)established
+/// otherwise. In a dry run, the measurements are printed instead and the database is left alone.
+pub async fn run_cycle(
+    options: &CycleOptions,
+    config: &Config,
+    stations: &[Station],
+    database: &mut Option<Database>,
+    outputs: &mut Outputs,
+) -> anyhow::Result<CycleSummary> {
+    let sessions = stations
+        .iter()
+        .map(|station| (station, Source::Connect))
+        .collect();
+
+    run_sessions(
+        options,
+        config,
+        stations,
+        sessions,
+        database,
+        &std::sync::Mutex::new(outputs),
+    )
+    .await
+}
+
+/// Downloads and inserts the measurements of `station` like a cycle of it alone does, over the
+/// connection its Pico opened in listen mode. The other `stations` are only needed to replay the
+/// spool. Several of these may run at once, sharing the `outputs`.
+pub async fn run_accepted(
+    options: &CycleOptions,
+    config: &Config,
+    stations: &[Station],
+    station: &Station,
+    pico_stream: TcpStream,
+    database: &mut Option<Database>,
+    outputs: &std::sync::Mutex<&mut Outputs>,
+) -> anyhow::Result<CycleSummary> {
+    run_sessions(
+        options,
+        config,
+        stations,
+        vec![(station, Source::Accepted(pico_stream))],
+        database,
+        outputs,
+    )
+    .await
+}
+
+/// Decodes and inserts the measurements recorded to the file at `path` like a cycle of `station`
+/// alone does with the ones its Pico sends, without connecting to it.
+pub async fn run_replay(
+    options: &CycleOptions,
+    config: &Config,
+    stations: &[Station],
+    station: &Station,
+    path: PathBuf,
+    database: &mut Option<Database>,
+    outputs: &mut Outputs,
+) -> anyhow::Result<CycleSummary> {
+    run_sessions(
+        options,
+        config,
+        stations,
+        vec![(station, Source::Replay(path))],
+        database,
+        &std::sync::Mutex::new(outputs),
+    )
+    .await
+}
+
+/// Runs [`fetch_sessions`] in the root span of the cycle.
+async fn run_sessions(
+    options: &CycleOptions,
+    config: &Config,
+    stations: &[Station],
+    sessions: Vec<(&Station, Source)>,
+    database: &mut Option<Database>,
+    outputs: &std::sync::Mutex<&mut Outputs>,
+) -> anyhow::Result<CycleSummary> {
+    traced(
+        info_span!("cycle", stations = sessions.len()),
+        fetch_sessions(options, config, stations, sessions, database, outputs),
+    )
+    .await
+}
+
+/// Fetches from every station of `sessions` from its source and inserts the measurements.
+async fn fetch_sessions(
+    options: &CycleOptions,
+    config: &Config,
+    stations: &[Station],
+    sessions: Vec<(&Station, Source)>,
+    database: &mut Option<Database>,
+    outputs: &std::sync::Mutex<&mut Outputs>,
+) -> anyhow::Result<CycleSummary> {
+    let mut errors = Vec::new();
+    let session_count = sessions.len();
+    let mut db_connect = None;
+    let connecting = Instant::now();
+
+    let mut db = if options.dry_run {
+        None
+    } else {
+        match database.take() {
+            Some(db) if !db.is_closed() => Some(database.insert(db)),
+            _ => match connect_database(config).await {
+                Ok(db) => {
+                    let elapsed = connecting.elapsed();
+                    info!("connected to the database in {elapsed:.1?}");
+                    METRICS.observe(Phase::DbConnect, elapsed);
+                    db_connect = Some(elapsed);
+                    Some(database.insert(db))
+                }
+                // Without a database, the measurements can still be buffered or spooled.
+                Err(err) if config.buffer_path.is_some() => {
+                    error!("{err}; buffering the measurements instead");
+                    errors.push(err.to_string());
+                    None
+                }
+                Err(err) if config.spool_path.is_some() => {
+                    error!("{err}; spooling the measurements instead");
+                    errors.push(err.to_string());
+                    None
+                }
+                Err(err) => return Err(err.into()),
+            },
+        }
+    };
+
+    if let Some(db) = db.as_mut() {
+        if let Err(err) = drain_buffer(config, stations, db).await {
+            error!("{err}");
+            errors.push(err.to_string());
+        }
+
+        if let Err(err) = replay_spool(config, stations, db).await {
+            error!("{err}");
+            errors.push(err.to_string());
+        }
+    }
+
+    let db = db.map(Mutex::new);
+    let stdout_taken = outputs.lock().unwrap().writes_to_stdout() || options.summary_to_stdout;
+    let alerts = Alerts::new(config);
+
+    // The downloads run concurrently, the inserts one after another on the shared connection.
+    let results: Vec<_> = stream::iter(sessions)
+        .map(|(station, source)| {
+            async {
+                if SHUTDOWN.is_requested() {
+                    return None;
+                }
+
+                let started = Instant::now();
+                let mut report = StationReport::new(station);
+                let mut download = DownloadInfo::default();
+                let result = async {
+                    // Everything but the insert needs all measurements, only a real run without
+                    // table or MQTT gets away with the chunks in flight.
+                    let keep_all = options.dry_run || options.print_table || config.mqtt.is_some();
+                    let (sender, receiver) = mpsc::channel::<Vec<Measurement>>(CHUNKS_IN_FLIGHT);
+                    // Whether the download succeeded, once it ended.
+                    let (downloaded, download_ended) = oneshot::channel();
+
+                    let store = async {
+                        let report = &mut report;
+                        let mut receiver = receiver;
+                        let mut fetched = 0;
+                        let mut all = Vec::new();
+                        let mut insert =
+                            StationInsert::new(config, station, options.fail_fast, None);
+                        let mut falling_back = db.is_none() && !options.dry_run;
+                        let mut spooled = 0;
+                        let mut buffered = 0;
+                        let mut gaps = GapDetector::new(config);
+                        // Buffers or spools the measurements which can't be inserted.
+                        let mut set_aside = async |measurements: &[Measurement]| {
+                            if config.buffer_path.is_some() {
+                                buffer_measurements(config, station, measurements).await?;
+                                buffered += measurements.len();
+                            } else {
+                                spool_measurements(config, station, measurements).await?;
+                                spooled += measurements.len();
+                            }
+                            anyhow::Ok(())
+                        };
+                        // Logs the insert failing if the measurements can be set aside instead.
+                        let fall_back = |err| {
+                            if config.buffer_path.is_some() {
+                                error!("{err}; buffering the remaining measurements instead");
+                            } else if config.spool_path.is_some() {
+                                error!("{err}; spooling the remaining measurements instead");
+                            } else {
+                                return Err(err);
+                            }
+                            Ok(())
+                        };
+
+                        while let Some(chunk) = receiver.recv().await {
+                            fetched += chunk.len();
+                            report.observe(&chunk);
+                            alerts.check(config, station, &chunk);
+
+                            for gap in gaps.iter_mut().flat_map(|gaps| gaps.observe(&chunk)) {
+                                warn!(
+                                    "no measurements for {}s between {} and {}",
+                                    gap.duration_secs,
+                                    gap.start.to_rfc3339(),
+                                    gap.end.to_rfc3339()
+                                );
+                                report.gaps.push(gap);
+                            }
+                            METRICS.record_fetched(station, &chunk);
+                            outputs.lock().unwrap().write(station, &chunk)?;
+
+                            if let (Some(db), false) = (&db, falling_back) {
+                                let mut db = db.lock().await;
+
+                                if let Err(err) = insert.push(&mut db, &chunk).await {
+                                    fall_back(err)?;
+                                    falling_back = true;
+                                    set_aside(&insert.take_pending()).await?;
+                                }
+                            } else if falling_back {
+                                set_aside(&chunk).await?;
+                            }
+
+                            if keep_all {
+                                all.extend(chunk);
+                            }
+                        }
+
+                        // The batch still open is only inserted after a complete download.
+                        if let (Some(db), false, Ok(true)) =
+                            (&db, falling_back, download_ended.await)
+                        {
+                            let mut db = db.lock().await;
+
+                            if let Err(err) = insert.flush(&mut db).await {
+                                fall_back(err)?;
+                                set_aside(&insert.take_pending()).await?;
+                            }
+                        }
+
+                        if let Some(warning) = report.check_clock(config, Utc::now()) {
+                            warn!("{warning}");
+                        }
+
+                        anyhow::Ok((fetched, insert, (spooled, buffered), all))
+                    };
+
+                    let record = options
+                        .record
+                        .as_deref()
+                        .map(|path| recording_path(path, station, stations.len()));
+                    let fetch = async {
+                        let fetch = fetch_measurements(
+                            config,
+                            station,
+                            source,
+                            record.as_deref(),
+                            sender,
+                            &mut download,
+                        )
+                        .await;
+                        let _ = downloaded.send(fetch.is_ok());
+                        fetch
+                    };
+                    let (fetch, store) = tokio::join!(fetch, store);
+
+                    let (stored, measurements) = match store {
+                        Ok((fetched, insert, (spooled, buffered), all)) => {
+                            let summary = InsertSummary {
+                                spooled,
+                                buffered,
+                                ..insert.finish()
+                            };
+                            if !options.dry_run {
+                                report.stored(fetched, &summary);
+                            }
+                            METRICS
+                                .measurements_inserted
+                                .fetch_add(summary.inserted, Ordering::Relaxed);
+                            METRICS
+                                .insert_errors
+                                .fetch_add(summary.failures.len() as u64, Ordering::Relaxed);
+                            (Ok((fetched, (!options.dry_run).then_some(summary))), all)
+                        }
+                        Err(err) => {
+                            METRICS.insert_errors.fetch_add(1, Ordering::Relaxed);
+                            (Err(err), Vec::new())
+                        }
+                    };
+
+                    let session = match (fetch, &stored) {
+                        (Ok(session), _) => session,
+                        (Err(_), Err(_)) => return stored,
+                        (Err(err), Ok(_)) => return Err(err.into()),
+                    };
+
+                    if options.print_table {
+                        print_table(
+                            station,
+                            &measurements,
+                            options.last,
+                            config.output_units.temperature,
+                        );
+                    }
+
+                    if options.dry_run && !stdout_taken && !options.print_table {
+                        print_measurements(station, &measurements, config.output_units.temperature);
+                    }
+
+                    // Only a complete insert is acknowledged, a dry run persists nothing. Buffered
+                    // measurements count as inserted, spooled ones don't.
+                    let persisted = match &stored {
+                        Ok((fetched, Some(summary)))
+                            if summary.failures.is_empty() && summary.spooled == 0 =>
+                        {
+                            *fetched as u32
+                        }
+                        _ => 0,
+                    };
+
+                    if let Err(err) = finish_session(config, station, session, persisted).await {
+                        error!("{err}");
+                    }
+
+                    if let Some(mqtt) = &config.mqtt {
+                        if let Err(err) = publish_mqtt(
+                            mqtt,
+                            station,
+                            &measurements,
+                            config.output_units.temperature,
+                        )
+                        .await
+                        {
+                            error!("publishing the measurements to MQTT failed: {err}");
+                        }
+                    }
+
+                    stored
+                }
+                .await;
+
+                info!("done in {:.1?}", started.elapsed());
+
+                report.downloaded(config, station, download);
+                METRICS.record_timings(&report.timings);
+
+                if let Err(err) = &result {
+                    failed(&Span::current(), err);
+                    report.errors.push(err.to_string());
+                }
+
+                Some((result, report))
+            }
+            .instrument(info_span!(
+                "station",
+                station_id = station.station_id,
+                pico = %station.pico,
+                pico_port = station.pico_port
+            ))
+            .map(move |result| result.map(|(result, report)| (station, result, report)))
+        })
+        .buffered(config.max_concurrent_fetches.get())
+        .filter_map(std::future::ready)
+        .collect()
+        .await;
+
+    alerts.finish().await;
+
+    if results.iter().any(|(_, result, _)| result.is_ok()) {
+        METRICS
+            .last_success_timestamp
+            .store(Utc::now().timestamp(), Ordering::Relaxed);
+    }
+
+    for (station, result, _) in &results {
+        match result {
+            Ok((fetched, None)) => info!(
+                station_id = station.station_id,
+                pico = %station.pico,
+                pico_port = station.pico_port,
+                fetched,
+                "fetched {fetched} measurements"
+            ),
+            Ok((fetched, Some(summary))) if summary.spooled > 0 => warn!(
+                station_id = station.station_id,
+                pico = %station.pico,
+                pico_port = station.pico_port,
+                fetched,
+                inserted = summary.inserted,
+                failed = summary.failures.len(),
+                spooled = summary.spooled,
+                "fetched {fetched}, inserted {} new, {} failed, {} spooled to be inserted later",
+                summary.inserted,
+                summary.failures.len(),
+                summary.spooled
+            ),
+            Ok((fetched, Some(summary))) if summary.buffered > 0 => warn!(
+                station_id = station.station_id,
+                pico = %station.pico,
+                pico_port = station.pico_port,
+                fetched,
+                inserted = summary.inserted,
+                failed = summary.failures.len(),
+                buffered = summary.buffered,
+                "fetched {fetched}, inserted {} new, {} failed, {} buffered to be inserted later",
+                summary.inserted,
+                summary.failures.len(),
+                summary.buffered
+            ),
+            Ok((fetched, Some(summary))) if summary.failures.is_empty() => info!(
+                station_id = station.station_id,
+                pico = %station.pico,
+                pico_port = station.pico_port,
+                fetched,
+                inserted = summary.inserted,
+                "fetched {fetched}, inserted {} new, {} already present",
+                summary.inserted,
+                *fetched as u64 - summary.inserted
+            ),
+            Ok((fetched, Some(summary))) => {
+                error!(
+                    station_id = station.station_id,
+                    pico = %station.pico,
+                    pico_port = station.pico_port,
+                    fetched,
+                    inserted = summary.inserted,
+                    failed = summary.failures.len(),
+                    "fetched {fetched}, inserted {} new, {} already present, {} failed",
+                    summary.inserted,
+                    *fetched as u64 - summary.inserted - summary.failures.len() as u64,
+                    summary.failures.len()
+                );
+
+                for failure in summary.failures.iter().take(REPORTED_ROW_FAILURES) {
+                    error!(
+                        station_id = station.station_id,
+                        time = %failure.time.to_rfc3339(),
+                        "inserting the measurement at {} failed: {}",
+                        failure.time.to_rfc3339(),
+                        failure.error
+                    );
+                }
+            }
+            Err(err) => error!(
+                station_id = station.station_id,
+                pico = %station.pico,
+                pico_port = station.pico_port,
+                "failed: {err}"
+            ),
+        }
+    }
+
+    let fetched = results
+        .iter()
+        .filter_map(|(_, result, _)| result.as_ref().ok())
+        .map(|(fetched, _)| fetched)
+        .sum::<usize>();
+    let succeeded = results
+        .iter()
+        .filter(|(_, result, _)| result.is_ok())
+        .count();
+
+    if options.dry_run && !stdout_taken && !options.print_table {
+        println!("{fetched} measurements fetched from {succeeded} of {session_count} stations");
+    }
+
+    let row_failures = results
+        .iter()
+        .filter_map(|(_, result, _)| result.as_ref().ok())
+        .any(|(_, stored)| {
+            stored
+                .as_ref()
+                .is_some_and(|summary| !summary.failures.is_empty())
+        });
+
+    let exit_code = if SHUTDOWN.is_requested() {
+        ExitCode::from(INTERRUPTED_EXIT_CODE)
+    } else if results.iter().all(|(_, result, _)| result.is_err()) {
+        // Every station failed, the first one decides why.
+        results
+            .first()
+            .and_then(|(_, result, _)| result.as_ref().err())
+            .and_then(Failure::of)
+            .map_or(ExitCode::FAILURE, Failure::exit_code)
+    } else if row_failures {
+        Failure::Database.exit_code()
+    } else {
+        ExitCode::SUCCESS
+    };
+
+    let summary = CycleSummary {
+        fetched,
+        inserted: results
+            .iter()
+            .filter_map(|(_, result, _)| result.as_ref().ok())
+            .filter_map(|(_, stored)| stored.as_ref())
+            .map(|summary| summary.inserted)
+            .sum(),
+        buffered: results
+            .iter()
+            .filter_map(|(_, result, _)| result.as_ref().ok())
+            .filter_map(|(_, stored)| stored.as_ref())
+            .map(|summary| summary.buffered)
+            .sum(),
+        succeeded,
+        stations: session_count,
+        exit_code,
+        reports: results.into_iter().map(|(_, _, report)| report).collect(),
+        errors,
+        db_connect,
+    };
+
+    // A dry run prints its own summary.
+    if !options.dry_run {
+        info!(target: SUMMARY_TARGET, "{summary}");
+    }
+
+    // Kept out of the summary, which is one line by default.
+    if let Some(db_connect) = db_connect {
+        debug!("timings: database connect {db_connect:.1?}");
+    }
+
+    for report in &summary.reports {
+        debug!(
+            station_id = report.station_id,
+            pico = %report.pico,
+            pico_port = report.pico_port,
+            "timings of station {}: {}",
+            report.station_id,
+            report.timings
+        );
+    }
+
+    Ok(summary)
+}
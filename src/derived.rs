@@ -0,0 +1,273 @@
+//! Quantities computed from the temperature and humidity of the measurements.
+
+use rust_decimal::{
+    prelude::{FromPrimitive, ToPrimitive},
+    Decimal, RoundingStrategy,
+};
+
+use crate::{
+    config::{Config, DerivedMetric, PerceivedTemp},
+    Measurement,
+};
+
+/// The coefficients of the Magnus formula as recommended by the WMO, accurate to 0.1 °C between
+/// -45 °C and 60 °C.
+const MAGNUS_A: f64 = 17.62;
+const MAGNUS_B: f64 = 243.12;
+
+/// The saturation vapour pressure over water in hectopascals at 0 °C, of the Magnus formula as
+/// recommended by the WMO.
+const MAGNUS_C: f64 = 6.112;
+
+/// The absolute humidity in g/m³ per hectopascal of vapour pressure divided by the temperature in
+/// kelvin, 100 Pa/hPa and 1000 g/kg over the specific gas constant of water vapour, 461.5 J/(kg K).
+const VAPOUR_DENSITY: f64 = 100.0 / 461.5 * 1000.0;
+
+/// The lowest temperature in degrees Celsius the heat index is defined for, 80 °F.
+const HEAT_INDEX_MIN_TEMP: f64 = (80.0 - 32.0) * 5.0 / 9.0;
+
+/// The lowest temperature in degrees Celsius the humidex is reported for.
+const HUMIDEX_MIN_TEMP: f64 = 20.0;
+
+/// Computes the quantities the config asks for from the (calibrated and range checked) values of
+/// `measurements`.
+pub fn derive_quantities(config: &Config, measurements: &mut [Measurement]) {
+    for measurement in measurements {
+        if config.compute_dew_point {
+            measurement.dew_point = dew_point(measurement.temp, measurement.humidity);
+        }
+
+        measurement.perceived_temp = config.perceived_temp.and_then(|perceived_temp| {
+            let (temp, humidity) = (measurement.temp, measurement.humidity);
+
+            match perceived_temp {
+                PerceivedTemp::HeatIndex => heat_index(temp, humidity),
+                PerceivedTemp::Humidex => humidex(temp, humidity),
+            }
+        });
+
+        if config.derives(DerivedMetric::AbsoluteHumidity) {
+            measurement.absolute_humidity =
+                absolute_humidity(measurement.temp, measurement.humidity);
+        }
+    }
+}
+
+/// The dew point in degrees Celsius at `temp` degrees Celsius and `humidity` percent, rounded to
+/// one decimal place, unless the humidity is outside of the range it's defined for.
+pub fn dew_point(temp: Decimal, humidity: Decimal) -> Option<Decimal> {
+    rounded(unrounded_dew_point(temp.to_f64()?, relative(humidity)?))
+}
+
+/// The absolute humidity in grams per cubic meter at `temp` degrees Celsius and `humidity`
+/// percent, rounded to two decimal places, from the saturation vapour pressure of the Magnus
+/// formula, unless the humidity is outside of the range it's defined for.
+pub fn absolute_humidity(temp: Decimal, humidity: Decimal) -> Option<Decimal> {
+    let (temp, humidity) = (temp.to_f64()?, relative(humidity)?);
+    let vapour_pressure = humidity * MAGNUS_C * (MAGNUS_A * temp / (MAGNUS_B + temp)).exp();
+
+    rounded_to(VAPOUR_DENSITY * vapour_pressure / (temp + 273.15), 2)
+}
+
+/// The heat index in degrees Celsius at `temp` degrees Celsius and `humidity` percent, rounded to
+/// one decimal place, computed with the regression of Rothfusz and the adjustments for low and
+/// high humidities the National Weather Service uses. Below 80 °F, where the regression doesn't
+/// hold, there is none.
+pub fn heat_index(temp: Decimal, humidity: Decimal) -> Option<Decimal> {
+    relative(humidity)?;
+
+    let (celsius, rh) = (temp.to_f64()?, humidity.to_f64()?);
+
+    if celsius < HEAT_INDEX_MIN_TEMP {
+        return None;
+    }
+
+    let t = celsius * 9.0 / 5.0 + 32.0;
+    let mut index = -42.379 + 2.04901523 * t + 10.14333127 * rh
+        - 0.22475541 * t * rh
+        - 0.00683783 * t * t
+        - 0.05481717 * rh * rh
+        + 0.00122874 * t * t * rh
+        + 0.00085282 * t * rh * rh
+        - 0.00000199 * t * t * rh * rh;
+
+    if rh < 13.0 && t <= 112.0 {
+        index -= (13.0 - rh) / 4.0 * ((17.0 - (t - 95.0).abs()) / 17.0).sqrt();
+    } else if rh > 85.0 && t <= 87.0 {
+        index += (rh - 85.0) / 10.0 * ((87.0 - t) / 5.0);
+    }
+
+    rounded((index - 32.0) * 5.0 / 9.0)
+}
+
+/// The humidex in degrees Celsius at `temp` degrees Celsius and `humidity` percent, rounded to
+/// one decimal place, as defined by Environment Canada. Below 20 °C, where it isn't reported,
+/// there is none.
+pub fn humidex(temp: Decimal, humidity: Decimal) -> Option<Decimal> {
+    let (temp, humidity) = (temp.to_f64()?, relative(humidity)?);
+
+    if temp < HUMIDEX_MIN_TEMP {
+        return None;
+    }
+
+    let dew_point = unrounded_dew_point(temp, humidity) + 273.15;
+    let vapour_pressure = 6.11 * (5417.7530 * (1.0 / 273.16 - 1.0 / dew_point)).exp();
+
+    rounded(temp + 0.5555 * (vapour_pressure - 10.0))
+}
+
+/// `humidity` in percent as a fraction, unless it's outside of the range the formulas are
+/// defined for.
+fn relative(humidity: Decimal) -> Option<f64> {
+    if humidity <= Decimal::ZERO || humidity > Decimal::ONE_HUNDRED {
+        return None;
+    }
+
+    Some(humidity.to_f64()? / 100.0)
+}
+
+/// The dew point in degrees Celsius at `temp` degrees Celsius and the relative `humidity`, with
+/// the Magnus formula.
+fn unrounded_dew_point(temp: f64, humidity: f64) -> f64 {
+    let gamma = humidity.ln() + MAGNUS_A * temp / (MAGNUS_B + temp);
+
+    MAGNUS_B * gamma / (MAGNUS_A - gamma)
+}
+
+/// Rounds `value` to one decimal place, halves away from zero, going by its shortest decimal
+/// representation so values like 16.65 aren't rounded down for being stored as 16.6499….
+fn rounded(value: f64) -> Option<Decimal> {
+    rounded_to(value, 1)
+}
+
+/// Rounds `value` to `places` decimal places like [`rounded`].
+fn rounded_to(value: f64, places: u32) -> Option<Decimal> {
+    let mut value = Decimal::from_f64(value)?
+        .round_dp_with_strategy(places, RoundingStrategy::MidpointAwayFromZero);
+
+    if value.is_zero() {
+        value.set_sign_positive(true);
+    }
+    value.rescale(places);
+
+    Some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dew_point_of(temp: i64, humidity: i64) -> Option<String> {
+        of(dew_point, temp, humidity)
+    }
+
+    /// `formula` at `temp` and `humidity` in tenths.
+    fn of(
+        formula: fn(Decimal, Decimal) -> Option<Decimal>,
+        temp: i64,
+        humidity: i64,
+    ) -> Option<String> {
+        formula(Decimal::new(temp, 1), Decimal::new(humidity, 1)).map(|value| value.to_string())
+    }
+
+    #[test]
+    fn computes_reference_dew_points() {
+        assert_eq!(dew_point_of(200, 500).as_deref(), Some("9.3"));
+        assert_eq!(dew_point_of(250, 600).as_deref(), Some("16.7"));
+        assert_eq!(dew_point_of(300, 800).as_deref(), Some("26.2"));
+        assert_eq!(dew_point_of(-100, 500).as_deref(), Some("-18.5"));
+        assert_eq!(dew_point_of(0, 1000).as_deref(), Some("0.0"));
+    }
+
+    #[test]
+    fn rounds_halves_away_from_zero() {
+        assert_eq!(rounded(16.65).unwrap().to_string(), "16.7");
+        assert_eq!(rounded(-18.45).unwrap().to_string(), "-18.5");
+        assert_eq!(rounded(-0.04).unwrap().to_string(), "0.0");
+    }
+
+    #[test]
+    fn has_no_dew_point_without_humidity() {
+        assert_eq!(dew_point_of(200, 0), None);
+        assert_eq!(dew_point_of(200, 1023), None);
+    }
+
+    #[test]
+    fn computes_reference_absolute_humidities() {
+        // 20 °C at 50 %, 30 °C at 80 %, 0 °C saturated and 25 °C at 60 % from the tables of
+        // saturation vapour density, which give 8.65, 24.3, 4.85 and 13.8 g/m³.
+        assert_eq!(of(absolute_humidity, 200, 500).as_deref(), Some("8.62"));
+        assert_eq!(of(absolute_humidity, 300, 800).as_deref(), Some("24.21"));
+        assert_eq!(of(absolute_humidity, 0, 1000).as_deref(), Some("4.85"));
+        assert_eq!(of(absolute_humidity, 250, 600).as_deref(), Some("13.78"));
+        // Over supercooled water below freezing.
+        assert_eq!(of(absolute_humidity, -100, 500).as_deref(), Some("1.18"));
+        assert_eq!(of(absolute_humidity, 200, 0), None);
+        assert_eq!(of(absolute_humidity, 200, 1023), None);
+    }
+
+    #[test]
+    fn computes_reference_heat_indices() {
+        // 90 °F at 50 %, 100 °F at 40 % and 86 °F at 70 % from the table of the NWS, which gives
+        // 95 °F, 109 °F and 95 °F.
+        assert_eq!(of(heat_index, 322, 500).as_deref(), Some("34.7"));
+        assert_eq!(of(heat_index, 378, 400).as_deref(), Some("43.0"));
+        assert_eq!(of(heat_index, 300, 700).as_deref(), Some("35.0"));
+        // With the adjustments for dry and humid air.
+        assert_eq!(of(heat_index, 400, 100).as_deref(), Some("36.7"));
+        assert_eq!(of(heat_index, 270, 900).as_deref(), Some("31.1"));
+    }
+
+    #[test]
+    fn computes_reference_humidices() {
+        // 30 °C at a dew point of 15 °C and 35 °C at 25 °C from the table of Environment Canada,
+        // which gives 34 and 47.
+        assert_eq!(of(humidex, 300, 402).as_deref(), Some("34.0"));
+        assert_eq!(of(humidex, 350, 563).as_deref(), Some("47.3"));
+        assert_eq!(of(humidex, 400, 317).as_deref(), Some("47.6"));
+    }
+
+    #[test]
+    fn has_no_perceived_temperature_outside_of_the_formulas_range() {
+        assert_eq!(of(heat_index, 266, 500), None);
+        assert_eq!(of(heat_index, 300, 0), None);
+        assert_eq!(of(humidex, 199, 900), None);
+        assert_eq!(of(humidex, 300, 1010), None);
+    }
+
+    #[test]
+    fn computes_from_the_calibrated_values() {
+        let config = Config {
+            perceived_temp: Some(PerceivedTemp::Humidex),
+            compute_dew_point: true,
+            derived_metrics: vec![DerivedMetric::AbsoluteHumidity],
+            ..Config::default()
+        };
+        let station = crate::config::Station {
+            temp_offset: Decimal::new(50, 1),
+            humidity_offset: Decimal::new(22, 1),
+            ..config.stations[0].clone()
+        };
+        let mut measurement = Measurement {
+            time: chrono::DateTime::parse_from_rfc3339("2024-06-01T12:00:00Z").unwrap(),
+            temp: Decimal::new(250, 1),
+            humidity: Decimal::new(380, 1),
+            uncalibrated: None,
+            out_of_range: false,
+            dew_point: None,
+            perceived_temp: None,
+            absolute_humidity: None,
+            packed: None,
+        };
+
+        station.calibrate(&mut measurement);
+        derive_quantities(&config, std::slice::from_mut(&mut measurement));
+
+        assert_eq!(
+            measurement.dew_point,
+            dew_point(Decimal::new(300, 1), Decimal::new(402, 1))
+        );
+        assert_eq!(measurement.perceived_temp.unwrap().to_string(), "34.0");
+        assert_eq!(measurement.absolute_humidity.unwrap().to_string(), "12.17");
+    }
+}
@@ -0,0 +1,113 @@
+//! Removing the measurements a Pico sent twice in one download, like a firmware writing the same
+//! measurement to its flash twice does.
+
+use std::collections::{hash_map::Entry, HashMap};
+
+use chrono::{DateTime, FixedOffset};
+use rust_decimal::Decimal;
+use tracing::warn;
+
+use crate::{config::Config, Measurement};
+
+/// Finds the measurements of a download taken at the same time as an earlier one of it.
+#[derive(Debug, Default)]
+pub struct DuplicateFilter {
+    /// The temperature and humidity of every time seen so far.
+    seen: HashMap<DateTime<FixedOffset>, (Decimal, Decimal)>,
+    /// How many measurements were removed.
+    pub removed: u64,
+}
+
+impl DuplicateFilter {
+    /// The filter of a download, unless the config turns it off with `dedupe_in_batch`.
+    pub fn new(config: &Config) -> Option<DuplicateFilter> {
+        config.dedupe_in_batch.then(DuplicateFilter::default)
+    }
+
+    /// Returns `measurements` without the ones taken at the same time as an earlier one, the next
+    /// ones of the download. A duplicate with different values than the one kept is logged.
+    pub fn filter(&mut self, measurements: Vec<Measurement>) -> Vec<Measurement> {
+        measurements
+            .into_iter()
+            .filter(|measurement| {
+                let values = (measurement.temp, measurement.humidity);
+
+                match self.seen.entry(measurement.time) {
+                    Entry::Vacant(entry) => {
+                        entry.insert(values);
+                        return true;
+                    }
+                    Entry::Occupied(entry) if *entry.get() != values => {
+                        let (temp, humidity) = entry.get();
+                        warn!(
+                            time = %measurement.time.to_rfc3339(),
+                            "the Pico sent two measurements at {}, keeping {temp} °C and \
+                             {humidity} % over {} °C and {} %",
+                            measurement.time.to_rfc3339(),
+                            measurement.temp,
+                            measurement.humidity
+                        );
+                    }
+                    Entry::Occupied(_) => {}
+                }
+
+                self.removed += 1;
+                false
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn measurement(time: &str, temp: i64) -> Measurement {
+        Measurement {
+            time: DateTime::parse_from_rfc3339(time).unwrap(),
+            temp: Decimal::new(temp, 1),
+            humidity: Decimal::new(480, 1),
+            uncalibrated: None,
+            out_of_range: false,
+            dew_point: None,
+            perceived_temp: None,
+            absolute_humidity: None,
+            packed: None,
+        }
+    }
+
+    #[test]
+    fn keeps_the_first_measurement_at_a_time() {
+        let mut filter = DuplicateFilter::new(&Config::default()).unwrap();
+
+        let first = filter.filter(vec![
+            measurement("2024-06-01T12:00:00Z", 215),
+            measurement("2024-06-01T12:00:00Z", 215),
+            measurement("2024-06-01T12:01:00Z", 216),
+        ]);
+        let second = filter.filter(vec![
+            measurement("2024-06-01T12:01:00Z", 230),
+            measurement("2024-06-01T12:02:00Z", 217),
+        ]);
+
+        assert_eq!(
+            first,
+            [
+                measurement("2024-06-01T12:00:00Z", 215),
+                measurement("2024-06-01T12:01:00Z", 216)
+            ]
+        );
+        assert_eq!(second, [measurement("2024-06-01T12:02:00Z", 217)]);
+        assert_eq!(filter.removed, 2);
+    }
+
+    #[test]
+    fn can_be_turned_off() {
+        let config = Config {
+            dedupe_in_batch: false,
+            ..Config::default()
+        };
+
+        assert!(DuplicateFilter::new(&config).is_none());
+    }
+}
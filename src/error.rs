@@ -0,0 +1,184 @@
+//! The errors of loading the config, fetching the measurements and storing them, which tell apart
+//! what went wrong without looking at their messages.
+
+use std::{io, path::PathBuf, time::Duration};
+
+use tokio_postgres::error::SqlState;
+
+use crate::{failure::Failure, protocol::DecodeError};
+
+/// An error of the main flow, displayed like a message of the program. The errors it wraps are
+/// part of the message rather than its source, so they aren't repeated when it's reported.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The config is missing or invalid.
+    #[error("{0}")]
+    Config(String),
+    /// The Pico couldn't be connected to, even after retrying.
+    #[error(
+        "Error connecting to the Pico at {host}:{port} after {attempts} attempts in {elapsed:.1?}: {reason}"
+    )]
+    PicoConnect {
+        host: String,
+        port: u16,
+        attempts: u32,
+        elapsed: Duration,
+        /// Why the last attempt failed.
+        reason: String,
+    },
+    /// The Pico rejected the pre-shared key of its station or failed to prove it knows it.
+    #[error("{0}")]
+    Auth(&'static str),
+    /// The Pico didn't follow the protocol at `offset`, counting the bytes it sent in the session
+    /// including those authenticating it.
+    #[error("{kind}")]
+    Protocol {
+        kind: ProtocolErrorKind,
+        offset: u64,
+    },
+    /// The measurement at `index` of the `count` the Pico announced couldn't be decoded.
+    #[error("{err} (measurement {} of {count})", index + 1)]
+    Decode {
+        index: u32,
+        count: u32,
+        err: DecodeError,
+    },
+    /// The checksum the Pico sent doesn't match the measurements.
+    #[error(
+        "Checksum mismatch in the measurements sent by the Pico: expected {expected:08x}, computed {computed:08x}"
+    )]
+    ChecksumMismatch { expected: u32, computed: u32 },
+    /// The session with the Pico took longer than `pico_socket.session_timeout_secs`.
+    #[error("Gave up on the Pico after pico_socket.session_timeout_secs ({0:?})")]
+    SessionTimeout(Duration),
+    /// Pinging the Pico took longer than `--timeout`.
+    #[error("Gave up on pinging the Pico after --timeout ({0:?})")]
+    PingTimeout(Duration),
+    /// The download was stopped since the measurements couldn't be stored.
+    #[error("Stopped downloading since the measurements aren't being stored")]
+    NotStored,
+    /// The database couldn't be reached or failed while `context`. `sql_state` is the SQLSTATE
+    /// code PostgreSQL reported, if it did.
+    #[error("Error {context}: {err}")]
+    Db {
+        context: String,
+        sql_state: Option<SqlState>,
+        err: Box<dyn std::error::Error + Send + Sync>,
+    },
+    /// Another instance holds the lock file at `path`, the one with `pid` if it wrote it.
+    #[error(
+        "Another instance is running{}, since the lock file {} is locked",
+        pid.map(|pid| format!(" (PID {pid})")).unwrap_or_default(),
+        path.display()
+    )]
+    Locked { path: PathBuf, pid: Option<u32> },
+    /// The clock of the Pico wasn't set since the host's looks wrong, for this reason, and it
+    /// had to be.
+    #[error("Not setting the clock of the Pico: {0} (pass --force-time-sync to set it anyway)")]
+    HostClock(String),
+    /// Shutdown was requested, which is displayed following "Interrupted".
+    #[error("Interrupted {0}")]
+    Interrupted(String),
+}
+
+/// What the database clients fail with.
+pub trait DbCause: Into<Box<dyn std::error::Error + Send + Sync>> {
+    /// The SQLSTATE code PostgreSQL reported, if it did.
+    fn sql_state(&self) -> Option<SqlState> {
+        None
+    }
+}
+
+impl DbCause for tokio_postgres::Error {
+    fn sql_state(&self) -> Option<SqlState> {
+        self.code().cloned()
+    }
+}
+
+impl DbCause for rusqlite::Error {}
+
+impl DbCause for reqwest::Error {}
+
+/// A problem the program noticed itself.
+impl DbCause for String {}
+
+impl DbCause for &str {}
+
+/// How the Pico didn't follow the protocol.
+#[derive(Debug, thiserror::Error)]
+pub enum ProtocolErrorKind {
+    /// Exchanging data with the Pico failed while doing `what`.
+    #[error("Error {what}: {err}")]
+    Io { what: &'static str, err: io::Error },
+    #[error("Pico closed the connection after {received} of {count} measurements")]
+    Closed { received: u32, count: u32 },
+    /// The Pico closed the connection after sending `read` bytes of the measurement at `index`.
+    #[error(
+        "Pico closed the connection after {read} of the 8 bytes of measurement {} of {count}",
+        index + 1
+    )]
+    Truncated { read: usize, index: u32, count: u32 },
+    #[error(
+        "Pico speaks protocol version {0}, but this program only supports versions up to {max} \
+         (if its firmware predates the version handshake, set legacy_protocol for the station)",
+        max = crate::protocol::PROTOCOL_VERSION
+    )]
+    UnsupportedVersion(u8),
+    #[error("Pico reported {count} measurements, more than fit into its flash ({capacity})")]
+    TooManyMeasurements { count: u32, capacity: u64 },
+    /// Like [`ProtocolErrorKind::TooManyMeasurements`] after the version handshake, which firmware
+    /// predating it takes the first byte of the count for.
+    #[error(
+        "Pico reported {count} measurements, more than fit into its flash ({capacity}) (if its \
+         firmware predates the version handshake, set legacy_protocol for the station)"
+    )]
+    ImplausibleCount { count: u32, capacity: u64 },
+    #[error(
+        "Pico reported {count} measurements, more than the {max} which can be held until their \
+         checksum is verified (turn off checksum for the station to download them)"
+    )]
+    TooManyToVerify { count: u32, max: u32 },
+    #[error("Pico sent the unknown authentication verdict {0}")]
+    UnknownVerdict(u8),
+    #[error(
+        "Pico answered the time sync with {0} instead of echoing it (if its firmware predates the \
+         millisecond time sync, set legacy_time_sync for the station)"
+    )]
+    UnexpectedEcho(u8),
+}
+
+impl Error {
+    /// The error of the database failing while `context`.
+    pub fn db(context: impl Into<String>, err: impl DbCause) -> Error {
+        Error::Db {
+            context: context.into(),
+            sql_state: err.sql_state(),
+            err: err.into(),
+        }
+    }
+
+    /// The error of exchanging data with the Pico failing while doing `what` at `offset`.
+    pub fn io(what: &'static str, offset: u64, err: io::Error) -> Error {
+        Error::Protocol {
+            kind: ProtocolErrorKind::Io { what, err },
+            offset,
+        }
+    }
+
+    /// The category of the error, `None` for an interruption.
+    pub fn failure(&self) -> Option<Failure> {
+        match self {
+            Error::Config(_) | Error::HostClock(_) => Some(Failure::Config),
+            Error::PicoConnect { .. }
+            | Error::Protocol { .. }
+            | Error::SessionTimeout(_)
+            | Error::PingTimeout(_)
+            | Error::NotStored => Some(Failure::Pico),
+            Error::Auth(_) => Some(Failure::Auth),
+            Error::Decode { .. } | Error::ChecksumMismatch { .. } => Some(Failure::Decode),
+            Error::Db { .. } => Some(Failure::Database),
+            Error::Locked { .. } => Some(Failure::Locked),
+            Error::Interrupted(_) => None,
+        }
+    }
+}
@@ -0,0 +1,425 @@
+//! Writing the stored measurements to stdout or a file, the `export` subcommand.
+
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    num::NonZeroU32,
+    path::Path,
+    pin::pin,
+};
+
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use rust_decimal::{prelude::FromPrimitive, Decimal};
+
+use crate::{
+    config::{Config, PicoTimezone, Storage, TemperatureUnit},
+    failure::{Categorize, Failure},
+    output::{csv_header, csv_record},
+    protocol,
+    sink::{connect_postgres_client, QuotedTable},
+    Measurement, StationMeasurement,
+};
+
+/// How many rows are fetched from the PostgreSQL portal at once, so large ranges aren't loaded
+/// into memory.
+const EXPORT_BATCH: i32 = 1000;
+
+/// The format the measurements are exported in, the same as with `--output-csv` and
+/// `--output-jsonl`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ExportFormat {
+    Csv,
+    Jsonl,
+}
+
+/// Which of the stored measurements are exported.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExportFilter {
+    pub station_id: Option<i32>,
+    /// The earliest time exported.
+    pub from: Option<DateTime<Utc>>,
+    /// The time before which the measurements are exported.
+    pub to: Option<DateTime<Utc>>,
+}
+
+/// Writes the stored measurements matching `filter` in chronological order to `path`, or stdout
+/// without one, in `format`. Returns how many were written.
+pub async fn export_measurements(
+    config: &Config,
+    filter: &ExportFilter,
+    format: ExportFormat,
+    path: Option<&Path>,
+) -> anyhow::Result<u64> {
+    let output: Box<dyn Write> =
+        match path {
+            Some(path) => Box::new(File::create(path).map_err(|err| {
+                anyhow!("Error creating the export file {}: {err}", path.display())
+            })?),
+            None => Box::new(std::io::stdout().lock()),
+        };
+    let mut writer = ExportWriter::new(
+        format,
+        output,
+        config.pico_timezone,
+        config.output_units.temperature,
+    )?;
+
+    match &config.storage {
+        Storage::Postgres => export_postgres(config, filter, &mut writer)
+            .await
+            .category(Failure::Database)?,
+        Storage::Sqlite { path } => {
+            export_sqlite(config, path, filter, &mut writer).category(Failure::Database)?
+        }
+        Storage::Influxdb { .. } => {
+            return Err(Failure::Config.tag(anyhow!(
+                "export isn't supported with InfluxDB storage, query the bucket instead"
+            )))
+        }
+    }
+
+    writer.finish()
+}
+
+/// The query of the measurements in the configured table matching the filter given by the
+/// parameters `station_id`, `from` and `to`, each matching everything if null.
+fn export_query(config: &Config, station_id: &str, from: &str, to: &str) -> String {
+    let table = QuotedTable::new(&config.table);
+    let (name, at, station) = (&table.name, &table.at, &table.station_id);
+
+    format!(
+        "select {station}, {at}, {}, {} from {name}
+            where ({station_id} is null or {station} = {station_id})
+                and ({from} is null or {at} >= {from})
+                and ({to} is null or {at} < {to})
+            order by {at}, {station}",
+        table.temp, table.humidity
+    )
+}
+
+async fn export_postgres(
+    config: &Config,
+    filter: &ExportFilter,
+    writer: &mut ExportWriter,
+) -> anyhow::Result<()> {
+    let mut client = connect_postgres_client(config).await?;
+
+    // Portals only live as long as their transaction.
+    let transaction = client
+        .build_transaction()
+        .read_only(true)
+        .start()
+        .await
+        .map_err(|err| anyhow!("Error starting the export transaction: {err}"))?;
+
+    let portal = transaction
+        .bind(
+            &export_query(config, "$1::int4", "$2::timestamptz", "$3::timestamptz"),
+            &[&filter.station_id, &filter.from, &filter.to],
+        )
+        .await
+        .map_err(|err| anyhow!("Error querying the measurements: {err}"))?;
+
+    loop {
+        let mut rows = pin!(transaction
+            .query_portal_raw(&portal, EXPORT_BATCH)
+            .await
+            .map_err(|err| anyhow!("Error querying the measurements: {err}"))?);
+        let mut fetched = 0;
+
+        while let Some(row) = rows
+            .try_next()
+            .await
+            .map_err(|err| anyhow!("Error reading the measurements: {err}"))?
+        {
+            fetched += 1;
+            writer.write(row.get(0), row.get(1), row.get(2), row.get(3))?;
+        }
+
+        if fetched < EXPORT_BATCH {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+fn export_sqlite(
+    config: &Config,
+    path: &Path,
+    filter: &ExportFilter,
+    writer: &mut ExportWriter,
+) -> anyhow::Result<()> {
+    let connection =
+        rusqlite::Connection::open_with_flags(path, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .map_err(|err| anyhow!("Error opening SQLite database {}: {err}", path.display()))?;
+
+    let mut statement = connection
+        .prepare(&export_query(config, "?1", "?2", "?3"))
+        .map_err(|err| anyhow!("Error querying the measurements: {err}"))?;
+
+    let mut rows = statement
+        .query(rusqlite::params![filter.station_id, filter.from, filter.to])
+        .map_err(|err| anyhow!("Error querying the measurements: {err}"))?;
+
+    while let Some(row) = rows
+        .next()
+        .map_err(|err| anyhow!("Error reading the measurements: {err}"))?
+    {
+        let read =
+            || -> rusqlite::Result<_> { Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)) };
+        let (station_id, time, temp, humidity): (i32, DateTime<Utc>, f64, f64) =
+            read().map_err(|err| anyhow!("Error reading the measurements: {err}"))?;
+
+        writer.write(
+            station_id,
+            time,
+            from_real(temp, config.temp_scale),
+            from_real(humidity, config.humidity_scale),
+        )?;
+    }
+
+    Ok(())
+}
+
+/// A value SQLite stored as a real, with at least as many decimal places as the Pico sends with
+/// `scale`, so they are formatted like as they are fetched.
+pub fn from_real(value: f64, scale: NonZeroU32) -> Decimal {
+    let mut value = Decimal::from_f64(value).unwrap_or_default();
+    let places = protocol::scale(0, scale).scale();
+
+    if value.scale() < places {
+        value.rescale(places);
+    }
+
+    value
+}
+
+/// Writes the exported measurements like the outputs of a fetch.
+struct ExportWriter {
+    output: ExportOutput,
+    timezone: PicoTimezone,
+    unit: TemperatureUnit,
+    written: u64,
+}
+
+enum ExportOutput {
+    Csv(Box<csv::Writer<Box<dyn Write>>>),
+    Jsonl(BufWriter<Box<dyn Write>>),
+}
+
+impl ExportWriter {
+    fn new(
+        format: ExportFormat,
+        output: Box<dyn Write>,
+        timezone: PicoTimezone,
+        unit: TemperatureUnit,
+    ) -> anyhow::Result<ExportWriter> {
+        let output = match format {
+            ExportFormat::Csv => {
+                let mut csv = csv::Writer::from_writer(output);
+                csv.write_record(csv_header(unit))
+                    .map_err(|err| anyhow!("Error writing the CSV header: {err}"))?;
+                ExportOutput::Csv(Box::new(csv))
+            }
+            ExportFormat::Jsonl => ExportOutput::Jsonl(BufWriter::new(output)),
+        };
+
+        Ok(ExportWriter {
+            output,
+            timezone,
+            unit,
+            written: 0,
+        })
+    }
+
+    fn write(
+        &mut self,
+        station_id: i32,
+        time: DateTime<Utc>,
+        temp: Decimal,
+        humidity: Decimal,
+    ) -> anyhow::Result<()> {
+        // In the time zone of the Pico, like the measurements as they are fetched.
+        let measurement = Measurement {
+            time: self.timezone.local(&time.fixed_offset()),
+            temp,
+            humidity,
+            uncalibrated: None,
+            out_of_range: false,
+            dew_point: None,
+            perceived_temp: None,
+            absolute_humidity: None,
+            packed: None,
+        };
+
+        let written = match &mut self.output {
+            ExportOutput::Csv(csv) => csv
+                .write_record(csv_record(station_id, &measurement, self.unit))
+                .map_err(|err| err.to_string()),
+            ExportOutput::Jsonl(jsonl) => serde_json::to_writer(
+                &mut *jsonl,
+                &StationMeasurement::new(station_id, &measurement, self.unit),
+            )
+            .map_err(|err| err.to_string())
+            .and_then(|()| jsonl.write_all(b"\n").map_err(|err| err.to_string())),
+        };
+
+        written.map_err(|err| anyhow!("Error writing the exported measurements: {err}"))?;
+        self.written += 1;
+        Ok(())
+    }
+
+    /// Flushes what's buffered, returning how many measurements were written.
+    fn finish(self) -> anyhow::Result<u64> {
+        match self.output {
+            ExportOutput::Csv(mut csv) => csv.flush(),
+            ExportOutput::Jsonl(mut jsonl) => jsonl.flush(),
+        }
+        .map_err(|err| anyhow!("Error writing the exported measurements: {err}"))?;
+
+        Ok(self.written)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::MeasurementTable;
+
+    #[tokio::test]
+    async fn exports_the_filtered_measurements_like_the_outputs() {
+        let dir = std::env::temp_dir().join("pico_humidity_temp_read_export");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let config = Config {
+            storage: Storage::Sqlite {
+                path: dir.join("measurements.db"),
+            },
+            pico_timezone: PicoTimezone::Named(chrono_tz::Europe::Berlin),
+            ..Config::default()
+        };
+        let station = config.stations[0].clone();
+        let measurements: Vec<_> = (0..3)
+            .map(|minute| Measurement {
+                time: DateTime::parse_from_rfc3339("2024-06-01T12:00:00+02:00").unwrap()
+                    + chrono::Duration::minutes(minute),
+                temp: Decimal::new(200 + minute, 1),
+                humidity: Decimal::new(480, 1),
+                uncalibrated: None,
+                out_of_range: false,
+                dew_point: None,
+                perceived_temp: None,
+                absolute_humidity: None,
+                packed: None,
+            })
+            .collect();
+
+        let mut db = crate::sink::connect_database(&config).await.unwrap();
+        crate::sink::insert_measurements(&config, &mut db, &station, &measurements, false)
+            .await
+            .unwrap();
+        drop(db);
+
+        let path = dir.join("export.csv");
+        let filter = ExportFilter {
+            station_id: Some(station.station_id),
+            from: Some(measurements[1].time.to_utc()),
+            to: None,
+        };
+
+        let exported = export_measurements(&config, &filter, ExportFormat::Csv, Some(&path))
+            .await
+            .unwrap();
+        let csv = std::fs::read_to_string(&path).unwrap();
+
+        // An unknown station just has no measurements.
+        let unknown = ExportFilter {
+            station_id: Some(station.station_id + 1),
+            ..filter
+        };
+        let none = export_measurements(&config, &unknown, ExportFormat::Jsonl, Some(&path))
+            .await
+            .unwrap();
+        let jsonl = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+
+        assert_eq!(exported, 2);
+        assert_eq!(
+            csv,
+            "time,station_id,temp_c,humidity_pct\n\
+             2024-06-01T12:01:00+02:00,0,20.1,48.0\n\
+             2024-06-01T12:02:00+02:00,0,20.2,48.0\n"
+        );
+        assert_eq!(none, 0);
+        assert!(jsonl.is_empty(), "{jsonl}");
+    }
+
+    #[tokio::test]
+    async fn exports_from_the_configured_table() {
+        let dir = std::env::temp_dir().join("pico_humidity_temp_read_export_table");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let config = Config {
+            storage: Storage::Sqlite {
+                path: dir.join("measurements.db"),
+            },
+            table: MeasurementTable {
+                name: "sensor_readings".to_string(),
+                time_column: "recorded_at".to_string(),
+                station_id_column: "sensor_id".to_string(),
+                temp_column: "Temperature".to_string(),
+                humidity_column: "order".to_string(),
+            },
+            ..Config::default()
+        };
+        let station = config.stations[0].clone();
+        let measurement = Measurement {
+            time: DateTime::parse_from_rfc3339("2024-06-01T12:00:00Z").unwrap(),
+            temp: Decimal::new(215, 1),
+            humidity: Decimal::new(480, 1),
+            uncalibrated: None,
+            out_of_range: false,
+            dew_point: None,
+            perceived_temp: None,
+            absolute_humidity: None,
+            packed: None,
+        };
+
+        let mut db = crate::sink::connect_database(&config).await.unwrap();
+        for _ in 0..2 {
+            crate::sink::insert_measurements(
+                &config,
+                &mut db,
+                &station,
+                std::slice::from_ref(&measurement),
+                false,
+            )
+            .await
+            .unwrap();
+        }
+        drop(db);
+
+        let path = dir.join("export.csv");
+        let exported = export_measurements(
+            &config,
+            &ExportFilter::default(),
+            ExportFormat::Csv,
+            Some(&path),
+        )
+        .await
+        .unwrap();
+        let csv = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+
+        assert_eq!(exported, 1);
+        assert_eq!(
+            csv,
+            "time,station_id,temp_c,humidity_pct\n2024-06-01T12:00:00+00:00,0,21.5,48.0\n"
+        );
+    }
+}
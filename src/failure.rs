@@ -0,0 +1,134 @@
+//! The categories of failures, which tell apart why the program exited.
+
+use std::{fmt, process::ExitCode};
+
+use crate::error::Error;
+
+/// What kind of problem an error is, deciding the exit code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// The config is missing or invalid.
+    Config,
+    /// A Pico couldn't be reached or didn't follow the protocol.
+    Pico,
+    /// A Pico rejected the pre-shared key of its station or failed to prove it knows it.
+    Auth,
+    /// A Pico sent measurements which couldn't be decoded or failed validation.
+    Decode,
+    /// The database couldn't be reached or rejected measurements.
+    Database,
+    /// `verify` found measurements of a Pico missing from the database or stored differently.
+    Discrepancy,
+    /// Another instance holds the lock file.
+    Locked,
+}
+
+impl Failure {
+    pub fn code(self) -> u8 {
+        match self {
+            Failure::Config => 10,
+            Failure::Pico => 20,
+            Failure::Auth => 21,
+            Failure::Decode => 30,
+            Failure::Database => 40,
+            Failure::Discrepancy => 50,
+            Failure::Locked => 60,
+        }
+    }
+
+    pub fn exit_code(self) -> ExitCode {
+        ExitCode::from(self.code())
+    }
+
+    /// Tags `err` with this category, unless it already has one.
+    pub fn tag(self, err: anyhow::Error) -> anyhow::Error {
+        if Failure::of(&err).is_some() {
+            err
+        } else {
+            Categorized { failure: self, err }.into()
+        }
+    }
+
+    /// The category of `err` if it's an [`Error`] of one, or else the one it was tagged with, if
+    /// any.
+    pub fn of(err: &anyhow::Error) -> Option<Failure> {
+        err.downcast_ref::<Error>()
+            .and_then(Error::failure)
+            .or_else(|| {
+                err.downcast_ref::<Categorized>()
+                    .map(|categorized| categorized.failure)
+            })
+    }
+}
+
+/// An error tagged with its [`Failure`] category, displayed just like the error itself.
+#[derive(Debug)]
+struct Categorized {
+    failure: Failure,
+    err: anyhow::Error,
+}
+
+impl fmt::Display for Categorized {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.err.fmt(f)
+    }
+}
+
+impl std::error::Error for Categorized {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.err.source()
+    }
+}
+
+/// Tagging errors with their [`Failure`] category.
+pub trait Categorize<T> {
+    /// Tags the error with `failure`, unless it already has a category.
+    fn category(self, failure: Failure) -> anyhow::Result<T>;
+}
+
+impl<T, E: Into<anyhow::Error>> Categorize<T> for Result<T, E> {
+    fn category(self, failure: Failure) -> anyhow::Result<T> {
+        self.map_err(|err| failure.tag(err.into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+
+    use super::*;
+
+    #[test]
+    fn tags_errors_without_changing_them() {
+        let result: anyhow::Result<()> = Err(anyhow!("Error connecting to the Pico"));
+        let err = result.category(Failure::Pico).unwrap_err();
+
+        assert_eq!(Failure::of(&err), Some(Failure::Pico));
+        assert_eq!(err.to_string(), "Error connecting to the Pico");
+    }
+
+    #[test]
+    fn keeps_the_first_category() {
+        let result: anyhow::Result<()> = Err(anyhow!("Pico sent invalid date"));
+        let err = result
+            .category(Failure::Decode)
+            .category(Failure::Pico)
+            .unwrap_err();
+
+        assert_eq!(Failure::of(&err), Some(Failure::Decode));
+        assert_eq!(Failure::of(&anyhow!("untagged")), None);
+    }
+
+    #[test]
+    fn knows_the_category_of_typed_errors() {
+        let err = anyhow::Error::from(Error::ChecksumMismatch {
+            expected: 1,
+            computed: 2,
+        });
+        let interrupted = Failure::Pico.tag(Error::Interrupted("while testing".to_string()).into());
+
+        assert_eq!(Failure::of(&Failure::Pico.tag(err)), Some(Failure::Decode));
+        assert_eq!(Failure::of(&interrupted), Some(Failure::Pico));
+        assert_eq!(interrupted.to_string(), "Interrupted while testing");
+    }
+}
@@ -0,0 +1,139 @@
+//! Finding the gaps in the measurements of a station, where the sensor or Pico must have missed
+//! some.
+
+use chrono::{DateTime, FixedOffset, TimeDelta};
+
+use crate::{config::Config, Measurement};
+
+/// A stretch of time without measurements.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct Gap {
+    /// The time of the measurement before the gap.
+    pub start: DateTime<FixedOffset>,
+    /// The time of the measurement after the gap.
+    pub end: DateTime<FixedOffset>,
+    pub duration_secs: i64,
+}
+
+/// Finds the gaps in the measurements of a station as they arrive.
+#[derive(Debug)]
+pub struct GapDetector {
+    /// Gaps longer than this are reported.
+    threshold: TimeDelta,
+    /// The time of the latest measurement seen.
+    previous: Option<DateTime<FixedOffset>>,
+}
+
+impl GapDetector {
+    /// The detector of the gaps longer than `gap_factor` sample intervals, unless the config
+    /// leaves the sample interval open.
+    pub fn new(config: &Config) -> Option<GapDetector> {
+        let interval = config.sample_interval_secs?;
+
+        Some(GapDetector {
+            threshold: TimeDelta::seconds(interval.saturating_mul(config.gap_factor.into()) as i64),
+            previous: None,
+        })
+    }
+
+    /// Returns the gaps before and between `measurements`, the next ones in the order the Pico
+    /// sent them.
+    ///
+    /// The gaps are measured between instants, so the hour repeated or skipped when daylight
+    /// saving time begins or ends is neither one nor hides one.
+    pub fn observe(&mut self, measurements: &[Measurement]) -> Vec<Gap> {
+        let mut gaps = Vec::new();
+
+        for measurement in measurements {
+            if let Some(previous) = self.previous.replace(measurement.time) {
+                let duration = measurement.time - previous;
+
+                if duration > self.threshold {
+                    gaps.push(Gap {
+                        start: previous,
+                        end: measurement.time,
+                        duration_secs: duration.num_seconds(),
+                    });
+                }
+            }
+        }
+
+        gaps
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+
+    use super::*;
+
+    fn measurement(time: &str) -> Measurement {
+        Measurement {
+            time: DateTime::parse_from_rfc3339(time).unwrap(),
+            temp: Decimal::new(215, 1),
+            humidity: Decimal::new(480, 1),
+            uncalibrated: None,
+            out_of_range: false,
+            dew_point: None,
+            perceived_temp: None,
+            absolute_humidity: None,
+            packed: None,
+        }
+    }
+
+    fn detector() -> GapDetector {
+        GapDetector::new(&Config {
+            sample_interval_secs: Some(60),
+            ..Config::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn finds_gaps_longer_than_the_threshold() {
+        let mut detector = detector();
+
+        let first = detector.observe(&[
+            measurement("2024-06-01T12:00:00Z"),
+            measurement("2024-06-01T12:03:00Z"),
+            measurement("2024-06-01T12:10:00Z"),
+        ]);
+        // The gaps between the chunks count as well.
+        let second = detector.observe(&[measurement("2024-06-01T13:00:00Z")]);
+
+        assert_eq!(
+            first,
+            [Gap {
+                start: DateTime::parse_from_rfc3339("2024-06-01T12:03:00Z").unwrap(),
+                end: DateTime::parse_from_rfc3339("2024-06-01T12:10:00Z").unwrap(),
+                duration_secs: 420,
+            }]
+        );
+        assert_eq!(second.len(), 1);
+        assert_eq!(second[0].duration_secs, 3000);
+    }
+
+    #[test]
+    fn measures_gaps_across_daylight_saving_time() {
+        // A minute apart, although the wall clock went back an hour.
+        let end_of_summer = detector().observe(&[
+            measurement("2024-10-27T02:59:30+02:00"),
+            measurement("2024-10-27T02:00:30+01:00"),
+        ]);
+        // 20 minutes apart, although the wall clock went forward by 80.
+        let start_of_summer = detector().observe(&[
+            measurement("2024-03-31T01:50:00+01:00"),
+            measurement("2024-03-31T03:10:00+02:00"),
+        ]);
+
+        assert!(end_of_summer.is_empty());
+        assert_eq!(start_of_summer.len(), 1);
+        assert_eq!(start_of_summer[0].duration_secs, 1200);
+    }
+
+    #[test]
+    fn is_off_without_a_sample_interval() {
+        assert!(GapDetector::new(&Config::default()).is_none());
+    }
+}
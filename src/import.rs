@@ -0,0 +1,318 @@
+//! Backfilling measurements recorded by other means from a CSV file, the `import` subcommand.
+
+use std::{fmt, num::NonZeroU32, path::Path};
+
+use anyhow::anyhow;
+use chrono::{DateTime, FixedOffset, LocalResult};
+use rust_decimal::{prelude::ToPrimitive, Decimal, RoundingStrategy};
+use tracing::warn;
+
+use crate::{
+    commands::{parse_time, TimeArg},
+    config::{AmbiguousTime, Config, NonexistentTime, Station},
+    derived::derive_quantities,
+    failure::{Categorize, Failure},
+    pico::MEASUREMENT_CHUNK,
+    protocol,
+    range::{apply_ranges, report_out_of_range},
+    sink::{connect_database, StationInsert},
+    Measurement,
+};
+
+/// What an import did.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImportSummary {
+    /// How many rows were read, including the skipped ones.
+    pub rows: usize,
+    /// How many rows were skipped for not being a measurement, or an implausible one.
+    pub skipped: usize,
+    /// How many measurements were outside of the plausible ranges, and handled as `out_of_range`
+    /// says.
+    pub out_of_range: u64,
+    /// How many measurements weren't already present.
+    pub inserted: u64,
+    /// How many measurements the database rejected.
+    pub failed: usize,
+}
+
+impl fmt::Display for ImportSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} rows read, {} skipped, {} inserted, {} already present",
+            self.rows,
+            self.skipped,
+            self.inserted,
+            self.rows as u64 - self.skipped as u64 - self.inserted - self.failed as u64
+        )?;
+
+        if self.failed > 0 {
+            write!(f, ", {} failed", self.failed)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Inserts the measurements of the CSV file at `path` for `station` like the fetched ones: at the
+/// resolution the Pico measures with, calibrated, checked against the plausible ranges and with
+/// the derived quantities. Rows which aren't a measurement are skipped with a warning, or fail the
+/// import before anything is inserted if `strict` is set.
+///
+/// The file needs a header with the columns `time`, `temp` and `humidity` (or `temp_c` and
+/// `humidity_pct`, like the exports), in any order and next to others. The times are in RFC 3339,
+/// or `YYYY-MM-DD HH:MM:SS` in `pico_timezone`.
+pub async fn import_csv(
+    config: &Config,
+    station: &Station,
+    path: &Path,
+    strict: bool,
+) -> anyhow::Result<ImportSummary> {
+    let mut reader = csv::Reader::from_path(path)
+        .map_err(|err| anyhow!("Error opening CSV file {}: {err}", path.display()))
+        .category(Failure::Config)?;
+
+    let headers = reader
+        .headers()
+        .map_err(|err| anyhow!("Error reading the header of {}: {err}", path.display()))
+        .category(Failure::Decode)?
+        .clone();
+    let column = |names: &[&str]| {
+        headers
+            .iter()
+            .position(|header| names.contains(&header.trim()))
+            .ok_or_else(|| {
+                Failure::Decode.tag(anyhow!(
+                    "Error importing {}: the header has no {} column",
+                    path.display(),
+                    names[0]
+                ))
+            })
+    };
+    let columns = [
+        column(&["time"])?,
+        column(&["temp", "temp_c"])?,
+        column(&["humidity", "humidity_pct"])?,
+    ];
+
+    let mut summary = ImportSummary::default();
+    let mut measurements = Vec::new();
+
+    for record in reader.records() {
+        summary.rows += 1;
+
+        let parsed = record.map_err(|err| err.to_string()).and_then(|record| {
+            let line = record.position().map_or(0, |position| position.line());
+            let field = |index: usize| record.get(columns[index]).unwrap_or_default().trim();
+
+            parse_row(config, field(0), field(1), field(2))
+                .map_err(|err| format!("line {line}: {err}"))
+        });
+
+        match parsed {
+            Ok(Some(mut measurement)) => {
+                station.calibrate(&mut measurement);
+                measurements.push(measurement);
+            }
+            Ok(None) => summary.skipped += 1,
+            Err(err) if strict => {
+                return Err(Failure::Decode.tag(anyhow!(
+                    "Error importing {}, nothing was inserted: {err}",
+                    path.display()
+                )))
+            }
+            Err(err) => {
+                warn!("skipping a row of {}: {err}", path.display());
+                summary.skipped += 1;
+            }
+        }
+    }
+
+    let mut measurements = apply_ranges(config, measurements, &mut summary.out_of_range);
+    report_out_of_range(config, summary.out_of_range);
+    derive_quantities(config, &mut measurements);
+    // The measurements skipped as implausible count as skipped rows, the clamped and flagged
+    // ones are inserted.
+    summary.skipped += summary.rows - summary.skipped - measurements.len();
+
+    // The imported measurements are likely older than the ones stored since.
+    let config = Config {
+        skip_known: false,
+        ..config.clone()
+    };
+
+    let mut db = connect_database(&config)
+        .await
+        .category(Failure::Database)?;
+    let mut insert = StationInsert::new(&config, station, false, Some(measurements.len()));
+
+    for chunk in measurements.chunks(MEASUREMENT_CHUNK) {
+        insert
+            .push(&mut db, chunk)
+            .await
+            .category(Failure::Database)?;
+    }
+
+    insert.flush(&mut db).await.category(Failure::Database)?;
+    let inserted = insert.finish();
+
+    for failure in &inserted.failures {
+        warn!(
+            "inserting the measurement at {} failed: {}",
+            failure.time.to_rfc3339(),
+            failure.error
+        );
+    }
+
+    summary.inserted = inserted.inserted;
+    summary.failed = inserted.failures.len();
+
+    Ok(summary)
+}
+
+/// The measurement of a row, unless its time doesn't map to a single instant and the config says
+/// to skip such measurements.
+fn parse_row(
+    config: &Config,
+    time: &str,
+    temp: &str,
+    humidity: &str,
+) -> Result<Option<Measurement>, String> {
+    let time = match parse_time(time)? {
+        TimeArg::Instant(time) => time,
+        TimeArg::Local(local_time) => {
+            match resolve(config, config.pico_timezone.resolve(&local_time)) {
+                Ok(Some(time)) => time,
+                Ok(None) => {
+                    warn!("skipping the row at the ambiguous or impossible time {local_time}");
+                    return Ok(None);
+                }
+                Err(err) => return Err(format!("{local_time} {err}")),
+            }
+        }
+    };
+
+    Ok(Some(Measurement {
+        time,
+        temp: at_resolution(temp, config.temp_scale)
+            .ok_or_else(|| format!("{temp:?} isn't a temperature in degrees Celsius"))?,
+        humidity: at_resolution(humidity, config.humidity_scale)
+            .ok_or_else(|| format!("{humidity:?} isn't a humidity in percent"))?,
+        uncalibrated: None,
+        out_of_range: false,
+        dew_point: None,
+        perceived_temp: None,
+        absolute_humidity: None,
+        packed: None,
+    }))
+}
+
+/// The instant of a local time like [`protocol::decode_measurement`] resolves it, `None` if it's
+/// to be skipped.
+fn resolve(
+    config: &Config,
+    result: LocalResult<DateTime<FixedOffset>>,
+) -> Result<Option<DateTime<FixedOffset>>, &'static str> {
+    match (result, config.ambiguous_time, config.nonexistent_time) {
+        (LocalResult::Single(time), _, _) => Ok(Some(time)),
+        (LocalResult::Ambiguous(first, second), AmbiguousTime::Earliest, _) => {
+            Ok(Some(first.min(second)))
+        }
+        (LocalResult::Ambiguous(first, second), AmbiguousTime::Latest, _) => {
+            Ok(Some(first.max(second)))
+        }
+        (LocalResult::Ambiguous(_, _), AmbiguousTime::Skip, _)
+        | (LocalResult::None, _, NonexistentTime::Skip) => Ok(None),
+        (LocalResult::Ambiguous(_, _), AmbiguousTime::Error, _) => {
+            Err("is ambiguous in pico_timezone")
+        }
+        (LocalResult::None, _, NonexistentTime::Error) => Err("doesn't exist in pico_timezone"),
+    }
+}
+
+/// `value` rounded to the resolution the Pico measures with at `scale`, like it would've sent it.
+fn at_resolution(value: &str, scale: NonZeroU32) -> Option<Decimal> {
+    let raw = (value.parse::<Decimal>().ok()? * Decimal::from(scale.get()))
+        .round_dp_with_strategy(0, RoundingStrategy::MidpointAwayFromZero)
+        .to_i32()?;
+
+    Some(protocol::scale(raw, scale))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{PicoTimezone, Storage};
+
+    #[test]
+    fn parses_rows_like_the_pico_sends_them() {
+        let config = Config {
+            pico_timezone: PicoTimezone::Named(chrono_tz::Europe::Berlin),
+            ambiguous_time: AmbiguousTime::Skip,
+            ..Config::default()
+        };
+
+        let measurement = parse_row(&config, "2024-06-01 12:00:00", "21.54", "48.05")
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(measurement.time.to_rfc3339(), "2024-06-01T12:00:00+02:00");
+        assert_eq!(measurement.temp.to_string(), "21.5");
+        assert_eq!(measurement.humidity.to_string(), "48.1");
+        assert_eq!(
+            parse_row(&config, "2024-10-27 02:30:00", "21.5", "48.0"),
+            Ok(None)
+        );
+        assert!(parse_row(&config, "2024-03-31 02:30:00", "21.5", "48.0").is_err());
+        assert!(parse_row(&config, "yesterday", "21.5", "48.0").is_err());
+        assert!(parse_row(&config, "2024-06-01T12:00:00Z", "warm", "48.0").is_err());
+    }
+
+    #[tokio::test]
+    async fn skips_bad_rows_unless_strict() {
+        let dir = std::env::temp_dir().join("pico_humidity_temp_read_import");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let config = Config {
+            storage: Storage::Sqlite {
+                path: dir.join("measurements.db"),
+            },
+            humidity_max: Some(Decimal::ONE_HUNDRED),
+            out_of_range: crate::config::OutOfRange::Skip,
+            ..Config::default()
+        };
+        let station = config.stations[0].clone();
+        let path = dir.join("old.csv");
+        std::fs::write(
+            &path,
+            "time,temp,humidity\n\
+             2024-01-01T00:00:00Z,20.1,50.0\n\
+             2024-01-01T00:15:00Z,,50.0\n\
+             2024-01-01T00:30:00Z,20.3,120.0\n\
+             2024-01-01T00:45:00Z,20.4,50.0\n",
+        )
+        .unwrap();
+
+        let strict = import_csv(&config, &station, &path, true).await;
+        let summary = import_csv(&config, &station, &path, false).await.unwrap();
+        let again = import_csv(&config, &station, &path, false).await.unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+
+        assert!(strict.unwrap_err().to_string().contains("line 3"));
+        assert_eq!(
+            summary,
+            ImportSummary {
+                rows: 4,
+                skipped: 2,
+                out_of_range: 1,
+                inserted: 2,
+                failed: 0,
+            }
+        );
+        assert_eq!(
+            again.to_string(),
+            "4 rows read, 2 skipped, 0 inserted, 2 already present"
+        );
+    }
+}
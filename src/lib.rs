@@ -0,0 +1,223 @@
+//! Reads the measurements from Picos running
+//! [PicoHumidityTemperature](https://github.com/Cookie04DE/PicoHumidityTemperature) and stores them.
+
+use std::{
+    borrow::Cow,
+    fmt::Display,
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, FixedOffset};
+use rand::Rng;
+use rust_decimal::{prelude::ToPrimitive, Decimal};
+use tracing::warn;
+
+pub mod aggregate;
+pub mod alerts;
+pub mod auth;
+pub mod buffer;
+pub mod clock;
+pub mod commands;
+pub mod config;
+pub mod cycle;
+pub mod derived;
+pub mod duplicates;
+pub mod error;
+pub mod export;
+pub mod failure;
+pub mod gaps;
+pub mod import;
+pub mod listen;
+pub mod live;
+pub mod lock;
+pub mod metrics;
+pub mod mqtt;
+pub mod output;
+pub mod pico;
+pub mod progress;
+pub mod protocol;
+pub mod protocol_trace;
+pub mod prune;
+pub mod range;
+pub mod recording;
+pub mod report;
+pub mod schedule;
+pub mod secrets;
+pub mod shutdown;
+pub mod simulate;
+pub mod sink;
+pub mod spikes;
+pub mod spool;
+pub mod stats;
+pub mod systemd;
+pub mod telemetry;
+pub mod trigger;
+pub mod verify;
+
+pub use config::Config;
+
+use config::TemperatureUnit;
+
+/// A measurement taken by a Pico.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct Measurement {
+    pub time: DateTime<FixedOffset>,
+    /// Temperature in degrees Celsius, serialized as a number.
+    #[serde(serialize_with = "serialize_decimal")]
+    pub temp: Decimal,
+    /// Relative humidity in percent, serialized as a number.
+    #[serde(serialize_with = "serialize_decimal")]
+    pub humidity: Decimal,
+    /// The temperature and humidity before the calibration offsets of the station were added, if
+    /// it has any.
+    #[serde(skip)]
+    pub uncalibrated: Option<(Decimal, Decimal)>,
+    /// The measurement is outside of the plausible ranges but stored anyway.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub out_of_range: bool,
+    /// The dew point in degrees Celsius, if `compute_dew_point` is set and the humidity allows
+    /// computing it, serialized as a number.
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_optional_decimal"
+    )]
+    pub dew_point: Option<Decimal>,
+    /// The perceived temperature in degrees Celsius, if `perceived_temp` is set and the formula
+    /// is valid for the values, serialized as a number.
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_optional_decimal"
+    )]
+    pub perceived_temp: Option<Decimal>,
+    /// The absolute humidity in grams per cubic meter, if `derived_metrics` has it and the humidity
+    /// allows computing it, serialized as a number.
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_optional_decimal"
+    )]
+    pub absolute_humidity: Option<Decimal>,
+    /// The packed measurement the Pico sent, if `store_raw_packed` is set. Its 61 bits fit into
+    /// the `bigint` of the `raw` column as they are.
+    #[serde(skip)]
+    pub packed: Option<i64>,
+}
+
+impl Measurement {
+    /// The temperature and humidity as sent by the Pico.
+    pub fn raw(&self) -> (&Decimal, &Decimal) {
+        match &self.uncalibrated {
+            Some((temp, humidity)) => (temp, humidity),
+            None => (&self.temp, &self.humidity),
+        }
+    }
+
+    /// This measurement with its temperature, dew point and perceived temperature in `unit`, for
+    /// presenting it.
+    pub fn in_unit(&self, unit: TemperatureUnit) -> Cow<'_, Measurement> {
+        if unit == TemperatureUnit::Celsius {
+            return Cow::Borrowed(self);
+        }
+
+        Cow::Owned(Measurement {
+            temp: unit.convert(self.temp),
+            dew_point: self.dew_point.map(|dew_point| unit.convert(dew_point)),
+            perceived_temp: self.perceived_temp.map(|temp| unit.convert(temp)),
+            ..self.clone()
+        })
+    }
+}
+
+fn serialize_decimal<S: serde::Serializer>(
+    value: &Decimal,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(value.to_f64().unwrap_or(f64::NAN))
+}
+
+fn serialize_optional_decimal<S: serde::Serializer>(
+    value: &Option<Decimal>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(value) => serialize_decimal(value, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// A measurement tagged with the station it was taken at, as the JSON outputs present it.
+#[derive(Debug, serde::Serialize)]
+pub struct StationMeasurement<'a> {
+    pub station_id: i32,
+    #[serde(flatten)]
+    pub measurement: Cow<'a, Measurement>,
+    /// The unit of the temperatures, unless it's Celsius.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temp_unit: Option<TemperatureUnit>,
+}
+
+impl<'a> StationMeasurement<'a> {
+    /// `measurement` taken at the station with `station_id`, with its temperatures in `unit`.
+    pub fn new(
+        station_id: i32,
+        measurement: &'a Measurement,
+        unit: TemperatureUnit,
+    ) -> StationMeasurement<'a> {
+        StationMeasurement {
+            station_id,
+            measurement: measurement.in_unit(unit),
+            temp_unit: (unit != TemperatureUnit::Celsius).then_some(unit),
+        }
+    }
+}
+
+/// An operation [`retry_with_backoff`] gave up on.
+#[derive(Debug)]
+pub struct Retried<E> {
+    pub attempts: u32,
+    pub elapsed: Duration,
+    /// The error of the last attempt.
+    pub err: E,
+}
+
+/// Runs `operation` until it succeeds, retrying it up to `retries` times with exponential backoff
+/// (plus jitter) starting at `backoff`, unless it fails with an error `retryable` rejects. `what`
+/// describes the operation for the warnings.
+pub async fn retry_with_backoff<T, E: Display, F: Future<Output = Result<T, E>>>(
+    retries: u32,
+    mut backoff: Duration,
+    what: &str,
+    retryable: impl Fn(&E) -> bool,
+    mut operation: impl FnMut() -> F,
+) -> Result<T, Retried<E>> {
+    let start = Instant::now();
+    let mut attempts = 0;
+
+    loop {
+        attempts += 1;
+
+        let err = match operation().await {
+            Ok(value) => return Ok(value),
+            Err(err) => err,
+        };
+
+        if attempts > retries || !retryable(&err) {
+            return Err(Retried {
+                attempts,
+                elapsed: start.elapsed(),
+                err,
+            });
+        }
+
+        let delay = backoff.mul_f64(rand::thread_rng().gen_range(0.5..1.5));
+
+        warn!(
+            attempt = attempts,
+            "{what} failed: {err}; retrying in {delay:.1?}"
+        );
+
+        tokio::time::sleep(delay).await;
+
+        backoff = backoff.saturating_mul(2);
+    }
+}
@@ -0,0 +1,183 @@
+//! Listen mode, where the Picos connect to the host instead of the other way around.
+
+use std::{net::SocketAddr, path::Path, time::Duration};
+
+use anyhow::anyhow;
+use chrono::Utc;
+use futures::{stream::FuturesUnordered, StreamExt};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{lookup_host, TcpListener, TcpStream},
+    signal::unix::{signal, SignalKind},
+};
+use tracing::{error, info, info_span, warn, Instrument};
+
+use crate::{
+    config::{Config, IdentifyBy, Station},
+    cycle::{run_accepted, CycleOptions},
+    failure::{Categorize, Failure},
+    output::Outputs,
+    report::RunReport,
+    shutdown::SHUTDOWN,
+    sink::Database,
+    systemd::notify,
+};
+
+/// Listens at `listen_address` until shutdown is requested, downloading and inserting the
+/// measurements of every Pico connecting like a cycle of its station alone does. Up to
+/// `max_concurrent_fetches` Picos are served at once, further ones wait to be accepted.
+///
+/// Like every cycle when polling, every session writes its summary to `summary_json`.
+pub async fn listen(
+    options: &CycleOptions,
+    config: &Config,
+    stations: &[Station],
+    outputs: &mut Outputs,
+    summary_json: Option<&Path>,
+) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(&config.listen_address)
+        .await
+        .map_err(|err| anyhow!("Error listening at {}: {err}", config.listen_address))
+        .category(Failure::Config)?;
+
+    // Without a handler, SIGHUP would end the program.
+    let mut sighup = signal(SignalKind::hangup())
+        .map_err(|err| anyhow!("Error installing the SIGHUP handler: {err}"))?;
+
+    info!("listening for the Picos at {}", config.listen_address);
+    notify("READY=1");
+
+    let shared = Shared {
+        options,
+        config,
+        stations,
+        idle: std::sync::Mutex::new(Vec::new()),
+        outputs: std::sync::Mutex::new(outputs),
+        summary_json,
+    };
+    let mut sessions = FuturesUnordered::new();
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept(), if sessions.len() < config.max_concurrent_fetches.get() => {
+                match accepted {
+                    Ok((pico_stream, peer)) => sessions.push(
+                        shared
+                            .serve(pico_stream, peer)
+                            .instrument(info_span!("connection", %peer)),
+                    ),
+                    Err(err) => error!("accepting a connection failed: {err}"),
+                }
+            }
+            Some(()) = sessions.next() => {}
+            _ = sighup.recv() => warn!("the config isn't reloaded in listen mode, restart the program instead"),
+            () = SHUTDOWN.requested() => break,
+        }
+    }
+
+    // The sessions still running notice the shutdown themselves.
+    while sessions.next().await.is_some() {}
+
+    Ok(())
+}
+
+/// What the sessions running at once share.
+struct Shared<'a> {
+    options: &'a CycleOptions,
+    config: &'a Config,
+    stations: &'a [Station],
+    /// The database connections idling between sessions, up to one per session running at once.
+    idle: std::sync::Mutex<Vec<Database>>,
+    outputs: std::sync::Mutex<&'a mut Outputs>,
+    summary_json: Option<&'a Path>,
+}
+
+impl Shared<'_> {
+    /// Identifies the station of the Pico which connected from `peer` and runs its session.
+    async fn serve(&self, mut pico_stream: TcpStream, peer: SocketAddr) {
+        let station = match identify(self.config, self.stations, &mut pico_stream, peer).await {
+            Ok(station) => station,
+            Err(err) => {
+                warn!("closing the connection: {err}");
+                let _ = pico_stream.shutdown().await;
+                return;
+            }
+        };
+
+        info!(station_id = station.station_id, "the Pico connected");
+
+        let start = Utc::now();
+        let mut database = self.idle.lock().unwrap().pop();
+        let result = run_accepted(
+            self.options,
+            self.config,
+            self.stations,
+            station,
+            pico_stream,
+            &mut database,
+            &self.outputs,
+        )
+        .await;
+
+        if let Some(db) = database {
+            self.idle.lock().unwrap().push(db);
+        }
+
+        if let Some(path) = self.summary_json {
+            if let Err(err) = RunReport::new(start, &result).write(path) {
+                error!("{err}");
+            }
+        }
+
+        match result {
+            Ok(summary) => notify(&format!("STATUS=station {}: {summary}", station.station_id)),
+            Err(err) => error!(station_id = station.station_id, "session failed: {err}"),
+        }
+    }
+}
+
+/// The station of the Pico which connected from `peer`, by its address or the station id it sends
+/// first, depending on `identify_by`.
+async fn identify<'a>(
+    config: &Config,
+    stations: &'a [Station],
+    pico_stream: &mut TcpStream,
+    peer: SocketAddr,
+) -> anyhow::Result<&'a Station> {
+    match config.identify_by {
+        IdentifyBy::Address => {
+            let address = peer.ip().to_canonical();
+
+            for station in stations {
+                match lookup_host((station.pico.as_str(), station.pico_port)).await {
+                    Ok(mut resolved) => {
+                        if resolved.any(|resolved| resolved.ip().to_canonical() == address) {
+                            return Ok(station);
+                        }
+                    }
+                    Err(err) => warn!(
+                        station_id = station.station_id,
+                        "resolving the Pico at {} failed: {err}", station.pico
+                    ),
+                }
+            }
+
+            Err(anyhow!("no station's pico is {address}, which connected"))
+        }
+        IdentifyBy::StationByte => {
+            let timeout = Duration::from_secs(config.read_timeout_secs);
+            let station_id = tokio::time::timeout(timeout, pico_stream.read_u8())
+                .await
+                .map_err(|_| anyhow!("timed out after {timeout:?}"))
+                .and_then(|read| read.map_err(anyhow::Error::from))
+                .map_err(|err| anyhow!("Error reading the station id from the Pico: {err}"))?;
+
+            stations
+                .iter()
+                .find(|station| station.station_id == i32::from(station_id))
+                .ok_or_else(|| {
+                    anyhow!("the Pico sent the station id {station_id}, which isn't configured")
+                })
+        }
+    }
+}
@@ -0,0 +1,182 @@
+//! Streaming the measurements to websocket clients at `/ws` in daemon mode, as they're inserted.
+
+use std::sync::{Arc, OnceLock};
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Query,
+    },
+    response::Response,
+};
+use tokio::sync::broadcast::{self, error::RecvError, Receiver, Sender};
+use tracing::{debug, warn};
+
+use crate::{
+    config::{Station, TemperatureUnit},
+    Measurement, StationMeasurement,
+};
+
+/// The version of the messages sent to the clients, announced to every client once it connects.
+pub const LIVE_PROTOCOL_VERSION: u32 = 1;
+
+/// How many measurements are buffered for every client. A client falling further behind misses
+/// the oldest ones instead of holding up the inserts.
+const CLIENT_BUFFER: usize = 1024;
+
+/// A measurement as sent to the clients.
+#[derive(Debug, Clone)]
+struct LiveMeasurement {
+    station_id: i32,
+    /// The measurement as JSON, like `--output-jsonl` writes it.
+    json: Arc<str>,
+}
+
+/// The measurements sent to the clients, once the first one connected.
+static LIVE: OnceLock<Sender<LiveMeasurement>> = OnceLock::new();
+
+/// Sends the newly inserted `measurements` of `station` to the connected clients with the
+/// temperatures in `unit`, without waiting for any of them.
+pub fn publish(station: &Station, measurements: &[&Measurement], unit: TemperatureUnit) {
+    let Some(sender) = LIVE.get() else {
+        return;
+    };
+
+    if sender.receiver_count() == 0 {
+        return;
+    }
+
+    for measurement in measurements {
+        let json = match serde_json::to_string(&StationMeasurement::new(
+            station.station_id,
+            measurement,
+            unit,
+        )) {
+            Ok(json) => json,
+            Err(err) => {
+                warn!("Error serializing a measurement for the live stream: {err}");
+                continue;
+            }
+        };
+
+        // Only fails once the last client disconnected.
+        let _ = sender.send(LiveMeasurement {
+            station_id: station.station_id,
+            json: json.into(),
+        });
+    }
+}
+
+/// The query of a client connecting to `/ws`.
+#[derive(Debug, serde::Deserialize)]
+pub struct LiveFilter {
+    /// Only send the measurements of this station.
+    station: Option<i32>,
+}
+
+/// Upgrades the connection of a client to a websocket streaming the measurements to it.
+pub async fn serve_live(upgrade: WebSocketUpgrade, Query(filter): Query<LiveFilter>) -> Response {
+    // Subscribed right away, so nothing inserted while the connection is upgraded is missed.
+    let receiver = LIVE
+        .get_or_init(|| broadcast::channel(CLIENT_BUFFER).0)
+        .subscribe();
+
+    upgrade.on_upgrade(move |socket| stream(socket, receiver, filter.station))
+}
+
+/// Announces the protocol version to the client, then sends it every measurement of `station`, or
+/// of every station without one, until it disconnects.
+async fn stream(
+    mut socket: WebSocket,
+    mut receiver: Receiver<LiveMeasurement>,
+    station: Option<i32>,
+) {
+    let hello = format!(r#"{{"protocol":"picoread-live","version":{LIVE_PROTOCOL_VERSION}}}"#);
+
+    if socket.send(Message::Text(hello.into())).await.is_err() {
+        return;
+    }
+
+    loop {
+        let message = tokio::select! {
+            received = receiver.recv() => match received {
+                Ok(measurement) if station.is_none_or(|station| station == measurement.station_id) => {
+                    Message::Text(measurement.json.as_ref().into())
+                }
+                Ok(_) => continue,
+                Err(RecvError::Lagged(missed)) => {
+                    warn!("a live stream client fell behind and missed {missed} measurements");
+                    Message::Text(format!(r#"{{"missed":{missed}}}"#).into())
+                }
+                Err(RecvError::Closed) => return,
+            },
+            received = socket.recv() => match received {
+                Some(Ok(Message::Close(_)) | Err(_)) | None => return,
+                Some(Ok(_)) => continue,
+            },
+        };
+
+        if let Err(err) = socket.send(message).await {
+            debug!("a live stream client disconnected: {err}");
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::DateTime;
+    use futures::StreamExt;
+    use rust_decimal::Decimal;
+    use tokio_tungstenite::tungstenite;
+
+    use super::*;
+    use crate::config::Config;
+
+    #[tokio::test]
+    async fn streams_the_measurements_of_the_requested_station() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let router = axum::Router::new().route("/ws", axum::routing::get(serve_live));
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        let (mut client, _) =
+            tokio_tungstenite::connect_async(format!("ws://127.0.0.1:{port}/ws?station=41"))
+                .await
+                .unwrap();
+
+        let station = |station_id| Station {
+            station_id,
+            ..Config::default().stations[0].clone()
+        };
+        let measurement = |temp| Measurement {
+            time: DateTime::parse_from_rfc3339("2024-06-01T12:00:00Z").unwrap(),
+            temp: Decimal::new(temp, 1),
+            humidity: Decimal::new(480, 1),
+            uncalibrated: None,
+            out_of_range: false,
+            dew_point: None,
+            perceived_temp: None,
+            absolute_humidity: None,
+            packed: None,
+        };
+        publish(&station(40), &[&measurement(200)], TemperatureUnit::Celsius);
+        publish(&station(41), &[&measurement(215)], TemperatureUnit::Celsius);
+
+        let mut received = Vec::new();
+        for _ in 0..2 {
+            match client.next().await.unwrap().unwrap() {
+                tungstenite::Message::Text(text) => received.push(text.to_string()),
+                other => panic!("expected a text message, got {other:?}"),
+            }
+        }
+
+        assert_eq!(
+            received,
+            [
+                r#"{"protocol":"picoread-live","version":1}"#,
+                r#"{"station_id":41,"time":"2024-06-01T12:00:00Z","temp":21.5,"humidity":48.0}"#
+            ]
+        );
+    }
+}
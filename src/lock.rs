@@ -0,0 +1,115 @@
+//! The lock file keeping two instances from fetching the measurements of the Picos at once.
+
+use std::{
+    fs::{File, OpenOptions, TryLockError},
+    io::{Read, Seek, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use tokio::time::{sleep, Instant};
+
+use crate::{error::Error, shutdown::SHUTDOWN};
+
+/// How often the lock file is tried again while waiting for it.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The lock on the lock file, held until it's dropped. Since the kernel releases it once the
+/// process ends, a lock file left behind by a crashed instance doesn't keep others from running.
+#[derive(Debug)]
+pub struct InstanceLock {
+    _file: File,
+}
+
+impl InstanceLock {
+    /// Locks the file at `path`, creating it if needed, and writes the PID of this process into
+    /// it. If another instance holds the lock, waits up to `wait` for it to be released.
+    pub async fn acquire(path: &Path, wait: Duration) -> Result<InstanceLock, Error> {
+        let failed = |err: std::io::Error| {
+            Error::Config(format!(
+                "Error locking the lock file {}: {err}",
+                path.display()
+            ))
+        };
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(failed)?;
+        let deadline = Instant::now() + wait;
+
+        loop {
+            match file.try_lock() {
+                Ok(()) => break,
+                Err(TryLockError::WouldBlock) if Instant::now() < deadline => {
+                    tokio::select! {
+                        () = sleep(POLL_INTERVAL) => {}
+                        () = SHUTDOWN.requested() => {
+                            return Err(Error::Interrupted("waiting for the lock file".to_string()));
+                        }
+                    }
+                }
+                Err(TryLockError::WouldBlock) => {
+                    return Err(Error::Locked {
+                        path: path.to_owned(),
+                        pid: holder(&mut file),
+                    })
+                }
+                Err(TryLockError::Error(err)) => return Err(failed(err)),
+            }
+        }
+
+        file.set_len(0)
+            .and_then(|()| file.rewind())
+            .and_then(|()| writeln!(file, "{}", std::process::id()))
+            .map_err(failed)?;
+
+        Ok(InstanceLock { _file: file })
+    }
+}
+
+/// The PID the instance holding the lock wrote into the lock file.
+fn holder(file: &mut File) -> Option<u32> {
+    let mut contents = String::new();
+    file.rewind().ok()?;
+    file.read_to_string(&mut contents).ok()?;
+    contents.trim().parse().ok()
+}
+
+/// The lock file of the config at `config_path`, next to it with `.lock` appended.
+pub fn default_lock_path(config_path: &Path) -> PathBuf {
+    let mut path = config_path.as_os_str().to_owned();
+    path.push(".lock");
+    path.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn tells_who_holds_the_lock() {
+        let path = std::env::temp_dir().join(format!("lock_test_{}.lock", std::process::id()));
+        let held = InstanceLock::acquire(&path, Duration::ZERO).await.unwrap();
+
+        match InstanceLock::acquire(&path, Duration::from_millis(150)).await {
+            Err(Error::Locked { pid, .. }) => assert_eq!(pid, Some(std::process::id())),
+            other => panic!("expected the lock to be held, got {other:?}"),
+        }
+
+        drop(held);
+        InstanceLock::acquire(&path, Duration::ZERO).await.unwrap();
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn puts_the_lock_file_next_to_the_config() {
+        assert_eq!(
+            default_lock_path(Path::new("/etc/picoread/config.toml")),
+            Path::new("/etc/picoread/config.toml.lock")
+        );
+    }
+}
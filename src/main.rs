@@ -1,174 +1,856 @@
-use std::{io::ErrorKind, process::ExitCode};
+use std::{
+    path::{Path, PathBuf},
+    process::ExitCode,
+    time::Duration,
+};
 
 use anyhow::anyhow;
-use chrono::{
-    offset::LocalResult, DateTime, Datelike, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone,
-    Timelike,
-};
-use tokio::{
-    fs,
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
+use chrono::{DateTime, SecondsFormat, SubsecRound, Utc};
+use clap::Parser;
+use pico_humidity_temp_read::{
+    clock,
+    commands::{
+        parse_packed, parse_time, ping_stations, print_decoded, print_status, sync_clocks, TimeArg,
+    },
+    config::{
+        config_path, load_config, reload_config, Config, Mode, OtlpConfig, Retention, Station,
+    },
+    cycle::{run_cycle, run_replay, CycleOptions, CycleSummary, SUMMARY_TARGET},
+    export::{export_measurements, ExportFilter, ExportFormat},
+    failure::{Categorize, Failure},
+    import::import_csv,
+    listen::listen,
+    live::serve_live,
+    lock::InstanceLock,
+    metrics::serve_metrics,
+    output::Outputs,
+    progress, protocol_trace,
+    prune::{prune_measurements, PruneFilter},
+    report::RunReport,
+    schedule::next_run,
+    secrets::set_keyring_password,
+    shutdown::{handle_signals, SHUTDOWN},
+    simulate::run_simulation,
+    sink::{check_schema, init_schema, register_stations, Database},
+    stats::{print_stats, station_stats, StatsFilter, StatsFormat},
+    systemd::{notify, spawn_watchdog},
+    telemetry::{self, otlp_layer},
+    trigger::{handle_sigusr1, serve_fetch, FETCH_TRIGGER},
+    verify::verify_stations,
 };
-use tokio_postgres::{types::Type, NoTls};
-
-#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
-struct Config {
-    db_url: String,
-    pico: String,
-    pico_port: u16,
-    station_id: i32,
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info, warn, Subscriber};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
+
+/// Reads the measurements from the Pico and inserts them into a PostgreSQL database.
+#[derive(Debug, Parser)]
+#[command(version)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Path of the config file, TOML if it ends in .toml and JSON otherwise; a default config is
+    /// written there if it doesn't exist [env: PICOREAD_CONFIG] [default: config.toml or
+    /// config.json]
+    #[arg(short, long, global = true)]
+    config: Option<PathBuf>,
+    /// Print the fetched measurements instead of inserting them, without connecting to the database
+    #[arg(long)]
+    dry_run: bool,
+    /// Record the measurements the Picos send to this file, overwriting it, to be replayed with
+    /// `replay`; with several stations, their ids are appended to it
+    #[arg(long, value_name = "PATH")]
+    record: Option<PathBuf>,
+    /// Also write the fetched measurements to this CSV file, appending if it already exists
+    #[arg(long, value_name = "PATH")]
+    output_csv: Option<PathBuf>,
+    /// Overwrite the CSV file instead of appending to it
+    #[arg(long, requires = "output_csv")]
+    csv_overwrite: bool,
+    /// Also write the fetched measurements as JSON lines to this file (appending) or `-` for stdout
+    #[arg(long, value_name = "PATH")]
+    output_jsonl: Option<PathBuf>,
+    /// Also write the fetched measurements to this Parquet file, overwriting it; it's complete
+    /// once the program exits
+    #[arg(long, value_name = "PATH")]
+    output_parquet: Option<PathBuf>,
+    /// How many measurements every row group of the Parquet file holds
+    #[arg(
+        long,
+        value_name = "ROWS",
+        default_value_t = 65536,
+        value_parser = clap::value_parser!(u32).range(1..),
+        requires = "output_parquet"
+    )]
+    parquet_row_group_size: u32,
+    /// Print the fetched measurements as a table (or tab separated values if stdout isn't a
+    /// terminal), also when they are stored
+    #[arg(long)]
+    print_table: bool,
+    /// Print only the latest N measurements of every station in the table
+    #[arg(long, value_name = "N", requires = "print_table")]
+    last: Option<usize>,
+    /// Write a summary of the run (or every cycle, when polling) as JSON to this file or `-` for
+    /// stdout
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["check_config", "init_db", "check_schema", "simulate"])]
+    summary_json: Option<PathBuf>,
+    /// Fetch the measurements once and exit, even with `poll_interval_secs` or `schedule`
+    #[arg(long)]
+    run_once: bool,
+    /// Stop inserting the measurements of a station at the first one that can't be inserted
+    #[arg(long)]
+    fail_fast: bool,
+    /// Set the clocks of the Picos even if the host's looks wrong according to
+    /// `min_plausible_time` or `require_ntp_sync`
+    #[arg(long, global = true)]
+    force_time_sync: bool,
+    /// Check the config for problems without connecting to anything, then exit
+    #[arg(long, conflicts_with_all = ["init_db", "check_schema", "simulate"])]
+    check_config: bool,
+    /// Create the measurement table and its unique index unless they already exist, then exit
+    #[arg(long, conflicts_with = "check_schema")]
+    init_db: bool,
+    /// Check that the database has the expected measurement table, then exit
+    #[arg(long)]
+    check_schema: bool,
+    /// Insert this many simulated measurements for every station instead of fetching any, then
+    /// report the insert throughput and exit
+    #[arg(long, value_name = "COUNT", conflicts_with = "dry_run")]
+    simulate: Option<u32>,
+    /// Seconds between the simulated measurements, the last one being taken now
+    #[arg(long, value_name = "SECS", default_value_t = 60, requires = "simulate")]
+    simulate_interval_secs: u32,
+    /// Wait up to this many seconds for another instance to release the lock file instead of
+    /// exiting right away
+    #[arg(long, value_name = "SECS")]
+    wait_lock: Option<u64>,
+    /// Append every byte sent to and received from the Picos to this file, as hex with the time,
+    /// station and direction, and log the bit fields of every measurement with `-vv`
+    #[arg(long, value_name = "PATH", global = true)]
+    trace_protocol: Option<PathBuf>,
+    /// Bytes the protocol trace may grow to before it's moved aside to its path with `.1`
+    /// appended and a new one started
+    #[arg(long, value_name = "BYTES", default_value_t = 16 << 20, requires = "trace_protocol", global = true)]
+    trace_max_bytes: u64,
+    /// Log only errors, and don't report the progress of long downloads and inserts
+    #[arg(short, long, global = true)]
+    quiet: bool,
+    /// Log more: once for the connections and how long the stations took, twice also for every
+    /// decoded measurement
+    #[arg(short, long, action = clap::ArgAction::Count, global = true, conflicts_with = "quiet")]
+    verbose: u8,
+    /// Log level or filter directives like `info,pico_humidity_temp_read=debug`, overriding
+    /// `--quiet` and `--verbose`; defaults to `RUST_LOG` without those, or else a summary of every
+    /// run besides the warnings and errors
+    #[arg(long, value_name = "FILTER", global = true)]
+    log_level: Option<String>,
+    /// Format of the log lines written to stderr
+    #[arg(long, value_enum, default_value_t = LogFormat::Text, global = true)]
+    log_format: LogFormat,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, clap::Subcommand)]
+enum Command {
+    /// Fetch the measurements of every station and store them, the default
+    Fetch,
+    /// Only set the clocks of the Picos, without downloading anything
+    SyncTime,
+    /// Print how many measurements the Picos have stored, without downloading them
+    Status,
+    /// Check that the Picos answer, printing how many measurements each has pending, without
+    /// downloading them or setting their clocks
+    Ping {
+        /// Give up on a Pico after this many seconds, connecting included
+        #[arg(long, value_name = "SECS", default_value_t = 10)]
+        timeout: u64,
+    },
+    /// Print the measurement a packed one (like in the `raw` column) decodes to, without
+    /// connecting to anything
+    Decode {
+        /// The packed measurement, in decimal or as hex with `0x`
+        #[arg(value_parser = parse_packed)]
+        packed: u64,
+    },
+    /// Write the stored measurements to stdout or a file in chronological order, without
+    /// connecting to the Picos
+    Export {
+        /// Only export the measurements of this station
+        #[arg(long, value_name = "ID")]
+        station: Option<i32>,
+        /// Only export the measurements taken at or after this time, in RFC 3339 or as
+        /// `YYYY-MM-DD [HH:MM:SS]` in pico_timezone
+        #[arg(long, value_name = "TIME", value_parser = parse_time)]
+        from: Option<TimeArg>,
+        /// Only export the measurements taken before this time, like `--from`
+        #[arg(long, value_name = "TIME", value_parser = parse_time)]
+        to: Option<TimeArg>,
+        #[arg(long, value_enum, default_value_t = ExportFormat::Csv)]
+        format: ExportFormat,
+        /// Write them to this file, overwriting it, instead of stdout
+        #[arg(short, long, value_name = "PATH")]
+        output: Option<PathBuf>,
+    },
+    /// Print the count, extremes, average and latest value of the temperature and humidity of
+    /// the stored measurements of every station, without connecting to the Picos
+    Stats {
+        /// Summarize the measurements of this station, may be given several times
+        #[arg(long, value_name = "ID", required_unless_present = "all_stations")]
+        station: Vec<i32>,
+        /// Summarize every station with measurements in the range
+        #[arg(long, conflicts_with = "station")]
+        all_stations: bool,
+        /// Only summarize the measurements of this long before `--to` (or now), like `7d` or
+        /// `12 hours`
+        #[arg(long, value_name = "DURATION", conflicts_with = "from")]
+        period: Option<Retention>,
+        /// Only summarize the measurements taken at or after this time, in RFC 3339 or as
+        /// `YYYY-MM-DD [HH:MM:SS]` in pico_timezone
+        #[arg(long, value_name = "TIME", value_parser = parse_time)]
+        from: Option<TimeArg>,
+        /// Only summarize the measurements taken before this time, like `--from`
+        #[arg(long, value_name = "TIME", value_parser = parse_time)]
+        to: Option<TimeArg>,
+        #[arg(long, value_enum, default_value_t = StatsFormat::Text)]
+        format: StatsFormat,
+    },
+    /// Delete the stored measurements older than `--keep` in batches, without connecting to the
+    /// Picos
+    Prune {
+        /// How long the measurements are kept, like `365d` or `12 hours`
+        #[arg(long, value_name = "DURATION")]
+        keep: Retention,
+        /// Only delete the measurements of this station
+        #[arg(long, value_name = "ID")]
+        station: Option<i32>,
+        /// Only print how many measurements would be deleted
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Ask for the database password without echoing it and store it in the OS keyring entry of
+    /// `db_password_keyring`
+    SetPassword,
+    /// Download the measurements like `fetch` without acknowledging them, and print which are
+    /// missing from the database or stored with different values instead of inserting them
+    Verify,
+    /// Insert the measurements of a CSV file with `time`, `temp` and `humidity` columns for a
+    /// station, like fetched ones
+    Import {
+        /// The configured station the measurements were taken at, by its id or name
+        #[arg(long, value_name = "STATION")]
+        station: String,
+        /// Fail without inserting anything at the first row which isn't a measurement, instead of
+        /// skipping it
+        #[arg(long)]
+        strict: bool,
+        file: PathBuf,
+    },
+    /// Decode and insert the measurements recorded with `--record` like fetched ones of a station,
+    /// without connecting to its Pico
+    Replay {
+        /// The configured station the measurements were recorded at, by its id or name
+        #[arg(long, value_name = "STATION")]
+        station: String,
+        file: PathBuf,
+    },
 }
 
-impl Default for Config {
-    fn default() -> Self {
-        Config {
-            db_url: "host = localhost user = humidity_temperature password = mypasswd dbname = humidity_temperature".to_string(),
-            pico: "pico_host_here".to_string(),
-            pico_port: 60438,
-            station_id: 0,
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+/// Logs to stderr according to `--log-level`, `--quiet` and `--verbose` (or `RUST_LOG`) and
+/// `--log-format`, exporting the spans to `otlp` if given.
+fn logging(
+    args: &Args,
+    otlp: Option<&OtlpConfig>,
+) -> anyhow::Result<impl Subscriber + Send + Sync> {
+    let verbosity = match (args.quiet, args.verbose) {
+        (true, _) => Some("error".to_string()),
+        (false, 0) => None,
+        (false, 1) => Some("info".to_string()),
+        (false, _) => Some("info,pico_humidity_temp_read=debug".to_string()),
+    };
+
+    let filter = match (&args.log_level, verbosity) {
+        (Some(log_level), _) => EnvFilter::try_new(log_level)
+            .map_err(|err| anyhow!("Error parsing log level {log_level:?}: {err}"))?,
+        (None, Some(verbosity)) => EnvFilter::new(verbosity),
+        (None, None) => EnvFilter::try_from_default_env()
+            .unwrap_or_else(|_| EnvFilter::new(format!("warn,{SUMMARY_TARGET}=info"))),
+    };
+
+    let fmt = tracing_subscriber::fmt::layer().with_writer(std::io::stderr);
+    let fmt = match args.log_format {
+        LogFormat::Text => fmt.boxed(),
+        LogFormat::Json => fmt.json().boxed(),
+    };
+
+    Ok(tracing_subscriber::registry()
+        .with(otlp.map(otlp_layer).transpose()?)
+        .with(fmt.with_filter(filter)))
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let result = run(Args::parse()).await;
+    telemetry::shutdown();
+
+    match result {
+        Ok(exit_code) => exit_code,
+        Err(err) => {
+            eprintln!("Error: {err:?}");
+            Failure::of(&err).map_or(ExitCode::FAILURE, Failure::exit_code)
         }
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
-struct Measurement {
-    time: DateTime<Local>,
-    temp: i32,
-    humidity: i32,
+/// The configured station with `station` as its id or name.
+fn configured_station<'s>(stations: &'s [Station], station: &str) -> anyhow::Result<&'s Station> {
+    stations
+        .iter()
+        .find(|configured| {
+            configured.name.as_deref() == Some(station)
+                || configured.station_id.to_string() == station
+        })
+        .ok_or_else(|| Failure::Config.tag(anyhow!("station {station} isn't configured")))
 }
 
-const CONFIG_PATH: &str = "config.json";
+/// Runs a cycle, writing its summary as requested by `--summary-json`.
+async fn run_reported_cycle(
+    args: &Args,
+    options: &CycleOptions,
+    config: &Config,
+    stations: &[Station],
+    database: &mut Option<Database>,
+    outputs: &mut Outputs,
+) -> anyhow::Result<CycleSummary> {
+    let start = Utc::now();
+    let result = run_cycle(options, config, stations, database, outputs).await;
 
-#[tokio::main]
-async fn main() -> anyhow::Result<ExitCode> {
-    let config = match fs::read_to_string(CONFIG_PATH).await {
-        Err(e) if e.kind() == ErrorKind::NotFound => {
-            fs::write(
-                CONFIG_PATH,
-                serde_json::to_string_pretty(&Config::default())
-                    .map_err(|err| anyhow!("Error serializing default config: {err}"))?,
-            )
-            .await?;
-            eprintln!("no config file found; default config has been written to {CONFIG_PATH}. Please fill it out");
-            return Ok(ExitCode::FAILURE);
-        }
-        result => result.map_err(|err| anyhow!("Error reading config file: {err}"))?,
+    if let Some(path) = &args.summary_json {
+        if let Err(err) = RunReport::new(start, &result).write(path) {
+            error!("{err}");
+        }
+    }
+
+    result
+}
+
+/// Serves the Prometheus metrics at `/metrics` and the live stream of the measurements at `/ws` on
+/// `port` in the background, and with `polling` also requests a fetch for `POST /fetch`.
+async fn spawn_metrics_endpoint(port: u16, polling: bool) -> anyhow::Result<()> {
+    let listener = tokio::net::TcpListener::bind(("0.0.0.0", port))
+        .await
+        .map_err(|err| anyhow!("Error binding the metrics endpoint to port {port}: {err}"))?;
+
+    let mut router = axum::Router::new()
+        .route("/metrics", axum::routing::get(serve_metrics))
+        .route("/ws", axum::routing::get(serve_live));
+
+    if polling {
+        router = router.route("/fetch", axum::routing::post(serve_fetch));
+    }
+
+    tokio::spawn(async move {
+        if let Err(err) = axum::serve(listener, router).await {
+            error!("metrics endpoint failed: {err}");
+        }
+    });
+
+    Ok(())
+}
+
+/// Reads the config at `path` again after SIGHUP, replacing `config` and `stations` unless the new
+/// one is invalid. Returns whether anything changed.
+async fn reload(path: &Path, config: &mut Config, stations: &mut Vec<Station>) -> bool {
+    let reloaded = match reload_config(path).await.and_then(|new| {
+        let new_stations = new.stations_with_credentials()?;
+        Ok((new, new_stations))
+    }) {
+        Ok((new, new_stations)) => register_stations(&new, new_stations)
+            .await
+            .map(|new_stations| (new, new_stations)),
+        Err(err) => Err(err),
+    };
+
+    let (new, new_stations) = match reloaded {
+        Ok((new, _)) if new.poll_interval_secs.is_none() && new.schedule.is_none() => {
+            error!("keeping the old config, the new one has neither poll_interval_secs nor schedule, which can't be removed while running");
+            return false;
+        }
+        Ok(reloaded) => reloaded,
+        Err(err) => {
+            error!("keeping the old config, reloading it failed: {err}");
+            return false;
+        }
     };
 
-    let config: Config = serde_json::from_str(&config)
-        .map_err(|err| anyhow!("Error deserializing config: {err}"))?;
+    let changes = config.changes(&new);
 
-    let (client, connection) = tokio_postgres::connect(&config.db_url, NoTls).await?;
+    if changes.is_empty() {
+        info!("reloaded the config, nothing changed");
+        return false;
+    }
 
-    tokio::spawn(connection);
+    info!("reloaded the config: {}", changes.join("; "));
 
-    let insert_statement = client
-        .prepare_typed("insert into measurement(at, station_id, temp, humidity) values ($1, $2, $3::decimal / 10, $4::decimal / 10) on conflict do nothing", &[Type::TIMESTAMPTZ, Type::INT4, Type::INT4, Type::INT4])
-        .await.map_err(|err| anyhow!("Error preparing measurement insertion statement: {err}"))?;
+    if new.metrics_port != config.metrics_port {
+        warn!("the new metrics_port only takes effect after a restart");
+    }
 
-    let now = Local::now();
+    if new.otlp != config.otlp {
+        warn!("the new otlp only takes effect after a restart");
+    }
 
-    let packed_now = [
-        (now.second() as u8) & 0b111111 | (now.minute() as u8) << 6,
-        (now.minute() as u8 >> 2) & 0b1111 | (now.hour() as u8) << 4,
-        ((now.hour() as u8) >> 4) & 0b1
-            | (now.weekday().number_from_sunday() as u8 - 1) << 1
-            | (now.day0() as u8) << 4,
-        ((now.day0() as u8) >> 4) & 0b1
-            | (now.month0() as u8 & 0b1111) << 1
-            | (now.year() as u8) << 5,
-        ((now.year() as u16) >> 3) as u8,
-        ((now.year() as u16) << 11) as u8,
-    ];
+    *config = new;
+    *stations = new_stations;
+    true
+}
 
-    let mut pico_stream = TcpStream::connect((config.pico, config.pico_port))
-        .await
-        .map_err(|err| anyhow!("Error connecting to the Pico: {err}"))?;
+/// When the cycle after the one which ended at `idle_since` runs: `poll_interval_secs` later, or
+/// at the next time of `schedule` after the one the ended cycle was `scheduled` at, if any.
+fn next_cycle(
+    config: &Config,
+    idle_since: DateTime<Utc>,
+    scheduled: Option<DateTime<Utc>>,
+) -> Option<DateTime<Utc>> {
+    match &config.schedule {
+        // The timer may wake up a bit before the scheduled time by the wall clock.
+        Some(schedule) => next_run(
+            schedule,
+            config.pico_timezone,
+            scheduled.map_or(idle_since, |scheduled| scheduled.max(idle_since)),
+        ),
+        None => Some(
+            idle_since
+                + chrono::Duration::seconds(config.poll_interval_secs.unwrap_or_default() as i64),
+        ),
+    }
+}
 
-    pico_stream
-        .write_all(&packed_now)
-        .await
-        .map_err(|err| anyhow!("Error writing the packed date time to the Pico: {err}"))?;
+async fn run(args: Args) -> anyhow::Result<ExitCode> {
+    // Until the config tells where to export the spans, only logging.
+    let logging_only =
+        tracing::subscriber::set_default(logging(&args, None).category(Failure::Config)?);
 
-    let measurement_count = pico_stream
-        .read_u32_le()
-        .await
-        .map_err(|err| anyhow!("Error reading measurement count from Pico: {err}"))?;
+    if args.quiet {
+        progress::disable();
+    }
 
-    const SECTOR_COUNT: u32 = 512;
-    const PAGES_PER_SECTOR: u32 = 16;
-    const MEASUREMENTS_PER_PAGE: u32 = 32;
+    if args.force_time_sync {
+        clock::force_time_sync();
+    }
 
-    if measurement_count > SECTOR_COUNT * PAGES_PER_SECTOR * MEASUREMENTS_PER_PAGE {
-        eprintln!("Pico reported more than the theoretical maximum measurement count");
-        return Ok(ExitCode::FAILURE);
+    if let Some(path) = &args.trace_protocol {
+        protocol_trace::enable(path, args.trace_max_bytes)
+            .map_err(|err| anyhow!("Error opening the protocol trace {}: {err}", path.display()))
+            .category(Failure::Config)?;
     }
 
-    let mut measurements = Vec::with_capacity(measurement_count as usize);
+    let config_path = config_path(args.config.clone());
+    let Some(config) = load_config(&config_path).await.category(Failure::Config)? else {
+        return Ok(Failure::Config.exit_code());
+    };
 
-    loop {
-        let packed_measurement = match pico_stream.read_u64_le().await {
-            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
-                break;
-            }
-            Err(err) => {
-                return Err(anyhow!(
-                    "Error reading a packed measurement from the Pico: {err}"
-                ))
+    drop(logging_only);
+    logging(&args, config.otlp.as_ref())
+        .category(Failure::Config)?
+        .try_init()
+        .category(Failure::Config)?;
+
+    if args.check_config {
+        println!(
+            "the config at {} is valid with {} stations",
+            config_path.display(),
+            config.stations().category(Failure::Config)?.len()
+        );
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if args.init_db {
+        init_schema(&config).await.category(Failure::Database)?;
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if args.check_schema {
+        let problems = check_schema(&config).await.category(Failure::Database)?;
+
+        if problems.is_empty() {
+            println!("the schema is as expected");
+            return Ok(ExitCode::SUCCESS);
+        }
+
+        for problem in problems {
+            println!("{problem}");
+        }
+
+        return Ok(Failure::Database.exit_code());
+    }
+
+    if let Some(Command::Decode { packed }) = args.command {
+        print_decoded(&config, packed)?;
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if let Some(Command::Export {
+        station,
+        from,
+        to,
+        format,
+        output,
+    }) = &args.command
+    {
+        let instant = |time: &Option<TimeArg>| {
+            time.map(|time| time.instant(config.pico_timezone))
+                .transpose()
+                .category(Failure::Config)
+        };
+        let filter = ExportFilter {
+            station_id: *station,
+            from: instant(from)?,
+            to: instant(to)?,
+        };
+
+        let exported = export_measurements(&config, &filter, *format, output.as_deref()).await?;
+        info!("exported {exported} measurements");
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if let Some(Command::Stats {
+        station,
+        all_stations,
+        period,
+        from,
+        to,
+        format,
+    }) = &args.command
+    {
+        let instant = |time: &Option<TimeArg>| {
+            time.map(|time| time.instant(config.pico_timezone))
+                .transpose()
+                .category(Failure::Config)
+        };
+        let to = instant(to)?;
+        let from = match period {
+            Some(period) => {
+                Some((to.unwrap_or_else(Utc::now) - period.duration()).trunc_subsecs(0))
             }
-            Ok(packed) => packed,
+            None => instant(from)?,
+        };
+        let filter = StatsFilter {
+            station_ids: (!*all_stations).then(|| station.clone()),
+            from,
+            to,
         };
 
-        let datetime_result = Local.from_local_datetime(&NaiveDateTime::new(
-            NaiveDate::from_ymd_opt(
-                ((packed_measurement >> 26) & 0b1111_1111_1111_1111) as i32,
-                (((packed_measurement >> 22) & 0b1111) + 1) as u32,
-                (((packed_measurement >> 17) & 0b11111) + 1) as u32,
-            )
-            .ok_or(anyhow!("Pico sent invalid date"))?,
-            NaiveTime::from_hms_opt(
-                ((packed_measurement >> 12) & 0b11111) as u32,
-                ((packed_measurement >> 6) & 0b111111) as u32,
-                (packed_measurement & 0b111111) as u32,
-            )
-            .ok_or(anyhow!("Pico sent invalid time"))?,
-        ));
+        let stats = station_stats(&config, &filter).await?;
+        print_stats(&config, &filter, &stats, *format)?;
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if let Some(Command::Prune {
+        keep,
+        station,
+        dry_run,
+    }) = &args.command
+    {
+        let filter = PruneFilter::new(keep.duration(), *station);
+        let deleted = prune_measurements(&config, &filter, *dry_run).await?;
+        let cutoff = config
+            .pico_timezone
+            .local(&filter.cutoff.fixed_offset())
+            .to_rfc3339();
+
+        match dry_run {
+            true => println!("would delete {deleted} measurements older than {cutoff}"),
+            false => println!("deleted {deleted} measurements older than {cutoff}"),
+        }
+
+        return Ok(ExitCode::SUCCESS);
+    }
 
-        let time = match datetime_result {
-            LocalResult::Single(datetime) => datetime,
-            LocalResult::Ambiguous(_, _) => return Err(anyhow!("Pico sent ambiguous time")),
-            LocalResult::None => return Err(anyhow!("Pico sent impossible time")),
+    if let Some(Command::SetPassword) = args.command {
+        let Some(entry) = &config.db_password_keyring else {
+            return Err(Failure::Config.tag(anyhow!(
+                "set-password needs db_password_keyring in the config"
+            )));
         };
 
-        measurements.push(Measurement {
-            time,
-            temp: ((packed_measurement >> 42) & 0b111111111) as i32,
-            humidity: ((packed_measurement >> 51) & 0b1111111111) as i32,
-        })
+        set_keyring_password(entry).category(Failure::Config)?;
+        println!(
+            "stored the database password for user {:?} of service {:?}",
+            entry.user, entry.service
+        );
+        return Ok(ExitCode::SUCCESS);
     }
 
-    pico_stream
-        .shutdown()
-        .await
-        .map_err(|err| anyhow!("Error shutting the connection to the Pico down: {err}"))?;
-
-    drop(pico_stream);
-
-    for measurement in measurements {
-        client
-            .execute(
-                &insert_statement,
-                &[
-                    &measurement.time,
-                    &config.station_id,
-                    &measurement.temp,
-                    &measurement.humidity,
-                ],
-            )
-            .await
-            .map_err(|err| anyhow!("Error inserting measurement: {err}"))?;
+    let stations = config
+        .stations_with_credentials()
+        .category(Failure::Config)?;
+
+    handle_signals()?;
+
+    let _lock = InstanceLock::acquire(
+        &config.lock_path(&config_path),
+        Duration::from_secs(args.wait_lock.unwrap_or(0)),
+    )
+    .await?;
+
+    let stations = register_stations(&config, stations).await?;
+
+    match args.command.clone().unwrap_or(Command::Fetch) {
+        Command::Fetch => {}
+        Command::SyncTime => return Ok(sync_clocks(&config, &stations).await),
+        Command::Status => return Ok(print_status(&config, &stations).await),
+        Command::Ping { timeout } => {
+            let timeout = Duration::from_secs(timeout);
+            return Ok(ping_stations(&config, &stations, timeout).await);
+        }
+        Command::Verify => {
+            let start = Utc::now();
+            let summary_to_stdout = args.summary_json.as_deref() == Some(Path::new("-"));
+            let result = verify_stations(&config, &stations, summary_to_stdout).await;
+
+            if let Some(path) = &args.summary_json {
+                if let Err(err) = RunReport::new(start, &result).write(path) {
+                    error!("{err}");
+                }
+            }
+
+            return result.map(|summary| summary.exit_code);
+        }
+        Command::Import {
+            station,
+            strict,
+            file,
+        } => {
+            let station = configured_station(&stations, &station)?;
+            let summary = import_csv(&config, station, &file, strict).await?;
+            info!(target: SUMMARY_TARGET, "{summary}");
+
+            return Ok(match summary.failed {
+                0 => ExitCode::SUCCESS,
+                _ => Failure::Database.exit_code(),
+            });
+        }
+        Command::Replay { .. } => {}
+        Command::Decode { .. }
+        | Command::Export { .. }
+        | Command::Stats { .. }
+        | Command::Prune { .. }
+        | Command::SetPassword => {
+            unreachable!("handled before the stations are loaded")
+        }
+    }
+
+    let mut outputs = Outputs::open(
+        args.output_csv.as_deref(),
+        args.csv_overwrite,
+        args.output_jsonl.as_deref(),
+        args.output_parquet.as_deref(),
+        args.parquet_row_group_size as usize,
+        config.output_units.temperature,
+    )?;
+
+    if args.print_table && outputs.writes_to_stdout() {
+        return Err(anyhow!(
+            "--print-table can't be combined with --output-jsonl -, both write to stdout"
+        ));
+    }
+
+    let summary_to_stdout = args.summary_json.as_deref() == Some(Path::new("-"));
+
+    if summary_to_stdout && (args.print_table || outputs.writes_to_stdout()) {
+        return Err(anyhow!(
+            "--summary-json - can't be combined with --print-table or --output-jsonl -, they write to stdout too"
+        ));
+    }
+
+    let options = CycleOptions {
+        dry_run: args.dry_run,
+        fail_fast: args.fail_fast,
+        print_table: args.print_table,
+        last: args.last,
+        summary_to_stdout,
+        record: args.record.clone(),
+    };
+
+    if let Some(count) = args.simulate {
+        let interval = chrono::Duration::seconds(args.simulate_interval_secs.into());
+        return run_simulation(&options, &config, &stations, count, interval).await;
+    }
+
+    if let Some(Command::Replay { station, file }) = &args.command {
+        let station = configured_station(&stations, station)?;
+        let start = Utc::now();
+        let result = run_replay(
+            &options,
+            &config,
+            &stations,
+            station,
+            file.clone(),
+            &mut None,
+            &mut outputs,
+        )
+        .await;
+
+        if let Some(path) = &args.summary_json {
+            if let Err(err) = RunReport::new(start, &result).write(path) {
+                error!("{err}");
+            }
+        }
+
+        outputs.finish()?;
+        return result.map(|summary| summary.exit_code);
+    }
+
+    if config.mode == Mode::Listen {
+        if let Some(metrics_port) = config.metrics_port {
+            spawn_metrics_endpoint(metrics_port, false).await?;
+        }
+
+        spawn_watchdog();
+        listen(
+            &options,
+            &config,
+            &stations,
+            &mut outputs,
+            args.summary_json.as_deref(),
+        )
+        .await?;
+
+        notify("STOPPING=1");
+        outputs.finish()?;
+        return Ok(ExitCode::SUCCESS);
     }
 
+    if args.run_once || (config.poll_interval_secs.is_none() && config.schedule.is_none()) {
+        let mut database = None;
+        let result = run_reported_cycle(
+            &args,
+            &options,
+            &config,
+            &stations,
+            &mut database,
+            &mut outputs,
+        )
+        .await;
+        outputs.finish()?;
+        return result.map(|summary| summary.exit_code);
+    }
+
+    if let Some(metrics_port) = config.metrics_port {
+        spawn_metrics_endpoint(metrics_port, true).await?;
+    }
+
+    spawn_watchdog();
+    handle_sigusr1()?;
+
+    let mut sighup = signal(SignalKind::hangup())
+        .map_err(|err| anyhow!("Error installing the SIGHUP handler: {err}"))?;
+    let (mut config, mut stations) = (config, stations);
+    let mut database = None;
+    let mut ready = false;
+    let mut scheduled = None;
+
+    'cycles: loop {
+        FETCH_TRIGGER.start();
+        let result = run_reported_cycle(
+            &args,
+            &options,
+            &config,
+            &stations,
+            &mut database,
+            &mut outputs,
+        )
+        .await;
+        FETCH_TRIGGER.finish();
+
+        if SHUTDOWN.is_requested() {
+            notify("STOPPING=1");
+            outputs.finish()?;
+            return result.map(|summary| summary.exit_code);
+        }
+
+        match result {
+            Ok(summary) => {
+                if !ready {
+                    notify("READY=1");
+                    ready = true;
+                }
+
+                notify(&format!("STATUS={summary}\nWATCHDOG=1"));
+            }
+            Err(err) => {
+                error!("cycle failed: {err}");
+                notify(&format!("STATUS=cycle failed: {err}\nWATCHDOG=1"));
+            }
+        }
+
+        // TimescaleDB drops the older chunks itself.
+        if let Some(retention) = config.retention.as_ref().filter(|_| !config.timescale) {
+            if !args.dry_run {
+                let filter = PruneFilter::new(retention.duration(), None);
+
+                if let Err(err) = prune_measurements(&config, &filter, false).await {
+                    error!("pruning the measurements failed: {err}");
+                }
+            }
+        }
+
+        // The config is only reloaded between the cycles, a SIGHUP during one waits for it. The
+        // interval starts over after a requested fetch as well.
+        let idle_since = Utc::now();
+        let mut next = next_cycle(&config, idle_since, scheduled);
+        let mut logged = None;
+
+        loop {
+            let Some(next_time) = next else {
+                warn!("stopping, the schedule has no further times");
+                break 'cycles;
+            };
+
+            if logged != next {
+                info!(
+                    target: SUMMARY_TARGET,
+                    "next run at {}",
+                    config
+                        .pico_timezone
+                        .local(&next_time.fixed_offset())
+                        .to_rfc3339_opts(SecondsFormat::Secs, false)
+                );
+                logged = next;
+            }
+
+            let sleep = (next_time - Utc::now()).to_std().unwrap_or_default();
+
+            tokio::select! {
+                _ = tokio::time::sleep(sleep) => {
+                    scheduled = Some(next_time);
+                    break;
+                }
+                () = FETCH_TRIGGER.requested() => {
+                    scheduled = None;
+                    break;
+                }
+                () = SHUTDOWN.requested() => break 'cycles,
+                _ = sighup.recv() => {
+                    if reload(&config_path, &mut config, &mut stations).await {
+                        next = next_cycle(&config, idle_since, scheduled);
+
+                        // The statements prepared on the connection depend on the config too.
+                        if database.take().is_some() {
+                            info!("reconnecting to the database with the new config");
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    notify("STOPPING=1");
+    outputs.finish()?;
+
     Ok(ExitCode::SUCCESS)
 }
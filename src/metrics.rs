@@ -0,0 +1,308 @@
+//! The Prometheus metrics served in daemon mode.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Mutex, PoisonError,
+    },
+    time::Duration,
+};
+
+use rust_decimal::Decimal;
+
+use crate::{config::Station, report::PhaseTimings, Measurement};
+
+/// The upper bounds of the buckets of the phase histograms, in seconds.
+const PHASE_BUCKETS: [f64; 12] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+/// A phase of a cycle timed into a histogram.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Connect,
+    TimeSync,
+    Download,
+    Decode,
+    DbConnect,
+    Insert,
+}
+
+impl Phase {
+    const ALL: [Phase; 6] = [
+        Phase::Connect,
+        Phase::TimeSync,
+        Phase::Download,
+        Phase::Decode,
+        Phase::DbConnect,
+        Phase::Insert,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            Phase::Connect => "connect",
+            Phase::TimeSync => "time_sync",
+            Phase::Download => "download",
+            Phase::Decode => "decode",
+            Phase::DbConnect => "db_connect",
+            Phase::Insert => "insert",
+        }
+    }
+}
+
+/// The durations of a phase, counted into the [`PHASE_BUCKETS`].
+struct Histogram {
+    /// How many durations fell into every bucket, not cumulative.
+    buckets: [AtomicU64; PHASE_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Histogram {
+        Histogram {
+            buckets: [const { AtomicU64::new(0) }; PHASE_BUCKETS.len()],
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+
+        if let Some(bucket) = PHASE_BUCKETS.iter().position(|bound| secs <= *bound) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+/// The counters and gauges exposed at the metrics endpoint.
+pub struct Metrics {
+    pub measurements_fetched: AtomicU64,
+    pub measurements_inserted: AtomicU64,
+    pub insert_errors: AtomicU64,
+    pub measurements_out_of_range: AtomicU64,
+    pub pico_connection_failures: AtomicU64,
+    pub downloaded_bytes: AtomicU64,
+    /// Unix timestamp of the last cycle in which at least one station succeeded.
+    pub last_success_timestamp: AtomicI64,
+    /// The temperature and humidity of the latest measurement per station id.
+    latest: Mutex<BTreeMap<i32, [Decimal; 2]>>,
+    /// How long the phases took, in the order of [`Phase::ALL`].
+    phases: [Histogram; Phase::ALL.len()],
+}
+
+pub static METRICS: Metrics = Metrics::new();
+
+impl Metrics {
+    const fn new() -> Metrics {
+        Metrics {
+            measurements_fetched: AtomicU64::new(0),
+            measurements_inserted: AtomicU64::new(0),
+            insert_errors: AtomicU64::new(0),
+            measurements_out_of_range: AtomicU64::new(0),
+            pico_connection_failures: AtomicU64::new(0),
+            downloaded_bytes: AtomicU64::new(0),
+            last_success_timestamp: AtomicI64::new(0),
+            latest: Mutex::new(BTreeMap::new()),
+            phases: [const { Histogram::new() }; Phase::ALL.len()],
+        }
+    }
+
+    pub fn record_fetched(&self, station: &Station, measurements: &[Measurement]) {
+        self.measurements_fetched
+            .fetch_add(measurements.len() as u64, Ordering::Relaxed);
+
+        if let Some(latest) = measurements
+            .iter()
+            .max_by_key(|measurement| measurement.time)
+        {
+            self.latest
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .insert(station.station_id, [latest.temp, latest.humidity]);
+        }
+    }
+
+    pub fn observe(&self, phase: Phase, duration: Duration) {
+        self.phases[phase as usize].observe(duration);
+    }
+
+    /// Takes the phases a station got through into account.
+    pub fn record_timings(&self, timings: &PhaseTimings) {
+        for (phase, duration) in [
+            (Phase::Connect, timings.connect),
+            (Phase::TimeSync, timings.time_sync),
+            (Phase::Download, timings.download),
+            (Phase::Decode, timings.decode),
+            (Phase::Insert, timings.insert),
+        ] {
+            if let Some(duration) = duration {
+                self.observe(phase, duration);
+            }
+        }
+
+        if let Some(bytes) = timings.download_bytes {
+            self.downloaded_bytes.fetch_add(bytes, Ordering::Relaxed);
+        }
+    }
+
+    /// Renders the metrics in the Prometheus text exposition format.
+    fn render(&self) -> String {
+        let mut out = String::new();
+
+        for (name, help, counter) in [
+            (
+                "picoread_measurements_fetched_total",
+                "Measurements fetched from the Picos.",
+                &self.measurements_fetched,
+            ),
+            (
+                "picoread_measurements_inserted_total",
+                "Measurements inserted into the database.",
+                &self.measurements_inserted,
+            ),
+            (
+                "picoread_insert_errors_total",
+                "Failed insertions of the measurements of a station.",
+                &self.insert_errors,
+            ),
+            (
+                "picoread_measurements_out_of_range_total",
+                "Fetched measurements outside of the plausible ranges.",
+                &self.measurements_out_of_range,
+            ),
+            (
+                "picoread_pico_connection_failures_total",
+                "Failed connection attempts to a Pico, after retries.",
+                &self.pico_connection_failures,
+            ),
+            (
+                "picoread_downloaded_bytes_total",
+                "Bytes the Picos sent in complete downloads.",
+                &self.downloaded_bytes,
+            ),
+        ] {
+            let _ = write!(
+                out,
+                "# HELP {name} {help}\n# TYPE {name} counter\n{name} {}\n",
+                counter.load(Ordering::Relaxed)
+            );
+        }
+
+        let _ = write!(
+            out,
+            "# HELP picoread_last_success_timestamp_seconds Time of the last cycle in which a station succeeded.\n\
+             # TYPE picoread_last_success_timestamp_seconds gauge\n\
+             picoread_last_success_timestamp_seconds {}\n",
+            self.last_success_timestamp.load(Ordering::Relaxed)
+        );
+
+        let latest = self.latest.lock().unwrap_or_else(PoisonError::into_inner);
+
+        for (name, help, field) in [
+            (
+                "picoread_temperature_celsius",
+                "Temperature of the latest measurement.",
+                0,
+            ),
+            (
+                "picoread_humidity_percent",
+                "Relative humidity of the latest measurement.",
+                1,
+            ),
+        ] {
+            let _ = write!(out, "# HELP {name} {help}\n# TYPE {name} gauge\n");
+
+            for (station_id, values) in latest.iter() {
+                let _ = writeln!(
+                    out,
+                    "{name}{{station_id=\"{station_id}\"}} {}",
+                    values[field]
+                );
+            }
+        }
+
+        let name = "picoread_phase_duration_seconds";
+        let _ = write!(
+            out,
+            "# HELP {name} How long the phases of fetching from a station (or connecting to the database) took.\n\
+             # TYPE {name} histogram\n"
+        );
+
+        for (phase, histogram) in Phase::ALL.iter().zip(&self.phases) {
+            let phase = phase.label();
+            let mut cumulative = 0;
+
+            for (bound, bucket) in PHASE_BUCKETS.iter().zip(&histogram.buckets) {
+                cumulative += bucket.load(Ordering::Relaxed);
+                let _ = writeln!(
+                    out,
+                    "{name}_bucket{{phase=\"{phase}\",le=\"{bound}\"}} {cumulative}"
+                );
+            }
+
+            let count = histogram.count.load(Ordering::Relaxed);
+            let sum = histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+            let _ = write!(
+                out,
+                "{name}_bucket{{phase=\"{phase}\",le=\"+Inf\"}} {count}\n\
+                 {name}_sum{{phase=\"{phase}\"}} {sum}\n\
+                 {name}_count{{phase=\"{phase}\"}} {count}\n"
+            );
+        }
+
+        out
+    }
+}
+
+pub async fn serve_metrics() -> impl axum::response::IntoResponse {
+    (
+        [(
+            axum::http::header::CONTENT_TYPE,
+            "text/plain; version=0.0.4",
+        )],
+        METRICS.render(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_the_phase_histograms_cumulatively() {
+        let metrics = Metrics::new();
+        for millis in [3, 40, 40, 700, 90_000] {
+            metrics.observe(Phase::Download, Duration::from_millis(millis));
+        }
+        metrics.observe(Phase::Insert, Duration::from_millis(20));
+
+        let rendered = metrics.render();
+        let download: Vec<_> = rendered
+            .lines()
+            .filter(|line| line.contains("phase=\"download\""))
+            .collect();
+
+        assert_eq!(download.len(), PHASE_BUCKETS.len() + 3);
+        for line in [
+            "picoread_phase_duration_seconds_bucket{phase=\"download\",le=\"0.005\"} 1",
+            "picoread_phase_duration_seconds_bucket{phase=\"download\",le=\"0.05\"} 3",
+            "picoread_phase_duration_seconds_bucket{phase=\"download\",le=\"1\"} 4",
+            "picoread_phase_duration_seconds_bucket{phase=\"download\",le=\"30\"} 4",
+            "picoread_phase_duration_seconds_bucket{phase=\"download\",le=\"+Inf\"} 5",
+            "picoread_phase_duration_seconds_sum{phase=\"download\"} 90.783",
+            "picoread_phase_duration_seconds_count{phase=\"download\"} 5",
+            "picoread_phase_duration_seconds_count{phase=\"insert\"} 1",
+            "picoread_phase_duration_seconds_count{phase=\"connect\"} 0",
+        ] {
+            assert!(rendered.lines().any(|rendered| rendered == line), "{line}");
+        }
+    }
+}
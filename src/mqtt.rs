@@ -0,0 +1,227 @@
+//! Publishing the fetched measurements to an MQTT broker.
+
+use std::time::Duration;
+
+use anyhow::anyhow;
+use rumqttc::{AsyncClient, Event, Incoming, MqttOptions, Outgoing, QoS};
+
+use crate::{
+    config::{MqttConfig, Station, TemperatureUnit},
+    Measurement, StationMeasurement,
+};
+
+/// How long publishing the measurements of a station to MQTT may take before it's given up.
+const MQTT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The config of a sensor published for Home Assistant's MQTT discovery.
+#[derive(Debug, serde::Serialize)]
+struct SensorDiscovery {
+    name: &'static str,
+    unique_id: String,
+    device_class: &'static str,
+    unit_of_measurement: &'static str,
+    state_class: &'static str,
+    state_topic: String,
+    value_template: &'static str,
+    device: DiscoveredDevice,
+}
+
+/// The device the sensors of a station are grouped under in Home Assistant.
+#[derive(Debug, serde::Serialize)]
+struct DiscoveredDevice {
+    identifiers: [String; 1],
+    name: String,
+    model: &'static str,
+}
+
+/// Publishes `measurements` to the MQTT broker: each one to the history topic of the station and
+/// the latest one, retained, to the station topic. With `discovery`, the retained configs of the
+/// station's sensors for Home Assistant are published first, with the station topic as their
+/// state topic. The temperatures are published in `unit`.
+pub async fn publish_mqtt(
+    mqtt: &MqttConfig,
+    station: &Station,
+    measurements: &[Measurement],
+    unit: TemperatureUnit,
+) -> anyhow::Result<()> {
+    let Some(latest) = measurements
+        .iter()
+        .max_by_key(|measurement| measurement.time)
+    else {
+        return Ok(());
+    };
+
+    let qos = match mqtt.qos {
+        0 => QoS::AtMostOnce,
+        1 => QoS::AtLeastOnce,
+        qos => return Err(anyhow!("Error in MQTT config: unsupported QoS {qos}")),
+    };
+
+    let mut options = MqttOptions::new(
+        format!("picoread-{}-{}", station.station_id, std::process::id()),
+        &mqtt.host,
+        mqtt.port,
+    );
+
+    if let Some(username) = &mqtt.username {
+        options.set_credentials(username, mqtt.password.as_deref().unwrap_or_default());
+    }
+
+    let (client, mut event_loop) = AsyncClient::new(options, 64);
+
+    let station_topic = format!("{}/{}", mqtt.topic_prefix, station.station_id);
+    let history_topic = format!("{station_topic}/history");
+
+    let payload = |measurement| {
+        serde_json::to_vec(&StationMeasurement::new(
+            station.station_id,
+            measurement,
+            unit,
+        ))
+    };
+
+    let discovery = match mqtt.discovery {
+        true => discovery_messages(station, &station_topic, unit)?,
+        false => Vec::new(),
+    };
+
+    let messages = measurements
+        .iter()
+        .map(|measurement| Ok((history_topic.clone(), false, payload(measurement)?)))
+        .chain([payload(latest).map(|payload| (station_topic.clone(), true, payload))])
+        .collect::<Result<Vec<_>, serde_json::Error>>()
+        .map_err(|err| anyhow!("Error serializing measurement for MQTT: {err}"))?;
+    let messages: Vec<_> = discovery.into_iter().chain(messages).collect();
+
+    let message_count = messages.len();
+
+    let publisher = client.clone();
+
+    let publishing = async move {
+        for (topic, retain, payload) in messages {
+            publisher.publish(topic, qos, retain, payload).await?;
+        }
+
+        Ok::<_, rumqttc::ClientError>(())
+    };
+
+    let delivery = async {
+        let mut delivered = 0;
+        let mut disconnecting = false;
+
+        loop {
+            let event = event_loop
+                .poll()
+                .await
+                .map_err(|err| anyhow!("Error communicating with the MQTT broker: {err}"))?;
+
+            match event {
+                Event::Outgoing(Outgoing::Publish(_)) if qos == QoS::AtMostOnce => delivered += 1,
+                Event::Incoming(Incoming::PubAck(_)) => delivered += 1,
+                Event::Outgoing(Outgoing::Disconnect) => return Ok(()),
+                _ => {}
+            }
+
+            if delivered == message_count && !disconnecting {
+                disconnecting = true;
+
+                client
+                    .try_disconnect()
+                    .map_err(|err| anyhow!("Error disconnecting from the MQTT broker: {err}"))?;
+            }
+        }
+    };
+
+    tokio::time::timeout(MQTT_TIMEOUT, async {
+        let (published, delivered) = tokio::join!(publishing, delivery);
+
+        published.map_err(|err| anyhow!("Error publishing measurement to MQTT: {err}"))?;
+
+        delivered
+    })
+    .await
+    .map_err(|_| {
+        anyhow!("Error publishing measurements to MQTT: timed out after {MQTT_TIMEOUT:?}")
+    })?
+}
+
+/// The retained discovery configs of the temperature and humidity sensors of `station`, whose
+/// latest measurement is published to `state_topic` with the temperature in `unit`.
+fn discovery_messages(
+    station: &Station,
+    state_topic: &str,
+    unit: TemperatureUnit,
+) -> anyhow::Result<Vec<(String, bool, Vec<u8>)>> {
+    [
+        ("temp", "Temperature", "temperature", unit.symbol()),
+        ("humidity", "Humidity", "humidity", "%"),
+    ]
+    .into_iter()
+    .map(|(metric, name, device_class, unit_of_measurement)| {
+        let object_id = format!("picoread_{}_{metric}", station.station_id);
+        let config = SensorDiscovery {
+            name,
+            unique_id: object_id.clone(),
+            device_class,
+            unit_of_measurement,
+            state_class: "measurement",
+            state_topic: state_topic.to_string(),
+            value_template: match metric {
+                "temp" => "{{ value_json.temp }}",
+                _ => "{{ value_json.humidity }}",
+            },
+            device: DiscoveredDevice {
+                identifiers: [format!("picoread_{}", station.station_id)],
+                name: format!("Pico station {}", station.station_id),
+                model: "PicoHumidityTemperature",
+            },
+        };
+
+        let payload = serde_json::to_vec(&config)
+            .map_err(|err| anyhow!("Error serializing the MQTT discovery config: {err}"))?;
+
+        Ok((
+            format!("homeassistant/sensor/{object_id}/config"),
+            true,
+            payload,
+        ))
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn groups_the_discovered_sensors_under_the_station() {
+        let station = Station {
+            station_id: 3,
+            ..Config::default().stations[0].clone()
+        };
+
+        let messages =
+            discovery_messages(&station, "picoread/3", TemperatureUnit::Celsius).unwrap();
+        let configs: Vec<serde_json::Value> = messages
+            .iter()
+            .map(|(_, _, payload)| serde_json::from_slice(payload).unwrap())
+            .collect();
+
+        assert_eq!(
+            messages
+                .iter()
+                .map(|(topic, retain, _)| (topic.as_str(), *retain))
+                .collect::<Vec<_>>(),
+            [
+                ("homeassistant/sensor/picoread_3_temp/config", true),
+                ("homeassistant/sensor/picoread_3_humidity/config", true)
+            ]
+        );
+        assert_eq!(configs[0]["device_class"], "temperature");
+        assert_eq!(configs[0]["unit_of_measurement"], "°C");
+        assert_eq!(configs[1]["value_template"], "{{ value_json.humidity }}");
+        assert_eq!(configs[1]["state_topic"], "picoread/3");
+        assert_eq!(configs[0]["device"], configs[1]["device"]);
+    }
+}
@@ -0,0 +1,413 @@
+//! The files and the terminal the fetched measurements are written to.
+
+use std::{
+    fs::File,
+    io::{IsTerminal, Write},
+    path::Path,
+    sync::{Arc, Once},
+};
+
+use anyhow::anyhow;
+use parquet::{
+    basic::Compression,
+    data_type::{DoubleType, Int32Type, Int64Type},
+    file::{properties::WriterProperties, writer::SerializedFileWriter},
+    schema::parser::parse_message_type,
+};
+use rust_decimal::prelude::ToPrimitive;
+
+use crate::{
+    config::{Station, TemperatureUnit},
+    Measurement, StationMeasurement,
+};
+
+/// The files the fetched measurements are written to in addition to the database.
+pub struct Outputs {
+    csv: Option<csv::Writer<std::fs::File>>,
+    jsonl: Option<Box<dyn Write + Send>>,
+    jsonl_to_stdout: bool,
+    parquet: Option<ParquetOutput>,
+    unit: TemperatureUnit,
+}
+
+impl Outputs {
+    /// Opens the CSV file at `csv_path` (appending to it unless `csv_overwrite` is set), the
+    /// JSON lines file at `jsonl_path`, which may be `-` for stdout, and the Parquet file at
+    /// `parquet_path` with row groups of `parquet_row_group_size` measurements, all of them with
+    /// the temperatures in `unit`.
+    pub fn open(
+        csv_path: Option<&Path>,
+        csv_overwrite: bool,
+        jsonl_path: Option<&Path>,
+        parquet_path: Option<&Path>,
+        parquet_row_group_size: usize,
+        unit: TemperatureUnit,
+    ) -> anyhow::Result<Self> {
+        let csv = csv_path
+            .map(|path| open_csv(path, csv_overwrite, unit))
+            .transpose()?;
+
+        let jsonl_to_stdout = jsonl_path == Some(Path::new("-"));
+
+        let jsonl = match jsonl_path {
+            None => None,
+            Some(_) if jsonl_to_stdout => {
+                Some(Box::new(std::io::stdout()) as Box<dyn Write + Send>)
+            }
+            Some(path) => Some(Box::new(
+                std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(|err| {
+                        anyhow!("Error opening JSON lines file {}: {err}", path.display())
+                    })?,
+            ) as Box<dyn Write + Send>),
+        };
+
+        let parquet = parquet_path
+            .map(|path| ParquetOutput::create(path, parquet_row_group_size, unit))
+            .transpose()?;
+
+        Ok(Outputs {
+            csv,
+            jsonl,
+            jsonl_to_stdout,
+            parquet,
+            unit,
+        })
+    }
+
+    /// Whether the measurements are written to stdout, which must then not be used for anything else.
+    pub fn writes_to_stdout(&self) -> bool {
+        self.jsonl_to_stdout
+    }
+
+    /// Writes the `measurements` of `station` to every output.
+    pub fn write(&mut self, station: &Station, measurements: &[Measurement]) -> anyhow::Result<()> {
+        if let Some(csv) = &mut self.csv {
+            for measurement in measurements {
+                csv.write_record(csv_record(station.station_id, measurement, self.unit))
+                    .map_err(|err| anyhow!("Error writing measurement to the CSV file: {err}"))?;
+            }
+
+            csv.flush()
+                .map_err(|err| anyhow!("Error writing measurements to the CSV file: {err}"))?;
+        }
+
+        if let Some(jsonl) = &mut self.jsonl {
+            for measurement in measurements {
+                serde_json::to_writer(
+                    &mut *jsonl,
+                    &StationMeasurement::new(station.station_id, measurement, self.unit),
+                )
+                .map_err(|err| anyhow!("Error writing measurement as JSON: {err}"))?;
+
+                jsonl
+                    .write_all(b"\n")
+                    .and_then(|()| jsonl.flush())
+                    .map_err(|err| anyhow!("Error writing measurement as JSON: {err}"))?;
+            }
+        }
+
+        if let Some(parquet) = &mut self.parquet {
+            parquet.write(station, measurements)?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes what's still buffered and closes the files which need it, the Parquet file only
+    /// being readable once its footer is written.
+    pub fn finish(&mut self) -> anyhow::Result<()> {
+        if let Some(parquet) = self.parquet.take() {
+            parquet.finish()?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The schema of the Parquet file with the temperatures in `unit`, with the names of the CSV
+/// header.
+fn parquet_schema(unit: TemperatureUnit) -> String {
+    format!(
+        "
+        message measurement {{
+            required int64 time (TIMESTAMP(MICROS, true));
+            required int32 station_id;
+            required double {};
+            required double humidity_pct;
+        }}
+        ",
+        unit.column()
+    )
+}
+
+/// A Parquet file the measurements are written to in row groups of a fixed size.
+struct ParquetOutput {
+    writer: SerializedFileWriter<File>,
+    row_group_size: usize,
+    unit: TemperatureUnit,
+    times: Vec<i64>,
+    station_ids: Vec<i32>,
+    temps: Vec<f64>,
+    humidities: Vec<f64>,
+}
+
+impl ParquetOutput {
+    /// Creates (or overwrites) the Parquet file at `path`, compressed with Snappy.
+    fn create(
+        path: &Path,
+        row_group_size: usize,
+        unit: TemperatureUnit,
+    ) -> anyhow::Result<ParquetOutput> {
+        let file = File::create(path)
+            .map_err(|err| anyhow!("Error creating Parquet file {}: {err}", path.display()))?;
+
+        let schema = Arc::new(
+            parse_message_type(&parquet_schema(unit)).expect("the Parquet schema should be valid"),
+        );
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .build();
+
+        let writer = SerializedFileWriter::new(file, schema, Arc::new(properties))
+            .map_err(|err| anyhow!("Error creating Parquet file {}: {err}", path.display()))?;
+
+        Ok(ParquetOutput {
+            writer,
+            row_group_size,
+            unit,
+            times: Vec::with_capacity(row_group_size),
+            station_ids: Vec::with_capacity(row_group_size),
+            temps: Vec::with_capacity(row_group_size),
+            humidities: Vec::with_capacity(row_group_size),
+        })
+    }
+
+    /// Buffers the `measurements` of `station`, writing a row group whenever enough are.
+    fn write(&mut self, station: &Station, measurements: &[Measurement]) -> anyhow::Result<()> {
+        for measurement in measurements {
+            self.times.push(measurement.time.timestamp_micros());
+            self.station_ids.push(station.station_id);
+            self.temps.push(
+                self.unit
+                    .convert(measurement.temp)
+                    .to_f64()
+                    .unwrap_or(f64::NAN),
+            );
+            self.humidities
+                .push(measurement.humidity.to_f64().unwrap_or(f64::NAN));
+
+            if self.times.len() == self.row_group_size {
+                self.write_row_group()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes the buffered measurements as a row group.
+    fn write_row_group(&mut self) -> anyhow::Result<()> {
+        let result = (|| {
+            let mut row_group = self.writer.next_row_group()?;
+
+            let mut column = row_group.next_column()?.expect("time column");
+            column
+                .typed::<Int64Type>()
+                .write_batch(&self.times, None, None)?;
+            column.close()?;
+
+            let mut column = row_group.next_column()?.expect("station_id column");
+            column
+                .typed::<Int32Type>()
+                .write_batch(&self.station_ids, None, None)?;
+            column.close()?;
+
+            for values in [&self.temps, &self.humidities] {
+                let mut column = row_group.next_column()?.expect("value column");
+                column
+                    .typed::<DoubleType>()
+                    .write_batch(values, None, None)?;
+                column.close()?;
+            }
+
+            row_group.close().map(|_| ())
+        })();
+
+        self.times.clear();
+        self.station_ids.clear();
+        self.temps.clear();
+        self.humidities.clear();
+
+        result.map_err(|err| anyhow!("Error writing measurements to the Parquet file: {err}"))
+    }
+
+    /// Writes the remaining measurements and the footer. Without any measurements, the file only
+    /// has the schema.
+    fn finish(mut self) -> anyhow::Result<()> {
+        if !self.times.is_empty() {
+            self.write_row_group()?;
+        }
+
+        self.writer
+            .close()
+            .map(|_| ())
+            .map_err(|err| anyhow!("Error closing the Parquet file: {err}"))
+    }
+}
+
+/// The header of the CSV files with the temperatures in `unit`.
+pub fn csv_header(unit: TemperatureUnit) -> [&'static str; 4] {
+    ["time", "station_id", unit.column(), "humidity_pct"]
+}
+
+/// The record of `measurement` at station `station_id` in the CSV files, with the temperature in
+/// `unit`.
+pub fn csv_record(
+    station_id: i32,
+    measurement: &Measurement,
+    unit: TemperatureUnit,
+) -> [String; 4] {
+    [
+        measurement.time.to_rfc3339(),
+        station_id.to_string(),
+        unit.convert(measurement.temp).to_string(),
+        measurement.humidity.to_string(),
+    ]
+}
+
+/// Opens the CSV file at `path`, appending to it unless `overwrite` is set.
+///
+/// The header is only written if the file is empty, so appending doesn't repeat it.
+fn open_csv(
+    path: &Path,
+    overwrite: bool,
+    unit: TemperatureUnit,
+) -> anyhow::Result<csv::Writer<std::fs::File>> {
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(!overwrite)
+        .truncate(overwrite)
+        .open(path)
+        .map_err(|err| anyhow!("Error opening CSV file {}: {err}", path.display()))?;
+
+    let is_empty = file
+        .metadata()
+        .map_err(|err| {
+            anyhow!(
+                "Error reading metadata of CSV file {}: {err}",
+                path.display()
+            )
+        })?
+        .len()
+        == 0;
+
+    let mut writer = csv::Writer::from_writer(file);
+
+    if is_empty {
+        writer
+            .write_record(csv_header(unit))
+            .map_err(|err| anyhow!("Error writing header to CSV file {}: {err}", path.display()))?;
+    }
+
+    Ok(writer)
+}
+
+/// Prints `measurements` to stdout in real units, the temperatures in `unit`.
+pub fn print_measurements(station: &Station, measurements: &[Measurement], unit: TemperatureUnit) {
+    println!(
+        "station {}: {} measurements",
+        station.station_id,
+        measurements.len()
+    );
+
+    for measurement in measurements {
+        println!(
+            "{}  {:>5.1} {}  {:>5.1} %",
+            measurement.time.to_rfc3339(),
+            unit.convert(measurement.temp),
+            unit.symbol(),
+            measurement.humidity
+        );
+    }
+}
+
+static TSV_HEADER: Once = Once::new();
+
+/// Prints the latest `last` (or all) of `measurements` to stdout: as a table fitted to the width of
+/// the terminal, or as tab separated values with a header if stdout isn't a terminal. The
+/// temperatures are in `unit`.
+pub fn print_table(
+    station: &Station,
+    measurements: &[Measurement],
+    last: Option<usize>,
+    unit: TemperatureUnit,
+) {
+    let measurements =
+        &measurements[measurements.len() - last.unwrap_or(usize::MAX).min(measurements.len())..];
+
+    let mut stdout = std::io::stdout().lock();
+
+    if !stdout.is_terminal() {
+        // The header is printed once, since the station id is part of every line.
+        TSV_HEADER.call_once(|| {
+            let _ = writeln!(stdout, "station_id\ttime\t{}\thumidity_pct", unit.column());
+        });
+
+        for measurement in measurements {
+            let _ = writeln!(
+                stdout,
+                "{}\t{}\t{:.1}\t{:.1}",
+                station.station_id,
+                measurement.time.to_rfc3339(),
+                unit.convert(measurement.temp),
+                measurement.humidity
+            );
+        }
+
+        return;
+    }
+
+    let width = terminal_size::terminal_size().map_or(usize::MAX, |(width, _)| width.0.into());
+
+    // The full time takes 25 characters and the values 21 more; narrower terminals get the time
+    // without the year, seconds and offset, and lines cut off if that still doesn't fit.
+    let time_format = if width >= 46 {
+        "%Y-%m-%dT%H:%M:%S%:z"
+    } else {
+        "%m-%d %H:%M"
+    };
+    let time_width = if width >= 46 { 25 } else { 11 };
+
+    let lines = [
+        format!(
+            "station {} ({}:{}), {} measurements",
+            station.station_id,
+            station.pico,
+            station.pico_port,
+            measurements.len()
+        ),
+        format!(
+            "{:<time_width$}  {:>7}  {:>10}",
+            "time",
+            format!("temp {}", unit.symbol()),
+            "humidity %"
+        ),
+    ]
+    .into_iter()
+    .chain(measurements.iter().map(|measurement| {
+        format!(
+            "{:<time_width$}  {:>7.1}  {:>10.1}",
+            measurement.time.format(time_format).to_string(),
+            unit.convert(measurement.temp),
+            measurement.humidity
+        )
+    }));
+
+    for line in lines {
+        let _ = writeln!(stdout, "{}", line.chars().take(width).collect::<String>());
+    }
+}
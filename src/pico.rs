@@ -0,0 +1,1383 @@
+//! The connection to a Pico.
+
+use std::{
+    future::Future,
+    io::ErrorKind,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+
+use chrono::{NaiveDateTime, SubsecRound, Utc};
+use futures::{stream::FuturesUnordered, StreamExt};
+use socket2::{SockRef, TcpKeepalive};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{lookup_host, TcpStream},
+    sync::mpsc,
+};
+use tracing::{debug, field, info, info_span, warn};
+
+use crate::{
+    auth::{self, Side, ACCEPTED, CHALLENGE_LEN, PROOF_LEN, REJECTED},
+    clock::host_clock_problem,
+    config::{Config, IpVersion, PicoAuth, Station},
+    derived::derive_quantities,
+    duplicates::DuplicateFilter,
+    error::{Error, ProtocolErrorKind},
+    metrics::METRICS,
+    progress::Progress,
+    protocol::{
+        decode_measurement, pack_datetime, pack_precise_datetime, BitFields,
+        LEGACY_PROTOCOL_VERSION, PROTOCOL_VERSION, TIME_SYNC_PROBE, TIME_SYNC_SKIP,
+    },
+    protocol_trace::{self, TracedStream},
+    range::{apply_ranges, check_times, report_out_of_range, ImplausibleTime},
+    recording::Recording,
+    retry_with_backoff,
+    shutdown::SHUTDOWN,
+    spikes::SpikeFilter,
+    telemetry::{failed, traced},
+    Measurement,
+};
+
+/// How long the attempts to connect to the addresses of a Pico made so far may take before one to
+/// the next address is started alongside them.
+const CONNECT_STAGGER: Duration = Duration::from_millis(250);
+
+/// Connects to the Pico at `station`, retrying with exponential backoff as configured.
+async fn connect_pico(config: &Config, station: &Station) -> Result<TcpStream, Error> {
+    let result = retry_with_backoff(
+        config.connect_retries,
+        Duration::from_millis(config.connect_backoff_ms),
+        &format!(
+            "connecting to the Pico at {}:{}",
+            station.pico, station.pico_port
+        ),
+        |_| true,
+        || {
+            connect_any(
+                &station.pico,
+                station.pico_port,
+                config.pico_socket.ip_version,
+                Duration::from_secs(config.connect_timeout_secs),
+            )
+        },
+    )
+    .await
+    .map_err(|retried| Error::PicoConnect {
+        host: station.pico.clone(),
+        port: station.pico_port,
+        attempts: retried.attempts,
+        elapsed: retried.elapsed,
+        reason: retried.err,
+    });
+
+    if result.is_err() {
+        METRICS
+            .pico_connection_failures
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    result
+}
+
+/// Connects to one of the addresses of `ip_version` which `host` resolves to, like
+/// [`connect_first`].
+async fn connect_any(
+    host: &str,
+    port: u16,
+    ip_version: IpVersion,
+    timeout: Duration,
+) -> Result<TcpStream, String> {
+    let addresses: Vec<SocketAddr> = lookup_host((host, port))
+        .await
+        .map_err(|err| format!("resolving {host} failed: {err}"))?
+        .filter(|address| match ip_version {
+            IpVersion::Any => true,
+            IpVersion::V4 => address.is_ipv4(),
+            IpVersion::V6 => address.is_ipv6(),
+        })
+        .collect();
+
+    if addresses.is_empty() {
+        let version = match ip_version {
+            IpVersion::Any => "",
+            IpVersion::V4 => "IPv4 ",
+            IpVersion::V6 => "IPv6 ",
+        };
+        return Err(format!("{host} resolves to no {version}address"));
+    }
+
+    connect_first(addresses, timeout).await
+}
+
+/// Connects to one of `addresses` in their order, each attempt getting `timeout`. Once the
+/// attempts made so far failed or took [`CONNECT_STAGGER`], one to the next address is started
+/// alongside them, and the first connection made is kept. Fails with the error of every address if
+/// none could be connected to.
+async fn connect_first(addresses: Vec<SocketAddr>, timeout: Duration) -> Result<TcpStream, String> {
+    let attempt = |address: SocketAddr| async move {
+        (
+            address,
+            with_timeout(timeout, TcpStream::connect(address)).await,
+        )
+    };
+    let mut remaining = addresses.into_iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut errors = Vec::new();
+
+    loop {
+        let stagger = match remaining.len() {
+            0 if attempts.is_empty() => break,
+            0 => None,
+            _ if attempts.is_empty() => Some(Duration::ZERO),
+            _ => Some(CONNECT_STAGGER),
+        };
+
+        tokio::select! {
+            Some((address, result)) = attempts.next() => match result {
+                Ok(pico_stream) => {
+                    debug!("connected to the Pico at {address}");
+                    return Ok(pico_stream);
+                }
+                Err(err) => {
+                    debug!("connecting to the Pico at {address} failed: {err}");
+                    errors.push(format!("{address}: {err}"));
+                }
+            },
+            () = tokio::time::sleep(stagger.unwrap_or_default()), if stagger.is_some() => {
+                attempts.extend(remaining.next().map(attempt));
+            }
+        }
+    }
+
+    Err(errors.join("; "))
+}
+
+/// Runs the I/O `operation`, failing it with [`ErrorKind::TimedOut`] if it doesn't complete within
+/// `timeout`.
+async fn with_timeout<T>(
+    timeout: Duration,
+    operation: impl Future<Output = std::io::Result<T>>,
+) -> std::io::Result<T> {
+    tokio::time::timeout(timeout, operation)
+        .await
+        .unwrap_or_else(|_| {
+            Err(std::io::Error::new(
+                ErrorKind::TimedOut,
+                format!("timed out after {timeout:?}"),
+            ))
+        })
+        .map_err(|err| match err.raw_os_error() {
+            // What a connection dropped for its unanswered keepalive probes fails with.
+            Some(_) if err.kind() == ErrorKind::TimedOut => std::io::Error::new(
+                ErrorKind::TimedOut,
+                format!("the Pico stopped responding, so the connection was dropped ({err})"),
+            ),
+            _ => err,
+        })
+}
+
+/// Sets the TCP options of `pico_socket` on the connection to a Pico, warning if that fails.
+fn tune_socket(config: &Config, pico_stream: &TcpStream) {
+    let options = &config.pico_socket;
+    let socket = SockRef::from(pico_stream);
+
+    let keepalive = match options.keepalive_time_secs {
+        0 => socket.set_keepalive(false),
+        time => socket.set_tcp_keepalive(
+            &TcpKeepalive::new()
+                .with_time(Duration::from_secs(time))
+                .with_interval(Duration::from_secs(options.keepalive_interval_secs))
+                .with_retries(options.keepalive_retries),
+        ),
+    };
+
+    if let Err(err) = keepalive.and_then(|()| pico_stream.set_nodelay(options.nodelay)) {
+        warn!("setting the TCP options of the connection to the Pico failed: {err}");
+    }
+}
+
+/// Fills `buf` from `stream`, returning how many bytes were read before the connection was closed.
+async fn read_full(
+    stream: &mut (impl AsyncRead + Unpin),
+    buf: &mut [u8],
+) -> std::io::Result<usize> {
+    let mut filled = 0;
+
+    while filled < buf.len() {
+        match stream.read(&mut buf[filled..]).await? {
+            0 => break,
+            read => filled += read,
+        }
+    }
+
+    Ok(filled)
+}
+
+/// How many measurements are handed on at once while downloading.
+pub const MEASUREMENT_CHUNK: usize = 1024;
+
+/// How many measurements a station sending a checksum may announce, since they're all held in
+/// memory until it was verified. Four times what fits into the flash of a Pico by default.
+pub const MAX_CHECKSUMMED_MEASUREMENTS: u32 = 1 << 20;
+
+/// How many chunks of [`MEASUREMENT_CHUNK`] measurements may wait to be stored before the download
+/// waits for them.
+pub const CHUNKS_IN_FLIGHT: usize = 4;
+
+/// Where the measurements of a station come from.
+#[derive(Debug)]
+pub enum Source {
+    /// A new connection to its Pico.
+    Connect,
+    /// The connection its Pico opened to the host in listen mode.
+    Accepted(TcpStream),
+    /// The file a session of its Pico was recorded to with `--record`.
+    Replay(PathBuf),
+}
+
+/// The still open connection to a Pico whose measurements were downloaded, which is to be ended
+/// with [`finish_session`].
+pub struct Session {
+    /// `None` for a replayed session.
+    pico_stream: Option<TracedStream>,
+    /// How many bytes the Pico sent in the session.
+    received: u64,
+}
+
+/// What the Pico sent before its measurements.
+struct Header {
+    protocol_version: u8,
+    /// How many measurements the Pico announced.
+    count: u32,
+    /// How many bytes the Pico sent before the measurements.
+    len: u64,
+    /// Whether the checksum of the measurements follows them.
+    checksum: bool,
+}
+
+/// What's known about a download while it runs, even if it fails.
+#[derive(Debug, Default)]
+pub struct DownloadInfo {
+    /// The count of measurements the Pico announced, once it did.
+    pub announced: Option<u32>,
+    /// The measurements skipped so far since their times can't be right.
+    pub implausible: Vec<ImplausibleTime>,
+    /// How many measurements were removed so far since the Pico already sent one at their time.
+    pub duplicates: u64,
+    /// Why the clock of the Pico wasn't set, if it wasn't.
+    pub clock_not_set: Option<String>,
+    /// How long connecting to the Pico took, authenticating it included, once connected.
+    pub connect: Option<Duration>,
+    /// How long setting the clock of the Pico took, until it announced its measurements.
+    pub time_sync: Option<Duration>,
+    /// How long receiving the measurements took, neither decoding them nor waiting for the inserts
+    /// to catch up, once all were received.
+    pub download: Option<Duration>,
+    /// How many bytes the Pico sent in the session, once all were received.
+    pub received: Option<u64>,
+    /// How long decoding the measurements took, once all were decoded.
+    pub decode: Option<Duration>,
+}
+
+/// What became of the clock of a Pico when connecting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClockSync {
+    /// It was set to this time, truncated like the Pico got it.
+    Set(NaiveDateTime),
+    /// It was left alone since the host's clock looks wrong, for this reason.
+    Skipped(String),
+}
+
+/// Synchronizes the time of the Pico at `station` and downloads its measurements, sending them to
+/// `measurements` in chunks as they are decoded. Returns the still open session. What's known
+/// about the download is kept in `info` as soon as it's known.
+///
+/// The download runs over the connection the Pico opened in listen mode or replays a recording
+/// instead of connecting to it, depending on `source`. Unless replaying, the measurements are
+/// recorded to `record` if given.
+///
+/// The measurements of a station sending a checksum are only sent once it was verified.
+pub async fn fetch_measurements(
+    config: &Config,
+    station: &Station,
+    source: Source,
+    record: Option<&Path>,
+    measurements: mpsc::Sender<Vec<Measurement>>,
+    info: &mut DownloadInfo,
+) -> Result<Session, Error> {
+    let session_timeout = Duration::from_secs(config.pico_socket.session_timeout_secs);
+    let deadline = tokio::time::Instant::now() + session_timeout;
+
+    let accepted = match source {
+        Source::Connect => None,
+        Source::Accepted(pico_stream) => Some(pico_stream),
+        Source::Replay(path) => return replay(config, station, &path, &measurements, info).await,
+    };
+
+    let connecting = async {
+        match accepted {
+            Some(pico_stream) => {
+                tune_socket(config, &pico_stream);
+                let pico_stream = TracedStream::new(pico_stream, station.station_id);
+                authenticated(config, station, pico_stream).await
+            }
+            None => open_connection(config, station).await,
+        }
+    };
+    let connecting = async {
+        tokio::time::timeout_at(deadline, connecting)
+            .await
+            .map_err(|_| Error::SessionTimeout(session_timeout))?
+    };
+    let start = Instant::now();
+    let mut pico_stream = traced(info_span!("pico-connect"), connecting).await?;
+    info.connect = Some(start.elapsed());
+    let timeout = Duration::from_secs(config.read_timeout_secs);
+
+    let download = async {
+        let start = Instant::now();
+        let (protocol_version, count, clock_sync) = traced(
+            info_span!("time-sync"),
+            start_session(config, station, &mut pico_stream, timeout),
+        )
+        .await?;
+        info.time_sync = Some(start.elapsed());
+        info.announced = Some(count);
+        if let ClockSync::Skipped(reason) = clock_sync {
+            info.clock_not_set = Some(reason);
+        }
+
+        let header = Header {
+            protocol_version,
+            count,
+            len: header_len(station),
+            checksum: station.checksum,
+        };
+        let mut recording = record.map(|path| Recording::create(path, count));
+        let span = info_span!("download", measurements = count, bytes = field::Empty);
+        let result = traced(
+            span.clone(),
+            receive_measurements(
+                config,
+                station,
+                &mut pico_stream,
+                header,
+                &measurements,
+                recording.as_mut(),
+                info,
+            ),
+        )
+        .await;
+
+        if let Ok(received) = &result {
+            span.record("bytes", received);
+        }
+
+        if let Some(recording) = recording {
+            recording.finish();
+        }
+
+        result
+    };
+
+    let download = tokio::select! {
+        result = download => Some(result),
+        () = tokio::time::sleep_until(deadline) => Some(Err(Error::SessionTimeout(session_timeout))),
+        () = SHUTDOWN.requested() => None,
+    };
+
+    let err = match download {
+        Some(Ok(received)) => {
+            return Ok(Session {
+                pico_stream: Some(pico_stream),
+                received,
+            })
+        }
+        Some(Err(err)) => err,
+        None => Error::Interrupted("while downloading the measurements".to_string()),
+    };
+
+    // The connection is shut down even if the download failed or was interrupted, so the Pico
+    // doesn't keep waiting for it.
+    let _ = with_timeout(timeout, pico_stream.shutdown()).await;
+
+    Err(err)
+}
+
+/// Only sets the clock of the Pico at `station`, closing the connection right after sending it the
+/// current time, which is returned. Fails without connecting if the host's clock looks wrong.
+pub async fn sync_time(config: &Config, station: &Station) -> Result<NaiveDateTime, Error> {
+    if let Some(problem) = host_clock_problem(config).await {
+        return Err(Error::HostClock(problem));
+    }
+
+    let mut pico_stream = open_connection(config, station).await?;
+    let timeout = Duration::from_secs(config.read_timeout_secs);
+    let now = match send_time(config, station, &mut pico_stream, timeout).await? {
+        ClockSync::Set(now) => now,
+        // The host's clock may have started to look wrong since the check above.
+        ClockSync::Skipped(problem) => return Err(Error::HostClock(problem)),
+    };
+    let offset = authentication_len(station) + time_sync_len(station);
+
+    with_timeout(timeout, pico_stream.shutdown())
+        .await
+        .map_err(|err| Error::io("shutting the connection to the Pico down", offset, err))?;
+
+    Ok(now)
+}
+
+/// Returns how many measurements the Pico at `station` has stored, closing the connection once it
+/// announced them instead of downloading them.
+///
+/// This sets the clock of the Pico like a download does.
+pub async fn measurement_count(config: &Config, station: &Station) -> Result<u32, Error> {
+    let mut pico_stream = open_connection(config, station).await?;
+    let timeout = Duration::from_secs(config.read_timeout_secs);
+
+    let result = start_session(config, station, &mut pico_stream, timeout).await;
+    let _ = with_timeout(timeout, pico_stream.shutdown()).await;
+
+    result.map(|(_, measurement_count, _)| measurement_count)
+}
+
+/// What pinging a Pico found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ping {
+    /// How many measurements the Pico announced.
+    pub pending: u32,
+    /// How long the Pico took to announce them once connected.
+    pub round_trip: Duration,
+}
+
+/// Checks that the Pico at `station` answers within `probe_timeout`, connecting and all, by reading
+/// how many measurements it announces without downloading them or setting its clock.
+///
+/// The clock of a Pico with `legacy_time_sync` is set all the same, since that firmware only
+/// announces its measurements once it received the time.
+pub async fn ping(
+    config: &Config,
+    station: &Station,
+    probe_timeout: Duration,
+) -> Result<Ping, Error> {
+    let probe = async {
+        let mut pico_stream = open_connection(config, station).await?;
+        let timeout = Duration::from_secs(config.read_timeout_secs);
+        let start = Instant::now();
+
+        let result = async {
+            match station.legacy_time_sync {
+                true => {
+                    send_time(config, station, &mut pico_stream, timeout).await?;
+                }
+                false => {
+                    skip_time_sync(&mut pico_stream, timeout, authentication_len(station)).await?
+                }
+            }
+
+            read_announcement(config, station, &mut pico_stream, timeout).await
+        }
+        .await;
+        let round_trip = start.elapsed();
+        let _ = with_timeout(timeout, pico_stream.shutdown()).await;
+
+        result.map(|(_, pending)| Ping {
+            pending,
+            round_trip,
+        })
+    };
+
+    tokio::time::timeout(probe_timeout, probe)
+        .await
+        .unwrap_or(Err(Error::PingTimeout(probe_timeout)))
+}
+
+/// Connects to the Pico at `station`, unless shutdown is requested meanwhile.
+async fn open_connection(config: &Config, station: &Station) -> Result<TracedStream, Error> {
+    let start = Instant::now();
+    let pico_stream = tokio::select! {
+        pico_stream = connect_pico(config, station) => pico_stream?,
+        () = SHUTDOWN.requested() => {
+            return Err(Error::Interrupted("while connecting to the Pico".to_string()));
+        }
+    };
+
+    tune_socket(config, &pico_stream);
+    let pico_stream = TracedStream::new(pico_stream, station.station_id);
+    let pico_stream = authenticated(config, station, pico_stream).await?;
+    info!("connected to the Pico in {:.1?}", start.elapsed());
+
+    Ok(pico_stream)
+}
+
+/// Authenticates the Pico on `pico_stream` if its station is configured to.
+async fn authenticated(
+    config: &Config,
+    station: &Station,
+    mut pico_stream: TracedStream,
+) -> Result<TracedStream, Error> {
+    if let (PicoAuth::Hmac, Some(psk)) = (station.auth, &station.psk) {
+        let timeout = Duration::from_secs(config.read_timeout_secs);
+
+        authenticate(&mut pico_stream, timeout, psk.as_bytes()).await?;
+    }
+
+    Ok(pico_stream)
+}
+
+/// How many bytes the Pico at `station` sends to authenticate itself.
+fn authentication_len(station: &Station) -> u64 {
+    match (station.auth, &station.psk) {
+        (PicoAuth::Hmac, Some(_)) => (CHALLENGE_LEN + 1 + PROOF_LEN) as u64,
+        _ => 0,
+    }
+}
+
+/// How many bytes the Pico at `station` sends during the time sync: the echo of the probe, unless
+/// it has the legacy time sync.
+fn time_sync_len(station: &Station) -> u64 {
+    u64::from(!station.legacy_time_sync)
+}
+
+/// How many bytes the Pico at `station` sends before its measurements: those authenticating it and
+/// of the time sync, its protocol version unless it speaks the legacy protocol, and the count of
+/// measurements.
+fn header_len(station: &Station) -> u64 {
+    authentication_len(station) + time_sync_len(station) + u64::from(!station.legacy_protocol) + 4
+}
+
+/// Proves to the Pico that the host knows the pre-shared key `psk` and has the Pico prove the
+/// same, as described in [`crate::auth`].
+async fn authenticate(
+    pico_stream: &mut TracedStream,
+    timeout: Duration,
+    psk: &[u8],
+) -> Result<(), Error> {
+    let mut pico_challenge = [0; CHALLENGE_LEN];
+
+    with_timeout(timeout, pico_stream.read_exact(&mut pico_challenge))
+        .await
+        .map_err(|err| {
+            Error::io(
+                "reading the authentication challenge from the Pico (does its firmware support auth = \"hmac\"?)",
+                0,
+                err,
+            )
+        })?;
+
+    let host_challenge: [u8; CHALLENGE_LEN] = rand::random();
+    let mut answer = auth::proof(psk, Side::Host, &[&pico_challenge]).to_vec();
+    answer.extend_from_slice(&host_challenge);
+
+    let offset = CHALLENGE_LEN as u64;
+
+    with_timeout(timeout, pico_stream.write_all(&answer))
+        .await
+        .map_err(|err| Error::io("writing the authentication proof to the Pico", offset, err))?;
+
+    let verdict = with_timeout(timeout, pico_stream.read_u8())
+        .await
+        .map_err(|err| {
+            Error::io(
+                "reading the authentication verdict of the Pico",
+                offset,
+                err,
+            )
+        })?;
+
+    match verdict {
+        ACCEPTED => {}
+        REJECTED => {
+            return Err(Error::Auth(
+                "Pico rejected the pre-shared key, its firmware has a different psk",
+            ))
+        }
+        _ => {
+            return Err(Error::Protocol {
+                kind: ProtocolErrorKind::UnknownVerdict(verdict),
+                offset,
+            })
+        }
+    }
+
+    let mut pico_proof = [0; PROOF_LEN];
+
+    with_timeout(timeout, pico_stream.read_exact(&mut pico_proof))
+        .await
+        .map_err(|err| {
+            Error::io(
+                "reading the authentication proof of the Pico",
+                offset + 1,
+                err,
+            )
+        })?;
+
+    if !auth::verify(
+        psk,
+        Side::Pico,
+        &[&host_challenge, &pico_challenge],
+        &pico_proof,
+    ) {
+        return Err(Error::Auth(
+            "Pico failed to prove it knows the pre-shared key, it has a different psk or isn't the \
+             configured Pico",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Sets the clock of the Pico, taking the time right before sending it.
+///
+/// Unless the station has `legacy_time_sync`, the host sends [`TIME_SYNC_PROBE`] first and times
+/// the Pico echoing it, then sends the time half that round trip ahead, with its milliseconds. The
+/// legacy time sync only sends the whole seconds, leaving the Pico's clock behind by up to a second
+/// plus the latency.
+///
+/// If the host's clock looks wrong, [`TIME_SYNC_SKIP`] is sent instead, leaving the Pico's clock
+/// alone, which the legacy time sync can't.
+async fn send_time(
+    config: &Config,
+    station: &Station,
+    pico_stream: &mut TracedStream,
+    timeout: Duration,
+) -> Result<ClockSync, Error> {
+    let offset = authentication_len(station);
+
+    if let Some(problem) = host_clock_problem(config).await {
+        if station.legacy_time_sync {
+            return Err(Error::HostClock(format!(
+                "{problem}, and firmware with legacy_time_sync can't be downloaded from without it"
+            )));
+        }
+
+        skip_time_sync(pico_stream, timeout, offset).await?;
+
+        warn!("not setting the clock of the Pico, {problem} (pass --force-time-sync to set it anyway)");
+        return Ok(ClockSync::Skipped(problem));
+    }
+
+    if station.legacy_time_sync {
+        let now = config.pico_timezone.now();
+
+        with_timeout(timeout, pico_stream.write_all(&pack_datetime(&now)))
+            .await
+            .map_err(|err| Error::io("writing the packed date time to the Pico", offset, err))?;
+
+        let now = now.trunc_subsecs(0);
+        info!("set the clock of the Pico to {now}, which is behind by up to a second plus the latency");
+        return Ok(ClockSync::Set(now));
+    }
+
+    let start = Instant::now();
+
+    with_timeout(timeout, pico_stream.write_u8(TIME_SYNC_PROBE))
+        .await
+        .map_err(|err| Error::io("writing the time sync probe to the Pico", offset, err))?;
+
+    read_time_sync_echo(pico_stream, timeout, offset, TIME_SYNC_PROBE).await?;
+    let round_trip = start.elapsed();
+
+    // The time arrives about half a round trip after it's sent.
+    let now = config.pico_timezone.now()
+        + chrono::Duration::from_std(round_trip / 2).unwrap_or(chrono::Duration::zero());
+
+    with_timeout(timeout, pico_stream.write_all(&pack_precise_datetime(&now)))
+        .await
+        .map_err(|err| Error::io("writing the packed date time to the Pico", offset + 1, err))?;
+
+    // Off by up to half the round trip if the latency is all in one direction, and by the
+    // truncated fraction of a millisecond.
+    let residual = round_trip / 2 + Duration::from_millis(1);
+    let now = now.trunc_subsecs(3);
+    info!(
+        "set the clock of the Pico to {now} after a round trip of {round_trip:.1?}, accurate to \
+         about ±{residual:.1?}"
+    );
+
+    Ok(ClockSync::Set(now))
+}
+
+/// Sends [`TIME_SYNC_SKIP`] at `offset`, so the Pico keeps its clock as it is.
+async fn skip_time_sync(
+    pico_stream: &mut TracedStream,
+    timeout: Duration,
+    offset: u64,
+) -> Result<(), Error> {
+    with_timeout(timeout, pico_stream.write_u8(TIME_SYNC_SKIP))
+        .await
+        .map_err(|err| Error::io("writing the time sync skip to the Pico", offset, err))?;
+
+    read_time_sync_echo(pico_stream, timeout, offset, TIME_SYNC_SKIP).await
+}
+
+/// Reads the Pico's echo of the byte starting the time sync, `sent`, at `offset`.
+async fn read_time_sync_echo(
+    pico_stream: &mut TracedStream,
+    timeout: Duration,
+    offset: u64,
+    sent: u8,
+) -> Result<(), Error> {
+    let echo = with_timeout(timeout, pico_stream.read_u8())
+        .await
+        .map_err(|err| {
+            Error::io(
+                "reading the echo of the time sync from the Pico (if its firmware predates the millisecond time sync, set legacy_time_sync for the station)",
+                offset,
+                err,
+            )
+        })?;
+
+    match echo == sent {
+        true => Ok(()),
+        false => Err(Error::Protocol {
+            kind: ProtocolErrorKind::UnexpectedEcho(echo),
+            offset,
+        }),
+    }
+}
+
+/// Sets the clock of the Pico and exchanges protocol versions with it, unless it speaks the
+/// legacy protocol, then reads how many measurements it announces. Returns its protocol version,
+/// that count and what became of its clock.
+async fn start_session(
+    config: &Config,
+    station: &Station,
+    pico_stream: &mut TracedStream,
+    timeout: Duration,
+) -> Result<(u8, u32, ClockSync), Error> {
+    let clock_sync = send_time(config, station, pico_stream, timeout).await?;
+    let (protocol_version, measurement_count) =
+        read_announcement(config, station, pico_stream, timeout).await?;
+
+    Ok((protocol_version, measurement_count, clock_sync))
+}
+
+/// Exchanges protocol versions with the Pico once the time sync is done, unless it speaks the
+/// legacy protocol, then reads how many measurements it announces. Returns its protocol version
+/// and that count.
+async fn read_announcement(
+    config: &Config,
+    station: &Station,
+    pico_stream: &mut TracedStream,
+    timeout: Duration,
+) -> Result<(u8, u32), Error> {
+    let offset = authentication_len(station) + time_sync_len(station);
+
+    let protocol_version = if station.legacy_protocol {
+        LEGACY_PROTOCOL_VERSION
+    } else {
+        handshake(pico_stream, timeout, offset).await?
+    };
+
+    let measurement_count =
+        read_measurement_count(station, pico_stream, timeout, header_len(station) - 4)
+            .await
+            .map_err(|err| match err {
+                Error::Protocol {
+                    kind: ProtocolErrorKind::TooManyMeasurements { count, capacity },
+                    offset,
+                } if !station.legacy_protocol => Error::Protocol {
+                    kind: ProtocolErrorKind::ImplausibleCount { count, capacity },
+                    offset,
+                },
+                err => err,
+            })?;
+
+    match flash_warning(config, station, measurement_count) {
+        Some(warning) => warn!("{warning}"),
+        None => info!(
+            "the Pico stores {measurement_count} measurements, {:.1}% of its flash",
+            station.flash_usage(measurement_count)
+        ),
+    }
+
+    Ok((protocol_version, measurement_count))
+}
+
+/// Reads the count of measurements the Pico at `station` announces at `offset`, which has to fit
+/// into its flash.
+async fn read_measurement_count(
+    station: &Station,
+    pico_stream: &mut (impl AsyncRead + Unpin),
+    timeout: Duration,
+    offset: u64,
+) -> Result<u32, Error> {
+    let measurement_count = with_timeout(timeout, pico_stream.read_u32_le())
+        .await
+        .map_err(|err| Error::io("reading measurement count from Pico", offset, err))?;
+
+    if u64::from(measurement_count) > station.capacity() {
+        return Err(Error::Protocol {
+            kind: ProtocolErrorKind::TooManyMeasurements {
+                count: measurement_count,
+                capacity: station.capacity(),
+            },
+            offset,
+        });
+    }
+
+    Ok(measurement_count)
+}
+
+/// Warns of the Pico at `station` storing `count` measurements if they fill more than
+/// `flash_warn_percent` of its flash.
+pub fn flash_warning(config: &Config, station: &Station, count: u32) -> Option<String> {
+    let usage = station.flash_usage(count);
+
+    (usage > f64::from(config.flash_warn_percent)).then(|| {
+        format!(
+            "the flash of the Pico is {usage:.1}% full with {count} of {} measurements, more than \
+             flash_warn_percent ({}%)",
+            station.capacity(),
+            config.flash_warn_percent
+        )
+    })
+}
+
+/// Warns of the Pico's clock having drifted if its newest measurement, `drift_secs` ahead of the
+/// host's clock when it was decoded, is more than `clock_drift_warn_secs` ahead or older than that
+/// plus the sample interval.
+pub fn clock_drift_warning(config: &Config, drift_secs: i64) -> Option<String> {
+    let threshold = i64::try_from(config.clock_drift_warn_secs?).unwrap_or(i64::MAX);
+    let interval = i64::try_from(config.sample_interval_secs.unwrap_or(0)).unwrap_or(i64::MAX);
+
+    if drift_secs > threshold {
+        Some(format!(
+            "the newest measurement is {drift_secs}s ahead of the host's clock, more than \
+             clock_drift_warn_secs ({threshold}s); the Pico's clock has drifted"
+        ))
+    } else if drift_secs < -threshold.saturating_add(interval) {
+        Some(format!(
+            "the newest measurement is {}s behind the host's clock, more than a sample interval \
+             and clock_drift_warn_secs ({threshold}s); the Pico's clock has drifted or it stopped \
+             taking measurements",
+            -drift_secs
+        ))
+    } else {
+        None
+    }
+}
+
+/// Ends the session with the Pico at `station` once its measurements were handled, first
+/// acknowledging how many of them were persisted if the station expects that.
+pub async fn finish_session(
+    config: &Config,
+    station: &Station,
+    session: Session,
+    persisted: u32,
+) -> Result<(), Error> {
+    let timeout = Duration::from_secs(config.read_timeout_secs);
+    let Session {
+        pico_stream,
+        received,
+    } = session;
+    let Some(mut pico_stream) = pico_stream else {
+        return Ok(());
+    };
+
+    if station.ack {
+        with_timeout(timeout, pico_stream.write_u32_le(persisted))
+            .await
+            .map_err(|err| Error::io("writing the acknowledgement to the Pico", received, err))?;
+    }
+
+    with_timeout(timeout, pico_stream.shutdown())
+        .await
+        .map_err(|err| Error::io("shutting the connection to the Pico down", received, err))
+}
+
+/// Replays the session recorded to the file at `path` as if the Pico at `station` had sent it
+/// speaking the current protocol version, see [`fetch_measurements`].
+async fn replay(
+    config: &Config,
+    station: &Station,
+    path: &Path,
+    measurements: &mpsc::Sender<Vec<Measurement>>,
+    info: &mut DownloadInfo,
+) -> Result<Session, Error> {
+    let failed = |err: std::io::Error| {
+        Error::Config(format!(
+            "Error reading the recording {}: {err}",
+            path.display()
+        ))
+    };
+
+    let mut file = BufReader::new(tokio::fs::File::open(path).await.map_err(failed)?);
+    let count = file.read_u32_le().await.map_err(failed)?;
+    info.announced = Some(count);
+
+    let header = Header {
+        protocol_version: PROTOCOL_VERSION,
+        count,
+        len: 4,
+        checksum: false,
+    };
+    let received =
+        receive_measurements(config, station, &mut file, header, measurements, None, info).await?;
+
+    Ok(Session {
+        pico_stream: None,
+        received,
+    })
+}
+
+/// Decodes what a Pico speaking the current protocol version sends on `pico_stream` after the
+/// handshake, the count of measurements followed by them (and their checksum if the station has
+/// `checksum`), like [`fetch_measurements`] does. Returns how many bytes were read.
+///
+/// Whatever the bytes are, this only ever fails with an [`Error`], which the fuzz target in `fuzz/`
+/// checks.
+pub async fn decode_session(
+    config: &Config,
+    station: &Station,
+    pico_stream: &mut (impl AsyncRead + Unpin),
+    measurements: &mpsc::Sender<Vec<Measurement>>,
+    info: &mut DownloadInfo,
+) -> Result<u64, Error> {
+    let timeout = Duration::from_secs(config.read_timeout_secs);
+    let count = read_measurement_count(station, pico_stream, timeout, 0).await?;
+    info.announced = Some(count);
+
+    let header = Header {
+        protocol_version: PROTOCOL_VERSION,
+        count,
+        len: 4,
+        checksum: station.checksum,
+    };
+
+    receive_measurements(
+        config,
+        station,
+        pico_stream,
+        header,
+        measurements,
+        None,
+        info,
+    )
+    .await
+}
+
+/// Receives the measurements the Pico announced in `header` from `pico_stream`, sending them to
+/// `measurements` in chunks and recording them to `recording` if given. The ones skipped and
+/// removed as duplicates are added to `info`, like how long receiving and decoding them took once
+/// done. Returns how many bytes the Pico sent in the session.
+async fn receive_measurements(
+    config: &Config,
+    station: &Station,
+    pico_stream: &mut (impl AsyncRead + Unpin),
+    header: Header,
+    measurements: &mpsc::Sender<Vec<Measurement>>,
+    mut recording: Option<&mut Recording>,
+    info: &mut DownloadInfo,
+) -> Result<u64, Error> {
+    let timeout = Duration::from_secs(config.read_timeout_secs);
+    let Header {
+        protocol_version,
+        count: measurement_count,
+        len: header_len,
+        checksum: with_checksum,
+    } = header;
+
+    if with_checksum && measurement_count > MAX_CHECKSUMMED_MEASUREMENTS {
+        return Err(Error::Protocol {
+            kind: ProtocolErrorKind::TooManyToVerify {
+                count: measurement_count,
+                max: MAX_CHECKSUMMED_MEASUREMENTS,
+            },
+            offset: header_len,
+        });
+    }
+
+    // Never sized by the announced count, which comes straight off the network and is only
+    // checked against the configurable capacity of the flash.
+    let mut chunk = Vec::with_capacity(MEASUREMENT_CHUNK);
+    // The chunks held back until the checksum is verified, and the last one. Bounded by
+    // MAX_CHECKSUMMED_MEASUREMENTS.
+    let mut held_back = Vec::new();
+    let mut checksum = crc32fast::Hasher::new();
+    let mut out_of_range = 0;
+    let mut previous = None;
+    let mut duplicates = DuplicateFilter::new(config);
+    let mut spikes = SpikeFilter::new(config);
+    let mut progress = Progress::new("decoded", Some(measurement_count.into()));
+    // Entered while decoding, between reading the measurements.
+    let decode = info_span!("decode", measurements = field::Empty);
+    let mut decoded = 0;
+    let start = Instant::now();
+    let mut decoding = Duration::ZERO;
+    // How long the chunks waited for the inserts to take them.
+    let mut stalled = Duration::ZERO;
+
+    // The last chunk also gets the measurement the spike filter held back.
+    let mut plausible = |chunk, last| {
+        let chunk = match duplicates.as_mut() {
+            Some(duplicates) => {
+                let chunk = duplicates.filter(chunk);
+                info.duplicates = duplicates.removed;
+                chunk
+            }
+            None => chunk,
+        };
+        let chunk = check_times(
+            config,
+            Utc::now(),
+            chunk,
+            &mut previous,
+            &mut info.implausible,
+        );
+        let chunk = match spikes.as_mut() {
+            Some(spikes) => spikes.filter(chunk, last),
+            None => chunk,
+        };
+        let mut chunk = apply_ranges(config, chunk, &mut out_of_range);
+        derive_quantities(config, &mut chunk);
+        chunk
+    };
+
+    let send = |chunk| async { measurements.send(chunk).await.map_err(|_| Error::NotStored) };
+
+    for index in 0..measurement_count {
+        let mut bytes = [0; 8];
+        let offset = header_len + u64::from(index) * 8;
+
+        let read = with_timeout(timeout, read_full(pico_stream, &mut bytes))
+            .await
+            .map_err(|err| Error::io("reading a packed measurement from the Pico", offset, err))?;
+
+        match read {
+            0 => {
+                return Err(Error::Protocol {
+                    kind: ProtocolErrorKind::Closed {
+                        received: index,
+                        count: measurement_count,
+                    },
+                    offset,
+                })
+            }
+            1..=7 => {
+                return Err(Error::Protocol {
+                    kind: ProtocolErrorKind::Truncated {
+                        read,
+                        index,
+                        count: measurement_count,
+                    },
+                    offset,
+                })
+            }
+            _ => {}
+        }
+
+        checksum.update(&bytes);
+
+        if let Some(recording) = recording.as_deref_mut() {
+            recording.measurement(&bytes);
+        }
+        progress.update(u64::from(index) + 1, Some(offset + 8));
+
+        let packed = u64::from_le_bytes(bytes);
+
+        if protocol_trace::enabled() {
+            debug!(index, bits = %BitFields(packed), "packed measurement");
+        }
+
+        let decoding_started = Instant::now();
+        let measurement = decode.in_scope(|| decode_measurement(config, protocol_version, packed));
+        decoding += decoding_started.elapsed();
+
+        if let Some(mut measurement) = measurement
+            .map_err(|err| Error::Decode {
+                index,
+                count: measurement_count,
+                err,
+            })
+            .inspect_err(|err| failed(&decode, err))?
+        {
+            measurement.packed = config.store_raw_packed.then_some(packed as i64);
+            station.calibrate(&mut measurement);
+
+            debug!(
+                index,
+                time = %measurement.time.to_rfc3339(),
+                temp = %measurement.temp,
+                humidity = %measurement.humidity,
+                "decoded measurement"
+            );
+
+            chunk.push(measurement);
+        }
+
+        if chunk.len() == MEASUREMENT_CHUNK {
+            let decoding_started = Instant::now();
+            let full = decode.in_scope(|| plausible(std::mem::take(&mut chunk), false));
+            decoding += decoding_started.elapsed();
+            decoded += full.len();
+
+            if with_checksum {
+                held_back.push(full);
+            } else {
+                let sending = Instant::now();
+                send(full).await?;
+                stalled += sending.elapsed();
+            }
+        }
+    }
+
+    let decoding_started = Instant::now();
+    let last = decode.in_scope(|| plausible(chunk, true));
+    decoding += decoding_started.elapsed();
+    info.decode = Some(decoding);
+
+    if !last.is_empty() {
+        decoded += last.len();
+        held_back.push(last);
+    }
+
+    decode.record("measurements", decoded);
+
+    let mut received = header_len + u64::from(measurement_count) * 8;
+
+    if with_checksum {
+        let expected = with_timeout(timeout, pico_stream.read_u32_le())
+            .await
+            .map_err(|err| {
+                Error::io(
+                    "reading the measurement checksum from the Pico",
+                    received,
+                    err,
+                )
+            })?;
+
+        let computed = checksum.finalize();
+
+        if expected != computed {
+            return Err(Error::ChecksumMismatch { expected, computed });
+        }
+
+        received += 4;
+    }
+
+    report_out_of_range(config, out_of_range);
+
+    let sending = Instant::now();
+    for chunk in held_back {
+        send(chunk).await?;
+    }
+    stalled += sending.elapsed();
+
+    // A Pico expecting an acknowledgement keeps the connection open, so only the others can be
+    // checked for bytes following the announced measurements.
+    if !station.ack {
+        let mut extra = Vec::new();
+
+        with_timeout(timeout, pico_stream.read_to_end(&mut extra))
+            .await
+            .map_err(|err| {
+                Error::io("reading the end of the stream from the Pico", received, err)
+            })?;
+
+        if !extra.is_empty() {
+            warn!(
+                "Pico sent {} bytes after the {measurement_count} announced measurements, ignoring them",
+                extra.len()
+            );
+        }
+
+        received += extra.len() as u64;
+    }
+
+    info.download = Some(start.elapsed().saturating_sub(stalled + decoding));
+    info.received = Some(received);
+
+    Ok(received)
+}
+
+/// Exchanges protocol versions with the Pico, returning the Pico's version if it's supported.
+///
+/// The handshake follows the packed date time: the host sends its protocol version as one byte and
+/// the Pico replies with its own at `offset`.
+async fn handshake(
+    pico_stream: &mut TracedStream,
+    timeout: Duration,
+    offset: u64,
+) -> Result<u8, Error> {
+    with_timeout(timeout, pico_stream.write_u8(PROTOCOL_VERSION))
+        .await
+        .map_err(|err| Error::io("writing the protocol version to the Pico", offset, err))?;
+
+    let pico_version = with_timeout(timeout, pico_stream.read_u8())
+        .await
+        .map_err(|err| {
+            Error::io(
+                "reading the protocol version of the Pico (if its firmware predates the version handshake, set legacy_protocol for the station)",
+                offset,
+                err,
+            )
+        })?;
+
+    if !(LEGACY_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&pico_version) {
+        return Err(Error::Protocol {
+            kind: ProtocolErrorKind::UnsupportedVersion(pico_version),
+            offset,
+        });
+    }
+
+    Ok(pico_version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An address nothing listens at.
+    fn refusing_address() -> SocketAddr {
+        std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn connects_to_the_first_address_accepting() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let refusing = refusing_address();
+        let accepting = listener.local_addr().unwrap();
+
+        let pico_stream = connect_first(vec![refusing, accepting], Duration::from_secs(1))
+            .await
+            .unwrap();
+
+        assert_eq!(pico_stream.peer_addr().unwrap(), accepting);
+    }
+
+    #[tokio::test]
+    async fn reports_every_address_failing() {
+        let (first, second) = (refusing_address(), refusing_address());
+
+        let err = connect_first(vec![first, second], Duration::from_secs(1))
+            .await
+            .unwrap_err();
+
+        assert!(err.starts_with(&format!("{first}: ")), "{err}");
+        assert!(err.contains(&format!("; {second}: ")), "{err}");
+    }
+
+    #[tokio::test]
+    async fn reports_where_the_pico_broke_off() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = Config {
+            stations: vec![Station {
+                pico: "127.0.0.1".to_string(),
+                pico_port: listener.local_addr().unwrap().port(),
+                ..Config::default().stations[0].clone()
+            }],
+            connect_retries: 0,
+            ..Config::default()
+        };
+        let pico = tokio::spawn(async move {
+            let (mut pico_stream, _) = listener.accept().await.unwrap();
+            assert_eq!(pico_stream.read_u8().await.unwrap(), TIME_SYNC_PROBE);
+            pico_stream.write_u8(TIME_SYNC_PROBE).await.unwrap();
+            let mut packed_now_and_version = [0; 9];
+            pico_stream
+                .read_exact(&mut packed_now_and_version)
+                .await
+                .unwrap();
+
+            let time = chrono::NaiveDate::from_ymd_opt(2024, 6, 1)
+                .unwrap()
+                .and_hms_opt(12, 0, 0)
+                .unwrap();
+            let mut sent = vec![PROTOCOL_VERSION];
+            sent.extend(2u32.to_le_bytes());
+            sent.extend(crate::protocol::encode_measurement(&time, 215, 480).to_le_bytes());
+            sent.extend([0; 3]);
+            pico_stream.write_all(&sent).await.unwrap();
+        });
+
+        let (sender, _receiver) = mpsc::channel(CHUNKS_IN_FLIGHT);
+        let result = fetch_measurements(
+            &config,
+            &config.stations[0],
+            Source::Connect,
+            None,
+            sender,
+            &mut DownloadInfo::default(),
+        )
+        .await;
+        pico.await.unwrap();
+
+        match result {
+            Err(Error::Protocol {
+                kind:
+                    ProtocolErrorKind::Truncated {
+                        read: 3,
+                        index: 1,
+                        count: 2,
+                    },
+                offset: 14,
+            }) => {}
+            Err(err) => panic!("{err:?}"),
+            Ok(_) => panic!("the download succeeded"),
+        }
+    }
+
+    #[tokio::test]
+    async fn decodes_sessions_from_bytes() {
+        let config = Config::default();
+        let station = Station {
+            checksum: true,
+            ..config.stations[0].clone()
+        };
+        let time = chrono::NaiveDate::from_ymd_opt(2024, 6, 1)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap();
+        let packed = crate::protocol::encode_measurement(&time, 215, 480).to_le_bytes();
+
+        let mut session = 1u32.to_le_bytes().to_vec();
+        session.extend(packed);
+        session.extend(crc32fast::hash(&packed).to_le_bytes());
+
+        let (sender, mut receiver) = mpsc::channel(CHUNKS_IN_FLIGHT);
+        let mut info = DownloadInfo::default();
+        let received = decode_session(&config, &station, &mut &session[..], &sender, &mut info)
+            .await
+            .unwrap();
+
+        assert_eq!(received, 16);
+        assert_eq!(receiver.recv().await.unwrap().len(), 1);
+
+        // The count is checked before anything is read for it.
+        let session = u32::MAX.to_le_bytes();
+        let result = decode_session(&config, &station, &mut &session[..], &sender, &mut info).await;
+
+        match result {
+            Err(Error::Protocol {
+                kind: ProtocolErrorKind::TooManyMeasurements { .. },
+                offset: 0,
+            }) => {}
+            result => panic!("{result:?}"),
+        }
+
+        // Even if they fit into the flash, no more are held back than the limit.
+        let station = Station {
+            sector_count: std::num::NonZeroU32::new(1 << 20),
+            ..station
+        };
+        let session = (MAX_CHECKSUMMED_MEASUREMENTS + 1).to_le_bytes();
+        let result = decode_session(&config, &station, &mut &session[..], &sender, &mut info).await;
+
+        match result {
+            Err(Error::Protocol {
+                kind: ProtocolErrorKind::TooManyToVerify { .. },
+                offset: 4,
+            }) => {}
+            result => panic!("{result:?}"),
+        }
+    }
+}
@@ -0,0 +1,156 @@
+//! Periodic progress reports of long downloads and inserts, logged like everything else so they
+//! don't garble the other log lines.
+
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use tracing::info;
+
+/// How often progress is reported at the least.
+const REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long an operation runs before progress is reported at every tenth of it, so short ones
+/// don't report at all.
+const MIN_ELAPSED: Duration = Duration::from_secs(1);
+
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Turns progress reporting off, for `--quiet`.
+pub fn disable() {
+    ENABLED.store(false, Ordering::Relaxed);
+}
+
+/// The progress of an operation on `total` (if known) measurements.
+pub struct Progress {
+    /// What is done to the measurements, like "decoded".
+    verb: &'static str,
+    total: Option<u64>,
+    start: Instant,
+    last_report: Instant,
+    /// The tenth of the total last reported.
+    last_tenth: u64,
+}
+
+impl Progress {
+    pub fn new(verb: &'static str, total: Option<u64>) -> Progress {
+        let now = Instant::now();
+
+        Progress {
+            verb,
+            total,
+            start: now,
+            last_report: now,
+            last_tenth: 0,
+        }
+    }
+
+    /// Reports that `done` measurements and `bytes` bytes (if counted) were handled so far, if
+    /// it's time to.
+    pub fn update(&mut self, done: u64, bytes: Option<u64>) {
+        if !ENABLED.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let now = Instant::now();
+        let elapsed = now - self.start;
+        let tenth = match self.total {
+            Some(total) if total > 0 => done * 10 / total,
+            _ => 0,
+        };
+
+        let due = now - self.last_report >= REPORT_INTERVAL
+            || (tenth > self.last_tenth && elapsed >= MIN_ELAPSED);
+
+        if !due {
+            return;
+        }
+
+        self.last_report = now;
+        self.last_tenth = tenth;
+
+        info!("{}", self.describe(done, bytes, elapsed));
+    }
+
+    fn describe(&self, done: u64, bytes: Option<u64>, elapsed: Duration) -> String {
+        let mut description = match self.total {
+            Some(total) => format!(
+                "{} {} / {} measurements",
+                self.verb,
+                thousands(done),
+                thousands(total)
+            ),
+            None => format!("{} {} measurements", self.verb, thousands(done)),
+        };
+
+        if let Some(bytes) = bytes {
+            description += &format!(", {:.1} MB received", bytes as f64 / 1e6);
+        }
+
+        let rate = done as f64 / elapsed.as_secs_f64();
+
+        match self.total {
+            Some(total) if done > 0 && done < total => {
+                let eta = ((total - done) as f64 / rate).ceil() as u64;
+                description += &format!(", ETA {}", format_secs(eta));
+            }
+            _ => description += &format!(", {} per second", thousands(rate as u64)),
+        }
+
+        description
+    }
+}
+
+/// Formats `n` with commas between the thousands.
+fn thousands(n: u64) -> String {
+    let digits = n.to_string();
+    let mut formatted = String::with_capacity(digits.len() + digits.len() / 3);
+
+    for (index, digit) in digits.chars().enumerate() {
+        if index > 0 && (digits.len() - index).is_multiple_of(3) {
+            formatted.push(',');
+        }
+        formatted.push(digit);
+    }
+
+    formatted
+}
+
+fn format_secs(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{secs}s"),
+        _ => format!("{}m {}s", secs / 60, secs % 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn separates_thousands() {
+        assert_eq!(thousands(0), "0");
+        assert_eq!(thousands(999), "999");
+        assert_eq!(thousands(1000), "1,000");
+        assert_eq!(thousands(262_144), "262,144");
+        assert_eq!(thousands(1_234_567), "1,234,567");
+    }
+
+    #[test]
+    fn describes_the_progress() {
+        let download = Progress::new("decoded", Some(262_144));
+
+        assert_eq!(
+            download.describe(120_000, Some(3_200_000), Duration::from_secs(30)),
+            "decoded 120,000 / 262,144 measurements, 3.2 MB received, ETA 36s"
+        );
+
+        let insert = Progress::new("inserted", None);
+
+        assert_eq!(
+            insert.describe(20_000, None, Duration::from_secs(8)),
+            "inserted 20,000 measurements, 2,500 per second"
+        );
+    }
+}
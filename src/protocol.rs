@@ -0,0 +1,831 @@
+//! Encoding and decoding of the data exchanged with the Pico.
+
+use std::{fmt, num::NonZeroU32};
+
+use chrono::{offset::LocalResult, Datelike, NaiveDate, NaiveDateTime, NaiveTime, Timelike};
+use rust_decimal::{prelude::ToPrimitive, Decimal, RoundingStrategy};
+use tracing::warn;
+
+use crate::{
+    config::{AmbiguousTime, Config, NonexistentTime},
+    Measurement,
+};
+
+/// The protocol version spoken by firmware without the version handshake.
+pub const LEGACY_PROTOCOL_VERSION: u8 = 1;
+
+/// The newest protocol version this program speaks, sent to the Pico during the handshake.
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// The byte starting the millisecond time sync, which the Pico echoes right away so the host can
+/// measure the round trip time.
+pub const TIME_SYNC_PROBE: u8 = 0x54;
+
+/// The byte sent instead of [`TIME_SYNC_PROBE`] to leave the clock of the Pico alone, which it
+/// echoes all the same, without the time following.
+pub const TIME_SYNC_SKIP: u8 = 0x4b;
+
+/// The geometry of the flash of the stock firmware, unless configured otherwise for a station.
+pub const SECTOR_COUNT: u32 = 512;
+pub const PAGES_PER_SECTOR: u32 = 16;
+pub const MEASUREMENTS_PER_PAGE: u32 = 32;
+
+/// The number of measurements fitting into the flash of a stock Pico.
+pub const MAX_MEASUREMENT_COUNT: u32 = SECTOR_COUNT * PAGES_PER_SECTOR * MEASUREMENTS_PER_PAGE;
+
+/// A field of `width` bits starting at bit `shift` of a packed measurement or date time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Field {
+    shift: u32,
+    width: u32,
+}
+
+impl Field {
+    const fn new(shift: u32, width: u32) -> Field {
+        Field { shift, width }
+    }
+
+    fn mask(self) -> u64 {
+        (1 << self.width) - 1
+    }
+
+    /// The value of this field in `packed`.
+    fn get(self, packed: u64) -> u64 {
+        packed >> self.shift & self.mask()
+    }
+
+    /// `value` placed in this field, truncated to its width.
+    fn put(self, value: u64) -> u64 {
+        (value & self.mask()) << self.shift
+    }
+}
+
+// The fields both the measurements and the date times the clocks are set with start with.
+const SECOND: Field = Field::new(0, 6);
+const MINUTE: Field = Field::new(6, 6);
+const HOUR: Field = Field::new(12, 5);
+
+// The other fields of a measurement of protocol version 1.
+const DAY0: Field = Field::new(17, 5);
+const MONTH0: Field = Field::new(22, 4);
+const YEAR: Field = Field::new(26, 16);
+const TEMP: Field = Field::new(42, 9);
+const HUMIDITY: Field = Field::new(51, 10);
+
+// The other fields of a packed date time, which has the weekday after the hour and the year up to
+// the end of its 6 bytes.
+const TIME_WEEKDAY: Field = Field::new(17, 3);
+const TIME_DAY0: Field = Field::new(20, 5);
+const TIME_MONTH0: Field = Field::new(25, 4);
+const TIME_YEAR: Field = Field::new(29, 19);
+
+/// Why a packed measurement couldn't be decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    InvalidDate,
+    InvalidTime,
+    /// The local time occurs twice and `ambiguous_time` is `error`.
+    AmbiguousTime,
+    /// The local time doesn't exist and `nonexistent_time` is `error`.
+    NonexistentTime,
+    UnsupportedVersion(u8),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::InvalidDate => write!(f, "Pico sent invalid date"),
+            DecodeError::InvalidTime => write!(f, "Pico sent invalid time"),
+            DecodeError::AmbiguousTime => write!(f, "Pico sent ambiguous time"),
+            DecodeError::NonexistentTime => write!(f, "Pico sent impossible time"),
+            DecodeError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported protocol version {version}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Packs `time` into the 6 bytes the Pico's clock is set with, from the least significant bit
+/// upwards as second (6 bits), minute (6), hour (5), weekday counted from Sunday (3), day - 1 (5),
+/// month - 1 (4) and year (19), little-endian.
+pub fn pack_datetime(time: &NaiveDateTime) -> [u8; 6] {
+    let packed = SECOND.put(time.second().into())
+        | MINUTE.put(time.minute().into())
+        | HOUR.put(time.hour().into())
+        | TIME_WEEKDAY.put(time.weekday().num_days_from_sunday().into())
+        | TIME_DAY0.put(time.day0().into())
+        | TIME_MONTH0.put(time.month0().into())
+        | TIME_YEAR.put(time.year() as u64);
+
+    packed.to_le_bytes()[..6].try_into().unwrap()
+}
+
+/// Packs `time` into the 8 bytes of the millisecond time sync: the 6 bytes of [`pack_datetime`]
+/// followed by the milliseconds as a little-endian `u16`. A leap second's milliseconds are capped
+/// at 999.
+pub fn pack_precise_datetime(time: &NaiveDateTime) -> [u8; 8] {
+    let millis = (time.nanosecond() / 1_000_000).min(999) as u16;
+    let mut packed = [0; 8];
+
+    packed[..6].copy_from_slice(&pack_datetime(time));
+    packed[6..].copy_from_slice(&millis.to_le_bytes());
+    packed
+}
+
+/// Unpacks a date time packed by [`pack_precise_datetime`], or returns `None` if it isn't a valid
+/// date and time.
+pub fn unpack_precise_datetime(packed: [u8; 8]) -> Option<NaiveDateTime> {
+    let millis = u16::from_le_bytes([packed[6], packed[7]]);
+
+    if millis > 999 {
+        return None;
+    }
+
+    unpack_datetime(packed[..6].try_into().unwrap())?.with_nanosecond(u32::from(millis) * 1_000_000)
+}
+
+/// Unpacks a date time packed by [`pack_datetime`], ignoring the weekday, or returns `None` if it
+/// isn't a valid date and time.
+pub fn unpack_datetime(packed: [u8; 6]) -> Option<NaiveDateTime> {
+    let mut bytes = [0; 8];
+    bytes[..6].copy_from_slice(&packed);
+    let packed = u64::from_le_bytes(bytes);
+
+    NaiveDate::from_ymd_opt(
+        TIME_YEAR.get(packed) as i32,
+        TIME_MONTH0.get(packed) as u32 + 1,
+        TIME_DAY0.get(packed) as u32 + 1,
+    )?
+    .and_hms_opt(
+        HOUR.get(packed) as u32,
+        MINUTE.get(packed) as u32,
+        SECOND.get(packed) as u32,
+    )
+}
+
+/// Decodes a measurement packed according to `protocol_version`, returning `None` if its time
+/// doesn't map to a single instant and the config says to skip such measurements.
+pub fn decode_measurement(
+    config: &Config,
+    protocol_version: u8,
+    packed_measurement: u64,
+) -> Result<Option<Measurement>, DecodeError> {
+    match protocol_version {
+        1 => decode_measurement_v1(config, packed_measurement),
+        version => Err(DecodeError::UnsupportedVersion(version)),
+    }
+}
+
+/// Decodes a measurement of protocol version 1, packed from the least significant bit upwards as
+/// second (6 bits), minute (6), hour (5), day - 1 (5), month - 1 (4), year (16), temperature in
+/// degrees Celsius times `temp_scale` (9, two's complement) and humidity in percent times
+/// `humidity_scale` (10).
+fn decode_measurement_v1(
+    config: &Config,
+    packed_measurement: u64,
+) -> Result<Option<Measurement>, DecodeError> {
+    let local_time = NaiveDateTime::new(
+        NaiveDate::from_ymd_opt(
+            YEAR.get(packed_measurement) as i32,
+            MONTH0.get(packed_measurement) as u32 + 1,
+            DAY0.get(packed_measurement) as u32 + 1,
+        )
+        .ok_or(DecodeError::InvalidDate)?,
+        NaiveTime::from_hms_opt(
+            HOUR.get(packed_measurement) as u32,
+            MINUTE.get(packed_measurement) as u32,
+            SECOND.get(packed_measurement) as u32,
+        )
+        .ok_or(DecodeError::InvalidTime)?,
+    );
+
+    let time = match (
+        config.pico_timezone.resolve(&local_time),
+        config.ambiguous_time,
+        config.nonexistent_time,
+    ) {
+        (LocalResult::Single(datetime), _, _) => datetime,
+        // The two candidates aren't necessarily in chronological order.
+        (LocalResult::Ambiguous(first, second), AmbiguousTime::Earliest, _) => first.min(second),
+        (LocalResult::Ambiguous(first, second), AmbiguousTime::Latest, _) => first.max(second),
+        (LocalResult::Ambiguous(_, _), AmbiguousTime::Skip, _) => {
+            warn!("Pico sent ambiguous time {local_time}, skipping the measurement");
+            return Ok(None);
+        }
+        (LocalResult::Ambiguous(_, _), AmbiguousTime::Error, _) => {
+            return Err(DecodeError::AmbiguousTime)
+        }
+        (LocalResult::None, _, NonexistentTime::Skip) => {
+            warn!("Pico sent impossible time {local_time}, skipping the measurement");
+            return Ok(None);
+        }
+        (LocalResult::None, _, NonexistentTime::Error) => return Err(DecodeError::NonexistentTime),
+    };
+
+    Ok(Some(Measurement {
+        time,
+        // The temperature is a 9 bit two's complement number; shifting it to the top of the i32
+        // and arithmetically back down sign-extends it.
+        temp: scale(
+            (TEMP.get(packed_measurement) as i32) << 23 >> 23,
+            config.temp_scale,
+        ),
+        humidity: scale(
+            HUMIDITY.get(packed_measurement) as i32,
+            config.humidity_scale,
+        ),
+        uncalibrated: None,
+        out_of_range: false,
+        dew_point: None,
+        perceived_temp: None,
+        absolute_humidity: None,
+        packed: None,
+    }))
+}
+
+/// Divides `raw` by `scale`, rounded half away from zero to as many decimal places as `scale`
+/// has digits after the first, so a scale of 10 always gives one decimal place (`20.0`, not `20`)
+/// and one of 100 two.
+pub fn scale(raw: i32, scale: NonZeroU32) -> Decimal {
+    let decimal_places = (scale.get() - 1)
+        .checked_ilog10()
+        .map_or(0, |digits| digits + 1);
+
+    let mut value = (Decimal::from(raw) / Decimal::from(scale.get()))
+        .round_dp_with_strategy(decimal_places, RoundingStrategy::MidpointAwayFromZero);
+    value.rescale(decimal_places);
+    value
+}
+
+/// Packs a measurement the way firmware speaking protocol version 1 does, see
+/// [`decode_measurement`]. Temperatures and humidities outside of the 9 and 10 bits are truncated.
+pub fn encode_measurement(time: &NaiveDateTime, temp: i32, humidity: i32) -> u64 {
+    SECOND.put(time.second().into())
+        | MINUTE.put(time.minute().into())
+        | HOUR.put(time.hour().into())
+        | DAY0.put(time.day0().into())
+        | MONTH0.put(time.month0().into())
+        | YEAR.put(time.year() as u64)
+        | TEMP.put(temp as u64)
+        | HUMIDITY.put(humidity as u64)
+}
+
+/// Packs `measurement` the way firmware speaking protocol version 1 does, so [`decode_measurement`]
+/// decodes it again with `config`: its time in `pico_timezone`, and its values as the Pico sent
+/// them, before any calibration, times `temp_scale` and `humidity_scale`. Returns `None` if a value
+/// doesn't fit into its field.
+///
+/// Together with [`encode_measurement`], this is the reference for the bit layout firmware has to
+/// produce.
+pub fn encode_decoded_measurement(config: &Config, measurement: &Measurement) -> Option<u64> {
+    let time = config.pico_timezone.local(&measurement.time).naive_local();
+    let (temp, humidity) = measurement.raw();
+    let scaled =
+        |value: &Decimal, scale: NonZeroU32| (value * Decimal::from(scale.get())).round().to_i32();
+
+    let temp = scaled(temp, config.temp_scale)?;
+    let humidity = scaled(humidity, config.humidity_scale)?;
+    let temp_limit = 1 << (TEMP.width - 1);
+
+    let fits = (0..=YEAR.mask() as i32).contains(&time.year())
+        && (-temp_limit..temp_limit).contains(&temp)
+        && (0..=HUMIDITY.mask() as i32).contains(&humidity);
+
+    fits.then(|| encode_measurement(&time, temp, humidity))
+}
+
+/// A measurement packed the way protocol version 1 does, displayed as its bit fields from the
+/// most significant bit downwards, each in binary followed by its raw value, for tracing the
+/// protocol.
+pub struct BitFields(pub u64);
+
+impl fmt::Display for BitFields {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const FIELDS: [(&str, Field); 9] = [
+            ("unused", Field::new(61, 3)),
+            ("humidity", HUMIDITY),
+            ("temp", TEMP),
+            ("year", YEAR),
+            ("month0", MONTH0),
+            ("day0", DAY0),
+            ("hour", HOUR),
+            ("minute", MINUTE),
+            ("second", SECOND),
+        ];
+
+        for (index, (name, field)) in FIELDS.into_iter().enumerate() {
+            let value = field.get(self.0);
+            let separator = if index == 0 { "" } else { " " };
+            write!(
+                f,
+                "{separator}{name}={value:0width$b} ({value})",
+                width = field.width as usize
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, FixedOffset};
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::config::PicoTimezone;
+
+    fn datetime(
+        year: i32,
+        month: u32,
+        day: u32,
+        hour: u32,
+        minute: u32,
+        second: u32,
+    ) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(year, month, day)
+            .unwrap()
+            .and_hms_opt(hour, minute, second)
+            .unwrap()
+    }
+
+    fn config(pico_timezone: PicoTimezone) -> Config {
+        Config {
+            pico_timezone,
+            ..Config::default()
+        }
+    }
+
+    fn utc(time: &str) -> DateTime<FixedOffset> {
+        DateTime::parse_from_rfc3339(time).unwrap()
+    }
+
+    fn decode_utc(packed: u64) -> Result<Option<Measurement>, DecodeError> {
+        decode_measurement(&config(PicoTimezone::Utc), PROTOCOL_VERSION, packed)
+    }
+
+    #[test]
+    fn decodes_last_second_of_year() {
+        let measurement = decode_utc(encode_measurement(
+            &datetime(2023, 12, 31, 23, 59, 59),
+            215,
+            480,
+        ))
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(measurement.time, utc("2023-12-31T23:59:59Z"));
+        assert_eq!(measurement.temp.to_string(), "21.5");
+        assert_eq!(measurement.humidity.to_string(), "48.0");
+    }
+
+    #[test]
+    fn decodes_first_and_last_month() {
+        for (month, expected) in [(1, "2024-01-15T00:00:00Z"), (12, "2024-12-15T00:00:00Z")] {
+            let measurement = decode_utc(encode_measurement(
+                &datetime(2024, month, 15, 0, 0, 0),
+                0,
+                0,
+            ))
+            .unwrap()
+            .unwrap();
+
+            assert_eq!(measurement.time, utc(expected));
+        }
+    }
+
+    #[test]
+    fn decodes_extreme_temperatures_and_humidity() {
+        let time = datetime(2024, 6, 1, 12, 0, 0);
+
+        for (temp, humidity, expected_temp, expected_humidity) in [
+            (255, 1023, "25.5", "102.3"),
+            (-256, 0, "-25.6", "0.0"),
+            (-1, 1, "-0.1", "0.1"),
+            (0, 1000, "0.0", "100.0"),
+        ] {
+            let measurement = decode_utc(encode_measurement(&time, temp, humidity))
+                .unwrap()
+                .unwrap();
+
+            assert_eq!(measurement.temp.to_string(), expected_temp);
+            assert_eq!(measurement.humidity.to_string(), expected_humidity);
+        }
+    }
+
+    #[test]
+    fn round_trips_negative_temperatures() {
+        let time = datetime(2024, 1, 15, 6, 0, 0);
+
+        // The sign bit set or not, around zero and at both ends of the field.
+        for (temp, expected) in [
+            (-256, "-25.6"),
+            (-255, "-25.5"),
+            (-103, "-10.3"),
+            (-1, "-0.1"),
+            (0, "0.0"),
+            (1, "0.1"),
+            (255, "25.5"),
+        ] {
+            let packed = encode_measurement(&time, temp, 500);
+            let measurement = decode_utc(packed).unwrap().unwrap();
+
+            assert_eq!(measurement.temp.to_string(), expected, "{temp}");
+            assert_eq!(measurement.temp, Decimal::new(temp.into(), 1));
+            assert_eq!(
+                encode_decoded_measurement(&config(PicoTimezone::Utc), &measurement),
+                Some(packed),
+                "{temp}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_dates_and_times() {
+        // Month 13.
+        assert_eq!(
+            decode_utc(encode_measurement(&datetime(2024, 12, 1, 0, 0, 0), 0, 0) + (1 << 22)),
+            Err(DecodeError::InvalidDate)
+        );
+        // February 30th.
+        assert_eq!(
+            decode_utc(encode_measurement(&datetime(2024, 2, 28, 0, 0, 0), 0, 0) + (2 << 17)),
+            Err(DecodeError::InvalidDate)
+        );
+        // Hour 24.
+        assert_eq!(
+            decode_utc(encode_measurement(&datetime(2024, 2, 28, 23, 0, 0), 0, 0) + (1 << 12)),
+            Err(DecodeError::InvalidTime)
+        );
+    }
+
+    /// The measurements of protocol version 1 with any valid time from 2020 to 2099, temperature
+    /// and humidity, packed.
+    fn valid_measurements() -> impl Strategy<Value = (NaiveDateTime, i32, i32)> {
+        let start = datetime(2020, 1, 1, 0, 0, 0);
+        let seconds = (datetime(2100, 1, 1, 0, 0, 0) - start).num_seconds();
+
+        (0..seconds, -256..=255, 0..=1023).prop_map(move |(second, temp, humidity)| {
+            (start + chrono::Duration::seconds(second), temp, humidity)
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn round_trips_valid_measurements((time, temp, humidity) in valid_measurements()) {
+            let config = config(PicoTimezone::Utc);
+            let packed = encode_measurement(&time, temp, humidity);
+            let measurement = decode_utc(packed).unwrap().unwrap();
+
+            prop_assert_eq!(measurement.time.naive_utc(), time);
+            prop_assert_eq!(measurement.temp, Decimal::new(temp.into(), 1));
+            prop_assert_eq!(measurement.humidity, Decimal::new(humidity.into(), 1));
+            prop_assert_eq!(encode_decoded_measurement(&config, &measurement), Some(packed));
+        }
+
+        #[test]
+        fn decodes_any_bits_without_panicking(packed in any::<u64>()) {
+            let config = config(PicoTimezone::Named(chrono_tz::Europe::Berlin));
+
+            // Whatever decodes encodes to the same bits, but for the unused ones.
+            if let Ok(Some(measurement)) = decode_measurement(&config, PROTOCOL_VERSION, packed) {
+                prop_assert_eq!(
+                    encode_decoded_measurement(&config, &measurement),
+                    Some(packed & ((1 << 61) - 1))
+                );
+            }
+        }
+
+        #[test]
+        fn rejects_months_and_days_beyond_the_calendar(
+            (time, temp, humidity) in valid_measurements(),
+            month0 in 12..=15u64,
+        ) {
+            let packed = encode_measurement(&time, temp, humidity);
+
+            prop_assert_eq!(
+                decode_utc(packed & !MONTH0.put(u64::MAX) | MONTH0.put(month0)),
+                Err(DecodeError::InvalidDate)
+            );
+            // Day 32.
+            prop_assert_eq!(
+                decode_utc(packed | DAY0.put(31)),
+                Err(DecodeError::InvalidDate)
+            );
+        }
+    }
+
+    #[test]
+    fn refuses_to_encode_values_beyond_the_fields() {
+        let config = config(PicoTimezone::Utc);
+        let measurement = decode_utc(encode_measurement(
+            &datetime(2024, 6, 1, 12, 0, 0),
+            255,
+            1023,
+        ))
+        .unwrap()
+        .unwrap();
+
+        for (temp, humidity) in [(256, 1023), (255, 1024), (-257, 0), (0, -1)] {
+            let measurement = Measurement {
+                temp: Decimal::new(temp, 1),
+                humidity: Decimal::new(humidity, 1),
+                ..measurement.clone()
+            };
+
+            assert_eq!(
+                encode_decoded_measurement(&config, &measurement),
+                None,
+                "{temp} {humidity}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_unsupported_versions() {
+        assert_eq!(
+            decode_measurement(&Config::default(), 2, 0),
+            Err(DecodeError::UnsupportedVersion(2))
+        );
+    }
+
+    #[test]
+    fn resolves_ambiguous_times_by_policy() {
+        let berlin = PicoTimezone::Named(chrono_tz::Europe::Berlin);
+        // Berlin falls back from 03:00 CEST to 02:00 CET, so 02:30 occurs twice.
+        let packed = encode_measurement(&datetime(2024, 10, 27, 2, 30, 0), 0, 0);
+
+        let decode = |ambiguous_time| {
+            let config = Config {
+                ambiguous_time,
+                ..config(berlin)
+            };
+
+            decode_measurement(&config, PROTOCOL_VERSION, packed)
+                .map(|measurement| measurement.map(|measurement| measurement.time))
+        };
+
+        assert_eq!(
+            decode(AmbiguousTime::Earliest),
+            Ok(Some(utc("2024-10-27T00:30:00Z")))
+        );
+        assert_eq!(
+            decode(AmbiguousTime::Latest),
+            Ok(Some(utc("2024-10-27T01:30:00Z")))
+        );
+        assert_eq!(decode(AmbiguousTime::Skip), Ok(None));
+        assert_eq!(
+            decode(AmbiguousTime::Error),
+            Err(DecodeError::AmbiguousTime)
+        );
+    }
+
+    #[test]
+    fn resolves_nonexistent_times_by_policy() {
+        let berlin = PicoTimezone::Named(chrono_tz::Europe::Berlin);
+        // Berlin springs forward from 02:00 CET to 03:00 CEST, so 02:30 doesn't exist.
+        let packed = encode_measurement(&datetime(2024, 3, 31, 2, 30, 0), 0, 0);
+
+        let decode = |nonexistent_time| {
+            let config = Config {
+                nonexistent_time,
+                ..config(berlin)
+            };
+
+            decode_measurement(&config, PROTOCOL_VERSION, packed)
+                .map(|measurement| measurement.map(|measurement| measurement.time))
+        };
+
+        assert_eq!(decode(NonexistentTime::Skip), Ok(None));
+        assert_eq!(
+            decode(NonexistentTime::Error),
+            Err(DecodeError::NonexistentTime)
+        );
+    }
+
+    #[test]
+    fn decodes_named_time_zones_around_daylight_saving_time() {
+        let config = config(PicoTimezone::Named(chrono_tz::Europe::Berlin));
+
+        for (local, expected) in [
+            (datetime(2024, 3, 31, 1, 59, 59), "2024-03-31T00:59:59Z"),
+            (datetime(2024, 3, 31, 3, 0, 0), "2024-03-31T01:00:00Z"),
+            (datetime(2024, 10, 27, 1, 59, 59), "2024-10-26T23:59:59Z"),
+            (datetime(2024, 10, 27, 3, 0, 0), "2024-10-27T02:00:00Z"),
+        ] {
+            let measurement =
+                decode_measurement(&config, PROTOCOL_VERSION, encode_measurement(&local, 0, 0))
+                    .unwrap()
+                    .unwrap();
+
+            assert_eq!(measurement.time, utc(expected), "{local}");
+        }
+    }
+
+    /// Unpacks the fields of a packed date time as second, minute, hour, weekday, day - 1,
+    /// month - 1 and year.
+    fn unpack_fields(packed: [u8; 6]) -> [u64; 7] {
+        let mut bytes = [0; 8];
+        bytes[..6].copy_from_slice(&packed);
+        let packed = u64::from_le_bytes(bytes);
+
+        [
+            packed & 0b111111,
+            packed >> 6 & 0b111111,
+            packed >> 12 & 0b11111,
+            packed >> 17 & 0b111,
+            packed >> 20 & 0b11111,
+            packed >> 25 & 0b1111,
+            packed >> 29,
+        ]
+    }
+
+    #[test]
+    fn packs_last_second_of_year() {
+        // December 31st, 2023 is a Sunday.
+        assert_eq!(
+            unpack_fields(pack_datetime(&datetime(2023, 12, 31, 23, 59, 59))),
+            [59, 59, 23, 0, 30, 11, 2023]
+        );
+    }
+
+    #[test]
+    fn packs_first_second_of_year() {
+        // January 1st, 2025 is a Wednesday.
+        assert_eq!(
+            unpack_fields(pack_datetime(&datetime(2025, 1, 1, 0, 0, 0))),
+            [0, 0, 0, 3, 0, 0, 2025]
+        );
+    }
+
+    #[test]
+    fn packs_the_milliseconds_of_the_last_instant_of_year() {
+        let time = datetime(2023, 12, 31, 23, 59, 59) + chrono::Duration::milliseconds(999);
+        let packed = pack_precise_datetime(&time);
+
+        assert_eq!(
+            unpack_fields(packed[..6].try_into().unwrap()),
+            [59, 59, 23, 0, 30, 11, 2023]
+        );
+        assert_eq!(packed[6..], 999u16.to_le_bytes());
+        assert_eq!(unpack_precise_datetime(packed), Some(time));
+    }
+
+    #[test]
+    fn packs_the_milliseconds_of_the_first_instant_of_year() {
+        let time = datetime(2025, 1, 1, 0, 0, 0) + chrono::Duration::microseconds(999);
+        let packed = pack_precise_datetime(&time);
+
+        // Truncated to whole milliseconds.
+        assert_eq!(packed[..6], pack_datetime(&time));
+        assert_eq!(packed[6..], [0, 0]);
+        assert_eq!(
+            unpack_precise_datetime(packed),
+            Some(datetime(2025, 1, 1, 0, 0, 0))
+        );
+    }
+
+    #[test]
+    fn rejects_more_than_999_milliseconds() {
+        let leap_second = NaiveDate::from_ymd_opt(2016, 12, 31)
+            .unwrap()
+            .and_hms_milli_opt(23, 59, 59, 1500)
+            .unwrap();
+        let mut packed = pack_precise_datetime(&leap_second);
+
+        assert_eq!(packed[6..], 999u16.to_le_bytes());
+        packed[6..].copy_from_slice(&1000u16.to_le_bytes());
+        assert_eq!(unpack_precise_datetime(packed), None);
+    }
+
+    #[test]
+    fn unpacks_packed_date_times() {
+        let time = datetime(2024, 2, 29, 13, 37, 42);
+
+        assert_eq!(unpack_datetime(pack_datetime(&time)), Some(time));
+    }
+
+    /// The fields [`unpack_fields`] should find in the date time `time` packs to.
+    fn fields(time: &NaiveDateTime) -> [u64; 7] {
+        [
+            time.second().into(),
+            time.minute().into(),
+            time.hour().into(),
+            time.weekday().num_days_from_sunday().into(),
+            time.day0().into(),
+            time.month0().into(),
+            time.year() as u64,
+        ]
+    }
+
+    #[test]
+    fn packs_every_minute_and_day_boundary() {
+        let mut time = datetime(2024, 1, 1, 0, 0, 0);
+
+        // Every second of a day, catching the fields spanning two bytes.
+        for _ in 0..24 * 60 * 60 {
+            assert_eq!(unpack_fields(pack_datetime(&time)), fields(&time), "{time}");
+            time += chrono::Duration::seconds(1);
+        }
+
+        // The first and last second of every day up to 2030.
+        let mut day = NaiveDate::from_ymd_opt(2024, 1, 1).unwrap();
+        while day.year() <= 2030 {
+            for time in [day.and_hms_opt(0, 0, 0), day.and_hms_opt(23, 59, 59)] {
+                let time = time.unwrap();
+
+                assert_eq!(unpack_fields(pack_datetime(&time)), fields(&time), "{time}");
+                assert_eq!(unpack_datetime(pack_datetime(&time)), Some(time));
+            }
+            day = day.succ_opt().unwrap();
+        }
+    }
+
+    #[test]
+    fn packs_the_years_beyond_eleven_bits() {
+        for year in [2047, 2048, 4095, 9999] {
+            let time = datetime(year, 6, 1, 12, 0, 0);
+
+            assert_eq!(unpack_fields(pack_datetime(&time))[6], year as u64);
+            assert_eq!(unpack_datetime(pack_datetime(&time)), Some(time));
+        }
+    }
+
+    #[test]
+    fn decodes_measurements_of_every_day_boundary() {
+        let mut day = NaiveDate::from_ymd_opt(2024, 1, 1).unwrap();
+
+        while day.year() <= 2030 {
+            for time in [day.and_hms_opt(0, 0, 0), day.and_hms_opt(23, 59, 59)] {
+                let time = time.unwrap();
+                let measurement = decode_utc(encode_measurement(&time, 215, 480))
+                    .unwrap()
+                    .unwrap();
+
+                assert_eq!(measurement.time.naive_utc(), time);
+            }
+            day = day.succ_opt().unwrap();
+        }
+    }
+
+    #[test]
+    fn encodes_what_it_decodes() {
+        let time = datetime(2024, 7, 14, 6, 5, 4);
+
+        for (temp, humidity) in [(215, 480), (-256, 1023), (-1, 0)] {
+            let measurement = decode_utc(encode_measurement(&time, temp, humidity))
+                .unwrap()
+                .unwrap();
+
+            assert_eq!(measurement.time, utc("2024-07-14T06:05:04Z"));
+            assert_eq!(
+                (measurement.temp, measurement.humidity),
+                (
+                    Decimal::new(temp.into(), 1),
+                    Decimal::new(humidity.into(), 1)
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn decodes_with_configured_scales() {
+        let config = Config {
+            temp_scale: NonZeroU32::new(100).unwrap(),
+            humidity_scale: NonZeroU32::new(1).unwrap(),
+            ..config(PicoTimezone::Utc)
+        };
+        let packed = encode_measurement(&datetime(2024, 6, 1, 12, 0, 0), -205, 48);
+
+        let measurement = decode_measurement(&config, PROTOCOL_VERSION, packed)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(measurement.temp.to_string(), "-2.05");
+        assert_eq!(measurement.humidity.to_string(), "48");
+    }
+
+    #[test]
+    fn rounds_scaled_values_half_away_from_zero() {
+        let eight = NonZeroU32::new(8).unwrap();
+
+        assert_eq!(scale(1, eight).to_string(), "0.1");
+        assert_eq!(scale(3, eight).to_string(), "0.4");
+        assert_eq!(scale(-3, eight).to_string(), "-0.4");
+        assert_eq!(scale(200, NonZeroU32::new(10).unwrap()).to_string(), "20.0");
+    }
+
+    #[test]
+    fn annotates_the_bit_fields() {
+        let packed = encode_measurement(&datetime(2024, 6, 1, 12, 0, 5), -1, 480);
+
+        assert_eq!(
+            BitFields(packed).to_string(),
+            "unused=000 (0) humidity=0111100000 (480) temp=111111111 (511) \
+             year=0000011111101000 (2024) month0=0101 (5) day0=00000 (0) hour=01100 (12) \
+             minute=000000 (0) second=000101 (5)"
+        );
+    }
+}
@@ -0,0 +1,240 @@
+//! Recording every byte exchanged with the Picos into a file, for `--trace-protocol`.
+//!
+//! The trace holds nothing but those bytes, the station ids and the addresses of the Picos, so it
+//! can be attached to an issue; in particular, no credentials from the config end up in it.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Mutex,
+    task::{ready, Context, Poll},
+};
+
+use chrono::Utc;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+use tracing::{error, warn};
+
+/// How many bytes are written as hex per line of the trace.
+const BYTES_PER_LINE: usize = 16;
+
+/// The trace being written, if `--trace-protocol` is given.
+static TRACE: Mutex<Option<Trace>> = Mutex::new(None);
+
+struct Trace {
+    path: PathBuf,
+    file: File,
+    /// How many bytes were written to the current file.
+    written: u64,
+    max_bytes: u64,
+}
+
+/// Which way bytes went between the host and a Pico.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+impl Direction {
+    fn marker(self) -> &'static str {
+        match self {
+            Direction::Sent => ">",
+            Direction::Received => "<",
+        }
+    }
+}
+
+/// Starts tracing into the file at `path`, appending to it. Once it would grow beyond `max_bytes`,
+/// it's moved to the path with `.1` appended, replacing the previous one, and a new file started.
+pub fn enable(path: &Path, max_bytes: u64) -> io::Result<()> {
+    let file = open(path)?;
+    let written = file.metadata()?.len();
+
+    *TRACE.lock().unwrap() = Some(Trace {
+        path: path.to_owned(),
+        file,
+        written,
+        max_bytes,
+    });
+
+    Ok(())
+}
+
+/// Whether `--trace-protocol` is given.
+pub fn enabled() -> bool {
+    TRACE.lock().unwrap().is_some()
+}
+
+fn open(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Records that `bytes` went `direction` between the host and the Pico of `station_id`.
+pub fn record(station_id: i32, direction: Direction, bytes: &[u8]) {
+    if bytes.is_empty() {
+        return;
+    }
+
+    write(&format_bytes(station_id, direction, bytes));
+}
+
+/// Records something happening to the connection to the Pico of `station_id`, like it being
+/// opened or closed.
+pub fn note(station_id: i32, event: &str) {
+    write(&format!("{} station {station_id} * {event}\n", timestamp()));
+}
+
+fn timestamp() -> String {
+    Utc::now().format("%Y-%m-%dT%H:%M:%S%.6fZ").to_string()
+}
+
+/// The lines recording `bytes`, as hex with [`BYTES_PER_LINE`] bytes per line, each starting with
+/// the time, the station and `>` for bytes sent to the Pico or `<` for ones received from it.
+fn format_bytes(station_id: i32, direction: Direction, bytes: &[u8]) -> String {
+    let timestamp = timestamp();
+    let mut lines = String::new();
+
+    for line in bytes.chunks(BYTES_PER_LINE) {
+        lines.push_str(&format!(
+            "{timestamp} station {station_id} {}",
+            direction.marker()
+        ));
+
+        for byte in line {
+            lines.push_str(&format!(" {byte:02x}"));
+        }
+
+        lines.push('\n');
+    }
+
+    lines
+}
+
+fn write(lines: &str) {
+    let mut guard = TRACE.lock().unwrap();
+    let Some(trace) = guard.as_mut() else {
+        return;
+    };
+
+    if let Err(err) = trace.write(lines) {
+        error!(
+            "Error writing the protocol trace {}, no longer tracing: {err}",
+            trace.path.display()
+        );
+        *guard = None;
+    }
+}
+
+impl Trace {
+    fn write(&mut self, lines: &str) -> io::Result<()> {
+        if self.written > 0 && self.written + lines.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+
+        self.file.write_all(lines.as_bytes())?;
+        self.written += lines.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(".1");
+        std::fs::rename(&self.path, rotated)?;
+
+        self.file = open(&self.path)?;
+        self.written = 0;
+        Ok(())
+    }
+}
+
+/// The connection to the Pico of a station, recording everything exchanged over it if tracing.
+pub struct TracedStream {
+    inner: TcpStream,
+    station_id: i32,
+}
+
+impl TracedStream {
+    pub fn new(inner: TcpStream, station_id: i32) -> TracedStream {
+        match inner.peer_addr() {
+            Ok(peer) => note(station_id, &format!("session with {peer}")),
+            Err(err) => warn!("the address of the Pico is unknown: {err}"),
+        }
+
+        TracedStream { inner, station_id }
+    }
+}
+
+impl AsyncRead for TracedStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+
+        if buf.filled().len() == before {
+            note(self.station_id, "closed by the Pico");
+        } else {
+            record(
+                self.station_id,
+                Direction::Received,
+                &buf.filled()[before..],
+            );
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for TracedStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let written = ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        record(self.station_id, Direction::Sent, &buf[..written]);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(Pin::new(&mut self.inner).poll_shutdown(cx))?;
+        note(self.station_id, "closed by the host");
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn marks_the_direction_of_every_line() {
+        let bytes: Vec<u8> = (0..20).collect();
+        let lines = format_bytes(7, Direction::Received, &bytes);
+        let lines: Vec<_> = lines.lines().collect();
+
+        assert_eq!(lines.len(), 2);
+        assert!(
+            lines[0].ends_with(" station 7 < 00 01 02 03 04 05 06 07 08 09 0a 0b 0c 0d 0e 0f"),
+            "{}",
+            lines[0]
+        );
+        assert!(
+            lines[1].ends_with(" station 7 < 10 11 12 13"),
+            "{}",
+            lines[1]
+        );
+        assert!(format_bytes(7, Direction::Sent, &[0xff]).ends_with(" station 7 > ff\n"));
+    }
+}
@@ -0,0 +1,264 @@
+//! Deleting the measurements older than a cutoff, the `prune` subcommand and `retention` when
+//! polling.
+//!
+//! They're deleted in batches with a pause after every one, so a large backlog doesn't lock the
+//! table for long.
+
+use std::{path::Path, time::Duration};
+
+use anyhow::anyhow;
+use chrono::{DateTime, SubsecRound, Utc};
+use tracing::info;
+
+use crate::{
+    config::{Config, Storage},
+    cycle::SUMMARY_TARGET,
+    failure::{Categorize, Failure},
+    shutdown::SHUTDOWN,
+    sink::{connect_postgres_client, QuotedTable},
+};
+
+/// How many measurements every statement deletes at most.
+const PRUNE_BATCH: u64 = 50_000;
+
+/// How long to wait after every batch, letting the inserts and queries waiting for it through.
+const BATCH_PAUSE: Duration = Duration::from_millis(100);
+
+/// The measurements taken before `cutoff` by the station with `station_id`, or by every one without.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PruneFilter {
+    pub cutoff: DateTime<Utc>,
+    pub station_id: Option<i32>,
+}
+
+impl PruneFilter {
+    /// The measurements older than `keep`.
+    pub fn new(keep: chrono::Duration, station_id: Option<i32>) -> PruneFilter {
+        PruneFilter {
+            cutoff: (Utc::now() - keep).trunc_subsecs(0),
+            station_id,
+        }
+    }
+}
+
+/// Deletes the measurements matching `filter`, or with `dry_run` only counts them. Returns how many
+/// were (or would be) deleted. Stops after the running batch if the program is asked to shut down.
+pub async fn prune_measurements(
+    config: &Config,
+    filter: &PruneFilter,
+    dry_run: bool,
+) -> anyhow::Result<u64> {
+    match &config.storage {
+        Storage::Postgres => prune_postgres(config, filter, dry_run)
+            .await
+            .category(Failure::Database),
+        Storage::Sqlite { path } => prune_sqlite(config, path, filter, dry_run)
+            .await
+            .category(Failure::Database),
+        Storage::Influxdb { .. } => Err(Failure::Config.tag(anyhow!(
+            "prune isn't supported with InfluxDB storage, set the retention period of the bucket instead"
+        ))),
+    }
+}
+
+/// The condition matching the measurements of the configured table older than the parameter
+/// `cutoff` of the station given by the parameter `station_id`, or all of them if null.
+fn older_than(config: &Config, cutoff: &str, station_id: &str) -> String {
+    let table = QuotedTable::new(&config.table);
+
+    format!(
+        "{} < {cutoff} and ({station_id} is null or {} = {station_id})",
+        table.at, table.station_id
+    )
+}
+
+fn count_query(config: &Config, cutoff: &str, station_id: &str) -> String {
+    format!(
+        "select count(*) from {} where {}",
+        QuotedTable::new(&config.table).name,
+        older_than(config, cutoff, station_id)
+    )
+}
+
+/// Deletes up to [`PRUNE_BATCH`] of the matching measurements by their station and time, which the
+/// unique index covers.
+fn delete_query(config: &Config, cutoff: &str, station_id: &str) -> String {
+    let QuotedTable {
+        name,
+        at,
+        station_id: station,
+        ..
+    } = QuotedTable::new(&config.table);
+
+    format!(
+        "delete from {name} where ({station}, {at}) in (
+            select {station}, {at} from {name} where {} limit {PRUNE_BATCH}
+        )",
+        older_than(config, cutoff, station_id)
+    )
+}
+
+/// Runs `delete_batch` until it deletes nothing or shutting down is requested, logging every
+/// batch.
+async fn delete_in_batches(
+    filter: &PruneFilter,
+    mut delete_batch: impl AsyncFnMut() -> anyhow::Result<u64>,
+) -> anyhow::Result<u64> {
+    let mut total = 0;
+
+    loop {
+        let deleted = delete_batch().await?;
+
+        if deleted == 0 {
+            return Ok(total);
+        }
+
+        total += deleted;
+        info!(
+            target: SUMMARY_TARGET,
+            "deleted {deleted} measurements older than {}, {total} so far",
+            filter.cutoff.to_rfc3339()
+        );
+
+        if SHUTDOWN.is_requested() {
+            return Ok(total);
+        }
+
+        tokio::time::sleep(BATCH_PAUSE).await;
+    }
+}
+
+async fn prune_postgres(
+    config: &Config,
+    filter: &PruneFilter,
+    dry_run: bool,
+) -> anyhow::Result<u64> {
+    let client = connect_postgres_client(config).await?;
+    let params: [&(dyn tokio_postgres::types::ToSql + Sync); 2] =
+        [&filter.cutoff, &filter.station_id];
+
+    if dry_run {
+        let count: i64 = client
+            .query_one(&count_query(config, "$1::timestamptz", "$2::int4"), &params)
+            .await
+            .map_err(|err| anyhow!("Error counting the measurements to delete: {err}"))?
+            .get(0);
+
+        return Ok(count as u64);
+    }
+
+    let statement = client
+        .prepare(&delete_query(config, "$1::timestamptz", "$2::int4"))
+        .await
+        .map_err(|err| anyhow!("Error preparing the deletion of the measurements: {err}"))?;
+
+    delete_in_batches(filter, async || {
+        client
+            .execute(&statement, &params)
+            .await
+            .map_err(|err| anyhow!("Error deleting the measurements: {err}"))
+    })
+    .await
+}
+
+async fn prune_sqlite(
+    config: &Config,
+    path: &Path,
+    filter: &PruneFilter,
+    dry_run: bool,
+) -> anyhow::Result<u64> {
+    let connection = rusqlite::Connection::open(path)
+        .map_err(|err| anyhow!("Error opening SQLite database {}: {err}", path.display()))?;
+    let params = rusqlite::params![filter.cutoff, filter.station_id];
+
+    if dry_run {
+        let count: i64 = connection
+            .query_row(&count_query(config, "?1", "?2"), params, |row| row.get(0))
+            .map_err(|err| anyhow!("Error counting the measurements to delete: {err}"))?;
+
+        return Ok(count as u64);
+    }
+
+    let mut statement = connection
+        .prepare(&delete_query(config, "?1", "?2"))
+        .map_err(|err| anyhow!("Error preparing the deletion of the measurements: {err}"))?;
+
+    delete_in_batches(filter, async || {
+        statement
+            .execute(params)
+            .map(|deleted| deleted as u64)
+            .map_err(|err| anyhow!("Error deleting the measurements: {err}"))
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use rust_decimal::Decimal;
+
+    use super::*;
+    use crate::{
+        sink::{connect_database, insert_measurements},
+        Measurement,
+    };
+
+    #[tokio::test]
+    async fn deletes_only_the_older_measurements_of_the_station() {
+        let dir = std::env::temp_dir().join("pico_humidity_temp_read_prune");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut config = Config {
+            storage: Storage::Sqlite {
+                path: dir.join("measurements.db"),
+            },
+            ..Config::default()
+        };
+        let mut other = config.stations[0].clone();
+        other.station_id = 1;
+        config.stations.push(other);
+
+        let now = Utc::now().fixed_offset();
+        let measurements: Vec<_> = [400, 10, 2]
+            .into_iter()
+            .map(|days| Measurement {
+                time: now - chrono::Duration::days(days),
+                temp: Decimal::new(207, 1),
+                humidity: Decimal::new(520, 1),
+                uncalibrated: None,
+                out_of_range: false,
+                dew_point: None,
+                perceived_temp: None,
+                absolute_humidity: None,
+                packed: None,
+            })
+            .collect();
+
+        let mut db = connect_database(&config).await.unwrap();
+        for station in &config.stations {
+            insert_measurements(&config, &mut db, station, &measurements, false)
+                .await
+                .unwrap();
+        }
+
+        let filter = PruneFilter::new(chrono::Duration::days(7), Some(0));
+        assert_eq!(prune_measurements(&config, &filter, true).await.unwrap(), 2);
+        assert_eq!(
+            prune_measurements(&config, &filter, false).await.unwrap(),
+            2
+        );
+        assert_eq!(prune_measurements(&config, &filter, true).await.unwrap(), 0);
+
+        // The other station keeps its measurements, unless every station is pruned.
+        let filter = PruneFilter::new(chrono::Duration::days(7), None);
+        assert_eq!(prune_measurements(&config, &filter, true).await.unwrap(), 2);
+        assert_eq!(
+            db.latest_time(&config, &config.stations[0]).await.unwrap(),
+            Some(measurements[2].time.to_utc())
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
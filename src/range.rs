@@ -0,0 +1,308 @@
+//! Handling of implausible measurements, like the field maximums reported by a glitching sensor
+//! or the dates of a Pico whose clock was reset.
+
+use std::sync::atomic::Ordering;
+
+use chrono::{DateTime, Duration, FixedOffset, Utc};
+use rust_decimal::Decimal;
+use tracing::{info, warn};
+
+use crate::{
+    config::{Config, OutOfRange},
+    metrics::METRICS,
+    Measurement,
+};
+
+/// Checks `measurements` against the plausible ranges of the config, skipping, clamping or
+/// flagging the ones outside of them as configured, and adds how many were to `out_of_range`.
+pub fn apply_ranges(
+    config: &Config,
+    measurements: Vec<Measurement>,
+    out_of_range: &mut u64,
+) -> Vec<Measurement> {
+    measurements
+        .into_iter()
+        .filter_map(|mut measurement| {
+            let temp = outside(measurement.temp, config.temp_min, config.temp_max);
+            let humidity = outside(
+                measurement.humidity,
+                config.humidity_min,
+                config.humidity_max,
+            );
+
+            if temp.is_none() && humidity.is_none() {
+                return Some(measurement);
+            }
+
+            *out_of_range += 1;
+
+            match config.out_of_range {
+                OutOfRange::Skip => {
+                    warn!(
+                        time = %measurement.time.to_rfc3339(),
+                        "skipping the measurement at {} with {}",
+                        measurement.time.to_rfc3339(),
+                        describe(&measurement, temp, humidity)
+                    );
+                    return None;
+                }
+                OutOfRange::Clamp => {
+                    if let Some(bound) = temp {
+                        measurement.temp = clamped(measurement.temp, bound);
+                    }
+                    if let Some(bound) = humidity {
+                        measurement.humidity = clamped(measurement.humidity, bound);
+                    }
+                }
+                OutOfRange::InsertWithFlag => measurement.out_of_range = true,
+            }
+
+            Some(measurement)
+        })
+        .collect()
+}
+
+/// A measurement skipped since its time can't be right.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct ImplausibleTime {
+    pub time: DateTime<FixedOffset>,
+    /// Why the time can't be right.
+    pub reason: String,
+}
+
+/// Skips the `measurements` taken before `min_timestamp` or more than `max_future_secs` after
+/// `now`, adding them to `implausible`, and warns about the others going back in time by more than
+/// `sample_interval_secs` since the `previous` one, which is updated.
+pub fn check_times(
+    config: &Config,
+    now: DateTime<Utc>,
+    measurements: Vec<Measurement>,
+    previous: &mut Option<DateTime<FixedOffset>>,
+    implausible: &mut Vec<ImplausibleTime>,
+) -> Vec<Measurement> {
+    let latest_plausible = now + Duration::seconds(config.max_future_secs as i64);
+    let interval = Duration::seconds(config.sample_interval_secs.unwrap_or(0) as i64);
+
+    measurements
+        .into_iter()
+        .filter(|measurement| {
+            let time = measurement.time;
+            let reason = match config.min_timestamp {
+                Some(min) if time.date_naive() < min => format!("before min_timestamp {min}"),
+                _ if time > latest_plausible => format!(
+                    "more than max_future_secs ({}) after the host's clock",
+                    config.max_future_secs
+                ),
+                _ => {
+                    if let Some(previous) = previous.replace(time) {
+                        if time < previous - interval {
+                            warn!(
+                                "the measurement at {} follows one at {}, the Pico's clock may have \
+                                 been reset",
+                                time.to_rfc3339(),
+                                previous.to_rfc3339()
+                            );
+                        }
+                    }
+
+                    return true;
+                }
+            };
+
+            warn!(
+                time = %time.to_rfc3339(),
+                "skipping the measurement at {}, {reason}",
+                time.to_rfc3339()
+            );
+            implausible.push(ImplausibleTime { time, reason });
+            false
+        })
+        .collect()
+}
+
+/// Logs and counts the `out_of_range` measurements found by [`apply_ranges`] in a download.
+pub fn report_out_of_range(config: &Config, out_of_range: u64) {
+    if out_of_range > 0 {
+        METRICS
+            .measurements_out_of_range
+            .fetch_add(out_of_range, Ordering::Relaxed);
+
+        match config.out_of_range {
+            OutOfRange::Skip => info!("skipped {out_of_range} measurements out of range"),
+            OutOfRange::Clamp => info!("clamped {out_of_range} measurements out of range"),
+            OutOfRange::InsertWithFlag => {
+                info!("flagged {out_of_range} measurements out of range")
+            }
+        }
+    }
+}
+
+/// Returns the bound `value` violates, if it's outside of `min..=max`.
+fn outside(value: Decimal, min: Option<Decimal>, max: Option<Decimal>) -> Option<Decimal> {
+    match (min, max) {
+        (Some(min), _) if value < min => Some(min),
+        (_, Some(max)) if value > max => Some(max),
+        _ => None,
+    }
+}
+
+/// Returns `bound` with at least as many decimal places as `value`, so clamping doesn't change
+/// how the values are rendered.
+fn clamped(value: Decimal, mut bound: Decimal) -> Decimal {
+    if bound.scale() < value.scale() {
+        bound.rescale(value.scale());
+    }
+    bound
+}
+
+/// Describes the values of `measurement` violating the bounds `temp` and `humidity`.
+fn describe(measurement: &Measurement, temp: Option<Decimal>, humidity: Option<Decimal>) -> String {
+    let temp =
+        temp.map(|bound| format!("a temperature of {} °C (bound {bound})", measurement.temp));
+    let humidity =
+        humidity.map(|bound| format!("a humidity of {} % (bound {bound})", measurement.humidity));
+
+    temp.into_iter()
+        .chain(humidity)
+        .collect::<Vec<_>>()
+        .join(" and ")
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::DateTime;
+
+    use super::*;
+
+    fn measurement(temp: i64, humidity: i64) -> Measurement {
+        Measurement {
+            time: DateTime::parse_from_rfc3339("2024-06-01T12:00:00Z").unwrap(),
+            temp: Decimal::new(temp, 1),
+            humidity: Decimal::new(humidity, 1),
+            uncalibrated: None,
+            out_of_range: false,
+            dew_point: None,
+            perceived_temp: None,
+            absolute_humidity: None,
+            packed: None,
+        }
+    }
+
+    fn measurement_at(time: &str) -> Measurement {
+        Measurement {
+            time: DateTime::parse_from_rfc3339(time).unwrap(),
+            ..measurement(215, 480)
+        }
+    }
+
+    #[test]
+    fn skips_measurements_at_implausible_times() {
+        let config = Config {
+            min_timestamp: Some(chrono::NaiveDate::from_ymd_opt(2023, 1, 1).unwrap()),
+            ..Config::default()
+        };
+        let now = "2024-06-01T12:00:00Z".parse().unwrap();
+        let measurements = vec![
+            measurement_at("2021-01-01T00:00:00Z"),
+            measurement_at("2024-06-01T11:59:00Z"),
+            measurement_at("2024-06-01T12:59:00Z"),
+            measurement_at("2024-06-01T13:01:00Z"),
+        ];
+        let mut implausible = Vec::new();
+
+        let plausible = check_times(&config, now, measurements, &mut None, &mut implausible);
+
+        assert_eq!(
+            plausible,
+            [
+                measurement_at("2024-06-01T11:59:00Z"),
+                measurement_at("2024-06-01T12:59:00Z")
+            ]
+        );
+        assert_eq!(
+            implausible,
+            [
+                ImplausibleTime {
+                    time: "2021-01-01T00:00:00Z".parse().unwrap(),
+                    reason: "before min_timestamp 2023-01-01".to_string(),
+                },
+                ImplausibleTime {
+                    time: "2024-06-01T13:01:00Z".parse().unwrap(),
+                    reason: "more than max_future_secs (3600) after the host's clock".to_string(),
+                },
+            ]
+        );
+    }
+
+    fn config(out_of_range: OutOfRange) -> Config {
+        Config {
+            temp_min: Some(Decimal::new(-400, 1)),
+            temp_max: Some(Decimal::new(500, 1)),
+            humidity_min: Some(Decimal::ZERO),
+            humidity_max: Some(Decimal::ONE_HUNDRED),
+            out_of_range,
+            ..Config::default()
+        }
+    }
+
+    fn ranged(config: &Config) -> Vec<Measurement> {
+        apply_ranges(config, glitches(), &mut 0)
+    }
+
+    fn glitches() -> Vec<Measurement> {
+        vec![
+            measurement(215, 480),
+            measurement(511, 480),
+            measurement(215, 1023),
+        ]
+    }
+
+    #[test]
+    fn keeps_everything_without_ranges() {
+        assert_eq!(ranged(&Config::default()), glitches());
+    }
+
+    #[test]
+    fn skips_measurements_out_of_range() {
+        assert_eq!(ranged(&config(OutOfRange::Skip)), [measurement(215, 480)]);
+    }
+
+    #[test]
+    fn clamps_measurements_out_of_range() {
+        assert_eq!(
+            ranged(&config(OutOfRange::Clamp)),
+            [
+                measurement(215, 480),
+                measurement(500, 480),
+                measurement(215, 1000)
+            ]
+        );
+    }
+
+    #[test]
+    fn clamps_to_the_decimal_places_of_the_values() {
+        let clamped = ranged(&config(OutOfRange::Clamp));
+
+        assert_eq!(clamped[2].humidity.to_string(), "100.0");
+    }
+
+    #[test]
+    fn flags_measurements_out_of_range() {
+        let flags: Vec<_> = ranged(&config(OutOfRange::InsertWithFlag))
+            .iter()
+            .map(|measurement| measurement.out_of_range)
+            .collect();
+
+        assert_eq!(flags, [false, true, true]);
+    }
+
+    #[test]
+    fn counts_measurements_out_of_range() {
+        let mut out_of_range = 0;
+
+        apply_ranges(&config(OutOfRange::Skip), glitches(), &mut out_of_range);
+        apply_ranges(&config(OutOfRange::Clamp), glitches(), &mut out_of_range);
+
+        assert_eq!(out_of_range, 4);
+    }
+}
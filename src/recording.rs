@@ -0,0 +1,100 @@
+//! Recording the measurements a Pico sent with `--record`, to be replayed with `replay` later.
+//!
+//! A recording holds the count of measurements the Pico announced as a little-endian `u32`,
+//! followed by the packed measurements as received, like the Pico sends them after the protocol
+//! version.
+
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use tracing::error;
+
+use crate::config::Station;
+
+/// The recording of a session being written.
+pub struct Recording {
+    path: PathBuf,
+    file: Option<BufWriter<File>>,
+}
+
+impl Recording {
+    /// Starts recording the `count` announced measurements into the file at `path`, overwriting
+    /// it. Failing to write the recording is logged, but doesn't fail the download.
+    pub fn create(path: &Path, count: u32) -> Recording {
+        let mut recording = Recording {
+            path: path.to_owned(),
+            file: None,
+        };
+
+        match File::create(path) {
+            Ok(file) => {
+                recording.file = Some(BufWriter::new(file));
+                recording.write(&count.to_le_bytes());
+            }
+            Err(err) => recording.failed(err),
+        }
+
+        recording
+    }
+
+    /// Records the next packed measurement.
+    pub fn measurement(&mut self, packed: &[u8; 8]) {
+        self.write(packed);
+    }
+
+    /// Writes the rest of the recording to its file.
+    pub fn finish(mut self) {
+        if let Some(Err(err)) = self.file.as_mut().map(BufWriter::flush) {
+            self.failed(err);
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        if let Some(Err(err)) = self.file.as_mut().map(|file| file.write_all(bytes)) {
+            self.failed(err);
+        }
+    }
+
+    fn failed(&mut self, err: std::io::Error) {
+        error!(
+            "Error writing the recording {}, no longer recording: {err}",
+            self.path.display()
+        );
+        self.file = None;
+    }
+}
+
+/// The recording of `station` for `--record <path>`: `path` itself if it's the only one of the
+/// `station_count` stations, or else `path` with `.` and its station id appended.
+pub fn recording_path(path: &Path, station: &Station, station_count: usize) -> PathBuf {
+    if station_count == 1 {
+        return path.to_owned();
+    }
+
+    let mut path = path.as_os_str().to_owned();
+    path.push(format!(".{}", station.station_id));
+    path.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn tells_the_recordings_of_several_stations_apart() {
+        let station = &Config::default().stations[0];
+
+        assert_eq!(
+            recording_path(Path::new("session.bin"), station, 1),
+            Path::new("session.bin")
+        );
+        assert_eq!(
+            recording_path(Path::new("session.bin"), station, 2),
+            PathBuf::from(format!("session.bin.{}", station.station_id))
+        );
+    }
+}
@@ -0,0 +1,557 @@
+//! The machine readable summary of a run written with `--summary-json`, for alerting on it
+//! instead of scraping the log.
+//!
+//! The fields are only ever added to, so the documents stay parseable by older consumers.
+
+use std::{fmt, path::Path, time::Duration};
+
+use anyhow::anyhow;
+use chrono::{DateTime, FixedOffset, Utc};
+use rust_decimal::Decimal;
+use tracing::info;
+
+use crate::{
+    config::{Config, Station},
+    cycle::CycleSummary,
+    gaps::Gap,
+    pico::{clock_drift_warning, flash_warning, DownloadInfo},
+    range::ImplausibleTime,
+    serialize_decimal,
+    sink::InsertSummary,
+    verify::Verification,
+    Measurement,
+};
+
+/// The summary of a run (or cycle, when polling).
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct RunReport {
+    /// Whether every station was fetched and stored without any errors.
+    pub ok: bool,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    /// The errors which didn't occur at a single station, like failing to connect to the
+    /// database.
+    pub errors: Vec<String>,
+    /// How many warnings the stations have, like their Pico's flash filling up.
+    pub warnings: usize,
+    /// How long connecting to the database and preparing the statements took, unless the
+    /// connection was still open from the cycle before or it's a dry run.
+    #[serde(rename = "db_connect_secs", serialize_with = "serialize_secs")]
+    pub db_connect: Option<Duration>,
+    pub stations: Vec<StationReport>,
+}
+
+impl RunReport {
+    /// The report of a cycle which started at `start` and just ended with `result`.
+    pub fn new(start: DateTime<Utc>, result: &anyhow::Result<CycleSummary>) -> RunReport {
+        let (errors, stations, db_connect) = match result {
+            Ok(summary) => (
+                summary.errors.clone(),
+                summary.reports.clone(),
+                summary.db_connect,
+            ),
+            Err(err) => (vec![err.to_string()], Vec::new(), None),
+        };
+
+        RunReport {
+            ok: errors.is_empty() && stations.iter().all(|station| station.errors.is_empty()),
+            start,
+            end: Utc::now(),
+            errors,
+            warnings: stations.iter().map(|station| station.warnings.len()).sum(),
+            db_connect,
+            stations,
+        }
+    }
+
+    /// Writes the report as JSON to `path`, or stdout if it's `-`.
+    pub fn write(&self, path: &Path) -> anyhow::Result<()> {
+        let json = serde_json::to_string_pretty(self)
+            .map_err(|err| anyhow!("Error serializing the run summary: {err}"))?;
+
+        if path == Path::new("-") {
+            println!("{json}");
+            return Ok(());
+        }
+
+        std::fs::write(path, json + "\n")
+            .map_err(|err| anyhow!("Error writing the run summary to {}: {err}", path.display()))
+    }
+}
+
+/// What happened at a station during a run.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct StationReport {
+    pub pico: String,
+    pub pico_port: u16,
+    pub station_id: i32,
+    /// How many measurements the Pico announced, unless it didn't get that far.
+    pub announced: Option<u32>,
+    /// How much of the Pico's flash the announced measurements fill, in percent.
+    pub flash_usage_percent: Option<f64>,
+    /// How many measurements were decoded and passed the plausible ranges.
+    pub decoded: usize,
+    /// How many measurements were inserted, unless in a dry run.
+    pub inserted: Option<u64>,
+    /// How many measurements were already present or older than the stored ones, unless in a
+    /// dry run.
+    pub skipped: Option<u64>,
+    /// How many measurements were spooled to be inserted later, unless in a dry run.
+    pub spooled: Option<usize>,
+    /// How many measurements were buffered to be inserted later, unless in a dry run.
+    pub buffered: Option<usize>,
+    pub errors: Vec<String>,
+    pub warnings: Vec<String>,
+    /// The time of the latest measurement decoded.
+    pub latest_time: Option<DateTime<FixedOffset>>,
+    /// How many seconds the latest measurement was ahead of the host's clock (negative if behind)
+    /// once decoded.
+    pub clock_drift_secs: Option<i64>,
+    /// Why the clock of the Pico wasn't set, if the host's looked wrong.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clock_not_set: Option<String>,
+    pub temp: Option<Extremes>,
+    pub humidity: Option<Extremes>,
+    /// The gaps between the decoded measurements, if `sample_interval_secs` is set.
+    pub gaps: Vec<Gap>,
+    /// The measurements skipped since their times can't be right.
+    pub implausible_times: Vec<ImplausibleTime>,
+    /// How many measurements were removed since the Pico sent one at the same time before.
+    pub in_batch_duplicates: u64,
+    /// How the decoded measurements compare to the stored ones, only with `verify`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verification: Option<Verification>,
+    /// How long the phases of the station took, as far as it got.
+    pub timings: PhaseTimings,
+}
+
+impl StationReport {
+    pub fn new(station: &Station) -> StationReport {
+        StationReport {
+            pico: station.pico.clone(),
+            pico_port: station.pico_port,
+            station_id: station.station_id,
+            announced: None,
+            flash_usage_percent: None,
+            decoded: 0,
+            inserted: None,
+            skipped: None,
+            spooled: None,
+            buffered: None,
+            errors: Vec::new(),
+            warnings: Vec::new(),
+            latest_time: None,
+            clock_drift_secs: None,
+            clock_not_set: None,
+            temp: None,
+            humidity: None,
+            gaps: Vec::new(),
+            implausible_times: Vec::new(),
+            in_batch_duplicates: 0,
+            verification: None,
+            timings: PhaseTimings::default(),
+        }
+    }
+
+    /// Takes what became known about the download from the Pico at `station` into account.
+    pub fn downloaded(&mut self, config: &Config, station: &Station, download: DownloadInfo) {
+        if let Some(count) = download.announced {
+            self.announced(config, station, count);
+        }
+
+        if let Some(reason) = download.clock_not_set {
+            self.warnings
+                .push(format!("didn't set the clock of the Pico, {reason}"));
+            self.clock_not_set = Some(reason);
+        }
+
+        if !download.implausible.is_empty() {
+            self.warnings.push(format!(
+                "skipped {} measurements with implausible times",
+                download.implausible.len()
+            ));
+            self.implausible_times = download.implausible;
+        }
+
+        if download.duplicates > 0 {
+            info!(
+                station_id = station.station_id,
+                pico = %station.pico,
+                pico_port = station.pico_port,
+                duplicates = download.duplicates,
+                "removed {} measurements sent twice in the download",
+                download.duplicates
+            );
+            self.in_batch_duplicates = download.duplicates;
+        }
+
+        self.timings.connect = download.connect;
+        self.timings.time_sync = download.time_sync;
+        self.timings.download = download.download;
+        self.timings.download_bytes = download.received;
+        self.timings.download_bytes_per_sec = download
+            .received
+            .zip(download.download)
+            .and_then(|(received, download)| per_sec(received as f64, download));
+        self.timings.decode = download.decode;
+    }
+
+    /// Takes the `count` of measurements the Pico at `station` announced into account.
+    pub fn announced(&mut self, config: &Config, station: &Station, count: u32) {
+        self.announced = Some(count);
+        self.flash_usage_percent = Some((station.flash_usage(count) * 10.0).round() / 10.0);
+        self.warnings.extend(flash_warning(config, station, count));
+    }
+
+    /// Takes the decoded `measurements` into account.
+    pub fn observe(&mut self, measurements: &[Measurement]) {
+        self.decoded += measurements.len();
+
+        for measurement in measurements {
+            let latest = self
+                .latest_time
+                .is_none_or(|latest_time| measurement.time >= latest_time);
+
+            if latest {
+                self.latest_time = Some(measurement.time);
+            }
+
+            Extremes::update(&mut self.temp, measurement.temp, latest);
+            Extremes::update(&mut self.humidity, measurement.humidity, latest);
+        }
+    }
+
+    /// Takes the time of the latest measurement relative to the host's clock at `now`, once all
+    /// are decoded, into account. Returns the warning if the Pico's clock seems to have drifted.
+    pub fn check_clock(&mut self, config: &Config, now: DateTime<Utc>) -> Option<String> {
+        let drift_secs = (self.latest_time? - now.fixed_offset()).num_seconds();
+        self.clock_drift_secs = Some(drift_secs);
+
+        let warning = clock_drift_warning(config, drift_secs)?;
+        self.warnings.push(warning.clone());
+        Some(warning)
+    }
+
+    /// Takes the insert of the `fetched` measurements into account.
+    pub fn stored(&mut self, fetched: usize, summary: &InsertSummary) {
+        let failed = summary.failures.len() as u64;
+
+        self.inserted = Some(summary.inserted);
+        self.skipped = Some(
+            fetched as u64
+                - summary.inserted
+                - failed
+                - summary.spooled as u64
+                - summary.buffered as u64,
+        );
+        self.spooled = Some(summary.spooled);
+        self.buffered = Some(summary.buffered);
+        self.timings.insert = Some(summary.elapsed);
+        self.timings.insert_rows = Some(summary.committed);
+        self.timings.insert_rows_per_sec = per_sec(summary.committed as f64, summary.elapsed);
+        self.errors.extend(summary.failures.iter().map(|failure| {
+            format!(
+                "inserting the measurement at {} failed: {}",
+                failure.time.to_rfc3339(),
+                failure.error
+            )
+        }));
+    }
+}
+
+/// How long the phases of fetching from and storing the measurements of a station took, measured
+/// with the monotonic clock. Each is `None` unless the station got through it.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+pub struct PhaseTimings {
+    /// Connecting to the Pico and authenticating it.
+    #[serde(rename = "connect_secs", serialize_with = "serialize_secs")]
+    pub connect: Option<Duration>,
+    /// Setting the clock of the Pico, until it announced its measurements.
+    #[serde(rename = "time_sync_secs", serialize_with = "serialize_secs")]
+    pub time_sync: Option<Duration>,
+    /// Receiving the measurements, neither decoding them nor waiting for the inserts.
+    #[serde(rename = "download_secs", serialize_with = "serialize_secs")]
+    pub download: Option<Duration>,
+    /// How many bytes the Pico sent in the session.
+    pub download_bytes: Option<u64>,
+    pub download_bytes_per_sec: Option<f64>,
+    /// Decoding the measurements, in between receiving them.
+    #[serde(rename = "decode_secs", serialize_with = "serialize_secs")]
+    pub decode: Option<Duration>,
+    /// Inserting the measurements, not waiting for the connection the stations share.
+    #[serde(rename = "insert_secs", serialize_with = "serialize_secs")]
+    pub insert: Option<Duration>,
+    /// How many measurements were sent to the database, new or not.
+    pub insert_rows: Option<usize>,
+    pub insert_rows_per_sec: Option<f64>,
+}
+
+impl fmt::Display for PhaseTimings {
+    /// The breakdown logged at the end of a cycle, like `connect 12.3ms, time sync 4.1ms,
+    /// download 1.2s (48005 bytes, 40004 B/s), decode 8.2ms, insert 310.5ms (6000 rows, 19324
+    /// rows/s)`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let download = self
+            .download_bytes
+            .zip(self.download_bytes_per_sec)
+            .map(|(bytes, rate)| format!("{bytes} bytes, {rate:.0} B/s"));
+        let insert = self
+            .insert_rows
+            .zip(self.insert_rows_per_sec)
+            .map(|(rows, rate)| format!("{rows} rows, {rate:.0} rows/s"));
+
+        let phases: Vec<_> = [
+            ("connect", self.connect, None),
+            ("time sync", self.time_sync, None),
+            ("download", self.download, download),
+            ("decode", self.decode, None),
+            ("insert", self.insert, insert),
+        ]
+        .into_iter()
+        .filter_map(|(phase, duration, detail)| {
+            let part = format!("{phase} {:.1?}", duration?);
+            Some(match detail {
+                Some(detail) => format!("{part} ({detail})"),
+                None => part,
+            })
+        })
+        .collect();
+
+        match phases.is_empty() {
+            true => write!(f, "no phases timed"),
+            false => write!(f, "{}", phases.join(", ")),
+        }
+    }
+}
+
+/// How many of `amount` there were per second of `duration`, unless it was too short to tell.
+fn per_sec(amount: f64, duration: Duration) -> Option<f64> {
+    (duration >= Duration::from_micros(1))
+        .then(|| (amount / duration.as_secs_f64() * 10.0).round() / 10.0)
+}
+
+/// Serializes a duration as seconds, to the millisecond.
+fn serialize_secs<S: serde::Serializer>(
+    duration: &Option<Duration>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match duration {
+        Some(duration) => {
+            serializer.serialize_f64((duration.as_secs_f64() * 1000.0).round() / 1000.0)
+        }
+        None => serializer.serialize_none(),
+    }
+}
+
+/// The lowest, highest and latest value of a quantity.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+pub struct Extremes {
+    #[serde(serialize_with = "serialize_decimal")]
+    pub min: Decimal,
+    #[serde(serialize_with = "serialize_decimal")]
+    pub max: Decimal,
+    #[serde(serialize_with = "serialize_decimal")]
+    pub latest: Decimal,
+}
+
+impl Extremes {
+    fn update(extremes: &mut Option<Extremes>, value: Decimal, latest: bool) {
+        let Some(extremes) = extremes else {
+            *extremes = Some(Extremes {
+                min: value,
+                max: value,
+                latest: value,
+            });
+            return;
+        };
+
+        extremes.min = extremes.min.min(value);
+        extremes.max = extremes.max.max(value);
+
+        if latest {
+            extremes.latest = value;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use serde_json::json;
+
+    use super::*;
+
+    fn measurement(minute: i64, temp: i64, humidity: i64) -> Measurement {
+        Measurement {
+            time: DateTime::parse_from_rfc3339("2024-06-01T12:00:00Z").unwrap()
+                + chrono::Duration::minutes(minute),
+            temp: Decimal::new(temp, 1),
+            humidity: Decimal::new(humidity, 1),
+            uncalibrated: None,
+            out_of_range: false,
+            dew_point: None,
+            perceived_temp: None,
+            absolute_humidity: None,
+            packed: None,
+        }
+    }
+
+    #[test]
+    fn serializes_the_stable_schema() {
+        let config = Config::default();
+        let station = config.stations[0].clone();
+        let mut report = StationReport::new(&station);
+        report.announced(&config, &station, 3);
+        report.observe(&[measurement(0, 215, 480), measurement(2, 198, 512)]);
+        report.observe(&[measurement(1, 220, 470)]);
+        report.check_clock(
+            &config,
+            Utc.with_ymd_and_hms(2024, 6, 1, 12, 2, 30).unwrap(),
+        );
+        report.stored(
+            3,
+            &InsertSummary {
+                inserted: 2,
+                committed: 3,
+                elapsed: Duration::from_micros(1500),
+                ..InsertSummary::default()
+            },
+        );
+        report.downloaded(
+            &config,
+            &station,
+            DownloadInfo {
+                connect: Some(Duration::from_micros(12_345)),
+                time_sync: Some(Duration::from_millis(4)),
+                download: Some(Duration::from_millis(250)),
+                received: Some(34),
+                decode: Some(Duration::from_micros(80)),
+                ..DownloadInfo::default()
+            },
+        );
+
+        let run = RunReport {
+            ok: true,
+            start: Utc.with_ymd_and_hms(2024, 6, 1, 12, 5, 0).unwrap(),
+            end: Utc.with_ymd_and_hms(2024, 6, 1, 12, 5, 3).unwrap(),
+            errors: Vec::new(),
+            warnings: 0,
+            db_connect: Some(Duration::from_millis(42)),
+            stations: vec![report],
+        };
+
+        assert_eq!(
+            serde_json::to_value(&run).unwrap(),
+            json!({
+                "ok": true,
+                "start": "2024-06-01T12:05:00Z",
+                "end": "2024-06-01T12:05:03Z",
+                "errors": [],
+                "warnings": 0,
+                "db_connect_secs": 0.042,
+                "stations": [{
+                    "pico": station.pico,
+                    "pico_port": station.pico_port,
+                    "station_id": station.station_id,
+                    "announced": 3,
+                    "flash_usage_percent": 0.0,
+                    "decoded": 3,
+                    "inserted": 2,
+                    "skipped": 1,
+                    "spooled": 0,
+                    "buffered": 0,
+                    "errors": [],
+                    "warnings": [],
+                    "latest_time": "2024-06-01T12:02:00Z",
+                    "clock_drift_secs": -30,
+                    "temp": { "min": 19.8, "max": 22.0, "latest": 19.8 },
+                    "humidity": { "min": 47.0, "max": 51.2, "latest": 51.2 },
+                    "gaps": [],
+                    "implausible_times": [],
+                    "in_batch_duplicates": 0,
+                    "timings": {
+                        "connect_secs": 0.012,
+                        "time_sync_secs": 0.004,
+                        "download_secs": 0.25,
+                        "download_bytes": 34,
+                        "download_bytes_per_sec": 136.0,
+                        "decode_secs": 0.0,
+                        "insert_secs": 0.002,
+                        "insert_rows": 3,
+                        "insert_rows_per_sec": 2000.0
+                    }
+                }]
+            })
+        );
+        assert_eq!(
+            run.stations[0].timings.to_string(),
+            "connect 12.3ms, time sync 4.0ms, download 250.0ms (34 bytes, 136 B/s), \
+             decode 80.0µs, insert 1.5ms (3 rows, 2000 rows/s)"
+        );
+        assert_eq!(PhaseTimings::default().to_string(), "no phases timed");
+    }
+
+    #[test]
+    fn warns_of_full_flash() {
+        let config = Config::default();
+        let station = config.stations[0].clone();
+        let mut report = StationReport::new(&station);
+        report.announced(&config, &station, 240_000);
+
+        let run = RunReport::new(
+            Utc::now(),
+            &Ok(CycleSummary {
+                fetched: 0,
+                inserted: 0,
+                buffered: 0,
+                succeeded: 1,
+                stations: 1,
+                exit_code: std::process::ExitCode::SUCCESS,
+                reports: vec![report],
+                errors: Vec::new(),
+                db_connect: None,
+            }),
+        );
+
+        assert!(run.ok);
+        assert_eq!(run.warnings, 1);
+        assert_eq!(run.stations[0].flash_usage_percent, Some(91.6));
+    }
+
+    #[test]
+    fn warns_of_clock_drift() {
+        let config = Config {
+            sample_interval_secs: Some(60),
+            clock_drift_warn_secs: Some(120),
+            ..Config::default()
+        };
+        let station = config.stations[0].clone();
+        let now = Utc.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap();
+        let check = |minute| {
+            let mut report = StationReport::new(&station);
+            report.observe(&[measurement(minute, 215, 480)]);
+            let warning = report.check_clock(&config, now);
+            (report.clock_drift_secs, warning.is_some())
+        };
+
+        assert_eq!(check(-3), (Some(-180), false));
+        assert_eq!(check(-4), (Some(-240), true));
+        assert_eq!(check(2), (Some(120), false));
+        assert_eq!(check(3), (Some(180), true));
+        assert!(StationReport::new(&station)
+            .check_clock(&config, now)
+            .is_none());
+    }
+
+    #[test]
+    fn reports_failed_runs() {
+        let run = RunReport::new(
+            Utc::now(),
+            &Err(anyhow!("Error connecting to the database")),
+        );
+
+        assert!(!run.ok);
+        assert_eq!(run.errors, ["Error connecting to the database"]);
+        assert!(run.stations.is_empty());
+    }
+}
@@ -0,0 +1,132 @@
+//! When the cycles of `schedule` run.
+//!
+//! The cron expression is matched against the wall clock of `pico_timezone`, so `0 0 6 * * *`
+//! stays at six in the morning across the changes to and from daylight saving time. A time
+//! repeated when the clocks go back runs once, at its first occurrence, and a time skipped when
+//! they go forward runs at the end of the gap instead.
+
+use chrono::{offset::LocalResult, DateTime, Duration, Utc};
+
+use crate::config::{CronSchedule, PicoTimezone};
+
+/// How far a time skipped by a change of the clocks is moved forward at most, searching for the
+/// end of the gap.
+const MAX_GAP_MINUTES: i64 = 24 * 60;
+
+/// The first time `schedule` fires after `after`, if it ever does again.
+pub fn next_run(
+    schedule: &CronSchedule,
+    timezone: PicoTimezone,
+    after: DateTime<Utc>,
+) -> Option<DateTime<Utc>> {
+    let wall_clock = timezone.local(&after.fixed_offset()).naive_local();
+
+    // The wall clock times are matched as if they were UTC, which has no gaps or repetitions.
+    for candidate in schedule.schedule().after(&wall_clock.and_utc()) {
+        let candidate = candidate.naive_utc();
+
+        let instant = (0..=MAX_GAP_MINUTES).find_map(|minutes| {
+            match timezone.resolve(&(candidate + Duration::minutes(minutes))) {
+                LocalResult::Single(time) => Some(time.to_utc()),
+                // The second occurrence only if `after` is past the first already, like when
+                // starting while the clocks show the repeated times.
+                LocalResult::Ambiguous(first, second) => match first > after {
+                    true => Some(first.to_utc()),
+                    false => Some(second.to_utc()),
+                },
+                LocalResult::None => None,
+            }
+        });
+
+        match instant {
+            // A time moved forward to the end of a gap may well be the one fired at last.
+            Some(instant) if instant > after => return Some(instant),
+            _ => continue,
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn next_runs(expression: &str, after: &str, count: usize) -> Vec<String> {
+        let schedule = CronSchedule::try_from(expression.to_string()).unwrap();
+        let timezone = PicoTimezone::Named(chrono_tz::Europe::Berlin);
+        let mut after = DateTime::parse_from_rfc3339(after).unwrap().to_utc();
+        let mut runs = Vec::new();
+
+        for _ in 0..count {
+            after = next_run(&schedule, timezone, after).unwrap();
+            runs.push(timezone.local(&after.fixed_offset()).to_rfc3339());
+        }
+
+        runs
+    }
+
+    #[test]
+    fn runs_at_the_wall_clock_times() {
+        assert_eq!(
+            next_runs("0 0 6,22 * * *", "2024-03-30T12:00:00+01:00", 3),
+            [
+                "2024-03-30T22:00:00+01:00",
+                "2024-03-31T06:00:00+02:00",
+                "2024-03-31T22:00:00+02:00",
+            ]
+        );
+        // Without the seconds.
+        assert_eq!(
+            next_runs("30 6 * * *", "2024-06-01T06:30:00+02:00", 1),
+            ["2024-06-02T06:30:00+02:00"]
+        );
+    }
+
+    #[test]
+    fn runs_the_repeated_times_once() {
+        assert_eq!(
+            next_runs("0 30 2 * * *", "2024-10-27T01:00:00+02:00", 2),
+            ["2024-10-27T02:30:00+02:00", "2024-10-28T02:30:00+01:00"]
+        );
+        assert_eq!(
+            next_runs("0 */30 * * * *", "2024-10-27T02:00:00+02:00", 3),
+            [
+                "2024-10-27T02:30:00+02:00",
+                "2024-10-27T03:00:00+01:00",
+                "2024-10-27T03:30:00+01:00",
+            ]
+        );
+        // Starting while the clocks show the repeated times the second time.
+        assert_eq!(
+            next_runs("0 */30 * * * *", "2024-10-27T02:10:00+01:00", 2),
+            ["2024-10-27T02:30:00+01:00", "2024-10-27T03:00:00+01:00"]
+        );
+    }
+
+    #[test]
+    fn runs_the_skipped_times_at_the_end_of_the_gap() {
+        assert_eq!(
+            next_runs("0 30 2 * * *", "2024-03-30T12:00:00+01:00", 3),
+            [
+                "2024-03-31T03:00:00+02:00",
+                "2024-04-01T02:30:00+02:00",
+                "2024-04-02T02:30:00+02:00",
+            ]
+        );
+        assert_eq!(
+            next_runs("0 */30 * * * *", "2024-03-31T01:30:00+01:00", 2),
+            ["2024-03-31T03:00:00+02:00", "2024-03-31T03:30:00+02:00"]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        for expression in ["", "* * *", "0 61 * * * *", "every hour"] {
+            assert!(
+                CronSchedule::try_from(expression.to_string()).is_err(),
+                "{expression}"
+            );
+        }
+    }
+}
@@ -0,0 +1,43 @@
+//! The database password in the OS keyring, read with `db_password_keyring` and stored by the
+//! `set-password` subcommand.
+
+use anyhow::{anyhow, bail};
+
+use crate::config::KeyringEntry;
+
+/// The password stored in `entry`.
+pub fn keyring_password(entry: &KeyringEntry) -> anyhow::Result<String> {
+    keyring::Entry::new(&entry.service, &entry.user)
+        .and_then(|keyring_entry| keyring_entry.get_password())
+        .map_err(|err| {
+            anyhow!(
+                "Error reading the database password of user {:?} of service {:?} from the \
+                 keyring: {err}",
+                entry.user,
+                entry.service
+            )
+        })
+}
+
+/// Asks for the password twice on the terminal, without echoing it, and stores it in `entry`.
+pub fn set_keyring_password(entry: &KeyringEntry) -> anyhow::Result<()> {
+    let prompt = |prompt: String| {
+        rpassword::prompt_password(prompt)
+            .map_err(|err| anyhow!("Error reading the password from the terminal: {err}"))
+    };
+
+    let password = prompt(format!(
+        "Database password for user {:?} of service {:?}: ",
+        entry.user, entry.service
+    ))?;
+    if password.is_empty() {
+        bail!("the password must not be empty");
+    }
+    if prompt("Repeat the password: ".to_string())? != password {
+        bail!("the passwords don't match");
+    }
+
+    keyring::Entry::new(&entry.service, &entry.user)
+        .and_then(|keyring_entry| keyring_entry.set_password(&password))
+        .map_err(|err| anyhow!("Error storing the database password in the keyring: {err}"))
+}
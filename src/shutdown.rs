@@ -0,0 +1,71 @@
+//! Stopping gracefully on SIGINT and SIGTERM.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::anyhow;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::Notify,
+};
+use tracing::{info, warn};
+
+/// The exit code after SIGINT or SIGTERM interrupted the program.
+pub const INTERRUPTED_EXIT_CODE: u8 = 130;
+
+/// Tracks whether SIGINT or SIGTERM was received, after which no new measurements are fetched or
+/// inserted.
+pub struct Shutdown {
+    requested: AtomicBool,
+    notify: Notify,
+}
+
+pub static SHUTDOWN: Shutdown = Shutdown {
+    requested: AtomicBool::new(false),
+    notify: Notify::const_new(),
+};
+
+impl Shutdown {
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    /// Completes once shutting down was requested.
+    pub async fn requested(&self) {
+        let notified = self.notify.notified();
+
+        if !self.is_requested() {
+            notified.await;
+        }
+    }
+}
+
+/// Requests shutting down on the first SIGINT or SIGTERM and aborts the program on the second one.
+pub fn handle_signals() -> anyhow::Result<()> {
+    let mut sigterm = signal(SignalKind::terminate())
+        .map_err(|err| anyhow!("Error installing the SIGTERM handler: {err}"))?;
+
+    let mut sigint = signal(SignalKind::interrupt())
+        .map_err(|err| anyhow!("Error installing the SIGINT handler: {err}"))?;
+
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = sigterm.recv() => {}
+                _ = sigint.recv() => {}
+            }
+
+            if SHUTDOWN.requested.swap(true, Ordering::SeqCst) {
+                warn!("received a second signal, aborting");
+                std::process::exit(INTERRUPTED_EXIT_CODE.into());
+            }
+
+            info!(
+                "received a signal, finishing the current insert (send another one to abort immediately)"
+            );
+
+            SHUTDOWN.notify.notify_waiters();
+        }
+    });
+
+    Ok(())
+}
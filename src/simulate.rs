@@ -0,0 +1,132 @@
+//! Synthetic measurements for load testing the storage without a Pico.
+
+use std::{process::ExitCode, time::Instant};
+
+use chrono::{DateTime, Duration, FixedOffset, SubsecRound, Utc};
+use rand::Rng;
+use rust_decimal::Decimal;
+use tracing::{error, info, info_span, Instrument};
+
+use crate::{
+    config::{Config, Station},
+    cycle::CycleOptions,
+    derived::derive_quantities,
+    failure::{Categorize, Failure},
+    shutdown::{INTERRUPTED_EXIT_CODE, SHUTDOWN},
+    sink::{connect_database, insert_measurements},
+    Measurement,
+};
+
+/// Generates `count` measurements `interval` apart, the last one taken at `end`, whose
+/// temperature and humidity follow a random walk within the range the Pico can report.
+pub fn simulate_measurements(
+    count: u32,
+    interval: Duration,
+    end: DateTime<FixedOffset>,
+) -> Vec<Measurement> {
+    let mut rng = rand::thread_rng();
+    let mut temp = rng.gen_range(150..250);
+    let mut humidity = rng.gen_range(400..600);
+
+    (0..count)
+        .map(|index| {
+            temp = (temp + rng.gen_range(-3..=3)).clamp(-256, 255);
+            humidity = (humidity + rng.gen_range(-5..=5)).clamp(0, 1000);
+
+            Measurement {
+                time: end - interval * (count - 1 - index) as i32,
+                temp: Decimal::new(temp.into(), 1),
+                humidity: Decimal::new(humidity.into(), 1),
+                uncalibrated: None,
+                out_of_range: false,
+                dew_point: None,
+                perceived_temp: None,
+                absolute_humidity: None,
+                packed: None,
+            }
+        })
+        .collect()
+}
+
+/// Inserts `count` simulated measurements for every station through the same path as fetched
+/// ones, logging the throughput.
+pub async fn run_simulation(
+    options: &CycleOptions,
+    config: &Config,
+    stations: &[Station],
+    count: u32,
+    interval: Duration,
+) -> anyhow::Result<ExitCode> {
+    let mut db = connect_database(config).await.category(Failure::Database)?;
+    info!("connected to the database");
+
+    let mut failed = false;
+
+    for station in stations {
+        if SHUTDOWN.is_requested() {
+            return Ok(ExitCode::from(INTERRUPTED_EXIT_CODE));
+        }
+
+        let mut measurements =
+            simulate_measurements(count, interval, Utc::now().trunc_subsecs(0).fixed_offset());
+        derive_quantities(config, &mut measurements);
+
+        async {
+            let start = Instant::now();
+            let result =
+                insert_measurements(config, &mut db, station, &measurements, options.fail_fast)
+                    .await;
+            let elapsed = start.elapsed();
+
+            match result {
+                Ok(summary) => {
+                    failed |= !summary.failures.is_empty();
+                    info!(
+                        inserted = summary.inserted,
+                        failed = summary.failures.len(),
+                        elapsed_ms = elapsed.as_millis() as u64,
+                        "inserted {} of {count} simulated measurements in {elapsed:.2?}, {:.0} rows/sec",
+                        summary.inserted,
+                        summary.inserted as f64 / elapsed.as_secs_f64()
+                    );
+                }
+                Err(err) => {
+                    failed = true;
+                    error!("inserting the simulated measurements failed: {err}");
+                }
+            }
+        }
+        .instrument(info_span!("station", station_id = station.station_id))
+        .await;
+    }
+
+    if SHUTDOWN.is_requested() {
+        return Ok(ExitCode::from(INTERRUPTED_EXIT_CODE));
+    }
+
+    Ok(if failed {
+        Failure::Database.exit_code()
+    } else {
+        ExitCode::SUCCESS
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spaces_the_measurements_up_to_the_end() {
+        let end = DateTime::parse_from_rfc3339("2024-06-01T12:00:00+02:00").unwrap();
+        let measurements = simulate_measurements(100, Duration::seconds(30), end);
+
+        assert_eq!(measurements.len(), 100);
+        assert_eq!(measurements[0].time, end - Duration::seconds(99 * 30));
+        assert_eq!(measurements[99].time, end);
+
+        for measurement in &measurements {
+            assert!((Decimal::new(-256, 1)..=Decimal::new(255, 1)).contains(&measurement.temp));
+            assert!((Decimal::ZERO..=Decimal::ONE_HUNDRED).contains(&measurement.humidity));
+        }
+    }
+}
@@ -0,0 +1,2884 @@
+//! Storing the measurements in PostgreSQL, SQLite or InfluxDB.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+    pin::pin,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, FixedOffset, Utc};
+use futures::{stream, StreamExt, TryStreamExt};
+use rust_decimal::{
+    prelude::{FromPrimitive, ToPrimitive},
+    Decimal,
+};
+use tokio_postgres::{
+    binary_copy::BinaryCopyInWriter,
+    error::SqlState,
+    types::{ToSql, Type},
+    Client, NoTls, Statement,
+};
+use tracing::{debug, field, info, info_span, warn, Span};
+
+use crate::{
+    aggregate::{self, Bucket, AGGREGATE_TABLE},
+    config::{
+        Config, DerivedMetric, IdentifyBy, MeasurementTable, Mode, Station, Storage,
+        UNREGISTERED_STATION_ID,
+    },
+    error::{DbCause, Error},
+    progress::Progress,
+    retry_with_backoff,
+    shutdown::SHUTDOWN,
+    telemetry::traced,
+    Measurement,
+};
+
+/// The connection to the configured storage.
+pub enum Database {
+    Postgres(Postgres),
+    Sqlite(rusqlite::Connection),
+    Influxdb(reqwest::Client),
+}
+
+impl Database {
+    pub fn is_closed(&self) -> bool {
+        match self {
+            Database::Postgres(postgres) => postgres.client.is_closed(),
+            Database::Sqlite(_) | Database::Influxdb(_) => false,
+        }
+    }
+
+    /// The time of the latest measurement stored for `station`, if any are and the storage can be
+    /// queried for it.
+    pub async fn latest_time(
+        &self,
+        config: &Config,
+        station: &Station,
+    ) -> Result<Option<DateTime<Utc>>, Error> {
+        let table = QuotedTable::new(&config.table);
+        let query = format!(
+            "select max({}) from {} where {} = $1",
+            table.at, table.name, table.station_id
+        );
+
+        match self {
+            Database::Postgres(postgres) => postgres
+                .client
+                .query_one(&query, &[&station.station_id])
+                .await
+                .map(|row| row.get(0))
+                .map_err(|err| Error::db("querying the latest stored measurement", err)),
+            Database::Sqlite(connection) => connection
+                .query_row(&query, [station.station_id], |row| row.get(0))
+                .map_err(|err| Error::db("querying the latest stored measurement", err)),
+            Database::Influxdb(_) => Ok(None),
+        }
+    }
+
+    /// The time, temperature and humidity of the measurements stored for `station` from `from`
+    /// up to and including `to`.
+    pub async fn stored_measurements(
+        &self,
+        config: &Config,
+        station: &Station,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<Vec<(DateTime<Utc>, Decimal, Decimal)>, Error> {
+        let QuotedTable {
+            name,
+            at,
+            station_id,
+            temp,
+            humidity,
+        } = QuotedTable::new(&config.table);
+        let query = format!(
+            "select {at}, {temp}, {humidity} from {name}
+                where {station_id} = $1 and {at} between $2 and $3"
+        );
+
+        match self {
+            Database::Postgres(postgres) => postgres
+                .client
+                .query(&query, &[&station.station_id, &from, &to])
+                .await
+                .map(|rows| {
+                    rows.iter()
+                        .map(|row| (row.get(0), row.get(1), row.get(2)))
+                        .collect()
+                })
+                .map_err(|err| Error::db("querying the stored measurements", err)),
+            Database::Sqlite(connection) => connection
+                .prepare(&query)
+                .and_then(|mut statement| {
+                    statement
+                        .query_map(rusqlite::params![station.station_id, from, to], |row| {
+                            let (temp, humidity): (f64, f64) = (row.get(1)?, row.get(2)?);
+                            Ok((
+                                row.get(0)?,
+                                Decimal::from_f64(temp).unwrap_or_default(),
+                                Decimal::from_f64(humidity).unwrap_or_default(),
+                            ))
+                        })?
+                        .collect()
+                })
+                .map_err(|err| Error::db("querying the stored measurements", err)),
+            Database::Influxdb(_) => Err(Error::db(
+                "querying the stored measurements",
+                "not supported with InfluxDB storage",
+            )),
+        }
+    }
+
+    /// The time of the latest measurement aggregated for `station`, if any is.
+    async fn latest_aggregated(&self, station: &Station) -> Result<Option<DateTime<Utc>>, Error> {
+        let query = format!("select max(last_at) from {AGGREGATE_TABLE} where station_id = $1");
+
+        match self {
+            Database::Postgres(postgres) => postgres
+                .client
+                .query_one(&query, &[&station.station_id])
+                .await
+                .map(|row| row.get(0))
+                .map_err(|err| Error::db("querying the latest aggregated measurement", err)),
+            Database::Sqlite(connection) => connection
+                .query_row(&query, [station.station_id], |row| row.get(0))
+                .map_err(|err| Error::db("querying the latest aggregated measurement", err)),
+            Database::Influxdb(_) => Ok(None),
+        }
+    }
+}
+
+/// The PostgreSQL connection and the statements prepared on it.
+pub struct Postgres {
+    client: Client,
+    insert_statements: InsertStatements,
+    /// Also insert the raw values, prepared if any station stores them.
+    insert_raw_statements: Option<InsertStatements>,
+}
+
+impl Postgres {
+    /// The statements inserting the measurements of `station`, taking [`insert_params`].
+    fn insert_statements(&self, station: &Station) -> &InsertStatements {
+        match &self.insert_raw_statements {
+            Some(insert_raw_statements) if station.store_raw => insert_raw_statements,
+            _ => &self.insert_statements,
+        }
+    }
+}
+
+/// The prepared statements inserting measurements.
+#[derive(Clone)]
+struct InsertStatements {
+    /// Inserts a single measurement.
+    row: Statement,
+    /// Inserts `rows_per_insert` measurements at once, unless that's 1.
+    rows: Option<Statement>,
+}
+
+/// `identifier` quoted for SQL, so mixed case and reserved words are taken as they are. The config
+/// rejects quotes in the names of the table and its columns, but they're doubled all the same.
+pub fn quote_identifier(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('"', "\"\""))
+}
+
+/// The quoted names of the configured measurement table and its columns.
+pub struct QuotedTable {
+    pub name: String,
+    pub at: String,
+    pub station_id: String,
+    pub temp: String,
+    pub humidity: String,
+}
+
+impl QuotedTable {
+    pub fn new(table: &MeasurementTable) -> QuotedTable {
+        QuotedTable {
+            name: quote_identifier(&table.name),
+            at: quote_identifier(&table.time_column),
+            station_id: quote_identifier(&table.station_id_column),
+            temp: quote_identifier(&table.temp_column),
+            humidity: quote_identifier(&table.humidity_column),
+        }
+    }
+}
+
+/// The `columns`, quoted and separated by commas.
+fn column_list(columns: &[&str]) -> String {
+    columns
+        .iter()
+        .map(|column| quote_identifier(column))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// The columns the measurements are inserted into, with their PostgreSQL types: time, station id,
+/// temperature and humidity, followed by the raw temperature and humidity if `store_raw` is set,
+/// the `out_of_range` flag if the config flags such measurements, the dew point and perceived
+/// temperature if they're computed and the packed measurement if `store_raw_packed` is set.
+fn insert_columns(config: &Config, store_raw: bool) -> Vec<(&str, Type)> {
+    let table = &config.table;
+    let mut columns = vec![
+        (table.time_column.as_str(), Type::TIMESTAMPTZ),
+        (&table.station_id_column, Type::INT4),
+        (&table.temp_column, Type::NUMERIC),
+        (&table.humidity_column, Type::NUMERIC),
+    ];
+
+    if store_raw {
+        columns.extend([("temp_raw", Type::NUMERIC), ("humidity_raw", Type::NUMERIC)]);
+    }
+
+    if config.flags_out_of_range() {
+        columns.push(("out_of_range", Type::BOOL));
+    }
+
+    if config.compute_dew_point {
+        columns.push(("dew_point", Type::NUMERIC));
+    }
+
+    if let Some(perceived_temp) = config.perceived_temp {
+        columns.push((perceived_temp.column(), Type::NUMERIC));
+    }
+
+    if config.derives(DerivedMetric::AbsoluteHumidity) {
+        columns.push((DerivedMetric::AbsoluteHumidity.column(), Type::NUMERIC));
+    }
+
+    if config.store_raw_packed {
+        columns.push(("raw", Type::INT8));
+    }
+
+    columns
+}
+
+/// How many columns the measurements are inserted into, with the raw values if any station stores
+/// them.
+pub fn insert_column_count(config: &Config) -> usize {
+    insert_columns(config, config.stores_raw()).len()
+}
+
+/// The parameters inserting `measurement` of `station`, matching [`insert_columns`].
+fn insert_params<'a>(
+    config: &Config,
+    station: &'a Station,
+    measurement: &'a Measurement,
+) -> Vec<&'a (dyn ToSql + Sync)> {
+    let mut params: Vec<&(dyn ToSql + Sync)> = vec![
+        &measurement.time,
+        &station.station_id,
+        &measurement.temp,
+        &measurement.humidity,
+    ];
+
+    if station.store_raw {
+        let (temp, humidity) = measurement.raw();
+        params.extend([temp as &(dyn ToSql + Sync), humidity]);
+    }
+
+    if config.flags_out_of_range() {
+        params.push(&measurement.out_of_range);
+    }
+
+    if config.compute_dew_point {
+        params.push(&measurement.dew_point);
+    }
+
+    if config.perceived_temp.is_some() {
+        params.push(&measurement.perceived_temp);
+    }
+
+    if config.derives(DerivedMetric::AbsoluteHumidity) {
+        params.push(&measurement.absolute_humidity);
+    }
+
+    if config.store_raw_packed {
+        params.push(&measurement.packed);
+    }
+
+    params
+}
+
+/// Prepares the statements inserting one and `rows_per_insert` measurements into
+/// [`insert_columns`].
+async fn prepare_inserts(
+    client: &Client,
+    config: &Config,
+    store_raw: bool,
+) -> Result<InsertStatements, tokio_postgres::Error> {
+    let row = prepare_insert(client, config, store_raw, 1).await?;
+    let rows = match config.rows_per_insert {
+        1 => None,
+        rows => Some(prepare_insert(client, config, store_raw, rows).await?),
+    };
+
+    Ok(InsertStatements { row, rows })
+}
+
+/// Prepares the statement inserting `rows` measurements into [`insert_columns`], taking the
+/// [`insert_params`] of one after another and returning the times of the inserted ones.
+async fn prepare_insert(
+    client: &Client,
+    config: &Config,
+    store_raw: bool,
+    rows: usize,
+) -> Result<Statement, tokio_postgres::Error> {
+    let columns = insert_columns(config, store_raw);
+
+    let (names, types): (Vec<_>, Vec<_>) = columns.into_iter().unzip();
+    let values = (0..rows)
+        .map(|row| {
+            let placeholders = (1..=names.len())
+                .map(|index| format!("${}", row * names.len() + index))
+                .collect::<Vec<_>>();
+            format!("({})", placeholders.join(", "))
+        })
+        .collect::<Vec<_>>();
+
+    client
+        .prepare_typed(
+            &format!(
+                "insert into {}({}) values {}{} returning {}",
+                quote_identifier(&config.table.name),
+                column_list(&names),
+                values.join(", "),
+                on_conflict_clause(config),
+                quote_identifier(&config.table.time_column)
+            ),
+            &types
+                .iter()
+                .cloned()
+                .cycle()
+                .take(rows * types.len())
+                .collect::<Vec<_>>(),
+        )
+        .await
+}
+
+pub async fn connect_database(config: &Config) -> Result<Database, Error> {
+    match &config.storage {
+        Storage::Postgres => Ok(Database::Postgres(connect_postgres(config).await?)),
+        Storage::Sqlite { path } => Ok(Database::Sqlite(open_sqlite(config, path)?)),
+        Storage::Influxdb { .. } => Ok(Database::Influxdb(reqwest::Client::new())),
+    }
+}
+
+/// Opens the SQLite database at `path`, creating the measurement table (and the station table with
+/// `station_table`) if it doesn't exist and adding the optional columns the config needs if they're
+/// missing.
+fn open_sqlite(config: &Config, path: &Path) -> Result<rusqlite::Connection, Error> {
+    let connection = rusqlite::Connection::open(path)
+        .map_err(|err| Error::db(format!("opening SQLite database {}", path.display()), err))?;
+
+    let QuotedTable {
+        name,
+        at,
+        station_id,
+        temp,
+        humidity,
+    } = QuotedTable::new(&config.table);
+
+    // SQLite can't add a foreign key to an existing table, so only new ones reference the
+    // stations.
+    let references = match config.station_table {
+        true => {
+            connection
+                .execute_batch(&format!(
+                    "pragma foreign_keys = on;
+                    create table if not exists {STATION_TABLE} (
+                        id integer primary key,
+                        name text unique,
+                        location text,
+                        created_at text not null default current_timestamp
+                    )"
+                ))
+                .map_err(|err| Error::db(format!("creating the {STATION_TABLE} table"), err))?;
+
+            format!(" references {STATION_TABLE} (id)")
+        }
+        false => String::new(),
+    };
+
+    connection
+        .execute_batch(&format!(
+            "create table if not exists {name} (
+                {at} text not null,
+                {station_id} integer not null{references},
+                {temp} real not null,
+                {humidity} real not null,
+                unique ({at}, {station_id})
+            )"
+        ))
+        .map_err(|err| Error::db("creating the measurement table", err))?;
+
+    add_sqlite_columns(
+        &connection,
+        &config.table.name,
+        optional_columns(config)
+            .into_iter()
+            .map(|(column, _, definition)| (column, definition)),
+    )?;
+
+    if config.has_station_metadata() {
+        connection
+            .execute_batch(&format!(
+                "create table if not exists {STATION_METADATA_TABLE} (
+                    station_id integer primary key,
+                    metadata text not null,
+                    updated_at text not null default current_timestamp
+                )"
+            ))
+            .map_err(|err| {
+                Error::db(format!("creating the {STATION_METADATA_TABLE} table"), err)
+            })?;
+    }
+
+    if let Some(aggregate) = &config.aggregate {
+        connection
+            .execute_batch(&format!(
+                "create table if not exists {AGGREGATE_TABLE} (
+                    bucket text not null,
+                    station_id integer not null,
+                    samples integer not null,
+                    last_at text not null,
+                    primary key (bucket, station_id)
+                )"
+            ))
+            .map_err(|err| Error::db(format!("creating the {AGGREGATE_TABLE} table"), err))?;
+
+        add_sqlite_columns(
+            &connection,
+            AGGREGATE_TABLE,
+            aggregate::columns(&aggregate.functions)
+                .into_iter()
+                .map(|column| (column.name(), "real")),
+        )?;
+    }
+
+    Ok(connection)
+}
+
+/// Adds the `columns` with their definitions to the SQLite `table` which it doesn't have yet.
+fn add_sqlite_columns<'c>(
+    connection: &rusqlite::Connection,
+    table: &str,
+    columns: impl IntoIterator<Item = (&'c str, &'c str)>,
+) -> Result<(), Error> {
+    for (column, definition) in columns {
+        let exists = connection
+            .query_row(
+                "select count(*) from pragma_table_info(?1) where name = ?2",
+                [table, column],
+                |row| row.get::<_, u32>(0),
+            )
+            .map_err(|err| Error::db(format!("reading the columns of the {table} table"), err))?
+            > 0;
+
+        if !exists {
+            connection
+                .execute_batch(&format!(
+                    "alter table {} add column {} {definition}",
+                    quote_identifier(table),
+                    quote_identifier(column)
+                ))
+                .map_err(|err| Error::db(format!("adding the {column} column"), err))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The clause making insertions skip measurements which are already present.
+///
+/// This relies on a unique index on the time and station id; with `plain_insert` set it's left out
+/// for databases without one.
+fn on_conflict_clause(config: &Config) -> String {
+    if config.plain_insert {
+        String::new()
+    } else {
+        let table = QuotedTable::new(&config.table);
+        format!(
+            " on conflict ({}, {}) do nothing",
+            table.at, table.station_id
+        )
+    }
+}
+
+/// Connects to PostgreSQL and prepares the insertion statement, retrying the connection with
+/// exponential backoff as configured.
+async fn connect_postgres(config: &Config) -> Result<Postgres, Error> {
+    let client = connect_postgres_client(config).await?;
+
+    // Statements using optional columns fail to prepare if they're missing.
+    let hint = if !optional_columns(config).is_empty() {
+        " (add the optional columns with --init-db)"
+    } else {
+        ""
+    };
+
+    let insert_statements = prepare_inserts(&client, config, false)
+        .await
+        .map_err(|err| {
+            Error::db(
+                format!("preparing measurement insertion statement{hint}"),
+                err,
+            )
+        })?;
+
+    let insert_raw_statements = if config.stores_raw() {
+        Some(
+            prepare_inserts(&client, config, true)
+                .await
+                .map_err(|err| {
+                    Error::db(
+                        format!("preparing measurement insertion statement{hint}"),
+                        err,
+                    )
+                })?,
+        )
+    } else {
+        None
+    };
+
+    Ok(Postgres {
+        client,
+        insert_statements,
+        insert_raw_statements,
+    })
+}
+
+/// Connects to PostgreSQL, retrying with exponential backoff as configured.
+pub async fn connect_postgres_client(config: &Config) -> Result<Client, Error> {
+    let postgres_config = config.postgres_config()?;
+
+    let (client, connection) = retry_with_backoff(
+        config.db_connect_retries,
+        Duration::from_millis(config.db_connect_backoff_ms),
+        "connecting to the database",
+        // Retrying doesn't help with the credentials or the database being wrong.
+        |err: &tokio_postgres::Error| {
+            !matches!(
+                err.code(),
+                Some(&SqlState::INVALID_PASSWORD)
+                    | Some(&SqlState::INVALID_AUTHORIZATION_SPECIFICATION)
+                    | Some(&SqlState::INVALID_CATALOG_NAME)
+            )
+        },
+        || postgres_config.connect(NoTls),
+    )
+    .await
+    .map_err(|retried| {
+        Error::db(
+            format!(
+                "connecting to the database after {} attempts in {:.1?}",
+                retried.attempts, retried.elapsed
+            ),
+            retried.err,
+        )
+    })?;
+
+    tokio::spawn(connection);
+
+    Ok(client)
+}
+
+/// The table the stations are registered in with `station_table`, referenced by the station id of
+/// the measurement table.
+pub const STATION_TABLE: &str = "station";
+
+/// The table the `metadata` of the stations is stored in, as a JSON object per station.
+pub const STATION_METADATA_TABLE: &str = "station_metadata";
+
+/// Registers `stations` in the station table if the config has one, returning them with the ids
+/// it assigned to the ones configured with just a name. The others are registered under their
+/// configured id, along with their name and location if they have any. Their metadata is stored
+/// as well, if any have some.
+pub async fn register_stations(
+    config: &Config,
+    stations: Vec<Station>,
+) -> Result<Vec<Station>, Error> {
+    if !config.station_table && !config.has_station_metadata() {
+        return Ok(stations);
+    }
+
+    let stations = match &config.storage {
+        Storage::Postgres => {
+            let client = connect_postgres_client(config).await?;
+            let stations = match config.station_table {
+                true => register_postgres_stations(&client, stations).await?,
+                false => stations,
+            };
+
+            if config.has_station_metadata() {
+                store_postgres_metadata(&client, &stations).await?;
+            }
+
+            stations
+        }
+        Storage::Sqlite { path } => {
+            let connection = open_sqlite(config, path)?;
+            let stations = match config.station_table {
+                true => register_sqlite_stations(&connection, stations)?,
+                false => stations,
+            };
+
+            if config.has_station_metadata() {
+                store_sqlite_metadata(&connection, &stations)?;
+            }
+
+            stations
+        }
+        Storage::Influxdb { .. } => stations,
+    };
+
+    let mut station_ids = BTreeMap::new();
+
+    for station in &stations {
+        if config.mode == Mode::Listen
+            && config.identify_by == IdentifyBy::StationByte
+            && u8::try_from(station.station_id).is_err()
+        {
+            return Err(Error::Config(format!(
+                "Error in config: the station {} is registered under the station_id {}, which \
+                 can't be sent as a byte for identify_by = \"station_byte\"",
+                station.name.as_deref().unwrap_or(&station.pico),
+                station.station_id
+            )));
+        }
+
+        if let Some(other) = station_ids.insert(station.station_id, station) {
+            return Err(Error::Config(format!(
+                "Error in config: the station {} is registered under the station_id {} of the \
+                 station at {}:{}",
+                station.name.as_deref().unwrap_or(&station.pico),
+                station.station_id,
+                other.pico,
+                other.pico_port
+            )));
+        }
+    }
+
+    Ok(stations)
+}
+
+async fn register_postgres_stations(
+    client: &Client,
+    mut stations: Vec<Station>,
+) -> Result<Vec<Station>, Error> {
+    let failed = |err| {
+        Error::db(
+            format!(
+                "registering the stations in the {STATION_TABLE} table (create it with --init-db)"
+            ),
+            err,
+        )
+    };
+
+    for station in stations
+        .iter()
+        .filter(|station| station.station_id != UNREGISTERED_STATION_ID)
+    {
+        client
+            .execute(
+                &format!(
+                    "insert into {STATION_TABLE} (id, name, location) values ($1, $2, $3)
+                    on conflict (id) do update set
+                        name = coalesce(excluded.name, {STATION_TABLE}.name),
+                        location = coalesce(excluded.location, {STATION_TABLE}.location)"
+                ),
+                &[&station.station_id, &station.name, &station.location],
+            )
+            .await
+            .map_err(failed)?;
+    }
+
+    // The ids inserted as they are don't advance the identity, which mustn't hand them out again.
+    client
+        .execute(
+            &format!(
+                "select setval(pg_get_serial_sequence('{STATION_TABLE}', 'id'), max(id))
+                from {STATION_TABLE}"
+            ),
+            &[],
+        )
+        .await
+        .map_err(failed)?;
+
+    for station in stations
+        .iter_mut()
+        .filter(|station| station.station_id == UNREGISTERED_STATION_ID)
+    {
+        station.station_id = client
+            .query_one(
+                &format!(
+                    "insert into {STATION_TABLE} (name, location) values ($1, $2)
+                    on conflict (name) do update set
+                        location = coalesce(excluded.location, {STATION_TABLE}.location)
+                    returning id"
+                ),
+                &[&station.name, &station.location],
+            )
+            .await
+            .map_err(failed)?
+            .get(0);
+    }
+
+    Ok(stations)
+}
+
+fn register_sqlite_stations(
+    connection: &rusqlite::Connection,
+    mut stations: Vec<Station>,
+) -> Result<Vec<Station>, Error> {
+    let failed = |err| {
+        Error::db(
+            format!("registering the stations in the {STATION_TABLE} table"),
+            err,
+        )
+    };
+
+    for station in stations
+        .iter()
+        .filter(|station| station.station_id != UNREGISTERED_STATION_ID)
+    {
+        connection
+            .execute(
+                &format!(
+                    "insert into {STATION_TABLE} (id, name, location) values (?1, ?2, ?3)
+                    on conflict (id) do update set
+                        name = coalesce(excluded.name, {STATION_TABLE}.name),
+                        location = coalesce(excluded.location, {STATION_TABLE}.location)"
+                ),
+                rusqlite::params![station.station_id, station.name, station.location],
+            )
+            .map_err(failed)?;
+    }
+
+    // SQLite assigns new ids above the largest one, so inserting some as they are is fine.
+    for station in stations
+        .iter_mut()
+        .filter(|station| station.station_id == UNREGISTERED_STATION_ID)
+    {
+        station.station_id = connection
+            .query_row(
+                &format!(
+                    "insert into {STATION_TABLE} (name, location) values (?1, ?2)
+                    on conflict (name) do update set
+                        location = coalesce(excluded.location, {STATION_TABLE}.location)
+                    returning id"
+                ),
+                rusqlite::params![station.name, station.location],
+                |row| row.get(0),
+            )
+            .map_err(failed)?;
+    }
+
+    Ok(stations)
+}
+
+/// Stores the metadata of `stations` in the station metadata table, replacing what's stored for
+/// them if it changed, and bumping its `updated_at` then.
+async fn store_postgres_metadata(client: &Client, stations: &[Station]) -> Result<(), Error> {
+    let table_exists = client
+        .query_one(
+            "select to_regclass($1) is not null",
+            &[&STATION_METADATA_TABLE],
+        )
+        .await
+        .map_err(|err| {
+            Error::db(
+                format!("checking for the {STATION_METADATA_TABLE} table"),
+                err,
+            )
+        })?
+        .get::<_, bool>(0);
+
+    if !table_exists {
+        return Err(Error::db(
+            "storing the station metadata",
+            format!("the {STATION_METADATA_TABLE} table doesn't exist, create it with --init-db"),
+        ));
+    }
+
+    for station in stations {
+        client
+            .execute(
+                &format!(
+                    "insert into {STATION_METADATA_TABLE} (station_id, metadata)
+                        values ($1, $2::text::jsonb)
+                    on conflict (station_id) do update set
+                        metadata = excluded.metadata,
+                        updated_at = now()
+                    where {STATION_METADATA_TABLE}.metadata <> excluded.metadata"
+                ),
+                &[&station.station_id, &metadata_json(station)],
+            )
+            .await
+            .map_err(|err| Error::db("storing the station metadata", err))?;
+    }
+
+    Ok(())
+}
+
+fn store_sqlite_metadata(
+    connection: &rusqlite::Connection,
+    stations: &[Station],
+) -> Result<(), Error> {
+    for station in stations {
+        connection
+            .execute(
+                &format!(
+                    "insert into {STATION_METADATA_TABLE} (station_id, metadata) values (?1, ?2)
+                    on conflict (station_id) do update set
+                        metadata = excluded.metadata,
+                        updated_at = current_timestamp
+                    where {STATION_METADATA_TABLE}.metadata <> excluded.metadata"
+                ),
+                rusqlite::params![station.station_id, metadata_json(station)],
+            )
+            .map_err(|err| Error::db("storing the station metadata", err))?;
+    }
+
+    Ok(())
+}
+
+/// The `metadata` of `station` as a JSON object, ordered by key so it only differs when it changed.
+fn metadata_json(station: &Station) -> String {
+    serde_json::to_string(&station.metadata).unwrap_or_default()
+}
+
+/// The types of the time, station id, temperature and humidity columns of the PostgreSQL
+/// measurement table, as named by `information_schema.columns`.
+const POSTGRES_COLUMN_TYPES: [&str; 4] =
+    ["timestamp with time zone", "integer", "numeric", "numeric"];
+
+/// The types of the time, station id, temperature and humidity columns of the SQLite measurement
+/// table.
+const SQLITE_COLUMN_TYPES: [&str; 4] = ["text", "integer", "real", "real"];
+
+/// The columns of the configured measurement `table` with their `types`.
+fn table_columns<'t>(table: &'t MeasurementTable, types: [&'t str; 4]) -> Vec<(&'t str, &'t str)> {
+    table
+        .columns()
+        .into_iter()
+        .zip(types)
+        .map(|((_, column), column_type)| (column, column_type))
+        .collect()
+}
+
+/// The columns of the PostgreSQL aggregate table besides the aggregates themselves.
+const POSTGRES_AGGREGATE_COLUMNS: [(&str, &str); 4] = [
+    ("bucket", "timestamp with time zone"),
+    ("station_id", "integer"),
+    ("samples", "integer"),
+    ("last_at", "timestamp with time zone"),
+];
+
+/// The columns of the SQLite aggregate table besides the aggregates themselves.
+const SQLITE_AGGREGATE_COLUMNS: [(&str, &str); 4] = [
+    ("bucket", "text"),
+    ("station_id", "integer"),
+    ("samples", "integer"),
+    ("last_at", "text"),
+];
+
+/// The columns of the PostgreSQL station table.
+const POSTGRES_STATION_COLUMNS: [(&str, &str); 4] = [
+    ("id", "integer"),
+    ("name", "text"),
+    ("location", "text"),
+    ("created_at", "timestamp with time zone"),
+];
+
+/// The columns of the SQLite station table.
+const SQLITE_STATION_COLUMNS: [(&str, &str); 4] = [
+    ("id", "integer"),
+    ("name", "text"),
+    ("location", "text"),
+    ("created_at", "text"),
+];
+
+/// The columns of the PostgreSQL station metadata table.
+const POSTGRES_STATION_METADATA_COLUMNS: [(&str, &str); 3] = [
+    ("station_id", "integer"),
+    ("metadata", "jsonb"),
+    ("updated_at", "timestamp with time zone"),
+];
+
+/// The columns of the SQLite station metadata table.
+const SQLITE_STATION_METADATA_COLUMNS: [(&str, &str); 3] = [
+    ("station_id", "integer"),
+    ("metadata", "text"),
+    ("updated_at", "text"),
+];
+
+/// The columns of the measurement table needed only by some configs, with their PostgreSQL and
+/// SQLite definitions, whose first word is the type: the uncalibrated values if any station stores
+/// them, the `out_of_range` flag if the config flags such measurements, the dew point and perceived
+/// temperature if they're computed and the packed measurement if `store_raw_packed` is set.
+fn optional_columns(config: &Config) -> Vec<(&'static str, &'static str, &'static str)> {
+    let mut columns = Vec::new();
+
+    if config.stores_raw() {
+        columns.extend([
+            ("temp_raw", "numeric", "real"),
+            ("humidity_raw", "numeric", "real"),
+        ]);
+    }
+
+    if config.flags_out_of_range() {
+        columns.push((
+            "out_of_range",
+            "boolean not null default false",
+            "integer not null default 0",
+        ));
+    }
+
+    if config.compute_dew_point {
+        columns.push(("dew_point", "numeric", "real"));
+    }
+
+    if let Some(perceived_temp) = config.perceived_temp {
+        columns.push((perceived_temp.column(), "numeric", "real"));
+    }
+
+    if config.derives(DerivedMetric::AbsoluteHumidity) {
+        columns.push((DerivedMetric::AbsoluteHumidity.column(), "numeric", "real"));
+    }
+
+    if config.store_raw_packed {
+        columns.push(("raw", "bigint", "integer"));
+    }
+
+    columns
+}
+
+/// The type in a column definition of [`optional_columns`].
+fn column_type(definition: &str) -> &str {
+    definition.split(' ').next().unwrap_or(definition)
+}
+
+/// Creates the measurement table and its unique index on the time and station id unless they
+/// already exist, printing what was created.
+pub async fn init_schema(config: &Config) -> Result<(), Error> {
+    let client = match &config.storage {
+        Storage::Postgres => connect_postgres_client(config).await?,
+        Storage::Sqlite { path } => {
+            let existed = path.exists();
+
+            let connection = open_sqlite(config, path)?;
+
+            if existed {
+                println!("the SQLite database {} already exists", path.display());
+            } else {
+                println!(
+                    "created the SQLite database {} with the {} table",
+                    path.display(),
+                    config.table.name
+                );
+            }
+
+            if config.station_table {
+                let stations = register_sqlite_stations(&connection, config.stations()?)?;
+                println!(
+                    "registered {} stations in the {STATION_TABLE} table",
+                    stations.len()
+                );
+            }
+
+            return Ok(());
+        }
+        Storage::Influxdb { .. } => {
+            return Err(Error::Config(
+                "InfluxDB buckets don't need a schema".to_string(),
+            ));
+        }
+    };
+
+    let table = &config.table;
+    let QuotedTable {
+        name,
+        at,
+        station_id,
+        temp,
+        humidity,
+    } = QuotedTable::new(table);
+
+    let table_exists = client
+        .query_one("select to_regclass($1) is not null", &[&name])
+        .await
+        .map_err(|err| Error::db("checking for the measurement table", err))?
+        .get::<_, bool>(0);
+
+    if table_exists {
+        println!("the {} table already exists", table.name);
+    } else {
+        client
+            .batch_execute(&format!(
+                "create table if not exists {name} (
+                    {at} timestamptz not null,
+                    {station_id} int4 not null,
+                    {temp} numeric not null,
+                    {humidity} numeric not null
+                )"
+            ))
+            .await
+            .map_err(|err| Error::db("creating the measurement table", err))?;
+
+        println!("created the {} table", table.name);
+    }
+
+    let index = format!(
+        "{} ({}, {})",
+        table.name, table.time_column, table.station_id_column
+    );
+
+    if has_unique_index(&client, table).await? {
+        println!("the unique index on {index} already exists");
+    } else {
+        client
+            .batch_execute(&format!("create unique index on {name} ({at}, {station_id})"))
+            .await
+            .map_err(|err| {
+                Error::db(format!("creating the unique index on {index}, which requires the table to be free of duplicates"), err)
+            })?;
+
+        println!("created the unique index on {index}");
+    }
+
+    add_postgres_columns(
+        &client,
+        &table.name,
+        optional_columns(config)
+            .into_iter()
+            .map(|(column, definition, _)| (column, definition)),
+    )
+    .await?;
+
+    if config.station_table {
+        init_station_table(&client, config).await?;
+    }
+
+    if config.has_station_metadata() {
+        init_station_metadata_table(&client).await?;
+    }
+
+    if config.timescale {
+        init_hypertable(&client, config).await?;
+    }
+
+    if let Some(aggregate) = &config.aggregate {
+        let table_exists = client
+            .query_one(
+                "select to_regclass($1) is not null",
+                &[&quote_identifier(AGGREGATE_TABLE)],
+            )
+            .await
+            .map_err(|err| Error::db(format!("checking for the {AGGREGATE_TABLE} table"), err))?
+            .get::<_, bool>(0);
+
+        if table_exists {
+            println!("the {AGGREGATE_TABLE} table already exists");
+        } else {
+            client
+                .batch_execute(&format!(
+                    "create table if not exists {AGGREGATE_TABLE} (
+                        bucket timestamptz not null,
+                        station_id int4 not null,
+                        samples int4 not null,
+                        last_at timestamptz not null,
+                        primary key (bucket, station_id)
+                    )"
+                ))
+                .await
+                .map_err(|err| Error::db(format!("creating the {AGGREGATE_TABLE} table"), err))?;
+
+            println!("created the {AGGREGATE_TABLE} table");
+        }
+
+        add_postgres_columns(
+            &client,
+            AGGREGATE_TABLE,
+            aggregate::columns(&aggregate.functions)
+                .into_iter()
+                .map(|column| (column.name(), "numeric")),
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Creates the station table, registers the configured stations in it and has the station id of the
+/// measurement table reference it, unless they already exist, printing what was created.
+async fn init_station_table(client: &Client, config: &Config) -> Result<(), Error> {
+    let table_exists = client
+        .query_one("select to_regclass($1) is not null", &[&STATION_TABLE])
+        .await
+        .map_err(|err| Error::db(format!("checking for the {STATION_TABLE} table"), err))?
+        .get::<_, bool>(0);
+
+    if table_exists {
+        println!("the {STATION_TABLE} table already exists");
+    } else {
+        client
+            .batch_execute(&format!(
+                "create table if not exists {STATION_TABLE} (
+                    id int4 generated by default as identity primary key,
+                    name text unique,
+                    location text,
+                    created_at timestamptz not null default now()
+                )"
+            ))
+            .await
+            .map_err(|err| Error::db(format!("creating the {STATION_TABLE} table"), err))?;
+
+        println!("created the {STATION_TABLE} table");
+    }
+
+    let stations = register_postgres_stations(client, config.stations()?).await?;
+    println!(
+        "registered {} stations in the {STATION_TABLE} table",
+        stations.len()
+    );
+
+    let table = &config.table;
+    let foreign_key = format!(
+        "the foreign key from {}.{} to {STATION_TABLE} (id)",
+        table.name, table.station_id_column
+    );
+    let foreign_key_exists = client
+        .query_one(
+            "select exists (
+                select from pg_constraint
+                where conrelid = to_regclass($1) and confrelid = to_regclass($2) and contype = 'f'
+            )",
+            &[&quote_identifier(&table.name), &STATION_TABLE],
+        )
+        .await
+        .map_err(|err| Error::db(format!("checking for {foreign_key}"), err))?
+        .get::<_, bool>(0);
+
+    if foreign_key_exists {
+        println!("{foreign_key} already exists");
+    } else {
+        client
+            .batch_execute(&format!(
+                "alter table {} add foreign key ({}) references {STATION_TABLE} (id)",
+                quote_identifier(&table.name),
+                quote_identifier(&table.station_id_column)
+            ))
+            .await
+            .map_err(|err| {
+                Error::db(
+                    format!("adding {foreign_key}, which requires the stations of all stored measurements to be configured"),
+                    err,
+                )
+            })?;
+
+        println!("added {foreign_key}");
+    }
+
+    Ok(())
+}
+
+/// Creates the station metadata table unless it already exists, printing whether it was created.
+async fn init_station_metadata_table(client: &Client) -> Result<(), Error> {
+    let table_exists = client
+        .query_one(
+            "select to_regclass($1) is not null",
+            &[&STATION_METADATA_TABLE],
+        )
+        .await
+        .map_err(|err| {
+            Error::db(
+                format!("checking for the {STATION_METADATA_TABLE} table"),
+                err,
+            )
+        })?
+        .get::<_, bool>(0);
+
+    if table_exists {
+        println!("the {STATION_METADATA_TABLE} table already exists");
+        return Ok(());
+    }
+
+    client
+        .batch_execute(&format!(
+            "create table if not exists {STATION_METADATA_TABLE} (
+                station_id int4 primary key,
+                metadata jsonb not null,
+                updated_at timestamptz not null default now()
+            )"
+        ))
+        .await
+        .map_err(|err| Error::db(format!("creating the {STATION_METADATA_TABLE} table"), err))?;
+
+    println!("created the {STATION_METADATA_TABLE} table");
+    Ok(())
+}
+
+/// Makes the measurement table a TimescaleDB hypertable with chunks of `timescale_chunk_interval`
+/// and adds the retention policy, unless they already exist, printing what was created.
+async fn init_hypertable(client: &Client, config: &Config) -> Result<(), Error> {
+    let installed = client
+        .query_one(
+            "select exists (select from pg_extension where extname = 'timescaledb')",
+            &[],
+        )
+        .await
+        .map_err(|err| Error::db("checking for the timescaledb extension", err))?
+        .get::<_, bool>(0);
+
+    if !installed {
+        return Err(Error::db(
+            "creating the hypertable",
+            "the timescaledb extension isn't installed in the database, install TimescaleDB and \
+             run `create extension timescaledb` first",
+        ));
+    }
+
+    let table = &config.table;
+    let created = client
+        .query_one(
+            "select created from create_hypertable(
+                $1::text::regclass, $2::text::name,
+                chunk_time_interval => $3::text::interval,
+                if_not_exists => true,
+                migrate_data => true
+            )",
+            &[
+                &quote_identifier(&table.name),
+                &table.time_column,
+                &config.timescale_chunk_interval,
+            ],
+        )
+        .await
+        .map_err(|err| Error::db("making the measurement table a hypertable", err))?
+        .get::<_, bool>(0);
+
+    match created {
+        true => println!(
+            "made the {} table a hypertable with chunks of {}",
+            table.name, config.timescale_chunk_interval
+        ),
+        false => println!("the {} table already is a hypertable", table.name),
+    }
+
+    let Some(retention) = &config.retention else {
+        return Ok(());
+    };
+
+    let policy_exists = client
+        .query_one(
+            "select exists (
+                select from timescaledb_information.jobs
+                where proc_name = 'policy_retention' and hypertable_name = $1
+            )",
+            &[&table.name],
+        )
+        .await
+        .map_err(|err| Error::db("checking for the retention policy", err))?
+        .get::<_, bool>(0);
+
+    if policy_exists {
+        println!(
+            "the retention policy on the {} table already exists",
+            table.name
+        );
+    } else {
+        client
+            .execute(
+                "select add_retention_policy($1::text::regclass, $2::text::interval)",
+                &[&quote_identifier(&table.name), &retention.as_str()],
+            )
+            .await
+            .map_err(|err| Error::db("adding the retention policy", err))?;
+
+        println!(
+            "added the retention policy dropping the measurements older than {}",
+            retention.as_str()
+        );
+    }
+
+    Ok(())
+}
+
+/// Adds the `columns` with their definitions to the PostgreSQL `table` which it doesn't have yet,
+/// printing which were added.
+async fn add_postgres_columns<'c>(
+    client: &Client,
+    table: &str,
+    columns: impl IntoIterator<Item = (&'c str, &'c str)>,
+) -> Result<(), Error> {
+    for (column, definition) in columns {
+        let column_exists = client
+            .query_one(
+                "select exists (
+                    select from information_schema.columns
+                    where table_name = $1
+                        and table_schema = any(current_schemas(false))
+                        and column_name = $2
+                )",
+                &[&table, &column],
+            )
+            .await
+            .map_err(|err| Error::db(format!("checking for the {column} column"), err))?
+            .get::<_, bool>(0);
+
+        if column_exists {
+            println!("the {column} column already exists");
+        } else {
+            client
+                .batch_execute(&format!(
+                    "alter table {} add column {} {definition}",
+                    quote_identifier(table),
+                    quote_identifier(column)
+                ))
+                .await
+                .map_err(|err| Error::db(format!("adding the {column} column"), err))?;
+
+            println!("added the {column} column");
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks whether the PostgreSQL measurement `table` has a unique index on exactly its time and
+/// station id.
+async fn has_unique_index(client: &Client, table: &MeasurementTable) -> Result<bool, Error> {
+    // Ordered like `attname` is, by its bytes.
+    let mut columns = [&table.time_column, &table.station_id_column];
+    columns.sort();
+
+    let row = client
+        .query_one(
+            "select exists (
+                select from pg_index index
+                where index.indrelid = to_regclass($1)
+                    and index.indisunique
+                    and (
+                        select array_agg(attname::text order by attname)
+                        from pg_attribute
+                        where attrelid = index.indrelid and attnum = any(index.indkey)
+                    ) = $2::text[]
+            )",
+            &[&quote_identifier(&table.name), &columns.as_slice()],
+        )
+        .await
+        .map_err(|err| {
+            Error::db(
+                "checking for the unique index on the measurement table",
+                err,
+            )
+        })?;
+
+    Ok(row.get(0))
+}
+
+/// Compares the measurement table (and the aggregate table, if configured) in the database with
+/// the ones this program expects, returning a description of every mismatch.
+pub async fn check_schema(config: &Config) -> Result<Vec<String>, Error> {
+    let table = &config.table;
+    let aggregate_columns = config
+        .aggregate
+        .as_ref()
+        .map(|aggregate| aggregate::columns(&aggregate.functions));
+
+    let (tables, missing_index) = match &config.storage {
+        Storage::Postgres => {
+            let client = connect_postgres_client(config).await?;
+
+            let columns = postgres_table_columns(&client, &table.name).await?;
+
+            let missing_index = !columns.is_empty()
+                && !config.plain_insert
+                && !has_unique_index(&client, table).await?;
+
+            let mut expected_columns = table_columns(table, POSTGRES_COLUMN_TYPES);
+            expected_columns.extend(
+                optional_columns(config)
+                    .into_iter()
+                    .map(|(name, postgres, _)| (name, column_type(postgres))),
+            );
+
+            let mut tables = vec![(table.name.as_str(), columns, expected_columns)];
+
+            if let Some(aggregate_columns) = aggregate_columns {
+                let mut expected_columns = POSTGRES_AGGREGATE_COLUMNS.to_vec();
+                expected_columns.extend(
+                    aggregate_columns
+                        .into_iter()
+                        .map(|column| (column.name(), "numeric")),
+                );
+
+                tables.push((
+                    AGGREGATE_TABLE,
+                    postgres_table_columns(&client, AGGREGATE_TABLE).await?,
+                    expected_columns,
+                ));
+            }
+
+            if config.station_table {
+                tables.push((
+                    STATION_TABLE,
+                    postgres_table_columns(&client, STATION_TABLE).await?,
+                    POSTGRES_STATION_COLUMNS.to_vec(),
+                ));
+            }
+
+            if config.has_station_metadata() {
+                tables.push((
+                    STATION_METADATA_TABLE,
+                    postgres_table_columns(&client, STATION_METADATA_TABLE).await?,
+                    POSTGRES_STATION_METADATA_COLUMNS.to_vec(),
+                ));
+            }
+
+            (tables, missing_index)
+        }
+        Storage::Sqlite { path } => {
+            if !path.exists() {
+                return Ok(vec![format!(
+                    "the SQLite database {} doesn't exist (create it with --init-db)",
+                    path.display()
+                )]);
+            }
+
+            let connection = rusqlite::Connection::open(path).map_err(|err| {
+                Error::db(format!("opening SQLite database {}", path.display()), err)
+            })?;
+
+            let mut expected_columns = table_columns(table, SQLITE_COLUMN_TYPES);
+            expected_columns.extend(
+                optional_columns(config)
+                    .into_iter()
+                    .map(|(name, _, sqlite)| (name, column_type(sqlite))),
+            );
+
+            let mut tables = vec![(
+                table.name.as_str(),
+                sqlite_table_columns(&connection, &table.name)?,
+                expected_columns,
+            )];
+
+            if let Some(aggregate_columns) = aggregate_columns {
+                let mut expected_columns = SQLITE_AGGREGATE_COLUMNS.to_vec();
+                expected_columns.extend(
+                    aggregate_columns
+                        .into_iter()
+                        .map(|column| (column.name(), "real")),
+                );
+
+                tables.push((
+                    AGGREGATE_TABLE,
+                    sqlite_table_columns(&connection, AGGREGATE_TABLE)?,
+                    expected_columns,
+                ));
+            }
+
+            if config.station_table {
+                tables.push((
+                    STATION_TABLE,
+                    sqlite_table_columns(&connection, STATION_TABLE)?,
+                    SQLITE_STATION_COLUMNS.to_vec(),
+                ));
+            }
+
+            if config.has_station_metadata() {
+                tables.push((
+                    STATION_METADATA_TABLE,
+                    sqlite_table_columns(&connection, STATION_METADATA_TABLE)?,
+                    SQLITE_STATION_METADATA_COLUMNS.to_vec(),
+                ));
+            }
+
+            // The SQLite table is always created along with its unique constraint.
+            (tables, false)
+        }
+        Storage::Influxdb { .. } => {
+            return Err(Error::Config(
+                "InfluxDB buckets don't have a schema to check".to_string(),
+            ));
+        }
+    };
+
+    let mut problems = Vec::new();
+
+    for (table, columns, expected_columns) in tables {
+        if columns.is_empty() {
+            problems.push(format!(
+                "the {table} table doesn't exist (create it with --init-db)"
+            ));
+            continue;
+        }
+
+        for (name, expected_type) in expected_columns {
+            match columns.get(name) {
+                None => problems.push(format!("the {table} table has no {name} column")),
+                Some(column_type) if column_type != expected_type => problems.push(format!(
+                    "the {name} column of the {table} table is of type {column_type} instead of {expected_type}"
+                )),
+                Some(_) => {}
+            }
+        }
+    }
+
+    if missing_index {
+        problems.push(format!(
+            "the {} table has no unique index on ({}, {}) (create it with --init-db or set plain_insert)",
+            table.name, table.time_column, table.station_id_column
+        ));
+    }
+
+    Ok(problems)
+}
+
+/// The columns of the PostgreSQL `table` and their types, empty if it doesn't exist.
+async fn postgres_table_columns(
+    client: &Client,
+    table: &str,
+) -> Result<BTreeMap<String, String>, Error> {
+    let rows = client
+        .query(
+            "select column_name::text, data_type::text from information_schema.columns
+            where table_name = $1 and table_schema = any(current_schemas(false))",
+            &[&table],
+        )
+        .await
+        .map_err(|err| Error::db(format!("reading the columns of the {table} table"), err))?;
+
+    Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+}
+
+/// The columns of the SQLite `table` and their types, empty if it doesn't exist.
+fn sqlite_table_columns(
+    connection: &rusqlite::Connection,
+    table: &str,
+) -> Result<BTreeMap<String, String>, Error> {
+    connection
+        .prepare("select name, lower(type) from pragma_table_info(?1)")
+        .and_then(|mut statement| {
+            statement
+                .query_map([table], |row| Ok((row.get(0)?, row.get(1)?)))
+                .and_then(|rows| rows.collect())
+        })
+        .map_err(|err| Error::db(format!("reading the columns of the {table} table"), err))
+}
+
+/// Batches with at least this many measurements are inserted with `COPY` instead of row by row.
+const COPY_THRESHOLD: usize = 1000;
+
+/// How many of the measurements which couldn't be inserted are reported individually.
+pub const REPORTED_ROW_FAILURES: usize = 5;
+
+/// A measurement which couldn't be inserted.
+#[derive(Debug)]
+pub struct RowFailure {
+    pub time: DateTime<FixedOffset>,
+    pub error: String,
+}
+
+/// The outcome of inserting the measurements of a station.
+#[derive(Debug, Default)]
+pub struct InsertSummary {
+    /// How many measurements weren't already present.
+    pub inserted: u64,
+    pub failures: Vec<RowFailure>,
+    /// How many measurements were spooled to be inserted later instead.
+    pub spooled: usize,
+    /// How many measurements were buffered to be inserted later instead.
+    pub buffered: usize,
+    /// How many buckets of the aggregate table were inserted or extended.
+    pub aggregated: u64,
+    /// How many measurements were sent to the database, new or not.
+    pub committed: usize,
+    /// How long the inserts took, not counting the waits for the connection.
+    pub elapsed: Duration,
+}
+
+/// Inserts `measurements` for `station`.
+///
+/// The measurements are committed in batches of `batch_size` (all at once by default), so an
+/// error leaves either all or none of the measurements of a batch in the database. Measurements
+/// which can't be inserted are skipped and reported in the summary, unless `fail_fast` is set, in
+/// which case the first one fails the whole insert.
+pub async fn insert_measurements(
+    config: &Config,
+    db: &mut Database,
+    station: &Station,
+    measurements: &[Measurement],
+    fail_fast: bool,
+) -> Result<InsertSummary, Error> {
+    let mut insert = StationInsert::new(config, station, fail_fast, Some(measurements.len()));
+    insert.write(db, measurements).await?;
+    Ok(insert.finish())
+}
+
+/// The insert of the measurements of a station arriving in chunks, see [`insert_measurements`].
+///
+/// The chunks are held until they fill a batch, so a download failing before the last one leaves
+/// nothing of the unfilled batch in the database.
+pub struct StationInsert<'a> {
+    config: &'a Config,
+    station: &'a Station,
+    fail_fast: bool,
+    /// The measurements pushed since the last batch was inserted.
+    pending: Vec<Measurement>,
+    /// The time of the latest measurement stored before the insert, once queried.
+    latest_stored: Option<Option<DateTime<Utc>>>,
+    /// Whether all measurements so far were already stored.
+    in_known_prefix: bool,
+    /// The time of the latest measurement aggregated before the insert, once queried.
+    latest_aggregated: Option<Option<DateTime<Utc>>>,
+    known: usize,
+    regressed: usize,
+    committed: usize,
+    /// How many batches were committed.
+    batches: usize,
+    summary: InsertSummary,
+    progress: Progress,
+    span: Span,
+}
+
+impl<'a> StationInsert<'a> {
+    /// Starts the insert of `total` measurements, if known up front.
+    pub fn new(
+        config: &'a Config,
+        station: &'a Station,
+        fail_fast: bool,
+        total: Option<usize>,
+    ) -> StationInsert<'a> {
+        StationInsert {
+            config,
+            station,
+            fail_fast,
+            pending: Vec::new(),
+            latest_stored: None,
+            in_known_prefix: true,
+            latest_aggregated: None,
+            known: 0,
+            regressed: 0,
+            committed: 0,
+            batches: 0,
+            summary: InsertSummary::default(),
+            progress: Progress::new("committed", total.map(|total| total as u64)),
+            span: info_span!("db-insert", rows = field::Empty, batches = field::Empty),
+        }
+    }
+
+    /// Adds the next chunk of measurements, inserting them as soon as they fill a batch of
+    /// `batch_size`.
+    pub async fn push(
+        &mut self,
+        db: &mut Database,
+        measurements: &[Measurement],
+    ) -> Result<(), Error> {
+        self.pending.extend_from_slice(measurements);
+
+        let Some(batch_size) = self.config.batch_size else {
+            return Ok(());
+        };
+
+        while self.pending.len() >= batch_size.max(1) {
+            let rest = self.pending.split_off(batch_size.max(1));
+            let batch = std::mem::replace(&mut self.pending, rest);
+
+            if let Err(err) = self.write(db, &batch).await {
+                self.pending.splice(0..0, batch);
+                return Err(err);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Inserts the measurements pushed since the last batch, once all of them were.
+    pub async fn flush(&mut self, db: &mut Database) -> Result<(), Error> {
+        let batch = std::mem::take(&mut self.pending);
+        let result = self.write(db, &batch).await;
+
+        if result.is_err() {
+            self.pending = batch;
+        }
+
+        result
+    }
+
+    /// Takes the measurements which weren't inserted yet, for storing them elsewhere after pushing
+    /// or flushing them failed.
+    pub fn take_pending(&mut self) -> Vec<Measurement> {
+        std::mem::take(&mut self.pending)
+    }
+
+    /// Inserts `measurements` right away.
+    async fn write(
+        &mut self,
+        db: &mut Database,
+        measurements: &[Measurement],
+    ) -> Result<(), Error> {
+        if measurements.is_empty() {
+            return Ok(());
+        }
+
+        let start = Instant::now();
+        let result = traced(self.span.clone(), self.push_chunk(db, measurements)).await;
+        self.summary.elapsed += start.elapsed();
+        result
+    }
+
+    async fn push_chunk(
+        &mut self,
+        db: &mut Database,
+        measurements: &[Measurement],
+    ) -> Result<(), Error> {
+        if self
+            .config
+            .aggregate
+            .as_ref()
+            .is_none_or(|aggregate| aggregate.keep_measurements)
+        {
+            self.insert(db, measurements).await?;
+        }
+
+        if let Some(aggregate) = &self.config.aggregate {
+            let latest_aggregated = match self.latest_aggregated {
+                Some(latest_aggregated) => latest_aggregated,
+                None => *self
+                    .latest_aggregated
+                    .insert(db.latest_aggregated(self.station).await?),
+            };
+
+            let buckets =
+                aggregate::aggregate(self.config, aggregate, measurements, latest_aggregated);
+
+            self.summary.aggregated +=
+                store_aggregates(self.config, db, self.station, &buckets).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Inserts the next chunk of measurements into the measurement table.
+    async fn insert(
+        &mut self,
+        db: &mut Database,
+        measurements: &[Measurement],
+    ) -> Result<(), Error> {
+        let measurements = if self.config.skip_known {
+            self.skip_known(db, measurements).await?
+        } else {
+            measurements
+        };
+
+        let batch_size = self.config.batch_size.unwrap_or(usize::MAX).max(1);
+
+        for batch in measurements.chunks(batch_size) {
+            if SHUTDOWN.is_requested() {
+                return Err(Error::Interrupted(format!(
+                    "after committing {} measurements",
+                    self.committed
+                )));
+            }
+
+            let failures = &mut self.summary.failures;
+
+            let inserted = match db {
+                Database::Postgres(postgres) => {
+                    insert_batch(
+                        self.config,
+                        postgres,
+                        self.station,
+                        batch,
+                        self.fail_fast,
+                        failures,
+                    )
+                    .await?
+                }
+                Database::Sqlite(connection) => insert_sqlite_batch(
+                    self.config,
+                    connection,
+                    self.station,
+                    batch,
+                    self.fail_fast,
+                    failures,
+                )?,
+                Database::Influxdb(client) => {
+                    write_influxdb(self.config, client, self.station, batch).await?
+                }
+            };
+
+            self.summary.inserted += inserted.len() as u64;
+            self.committed += batch.len();
+            self.batches += 1;
+            self.progress.update(self.committed as u64, None);
+            crate::live::publish(
+                self.station,
+                &inserted,
+                self.config.output_units.temperature,
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Skips the leading measurements which aren't newer than the latest one already stored for
+    /// the station.
+    ///
+    /// Measurements which follow a newer one are kept even if they are older than the latest stored
+    /// one, since the Pico's clock must have gone backwards for them.
+    async fn skip_known<'m>(
+        &mut self,
+        db: &Database,
+        measurements: &'m [Measurement],
+    ) -> Result<&'m [Measurement], Error> {
+        let latest_stored = match self.latest_stored {
+            Some(latest_stored) => latest_stored,
+            None => *self
+                .latest_stored
+                .insert(db.latest_time(self.config, self.station).await?),
+        };
+
+        let Some(latest_time) = latest_stored else {
+            return Ok(measurements);
+        };
+
+        let mut measurements = measurements;
+
+        if self.in_known_prefix {
+            let known = measurements
+                .iter()
+                .take_while(|measurement| measurement.time <= latest_time)
+                .count();
+
+            self.known += known;
+            measurements = &measurements[known..];
+            self.in_known_prefix = measurements.is_empty();
+        }
+
+        self.regressed += measurements
+            .iter()
+            .filter(|measurement| measurement.time <= latest_time)
+            .count();
+
+        Ok(measurements)
+    }
+
+    /// Ends the insert, returning its summary.
+    pub fn finish(self) -> InsertSummary {
+        if self.summary.aggregated > 0 {
+            info!(
+                "updated {} buckets of the {AGGREGATE_TABLE} table",
+                self.summary.aggregated
+            );
+        }
+
+        if self.known > 0 {
+            info!(
+                "skipped {} measurements which are already stored",
+                self.known
+            );
+        }
+
+        if self.regressed > 0 {
+            warn!(
+                "{} measurements are older than the latest stored one although they follow newer ones, \
+                 the Pico's clock may have gone backwards; they were inserted anyway",
+                self.regressed
+            );
+        }
+
+        self.span.record("rows", self.summary.inserted);
+        self.span.record("batches", self.batches);
+
+        InsertSummary {
+            committed: self.committed,
+            ..self.summary
+        }
+    }
+}
+
+/// Inserts `batch` in one transaction, returning those of its measurements which weren't already
+/// present.
+///
+/// Large batches are copied into the database in one go, falling back to inserting them row by row
+/// if that fails. When inserting row by row, a lost database connection is reestablished and the
+/// batch is retried; since its transaction wasn't committed, no measurement ends up inserted twice.
+/// If the database rejects the batch otherwise, its measurements are inserted one at a time, adding
+/// the rejected ones to `failures` (unless `fail_fast` is set).
+async fn insert_batch<'m>(
+    config: &Config,
+    db: &mut Postgres,
+    station: &Station,
+    batch: &'m [Measurement],
+    fail_fast: bool,
+    failures: &mut Vec<RowFailure>,
+) -> Result<Vec<&'m Measurement>, Error> {
+    if batch.len() >= COPY_THRESHOLD {
+        match copy_measurements(config, &mut db.client, station, batch).await {
+            Ok(inserted) => return Ok(inserted),
+            Err(err) => warn!(
+                "copying the measurements into the database failed: {err}; inserting them row by row"
+            ),
+        }
+    }
+
+    let mut reconnects = 0;
+    let mut one_at_a_time = false;
+
+    loop {
+        let insert_statements = db.insert_statements(station).clone();
+        let start = Instant::now();
+
+        let result = if one_at_a_time {
+            execute_rows(
+                config,
+                &mut db.client,
+                &insert_statements.row,
+                station,
+                batch,
+            )
+            .await
+        } else {
+            execute_batch(config, &mut db.client, &insert_statements, station, batch)
+                .await
+                .map(|inserted| (inserted, Vec::new()))
+        };
+
+        let err = match result {
+            Ok((inserted, batch_failures)) => {
+                let elapsed = start.elapsed();
+                let rows_per_insert = match (one_at_a_time, &insert_statements.rows) {
+                    (false, Some(_)) => config.rows_per_insert,
+                    _ => 1,
+                };
+                let pipeline_depth = match one_at_a_time {
+                    false => config.insert_pipeline_depth,
+                    true => 1,
+                };
+                debug!(
+                    rows_per_insert,
+                    pipeline_depth,
+                    elapsed_ms = elapsed.as_millis() as u64,
+                    "inserted a batch of {} measurements in {elapsed:.1?} with {rows_per_insert} rows per statement and up to {pipeline_depth} in flight, {:.0} rows/sec",
+                    batch.len(),
+                    batch.len() as f64 / elapsed.as_secs_f64()
+                );
+                failures.extend(batch_failures);
+                return Ok(inserted);
+            }
+            Err(err) => err,
+        };
+
+        if !db.client.is_closed() {
+            if fail_fast || one_at_a_time {
+                return Err(Error::db("inserting measurement", err));
+            }
+
+            warn!(
+                "inserting a batch of {} measurements failed: {err}; inserting them one at a time",
+                batch.len()
+            );
+
+            one_at_a_time = true;
+            continue;
+        }
+
+        if reconnects >= config.db_connect_retries {
+            return Err(Error::db(
+                format!(
+                    "inserting measurements: database connection lost {} times",
+                    reconnects + 1
+                ),
+                err,
+            ));
+        }
+
+        reconnects += 1;
+
+        warn!(
+            "database connection lost while inserting a batch of {} measurements: {err}; reconnecting",
+            batch.len()
+        );
+
+        *db = connect_postgres(config).await?;
+    }
+}
+
+/// Inserts the aggregated `buckets` of `station` into the aggregate table in one transaction,
+/// adding them to the ones already stored. Returns how many buckets were inserted or extended.
+async fn store_aggregates(
+    config: &Config,
+    db: &mut Database,
+    station: &Station,
+    buckets: &[Bucket],
+) -> Result<u64, Error> {
+    let Some(aggregate) = &config.aggregate else {
+        return Ok(0);
+    };
+
+    if buckets.is_empty() {
+        return Ok(0);
+    }
+
+    fn failed(err: impl DbCause) -> Error {
+        Error::db("storing the aggregated measurements", err)
+    }
+
+    let columns = aggregate::columns(&aggregate.functions);
+    let (least, greatest) = match db {
+        Database::Sqlite(_) => ("min", "max"),
+        _ => ("least", "greatest"),
+    };
+    let names: Vec<_> = columns.iter().map(|column| column.name()).collect();
+    let updates: Vec<_> = columns
+        .iter()
+        .map(|column| format!("{} = {}", column.name(), column.merged(least, greatest)))
+        .collect();
+    let statement = |placeholder: char| {
+        format!(
+            "insert into {AGGREGATE_TABLE} (bucket, station_id, samples, last_at{}) values ({})
+            on conflict (bucket, station_id) do update set
+                samples = {AGGREGATE_TABLE}.samples + excluded.samples,
+                last_at = {greatest}({AGGREGATE_TABLE}.last_at, excluded.last_at){}",
+            names
+                .iter()
+                .map(|name| format!(", {name}"))
+                .collect::<String>(),
+            (1..=names.len() + 4)
+                .map(|index| format!("{placeholder}{index}"))
+                .collect::<Vec<_>>()
+                .join(", "),
+            updates
+                .iter()
+                .map(|update| format!(",\n{update}"))
+                .collect::<String>(),
+        )
+    };
+
+    match db {
+        Database::Postgres(postgres) => {
+            let statement = statement('$');
+            let transaction = postgres.client.transaction().await.map_err(failed)?;
+
+            for bucket in buckets {
+                let samples = bucket.samples as i32;
+                let values: Vec<_> = columns.iter().map(|column| column.value(bucket)).collect();
+                let mut params: Vec<&(dyn ToSql + Sync)> = vec![
+                    &bucket.start,
+                    &station.station_id,
+                    &samples,
+                    &bucket.last_at,
+                ];
+                params.extend(values.iter().map(|value| value as &(dyn ToSql + Sync)));
+
+                transaction
+                    .execute(&statement, &params)
+                    .await
+                    .map_err(failed)?;
+            }
+
+            transaction.commit().await.map_err(failed)?;
+        }
+        Database::Sqlite(connection) => {
+            let statement = statement('?');
+            let transaction = connection.transaction().map_err(failed)?;
+
+            for bucket in buckets {
+                let mut params: Vec<Box<dyn rusqlite::ToSql>> = vec![
+                    Box::new(bucket.start.with_timezone(&Utc)),
+                    Box::new(station.station_id),
+                    Box::new(bucket.samples),
+                    Box::new(bucket.last_at.with_timezone(&Utc)),
+                ];
+                params.extend(columns.iter().map(|column| {
+                    Box::new(column.value(bucket).to_f64()) as Box<dyn rusqlite::ToSql>
+                }));
+
+                transaction
+                    .execute(&statement, rusqlite::params_from_iter(params))
+                    .map_err(failed)?;
+            }
+
+            transaction.commit().map_err(failed)?;
+        }
+        Database::Influxdb(_) => {
+            return Err(failed("InfluxDB storage doesn't support aggregates"));
+        }
+    }
+
+    Ok(buckets.len() as u64)
+}
+
+/// The maximum number of lines written to InfluxDB in one request.
+const INFLUXDB_BATCH_SIZE: usize = 5000;
+
+/// Writes `batch` to the configured InfluxDB bucket in line protocol, in requests of at most
+/// [`INFLUXDB_BATCH_SIZE`] lines.
+///
+/// Requests which are rate limited or fail with a server error are retried with exponential
+/// backoff as configured for the database connection. Since InfluxDB overwrites points with the
+/// same timestamp, every written measurement counts as inserted.
+async fn write_influxdb<'m>(
+    config: &Config,
+    client: &reqwest::Client,
+    station: &Station,
+    batch: &'m [Measurement],
+) -> Result<Vec<&'m Measurement>, Error> {
+    let Storage::Influxdb {
+        url,
+        org,
+        bucket,
+        token,
+    } = &config.storage
+    else {
+        unreachable!("InfluxDB client without InfluxDB storage")
+    };
+
+    let write_url = format!("{}/api/v2/write", url.trim_end_matches('/'));
+
+    for lines in batch.chunks(INFLUXDB_BATCH_SIZE) {
+        let body = lines
+            .iter()
+            .map(|measurement| {
+                let timestamp = measurement.time.timestamp_nanos_opt().ok_or_else(|| {
+                    Error::db(
+                        format!("converting {} to an InfluxDB timestamp", measurement.time),
+                        "out of range",
+                    )
+                })?;
+
+                let mut extra_fields = String::new();
+
+                if station.store_raw {
+                    let (temp, humidity) = measurement.raw();
+                    extra_fields += &format!(",temp_raw={temp},humidity_raw={humidity}");
+                }
+
+                if config.flags_out_of_range() {
+                    extra_fields += &format!(",out_of_range={}", measurement.out_of_range);
+                }
+
+                if let Some(dew_point) = measurement.dew_point {
+                    extra_fields += &format!(",dew_point={dew_point}");
+                }
+
+                if let (Some(perceived_temp), Some(value)) =
+                    (config.perceived_temp, measurement.perceived_temp)
+                {
+                    extra_fields += &format!(",{}={value}", perceived_temp.column());
+                }
+
+                if let Some(absolute_humidity) = measurement.absolute_humidity {
+                    extra_fields += &format!(
+                        ",{}={absolute_humidity}",
+                        DerivedMetric::AbsoluteHumidity.column()
+                    );
+                }
+
+                if let (true, Some(packed)) = (config.store_raw_packed, measurement.packed) {
+                    extra_fields += &format!(",raw={packed}i");
+                }
+
+                Ok(format!(
+                    "environment,station_id={} temp={},humidity={}{extra_fields} {timestamp}\n",
+                    station.station_id, measurement.temp, measurement.humidity
+                ))
+            })
+            .collect::<Result<String, Error>>()?;
+
+        let mut backoff = Duration::from_millis(config.db_connect_backoff_ms);
+        let mut attempts = 0;
+
+        loop {
+            attempts += 1;
+
+            let response = client
+                .post(&write_url)
+                .query(&[
+                    ("org", org.as_str()),
+                    ("bucket", bucket.as_str()),
+                    ("precision", "ns"),
+                ])
+                .header("Authorization", format!("Token {token}"))
+                .header("Content-Type", "text/plain; charset=utf-8")
+                .body(body.clone())
+                .send()
+                .await
+                .map_err(|err| Error::db("sending measurements to InfluxDB", err))?;
+
+            let status = response.status();
+
+            if status.is_success() {
+                break;
+            }
+
+            let retryable =
+                status == reqwest::StatusCode::TOO_MANY_REQUESTS || status.is_server_error();
+
+            let response_body = response.text().await.unwrap_or_default();
+
+            if !retryable || attempts > config.db_connect_retries {
+                return Err(Error::db(
+                    format!("writing measurements to InfluxDB after {attempts} attempts"),
+                    format!("{status}: {response_body}"),
+                ));
+            }
+
+            warn!(
+                attempt = attempts,
+                "writing measurements to InfluxDB failed: {status}: {response_body}; retrying in {backoff:.1?}"
+            );
+
+            tokio::time::sleep(backoff).await;
+
+            backoff = backoff.saturating_mul(2);
+        }
+    }
+
+    // InfluxDB overwrites the points with the same time, so every one counts as written.
+    Ok(batch.iter().collect())
+}
+
+/// Inserts `batch` into the SQLite database in one transaction, skipping the measurements which
+/// are already present and returning the inserted ones.
+fn insert_sqlite_batch<'m>(
+    config: &Config,
+    connection: &mut rusqlite::Connection,
+    station: &Station,
+    batch: &'m [Measurement],
+    fail_fast: bool,
+    failures: &mut Vec<RowFailure>,
+) -> Result<Vec<&'m Measurement>, Error> {
+    let transaction = connection
+        .transaction()
+        .map_err(|err| Error::db("starting SQLite transaction", err))?;
+
+    let mut inserted = Vec::new();
+
+    {
+        let names: Vec<_> = insert_columns(config, station.store_raw)
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        let placeholders: Vec<_> = (1..=names.len()).map(|index| format!("?{index}")).collect();
+
+        let mut statement = transaction
+            .prepare_cached(&format!(
+                "insert or ignore into {} ({}) values ({})",
+                quote_identifier(&config.table.name),
+                column_list(&names),
+                placeholders.join(", ")
+            ))
+            .map_err(|err| Error::db("preparing measurement insertion statement", err))?;
+
+        for measurement in batch {
+            let (raw_temp, raw_humidity) = measurement.raw();
+            let mut params: Vec<Box<dyn rusqlite::ToSql>> = vec![
+                Box::new(measurement.time.with_timezone(&Utc)),
+                Box::new(station.station_id),
+                Box::new(measurement.temp.to_f64()),
+                Box::new(measurement.humidity.to_f64()),
+            ];
+
+            if station.store_raw {
+                params.push(Box::new(raw_temp.to_f64()));
+                params.push(Box::new(raw_humidity.to_f64()));
+            }
+
+            if config.flags_out_of_range() {
+                params.push(Box::new(measurement.out_of_range));
+            }
+
+            if config.compute_dew_point {
+                params.push(Box::new(
+                    measurement
+                        .dew_point
+                        .and_then(|dew_point| dew_point.to_f64()),
+                ));
+            }
+
+            if config.perceived_temp.is_some() {
+                params.push(Box::new(
+                    measurement.perceived_temp.and_then(|value| value.to_f64()),
+                ));
+            }
+
+            if config.derives(DerivedMetric::AbsoluteHumidity) {
+                params.push(Box::new(
+                    measurement
+                        .absolute_humidity
+                        .and_then(|value| value.to_f64()),
+                ));
+            }
+
+            if config.store_raw_packed {
+                params.push(Box::new(measurement.packed));
+            }
+
+            // A failed statement is rolled back on its own, leaving the transaction usable.
+            match statement.execute(rusqlite::params_from_iter(params)) {
+                Ok(0) => {}
+                Ok(_) => inserted.push(measurement),
+                Err(err) if fail_fast => return Err(Error::db("inserting measurement", err)),
+                Err(err) => failures.push(RowFailure {
+                    time: measurement.time,
+                    error: err.to_string(),
+                }),
+            }
+        }
+    }
+
+    transaction
+        .commit()
+        .map_err(|err| Error::db("committing SQLite transaction", err))?;
+
+    Ok(inserted)
+}
+
+/// Inserts `batch` in one transaction, `rows_per_insert` measurements per statement and the rest
+/// which doesn't fill one row by row, and returns the inserted measurements.
+///
+/// Up to `insert_pipeline_depth` statements are in flight at once, which [`insert_batch`] only
+/// gets to for batches below [`COPY_THRESHOLD`]. They're still executed in the
+/// order of the measurements, and their results taken in that order, so a failing statement
+/// returns its own error rather than one of the later ones failing in the aborted transaction.
+async fn execute_batch<'m>(
+    config: &Config,
+    client: &mut Client,
+    insert_statements: &InsertStatements,
+    station: &Station,
+    batch: &'m [Measurement],
+) -> Result<Vec<&'m Measurement>, tokio_postgres::Error> {
+    let transaction = client.transaction().await?;
+    let mut statements = Vec::new();
+    let mut rest = batch;
+
+    if let Some(rows_statement) = &insert_statements.rows {
+        let mut rows = batch.chunks_exact(config.rows_per_insert);
+        statements.extend((&mut rows).map(|rows| (rows_statement, rows)));
+        rest = rows.remainder();
+    }
+
+    statements.extend(rest.chunks(1).map(|row| (&insert_statements.row, row)));
+
+    let transaction_ref = &transaction;
+    let inserted = stream::iter(statements)
+        .map(|(statement, rows)| async move {
+            let params = rows
+                .iter()
+                .flat_map(|measurement| insert_params(config, station, measurement))
+                .collect::<Vec<_>>();
+
+            let returned = transaction_ref.query(statement, &params).await?;
+            written(rows, &returned)
+        })
+        .buffered(config.insert_pipeline_depth)
+        .try_fold(Vec::new(), |mut inserted, rows| async move {
+            inserted.extend(rows);
+            Ok(inserted)
+        })
+        .await?;
+
+    transaction.commit().await?;
+
+    Ok(inserted)
+}
+
+/// Inserts `batch` in one transaction with a savepoint per measurement, so the measurements the
+/// database rejects are skipped and returned along with the inserted ones.
+async fn execute_rows<'m>(
+    config: &Config,
+    client: &mut Client,
+    insert_statement: &Statement,
+    station: &Station,
+    batch: &'m [Measurement],
+) -> Result<(Vec<&'m Measurement>, Vec<RowFailure>), tokio_postgres::Error> {
+    let mut transaction = client.transaction().await?;
+    let mut inserted = Vec::new();
+    let mut failures = Vec::new();
+
+    for measurement in batch {
+        let savepoint = transaction.savepoint("measurement").await?;
+
+        let result = savepoint
+            .execute(
+                insert_statement,
+                &insert_params(config, station, measurement),
+            )
+            .await;
+
+        match result {
+            Ok(rows) => {
+                savepoint.commit().await?;
+
+                if rows > 0 {
+                    inserted.push(measurement);
+                }
+            }
+            Err(err) if err.as_db_error().is_some() => {
+                savepoint.rollback().await?;
+                failures.push(RowFailure {
+                    time: measurement.time,
+                    error: err.to_string(),
+                });
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    transaction.commit().await?;
+
+    Ok((inserted, failures))
+}
+
+/// Inserts `measurements` with a binary `COPY` into a temporary table, from which they are moved
+/// into the measurement table, skipping the ones which are already present and returning the
+/// inserted ones.
+async fn copy_measurements<'m>(
+    config: &Config,
+    client: &mut Client,
+    station: &Station,
+    measurements: &'m [Measurement],
+) -> Result<Vec<&'m Measurement>, tokio_postgres::Error> {
+    let transaction = client.transaction().await?;
+    let table = quote_identifier(&config.table.name);
+
+    transaction
+        .batch_execute(&format!(
+            "create temporary table measurement_copy (like {table} including defaults) on commit drop"
+        ))
+        .await?;
+
+    let (names, types): (Vec<_>, Vec<_>) = insert_columns(config, station.store_raw)
+        .into_iter()
+        .unzip();
+
+    let sink = transaction
+        .copy_in(&format!(
+            "copy measurement_copy({}) from stdin binary",
+            column_list(&names)
+        ))
+        .await?;
+
+    let mut writer = pin!(BinaryCopyInWriter::new(sink, &types));
+
+    for measurement in measurements {
+        writer
+            .as_mut()
+            .write(&insert_params(config, station, measurement))
+            .await?;
+    }
+
+    writer.finish().await?;
+
+    let returned = transaction
+        .query(
+            &format!(
+                "insert into {table} select * from measurement_copy{} returning {}",
+                on_conflict_clause(config),
+                quote_identifier(&config.table.time_column)
+            ),
+            &[],
+        )
+        .await?;
+    let inserted = written(measurements, &returned)?;
+
+    transaction.commit().await?;
+
+    Ok(inserted)
+}
+
+/// The measurements of `rows` whose times an insert of them `returned`, taking the first of the
+/// ones sharing a time, since the insert skips the later ones as already present.
+fn written<'m>(
+    rows: &'m [Measurement],
+    returned: &[tokio_postgres::Row],
+) -> Result<Vec<&'m Measurement>, tokio_postgres::Error> {
+    let mut times = HashMap::<_, usize>::new();
+
+    for row in returned {
+        *times
+            .entry(row.try_get::<_, DateTime<Utc>>(0)?)
+            .or_default() += 1;
+    }
+
+    Ok(rows
+        .iter()
+        .filter(
+            |measurement| match times.get_mut(&measurement.time.with_timezone(&Utc)) {
+                Some(count) if *count > 0 => {
+                    *count -= 1;
+                    true
+                }
+                _ => false,
+            },
+        )
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::protocol::{decode_measurement, encode_measurement, PROTOCOL_VERSION};
+
+    /// The config of the tests against the PostgreSQL database at `PICOREAD_TEST_DB_URL`, with the
+    /// measurements in `table`, created anew.
+    async fn postgres_config(table: &str) -> Config {
+        let config = Config {
+            db_url: std::env::var("PICOREAD_TEST_DB_URL")
+                .expect("PICOREAD_TEST_DB_URL names the database to test against"),
+            table: crate::config::MeasurementTable {
+                name: table.to_string(),
+                ..Default::default()
+            },
+            ..Config::default()
+        };
+
+        connect_postgres_client(&config)
+            .await
+            .unwrap()
+            .batch_execute(&format!(
+                "drop table if exists {}",
+                QuotedTable::new(&config.table).name
+            ))
+            .await
+            .unwrap();
+        init_schema(&config).await.unwrap();
+
+        config
+    }
+
+    /// Measurements taken `minutes` after noon.
+    fn measurements_at(minutes: impl IntoIterator<Item = i64>) -> Vec<Measurement> {
+        minutes
+            .into_iter()
+            .map(|minute| Measurement {
+                time: DateTime::parse_from_rfc3339("2024-06-01T12:00:00Z").unwrap()
+                    + chrono::Duration::minutes(minute),
+                temp: Decimal::new(215, 1),
+                humidity: Decimal::new(480, 1),
+                uncalibrated: None,
+                out_of_range: false,
+                dew_point: None,
+                perceived_temp: None,
+                absolute_humidity: None,
+                packed: None,
+            })
+            .collect()
+    }
+
+    /// Inserts measurements sent twice in a download and again in the next one, which must only
+    /// be stored once, the latter without skipping the known ones up front.
+    async fn check_duplicates(config: Config) {
+        let config = Config {
+            skip_known: false,
+            ..config
+        };
+        let station = &config.stations[0];
+        let mut db = connect_database(&config).await.unwrap();
+        let mut insert = async |minutes: Vec<i64>| {
+            let summary =
+                insert_measurements(&config, &mut db, station, &measurements_at(minutes), false)
+                    .await
+                    .unwrap();
+            assert!(summary.failures.is_empty(), "{:?}", summary.failures);
+            summary.inserted
+        };
+
+        // The same time twice in one download.
+        assert_eq!(insert(vec![0, 1, 1, 2]).await, 3);
+        // The next run resends what's stored.
+        assert_eq!(insert(vec![0, 1, 2, 3]).await, 1);
+        assert_eq!(insert(vec![0, 1, 2, 3]).await, 0);
+        // Enough for a COPY on PostgreSQL, again with a time twice.
+        assert_eq!(insert((0..1100).chain([500]).collect()).await, 1096);
+
+        let from = measurements_at([0])[0].time.to_utc();
+        let stored = db
+            .stored_measurements(&config, station, from, from + chrono::Duration::days(1))
+            .await
+            .unwrap();
+        assert_eq!(stored.len(), 1100);
+    }
+
+    #[tokio::test]
+    async fn stores_duplicates_once_in_sqlite() {
+        let dir = std::env::temp_dir().join("pico_humidity_temp_read_duplicates");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        check_duplicates(Config {
+            storage: Storage::Sqlite {
+                path: dir.join("measurements.db"),
+            },
+            ..Config::default()
+        })
+        .await;
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn commits_the_pushed_chunks_once_they_fill_a_batch() {
+        let dir = std::env::temp_dir().join("pico_humidity_temp_read_batches");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let config = Config {
+            storage: Storage::Sqlite {
+                path: dir.join("measurements.db"),
+            },
+            batch_size: Some(3),
+            ..Config::default()
+        };
+        let station = &config.stations[0];
+        let mut db = connect_database(&config).await.unwrap();
+        let from = measurements_at([0])[0].time.to_utc();
+        let mut insert = StationInsert::new(&config, station, false, None);
+        let stored = async |db: &mut Database| {
+            db.stored_measurements(&config, station, from, from + chrono::Duration::hours(1))
+                .await
+                .unwrap()
+                .len()
+        };
+
+        insert.push(&mut db, &measurements_at(0..2)).await.unwrap();
+        assert_eq!(stored(&mut db).await, 0);
+        insert.push(&mut db, &measurements_at(2..4)).await.unwrap();
+        assert_eq!(stored(&mut db).await, 3);
+        insert.flush(&mut db).await.unwrap();
+        assert_eq!(stored(&mut db).await, 4);
+        assert_eq!(insert.finish().inserted, 4);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs the PostgreSQL database at PICOREAD_TEST_DB_URL"]
+    async fn stores_duplicates_once_in_postgres() {
+        check_duplicates(postgres_config("duplicates_test").await).await;
+    }
+
+    /// Inserts batches repeating stored measurements and ones within the batch, checking that only
+    /// the newly inserted ones are returned for the live stream, in their order.
+    async fn check_written(config: Config) {
+        let station = &config.stations[0];
+        let mut db = connect_database(&config).await.unwrap();
+        let mut insert = async |minutes: Vec<i64>| {
+            let batch = measurements_at(minutes);
+            let mut failures = Vec::new();
+            let written = match &mut db {
+                Database::Postgres(postgres) => {
+                    insert_batch(&config, postgres, station, &batch, false, &mut failures).await
+                }
+                Database::Sqlite(connection) => {
+                    insert_sqlite_batch(&config, connection, station, &batch, false, &mut failures)
+                }
+                Database::Influxdb(_) => unreachable!("the test doesn't write to InfluxDB"),
+            }
+            .unwrap();
+            assert!(failures.is_empty(), "{failures:?}");
+            written
+                .into_iter()
+                .map(|measurement| measurement.time)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            insert(vec![2, 0, 0, 1]).await,
+            measurements_at([2, 0, 1])
+                .iter()
+                .map(|measurement| measurement.time)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            insert(vec![1, 3, 2]).await,
+            measurements_at([3])
+                .iter()
+                .map(|measurement| measurement.time)
+                .collect::<Vec<_>>()
+        );
+        // Enough for a COPY on PostgreSQL.
+        assert_eq!(
+            insert((0..1100).rev().chain([1050]).collect()).await,
+            measurements_at((4..1100).rev())
+                .iter()
+                .map(|measurement| measurement.time)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn returns_only_the_inserted_measurements_from_sqlite() {
+        let dir = std::env::temp_dir().join("pico_humidity_temp_read_written");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        check_written(Config {
+            storage: Storage::Sqlite {
+                path: dir.join("measurements.db"),
+            },
+            ..Config::default()
+        })
+        .await;
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs the PostgreSQL database at PICOREAD_TEST_DB_URL"]
+    async fn returns_only_the_inserted_measurements_from_postgres() {
+        check_written(postgres_config("written_test").await).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs the PostgreSQL database at PICOREAD_TEST_DB_URL"]
+    async fn keeps_the_order_of_pipelined_statements() {
+        let config = Config {
+            rows_per_insert: 2,
+            insert_pipeline_depth: 8,
+            ..postgres_config("pipeline_test").await
+        };
+        let station = &config.stations[0];
+        let mut postgres = connect_postgres(&config).await.unwrap();
+        postgres
+            .client
+            .batch_execute("alter table pipeline_test add constraint below_50 check (temp < 50)")
+            .await
+            .unwrap();
+
+        // Rejected in the third and sixth of the statements in flight together.
+        let mut batch = measurements_at(0..15);
+        batch[5].temp = Decimal::new(605, 1);
+        batch[11].temp = Decimal::new(705, 1);
+
+        let statements = postgres.insert_statements(station).clone();
+        let err = execute_batch(&config, &mut postgres.client, &statements, station, &batch)
+            .await
+            .unwrap_err();
+        let detail = err.as_db_error().and_then(|err| err.detail()).unwrap();
+        assert!(detail.contains("60.5"), "{detail}");
+
+        let mut failures = Vec::new();
+        let written = insert_batch(
+            &config,
+            &mut postgres,
+            station,
+            &batch,
+            false,
+            &mut failures,
+        )
+        .await
+        .unwrap();
+
+        let times = |minutes: &[usize]| {
+            minutes
+                .iter()
+                .map(|&minute| batch[minute].time)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            written
+                .into_iter()
+                .map(|measurement| measurement.time)
+                .collect::<Vec<_>>(),
+            times(&[0, 1, 2, 3, 4, 6, 7, 8, 9, 10, 12, 13, 14])
+        );
+        assert_eq!(
+            failures
+                .iter()
+                .map(|failure| failure.time)
+                .collect::<Vec<_>>(),
+            times(&[5, 11])
+        );
+    }
+
+    /// Stores decoded measurements, checking that they're read back exactly with one decimal place,
+    /// the negative temperatures included.
+    async fn check_exact_values(config: Config) {
+        let station = &config.stations[0];
+        let start = chrono::NaiveDate::from_ymd_opt(2024, 6, 1)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap();
+
+        // The extremes of the fields, zero and values without an exact binary representation.
+        let raw = [(255, 1023), (0, 0), (-1, 1), (-256, 480), (-103, 333)];
+        let mut measurements: Vec<_> = raw
+            .iter()
+            .enumerate()
+            .map(|(minute, &(temp, humidity))| {
+                let time = start + chrono::Duration::minutes(minute as i64);
+                let packed = encode_measurement(&time, temp, humidity);
+
+                decode_measurement(&config, PROTOCOL_VERSION, packed)
+                    .unwrap()
+                    .unwrap()
+            })
+            .collect();
+        // Beyond the field, like after calibration.
+        let mut calibrated = measurements[0].clone();
+        calibrated.time += chrono::Duration::minutes(30);
+        calibrated.temp = Decimal::new(511, 1);
+        measurements.push(calibrated);
+        let raw = [raw.as_slice(), &[(511, 1023)]].concat();
+
+        let mut db = connect_database(&config).await.unwrap();
+        insert_measurements(&config, &mut db, station, &measurements, false)
+            .await
+            .unwrap();
+
+        let from = measurements[0].time.to_utc();
+        let stored = db
+            .stored_measurements(&config, station, from, from + chrono::Duration::hours(1))
+            .await
+            .unwrap();
+
+        let expected: Vec<_> = measurements
+            .iter()
+            .zip(raw)
+            .map(|(measurement, (temp, humidity))| {
+                // One decimal place, whatever the value.
+                assert_eq!(measurement.temp.scale(), 1);
+                assert_eq!(measurement.humidity.scale(), 1);
+
+                (
+                    measurement.time.to_utc(),
+                    Decimal::new(temp.into(), 1),
+                    Decimal::new(humidity.into(), 1),
+                )
+            })
+            .collect();
+        assert_eq!(stored, expected);
+    }
+
+    #[tokio::test]
+    async fn stores_the_decoded_values_exactly_in_sqlite() {
+        let dir = std::env::temp_dir().join("pico_humidity_temp_read_sink");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        check_exact_values(Config {
+            storage: Storage::Sqlite {
+                path: dir.join("measurements.db"),
+            },
+            ..Config::default()
+        })
+        .await;
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Unlike SQLite, which stores them as floating point numbers, the numeric columns keep the
+    /// decimals as they were bound.
+    #[tokio::test]
+    #[ignore = "needs the PostgreSQL database at PICOREAD_TEST_DB_URL"]
+    async fn stores_the_decoded_values_exactly_in_postgres() {
+        check_exact_values(postgres_config("exact_values_test").await).await;
+    }
+}
@@ -0,0 +1,211 @@
+//! Filtering the single-sample spikes of glitching sensors out of a download, like the DHT22
+//! reporting 99.9 % between two measurements of 55 %, with `spike_filter`.
+
+use rust_decimal::Decimal;
+use tracing::warn;
+
+use crate::{
+    config::{Config, SpikeAction, SpikeFilterConfig},
+    Measurement,
+};
+
+/// Finds the measurements of a download differing from both of their neighbors, which may come
+/// in another chunk of it.
+#[derive(Debug)]
+pub struct SpikeFilter<'c> {
+    config: &'c SpikeFilterConfig,
+    /// The measurement before `pending`, as the Pico sent it.
+    before: Option<Measurement>,
+    /// The last measurement so far, which can only be checked once the next one is known.
+    pending: Option<Measurement>,
+}
+
+impl<'c> SpikeFilter<'c> {
+    /// The filter of a download, if the config sets `spike_filter`.
+    pub fn new(config: &'c Config) -> Option<SpikeFilter<'c>> {
+        config.spike_filter.as_ref().map(|config| SpikeFilter {
+            config,
+            before: None,
+            pending: None,
+        })
+    }
+
+    /// Returns `measurements`, the next ones of the download, with the spikes dropped or replaced.
+    /// The last one is held back until the next call, which passes it on with `last` set.
+    pub fn filter(&mut self, measurements: Vec<Measurement>, last: bool) -> Vec<Measurement> {
+        let mut filtered = Vec::with_capacity(measurements.len() + 1);
+
+        for next in measurements {
+            if let Some(current) = self.pending.take() {
+                match &self.before {
+                    Some(before) => filtered.extend(self.unspiked(before, current.clone(), &next)),
+                    // The first measurement of the download has no measurement before it.
+                    None => filtered.push(current.clone()),
+                }
+
+                self.before = Some(current);
+            }
+
+            self.pending = Some(next);
+        }
+
+        if last {
+            filtered.extend(self.pending.take());
+        }
+
+        filtered
+    }
+
+    /// `current` unless it's a spike between `before` and `after`, else what the config does with
+    /// it.
+    fn unspiked(
+        &self,
+        before: &Measurement,
+        mut current: Measurement,
+        after: &Measurement,
+    ) -> Option<Measurement> {
+        let temp = spike(
+            before.temp,
+            current.temp,
+            after.temp,
+            self.config.max_temp_delta,
+        );
+        let humidity = spike(
+            before.humidity,
+            current.humidity,
+            after.humidity,
+            self.config.max_humidity_delta,
+        );
+
+        if !temp && !humidity {
+            return Some(current);
+        }
+
+        let time = current.time.to_rfc3339();
+
+        match self.config.action {
+            SpikeAction::Drop => {
+                warn!(
+                    time = %time,
+                    "dropping the spike at {time} with {} °C and {} %, between {} °C and {} % \
+                     before and {} °C and {} % after",
+                    current.temp,
+                    current.humidity,
+                    before.temp,
+                    before.humidity,
+                    after.temp,
+                    after.humidity
+                );
+                return None;
+            }
+            SpikeAction::Average => {
+                if temp {
+                    let average = average(before.temp, after.temp);
+                    warn!(
+                        time = %time,
+                        "replacing the temperature spike at {time} of {} °C by {average} °C",
+                        current.temp
+                    );
+                    current.temp = average;
+                }
+                if humidity {
+                    let average = average(before.humidity, after.humidity);
+                    warn!(
+                        time = %time,
+                        "replacing the humidity spike at {time} of {} % by {average} %",
+                        current.humidity
+                    );
+                    current.humidity = average;
+                }
+            }
+        }
+
+        Some(current)
+    }
+}
+
+/// Whether `value` differs from both `before` and `after` by more than `max_delta`, if given.
+fn spike(before: Decimal, value: Decimal, after: Decimal, max_delta: Option<Decimal>) -> bool {
+    max_delta.is_some_and(|max_delta| {
+        (value - before).abs() > max_delta && (value - after).abs() > max_delta
+    })
+}
+
+fn average(before: Decimal, after: Decimal) -> Decimal {
+    ((before + after) / Decimal::TWO).normalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Duration};
+
+    use super::*;
+
+    fn measurements(humidity: &[i64]) -> Vec<Measurement> {
+        humidity
+            .iter()
+            .enumerate()
+            .map(|(minute, humidity)| Measurement {
+                time: DateTime::parse_from_rfc3339("2024-06-01T12:00:00Z").unwrap()
+                    + Duration::minutes(minute as i64),
+                temp: Decimal::new(207, 1),
+                humidity: Decimal::new(*humidity, 1),
+                uncalibrated: None,
+                out_of_range: false,
+                dew_point: None,
+                perceived_temp: None,
+                absolute_humidity: None,
+                packed: None,
+            })
+            .collect()
+    }
+
+    fn humidity(measurements: &[Measurement]) -> Vec<String> {
+        measurements
+            .iter()
+            .map(|measurement| measurement.humidity.to_string())
+            .collect()
+    }
+
+    fn config(action: SpikeAction) -> Config {
+        Config {
+            spike_filter: Some(SpikeFilterConfig {
+                max_temp_delta: None,
+                max_humidity_delta: Some(Decimal::new(200, 1)),
+                action,
+            }),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn handles_the_spikes_across_chunks() {
+        let all = measurements(&[999, 550, 552, 999, 551, 553, 999]);
+
+        for action in [SpikeAction::Drop, SpikeAction::Average] {
+            let config = config(action);
+            let mut filter = SpikeFilter::new(&config).unwrap();
+
+            // The spike is the last measurement of the first chunk.
+            let mut filtered = filter.filter(all[..4].to_vec(), false);
+            filtered.extend(filter.filter(all[4..].to_vec(), true));
+
+            // The first and last measurements have only one neighbor.
+            let expected: &[&str] = match action {
+                SpikeAction::Drop => &["99.9", "55.0", "55.2", "55.1", "55.3", "99.9"],
+                SpikeAction::Average => &["99.9", "55.0", "55.2", "55.15", "55.1", "55.3", "99.9"],
+            };
+            assert_eq!(humidity(&filtered), expected, "{action:?}");
+        }
+    }
+
+    #[test]
+    fn keeps_the_measurements_close_to_a_neighbor() {
+        let config = config(SpikeAction::Drop);
+        let mut filter = SpikeFilter::new(&config).unwrap();
+        let all = measurements(&[550, 990, 990, 550, 700, 551]);
+
+        assert_eq!(filter.filter(all.clone(), true), all);
+        assert!(SpikeFilter::new(&Config::default()).is_none());
+    }
+}
@@ -0,0 +1,352 @@
+//! Keeping the measurements which couldn't be inserted on disk until the database is back.
+
+use std::{
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Write},
+    path::Path,
+};
+
+use anyhow::anyhow;
+use chrono::{DateTime, FixedOffset};
+use rust_decimal::Decimal;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+use crate::{
+    config::{Config, Station},
+    pico::MEASUREMENT_CHUNK,
+    sink::{insert_measurements, Database},
+    Measurement,
+};
+
+/// Held while the spool file is appended to or replayed, since the sessions of listen mode may
+/// do either at the same time.
+static SPOOL: Mutex<()> = Mutex::const_new(());
+
+/// A line of the spool file.
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+struct SpooledMeasurement {
+    station_id: i32,
+    time: DateTime<FixedOffset>,
+    temp: Decimal,
+    humidity: Decimal,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    uncalibrated: Option<(Decimal, Decimal)>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    out_of_range: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dew_point: Option<Decimal>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    perceived_temp: Option<Decimal>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    absolute_humidity: Option<Decimal>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    packed: Option<i64>,
+}
+
+impl SpooledMeasurement {
+    fn new(station: &Station, measurement: &Measurement) -> SpooledMeasurement {
+        SpooledMeasurement {
+            station_id: station.station_id,
+            time: measurement.time,
+            temp: measurement.temp,
+            humidity: measurement.humidity,
+            uncalibrated: measurement.uncalibrated,
+            out_of_range: measurement.out_of_range,
+            dew_point: measurement.dew_point,
+            perceived_temp: measurement.perceived_temp,
+            absolute_humidity: measurement.absolute_humidity,
+            packed: measurement.packed,
+        }
+    }
+
+    fn into_measurement(self) -> Measurement {
+        Measurement {
+            time: self.time,
+            temp: self.temp,
+            humidity: self.humidity,
+            uncalibrated: self.uncalibrated,
+            out_of_range: self.out_of_range,
+            dew_point: self.dew_point,
+            perceived_temp: self.perceived_temp,
+            absolute_humidity: self.absolute_humidity,
+            packed: self.packed,
+        }
+    }
+}
+
+/// Appends `measurements` of `station` to the spool file, unless that would grow it beyond
+/// `spool_max_bytes`.
+pub async fn spool_measurements(
+    config: &Config,
+    station: &Station,
+    measurements: &[Measurement],
+) -> anyhow::Result<()> {
+    let Some(path) = &config.spool_path else {
+        return Err(anyhow!(
+            "Error spooling the measurements: no spool_path configured"
+        ));
+    };
+    let _spool = SPOOL.lock().await;
+
+    let mut lines = String::new();
+
+    for measurement in measurements {
+        lines += &serde_json::to_string(&SpooledMeasurement::new(station, measurement))
+            .map_err(|err| anyhow!("Error serializing a spooled measurement: {err}"))?;
+        lines.push('\n');
+    }
+
+    let size = match fs::metadata(path) {
+        Ok(metadata) => metadata.len(),
+        Err(err) if err.kind() == ErrorKind::NotFound => 0,
+        Err(err) => return Err(anyhow!("Error reading the spool {}: {err}", path.display())),
+    };
+
+    if size + lines.len() as u64 > config.spool_max_bytes {
+        return Err(anyhow!(
+            "Error spooling {} measurements: the spool {} would grow beyond spool_max_bytes ({})",
+            measurements.len(),
+            path.display(),
+            config.spool_max_bytes
+        ));
+    }
+
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(lines.as_bytes()))
+        .map_err(|err| anyhow!("Error writing to the spool {}: {err}", path.display()))
+}
+
+/// Inserts the spooled measurements and deletes the spool file. If inserting fails, the
+/// measurements which weren't inserted yet are left in it.
+pub async fn replay_spool(
+    config: &Config,
+    stations: &[Station],
+    db: &mut Database,
+) -> anyhow::Result<()> {
+    let Some(path) = &config.spool_path else {
+        return Ok(());
+    };
+    let _spool = SPOOL.lock().await;
+
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(anyhow!("Error reading the spool {}: {err}", path.display())),
+    };
+
+    let lines: Vec<_> = contents.lines().filter(|line| !line.is_empty()).collect();
+
+    // The spooled measurements may well be older than the ones stored since.
+    let config = Config {
+        skip_known: false,
+        ..config.clone()
+    };
+
+    let mut replayed = 0;
+    let mut inserted = 0;
+
+    while replayed < lines.len() {
+        let mut group: Vec<SpooledMeasurement> = Vec::new();
+        let mut consumed = 0;
+
+        for line in &lines[replayed..] {
+            if group.len() == MEASUREMENT_CHUNK {
+                break;
+            }
+
+            match serde_json::from_str::<SpooledMeasurement>(line) {
+                Ok(spooled)
+                    if group
+                        .first()
+                        .is_none_or(|first| first.station_id == spooled.station_id) =>
+                {
+                    group.push(spooled)
+                }
+                Ok(_) => break,
+                Err(err) => warn!("dropping an unreadable line of the spool: {err}"),
+            }
+
+            consumed += 1;
+        }
+
+        let Some(station_id) = group.first().map(|spooled| spooled.station_id) else {
+            replayed += consumed;
+            continue;
+        };
+
+        let station = stations
+            .iter()
+            .find(|station| station.station_id == station_id)
+            .cloned()
+            .unwrap_or_else(|| Station {
+                pico: String::new(),
+                pico_port: 0,
+                station_id,
+                ..Config::default().stations[0].clone()
+            });
+
+        let measurements: Vec<_> = group
+            .into_iter()
+            .map(SpooledMeasurement::into_measurement)
+            .collect();
+
+        match insert_measurements(&config, db, &station, &measurements, false).await {
+            Ok(summary) => {
+                inserted += summary.inserted;
+
+                for failure in &summary.failures {
+                    warn!(
+                        station_id,
+                        "dropping the spooled measurement at {}, which was rejected: {}",
+                        failure.time.to_rfc3339(),
+                        failure.error
+                    );
+                }
+            }
+            Err(err) => {
+                let remaining = lines.len() - replayed;
+                keep_remainder(path, &lines[replayed..])?;
+                return Err(anyhow!(
+                    "Error replaying the spool {}, {remaining} measurements are left in it: {err}",
+                    path.display()
+                ));
+            }
+        }
+
+        replayed += consumed;
+    }
+
+    fs::remove_file(path)
+        .map_err(|err| anyhow!("Error deleting the spool {}: {err}", path.display()))?;
+
+    info!(
+        "replayed {} spooled measurements, inserted {inserted} new",
+        lines.len()
+    );
+
+    Ok(())
+}
+
+/// Replaces the spool file with `lines`, atomically so a crash can't lose any of them.
+fn keep_remainder(path: &Path, lines: &[&str]) -> anyhow::Result<()> {
+    let temporary = path.with_extension("tmp");
+    let mut contents = lines.join("\n");
+    contents.push('\n');
+
+    fs::write(&temporary, contents)
+        .and_then(|()| fs::rename(&temporary, path))
+        .map_err(|err| anyhow!("Error rewriting the spool {}: {err}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spools_everything_needed_to_insert_later() {
+        let station = Config::default().stations[0].clone();
+        let measurement = Measurement {
+            time: DateTime::parse_from_rfc3339("2024-06-01T12:00:00+02:00").unwrap(),
+            temp: Decimal::new(207, 1),
+            humidity: Decimal::new(520, 1),
+            uncalibrated: Some((Decimal::new(215, 1), Decimal::new(480, 1))),
+            out_of_range: true,
+            dew_point: Some(Decimal::new(102, 1)),
+            perceived_temp: Some(Decimal::new(207, 1)),
+            absolute_humidity: Some(Decimal::new(912, 2)),
+            packed: Some(0x0123_4567_89ab_cdef),
+        };
+
+        let line = serde_json::to_string(&SpooledMeasurement::new(&station, &measurement)).unwrap();
+        let spooled: SpooledMeasurement = serde_json::from_str(&line).unwrap();
+
+        assert_eq!(spooled.station_id, station.station_id);
+        assert_eq!(spooled.into_measurement(), measurement);
+    }
+
+    #[tokio::test]
+    async fn refuses_to_grow_beyond_the_cap() {
+        let path = std::env::temp_dir().join("pico_humidity_temp_read_spool_cap.jsonl");
+        let _ = fs::remove_file(&path);
+
+        let config = Config {
+            spool_path: Some(path.clone()),
+            spool_max_bytes: 200,
+            ..Config::default()
+        };
+        let station = config.stations[0].clone();
+        let measurement = Measurement {
+            time: DateTime::parse_from_rfc3339("2024-06-01T12:00:00Z").unwrap(),
+            temp: Decimal::new(215, 1),
+            humidity: Decimal::new(480, 1),
+            uncalibrated: None,
+            out_of_range: false,
+            dew_point: None,
+            perceived_temp: None,
+            absolute_humidity: None,
+            packed: None,
+        };
+
+        spool_measurements(&config, &station, std::slice::from_ref(&measurement))
+            .await
+            .unwrap();
+        let second =
+            spool_measurements(&config, &station, &[measurement.clone(), measurement]).await;
+        let lines = fs::read_to_string(&path).unwrap().lines().count();
+        fs::remove_file(path).unwrap();
+
+        assert!(second.is_err());
+        assert_eq!(lines, 1);
+    }
+
+    #[tokio::test]
+    async fn replays_and_deletes_the_spool() {
+        let dir = std::env::temp_dir().join("pico_humidity_temp_read_spool_replay");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let config = Config {
+            storage: crate::config::Storage::Sqlite {
+                path: dir.join("measurements.db"),
+            },
+            spool_path: Some(dir.join("spool.jsonl")),
+            ..Config::default()
+        };
+        let station = config.stations[0].clone();
+        let measurements: Vec<_> = (0..3)
+            .map(|minute| Measurement {
+                time: DateTime::parse_from_rfc3339("2024-06-01T12:00:00Z").unwrap()
+                    + chrono::Duration::minutes(minute),
+                temp: Decimal::new(215, 1),
+                humidity: Decimal::new(480, 1),
+                uncalibrated: None,
+                out_of_range: false,
+                dew_point: None,
+                perceived_temp: None,
+                absolute_humidity: None,
+                packed: None,
+            })
+            .collect();
+
+        spool_measurements(&config, &station, &measurements)
+            .await
+            .unwrap();
+
+        let mut db = crate::sink::connect_database(&config).await.unwrap();
+        replay_spool(&config, std::slice::from_ref(&station), &mut db)
+            .await
+            .unwrap();
+
+        assert!(!dir.join("spool.jsonl").exists());
+        assert_eq!(
+            db.latest_time(&config, &station).await.unwrap(),
+            Some(measurements[2].time.to_utc())
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,565 @@
+//! Summarizing the stored measurements of every station over a range of time, the `stats`
+//! subcommand.
+
+use std::path::Path;
+
+use anyhow::anyhow;
+use chrono::{DateTime, FixedOffset, Utc};
+use rust_decimal::{Decimal, RoundingStrategy};
+
+use crate::{
+    config::{Config, Storage, TemperatureUnit},
+    export::from_real,
+    failure::{Categorize, Failure},
+    serialize_decimal,
+    sink::{connect_postgres_client, QuotedTable},
+};
+
+/// How many decimal places the averages are rounded to.
+const AVERAGE_PLACES: u32 = 2;
+
+/// The format the statistics are printed in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum StatsFormat {
+    Text,
+    Json,
+}
+
+/// Which of the stored measurements are summarized.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StatsFilter {
+    /// The stations summarized, or every one with stored measurements.
+    pub station_ids: Option<Vec<i32>>,
+    /// The earliest time summarized.
+    pub from: Option<DateTime<Utc>>,
+    /// The time before which the measurements are summarized.
+    pub to: Option<DateTime<Utc>>,
+}
+
+/// The statistics of the measurements of a station.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct StationStats {
+    pub station_id: i32,
+    pub count: u64,
+    /// The statistics of the temperatures, unless there are no measurements.
+    pub temp: Option<ValueStats>,
+    /// The statistics of the humidities, unless there are no measurements.
+    pub humidity: Option<ValueStats>,
+}
+
+/// The statistics of the temperatures or humidities of a station, the times of the extremes being
+/// the first they were measured at.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct ValueStats {
+    pub min: TimedValue,
+    pub max: TimedValue,
+    #[serde(serialize_with = "serialize_decimal")]
+    pub avg: Decimal,
+    pub latest: TimedValue,
+}
+
+/// A value and the time of the measurement it's from.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct TimedValue {
+    #[serde(serialize_with = "serialize_decimal")]
+    pub value: Decimal,
+    pub time: DateTime<FixedOffset>,
+}
+
+/// The statistics of the stations, as printed with `--format json`.
+#[derive(Debug, serde::Serialize)]
+struct StatsReport<'s> {
+    from: Option<DateTime<FixedOffset>>,
+    to: Option<DateTime<FixedOffset>>,
+    /// The unit of the temperatures, unless it's Celsius.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temp_unit: Option<TemperatureUnit>,
+    stations: &'s [StationStats],
+}
+
+/// A stored measurement with one of the extremes or the latest time.
+struct Row {
+    at: DateTime<Utc>,
+    temp: Decimal,
+    humidity: Decimal,
+}
+
+/// The count and averages of the matching measurements, and those with the lowest and highest
+/// temperature, the lowest and highest humidity and the latest time, all of them `None` without
+/// any.
+struct Summary {
+    count: u64,
+    averages: Option<(Decimal, Decimal)>,
+    rows: Option<[Row; 5]>,
+}
+
+/// Computes the statistics of the stored measurements matching `filter`, one per station ordered
+/// by its id.
+pub async fn station_stats(
+    config: &Config,
+    filter: &StatsFilter,
+) -> anyhow::Result<Vec<StationStats>> {
+    let summaries = match &config.storage {
+        Storage::Postgres => stats_postgres(config, filter)
+            .await
+            .category(Failure::Database)?,
+        Storage::Sqlite { path } => {
+            stats_sqlite(config, path, filter).category(Failure::Database)?
+        }
+        Storage::Influxdb { .. } => {
+            return Err(Failure::Config.tag(anyhow!(
+                "stats isn't supported with InfluxDB storage, query the bucket instead"
+            )))
+        }
+    };
+
+    Ok(summaries
+        .into_iter()
+        .map(|(station_id, summary)| summary.stats(config, station_id))
+        .collect())
+}
+
+/// Prints `stats` in `format`, their temperatures in `output_units`.
+pub fn print_stats(
+    config: &Config,
+    filter: &StatsFilter,
+    stats: &[StationStats],
+    format: StatsFormat,
+) -> anyhow::Result<()> {
+    let unit = config.output_units.temperature;
+    let local = |time: Option<DateTime<Utc>>| {
+        time.map(|time| config.pico_timezone.local(&time.fixed_offset()))
+    };
+
+    if format == StatsFormat::Json {
+        let report = StatsReport {
+            from: local(filter.from),
+            to: local(filter.to),
+            temp_unit: (unit != TemperatureUnit::Celsius).then_some(unit),
+            stations: stats,
+        };
+        let json = serde_json::to_string_pretty(&report)
+            .map_err(|err| anyhow!("Error serializing the statistics: {err}"))?;
+        println!("{json}");
+        return Ok(());
+    }
+
+    for station in stats {
+        println!(
+            "station {}: {} measurements",
+            station.station_id, station.count
+        );
+
+        let values = [
+            (format!("temp {}", unit.symbol()), &station.temp),
+            ("humidity %".to_string(), &station.humidity),
+        ];
+
+        for (name, values) in values {
+            if let Some(values) = values {
+                println!(
+                    "  {name:<11} min {} at {}, max {} at {}, avg {}, latest {} at {}",
+                    values.min.value,
+                    values.min.time.to_rfc3339(),
+                    values.max.value,
+                    values.max.time.to_rfc3339(),
+                    values.avg,
+                    values.latest.value,
+                    values.latest.time.to_rfc3339()
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+impl Summary {
+    /// The statistics of the station with `station_id`, in the time zone and units of `config`.
+    fn stats(self, config: &Config, station_id: i32) -> StationStats {
+        let unit = config.output_units.temperature;
+        let timed = |row: &Row, value: Decimal| TimedValue {
+            value,
+            time: config.pico_timezone.local(&row.at.fixed_offset()),
+        };
+
+        let (Some((temp_avg, humidity_avg)), Some(rows)) = (self.averages, self.rows) else {
+            return StationStats {
+                station_id,
+                count: self.count,
+                temp: None,
+                humidity: None,
+            };
+        };
+        let [min_temp, max_temp, min_humidity, max_humidity, latest] = &rows;
+
+        StationStats {
+            station_id,
+            count: self.count,
+            temp: Some(ValueStats {
+                min: timed(min_temp, unit.convert(min_temp.temp)),
+                max: timed(max_temp, unit.convert(max_temp.temp)),
+                avg: unit.convert(average(temp_avg)),
+                latest: timed(latest, unit.convert(latest.temp)),
+            }),
+            humidity: Some(ValueStats {
+                min: timed(min_humidity, min_humidity.humidity),
+                max: timed(max_humidity, max_humidity.humidity),
+                avg: average(humidity_avg),
+                latest: timed(latest, latest.humidity),
+            }),
+        }
+    }
+}
+
+fn average(value: Decimal) -> Decimal {
+    let mut value =
+        value.round_dp_with_strategy(AVERAGE_PLACES, RoundingStrategy::MidpointAwayFromZero);
+    value.rescale(AVERAGE_PLACES);
+    value
+}
+
+/// The condition matching the measurements of the configured table taken from the parameter
+/// `from` and before `to`, which match everything if null.
+fn in_range(config: &Config, from: &str, to: &str) -> String {
+    let at = QuotedTable::new(&config.table).at;
+
+    format!("({from} is null or {at} >= {from}) and ({to} is null or {at} < {to})")
+}
+
+/// Like [`in_range`], only matching the measurements of the station given by the parameter
+/// `station_id`.
+fn of_station(config: &Config, station_id: &str, from: &str, to: &str) -> String {
+    format!(
+        "{} = {station_id} and {}",
+        QuotedTable::new(&config.table).station_id,
+        in_range(config, from, to)
+    )
+}
+
+/// The query of the ids of the stations with measurements in the range.
+fn stations_query(config: &Config, from: &str, to: &str) -> String {
+    let table = QuotedTable::new(&config.table);
+
+    format!(
+        "select distinct {station} from {} where {} order by {station}",
+        table.name,
+        in_range(config, from, to),
+        station = table.station_id
+    )
+}
+
+fn summary_query(config: &Config, station_id: &str, from: &str, to: &str) -> String {
+    let table = QuotedTable::new(&config.table);
+
+    format!(
+        "select count(*), avg({}), avg({}) from {} where {}",
+        table.temp,
+        table.humidity,
+        table.name,
+        of_station(config, station_id, from, to)
+    )
+}
+
+/// The queries of the measurements of [`Summary::rows`], each of which is the first one in its
+/// order of the measurements in the range.
+fn row_queries(config: &Config, station_id: &str, from: &str, to: &str) -> [String; 5] {
+    let table = QuotedTable::new(&config.table);
+    let QuotedTable {
+        at, temp, humidity, ..
+    } = &table;
+
+    [
+        format!("{temp}, {at}"),
+        format!("{temp} desc, {at}"),
+        format!("{humidity}, {at}"),
+        format!("{humidity} desc, {at}"),
+        format!("{at} desc"),
+    ]
+    .map(|order| {
+        format!(
+            "select {at}, {temp}, {humidity} from {} where {} order by {order} limit 1",
+            table.name,
+            of_station(config, station_id, from, to)
+        )
+    })
+}
+
+/// The ids of the stations of `filter`, or those with measurements in its range.
+fn station_ids(
+    filter: &StatsFilter,
+    stored: impl FnOnce() -> anyhow::Result<Vec<i32>>,
+) -> anyhow::Result<Vec<i32>> {
+    let mut station_ids = match &filter.station_ids {
+        Some(station_ids) => station_ids.clone(),
+        None => stored()?,
+    };
+
+    station_ids.sort_unstable();
+    station_ids.dedup();
+    Ok(station_ids)
+}
+
+async fn stats_postgres(
+    config: &Config,
+    filter: &StatsFilter,
+) -> anyhow::Result<Vec<(i32, Summary)>> {
+    let mut client = connect_postgres_client(config).await?;
+
+    // A snapshot, so the rows of a station can't be deleted between the queries of its summary.
+    let transaction = client
+        .build_transaction()
+        .read_only(true)
+        .isolation_level(tokio_postgres::IsolationLevel::RepeatableRead)
+        .start()
+        .await
+        .map_err(|err| anyhow!("Error starting the statistics transaction: {err}"))?;
+
+    let query_error = |err| anyhow!("Error querying the statistics: {err}");
+    let range: [&(dyn tokio_postgres::types::ToSql + Sync); 2] = [&filter.from, &filter.to];
+    let stored = transaction
+        .query(
+            &stations_query(config, "$1::timestamptz", "$2::timestamptz"),
+            &range,
+        )
+        .await
+        .map_err(query_error)?
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+    let station_ids = station_ids(filter, || Ok(stored))?;
+
+    let params = ("$1::int4", "$2::timestamptz", "$3::timestamptz");
+    let summary_query = summary_query(config, params.0, params.1, params.2);
+    let row_queries = row_queries(config, params.0, params.1, params.2);
+    let mut summaries = Vec::with_capacity(station_ids.len());
+
+    for station_id in station_ids {
+        let params: [&(dyn tokio_postgres::types::ToSql + Sync); 3] =
+            [&station_id, &filter.from, &filter.to];
+
+        let row = transaction
+            .query_one(&summary_query, &params)
+            .await
+            .map_err(query_error)?;
+        let count: i64 = row.get(0);
+        let averages: (Option<Decimal>, Option<Decimal>) = (row.get(1), row.get(2));
+
+        let rows = match count {
+            0 => None,
+            _ => {
+                let mut rows = Vec::with_capacity(row_queries.len());
+
+                for query in &row_queries {
+                    let row = transaction
+                        .query_one(query, &params)
+                        .await
+                        .map_err(query_error)?;
+                    rows.push(Row {
+                        at: row.get(0),
+                        temp: row.get(1),
+                        humidity: row.get(2),
+                    });
+                }
+
+                rows.try_into().ok()
+            }
+        };
+
+        summaries.push((
+            station_id,
+            Summary {
+                count: count as u64,
+                averages: averages.0.zip(averages.1),
+                rows,
+            },
+        ));
+    }
+
+    Ok(summaries)
+}
+
+fn stats_sqlite(
+    config: &Config,
+    path: &Path,
+    filter: &StatsFilter,
+) -> anyhow::Result<Vec<(i32, Summary)>> {
+    let connection =
+        rusqlite::Connection::open_with_flags(path, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .map_err(|err| anyhow!("Error opening SQLite database {}: {err}", path.display()))?;
+
+    let query_error = |err| anyhow!("Error querying the statistics: {err}");
+    let station_ids = station_ids(filter, || {
+        let mut statement = connection
+            .prepare(&stations_query(config, "?1", "?2"))
+            .map_err(query_error)?;
+        let stored = statement
+            .query_map(rusqlite::params![filter.from, filter.to], |row| row.get(0))
+            .and_then(|rows| rows.collect())
+            .map_err(query_error)?;
+
+        Ok(stored)
+    })?;
+
+    let summary_query = summary_query(config, "?1", "?2", "?3");
+    let row_queries = row_queries(config, "?1", "?2", "?3");
+    let mut summaries = Vec::with_capacity(station_ids.len());
+
+    for station_id in station_ids {
+        let params = rusqlite::params![station_id, filter.from, filter.to];
+
+        let (count, temp_avg, humidity_avg): (i64, Option<f64>, Option<f64>) = connection
+            .query_row(&summary_query, params, |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })
+            .map_err(query_error)?;
+
+        let rows = match count {
+            0 => None,
+            _ => row_queries
+                .iter()
+                .map(|query| {
+                    connection.query_row(query, params, |row| {
+                        Ok(Row {
+                            at: row.get(0)?,
+                            temp: from_real(row.get(1)?, config.temp_scale),
+                            humidity: from_real(row.get(2)?, config.humidity_scale),
+                        })
+                    })
+                })
+                .collect::<rusqlite::Result<Vec<_>>>()
+                .map_err(query_error)?
+                .try_into()
+                .ok(),
+        };
+
+        summaries.push((
+            station_id,
+            Summary {
+                count: count as u64,
+                averages: temp_avg.zip(humidity_avg).map(|(temp, humidity)| {
+                    (
+                        from_real(temp, config.temp_scale),
+                        from_real(humidity, config.humidity_scale),
+                    )
+                }),
+                rows,
+            },
+        ));
+    }
+
+    Ok(summaries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        sink::{connect_database, insert_measurements},
+        Measurement,
+    };
+
+    #[tokio::test]
+    async fn summarizes_the_measurements_of_every_station_in_the_range() {
+        let dir = std::env::temp_dir().join("pico_humidity_temp_read_stats");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut config = Config {
+            storage: Storage::Sqlite {
+                path: dir.join("measurements.db"),
+            },
+            pico_timezone: crate::config::PicoTimezone::Named(chrono_tz::Europe::Berlin),
+            ..Config::default()
+        };
+        let mut other = config.stations[0].clone();
+        other.station_id = 3;
+        config.stations.push(other);
+
+        let start = DateTime::parse_from_rfc3339("2024-06-01T12:00:00+02:00").unwrap();
+        let measurements: Vec<_> = [(215, 480), (198, 512), (230, 455), (198, 470)]
+            .into_iter()
+            .enumerate()
+            .map(|(minute, (temp, humidity))| Measurement {
+                time: start + chrono::Duration::minutes(minute as i64),
+                temp: Decimal::new(temp, 1),
+                humidity: Decimal::new(humidity, 1),
+                uncalibrated: None,
+                out_of_range: false,
+                dew_point: None,
+                perceived_temp: None,
+                absolute_humidity: None,
+                packed: None,
+            })
+            .collect();
+
+        let mut db = connect_database(&config).await.unwrap();
+        insert_measurements(&config, &mut db, &config.stations[0], &measurements, false)
+            .await
+            .unwrap();
+        insert_measurements(
+            &config,
+            &mut db,
+            &config.stations[1],
+            &measurements[..1],
+            false,
+        )
+        .await
+        .unwrap();
+        drop(db);
+
+        let every_station = StatsFilter::default();
+        let stats = station_stats(&config, &every_station).await.unwrap();
+        let temp = stats[0].temp.as_ref().unwrap();
+        let humidity = stats[0].humidity.as_ref().unwrap();
+
+        assert_eq!(
+            stats
+                .iter()
+                .map(|stats| (stats.station_id, stats.count))
+                .collect::<Vec<_>>(),
+            [(0, 4), (3, 1)]
+        );
+        // The first of the equal lowest temperatures.
+        assert_eq!(temp.min.value.to_string(), "19.8");
+        assert_eq!(temp.min.time.to_rfc3339(), "2024-06-01T12:01:00+02:00");
+        assert_eq!(temp.max.value.to_string(), "23.0");
+        assert_eq!(temp.avg.to_string(), "21.03");
+        assert_eq!(temp.latest.time.to_rfc3339(), "2024-06-01T12:03:00+02:00");
+        assert_eq!(humidity.min.value.to_string(), "45.5");
+        assert_eq!(humidity.max.time.to_rfc3339(), "2024-06-01T12:01:00+02:00");
+        assert_eq!(humidity.avg.to_string(), "47.93");
+        assert_eq!(humidity.latest.value.to_string(), "47.0");
+
+        // Past the measurements, and a station without any.
+        let empty = StatsFilter {
+            station_ids: Some(vec![0, 5]),
+            from: Some(start.to_utc() + chrono::Duration::minutes(4)),
+            to: None,
+        };
+        assert_eq!(
+            station_stats(&config, &empty).await.unwrap(),
+            [0, 5].map(|station_id| StationStats {
+                station_id,
+                count: 0,
+                temp: None,
+                humidity: None,
+            })
+        );
+        let range = StatsFilter {
+            station_ids: Some(vec![0]),
+            from: Some(start.to_utc() + chrono::Duration::minutes(1)),
+            to: Some(start.to_utc() + chrono::Duration::minutes(3)),
+        };
+        assert_eq!(station_stats(&config, &range).await.unwrap()[0].count, 2);
+
+        // In Fahrenheit, converted from the average in Celsius.
+        config.output_units.temperature = TemperatureUnit::Fahrenheit;
+        let stats = station_stats(&config, &every_station).await.unwrap();
+        let temp = stats[0].temp.as_ref().unwrap();
+        assert_eq!(temp.min.value.to_string(), "67.6");
+        assert_eq!(temp.avg.to_string(), "69.9");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,87 @@
+//! Notifying systemd of the state of the daemon when it runs as a `Type=notify` service, which is
+//! detected by `NOTIFY_SOCKET` being set, and finding the credentials passed with
+//! `LoadCredential=`.
+
+use std::{
+    os::unix::net::{SocketAddr, UnixDatagram},
+    path::PathBuf,
+    time::Duration,
+};
+
+use tracing::{debug, warn};
+
+/// Sends `state`, made of newline separated assignments like `READY=1`, to systemd. Does nothing
+/// unless `NOTIFY_SOCKET` is set.
+pub fn notify(state: &str) {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+
+    let result = (|| {
+        let address = match path.as_encoded_bytes().strip_prefix(b"@") {
+            Some(name) => abstract_address(name)?,
+            None => SocketAddr::from_pathname(&path)?,
+        };
+
+        UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &address)
+    })();
+
+    match result {
+        Ok(_) => debug!("notified systemd: {}", state.replace('\n', ", ")),
+        Err(err) => warn!("notifying systemd failed: {err}"),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn abstract_address(name: &[u8]) -> std::io::Result<SocketAddr> {
+    use std::os::linux::net::SocketAddrExt;
+
+    SocketAddr::from_abstract_name(name)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn abstract_address(_name: &[u8]) -> std::io::Result<SocketAddr> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "abstract sockets are only supported on Linux",
+    ))
+}
+
+/// The interval in which systemd expects watchdog pings from this process, if it has the watchdog
+/// enabled for it.
+pub fn watchdog_interval() -> Option<Duration> {
+    std::env::var_os("NOTIFY_SOCKET")?;
+
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse() != Ok(std::process::id()) {
+            return None;
+        }
+    }
+
+    let usec = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+
+    Some(Duration::from_micros(usec)).filter(|interval| !interval.is_zero())
+}
+
+/// Pings the systemd watchdog twice per interval from a separate task, so slow downloads don't
+/// get the daemon restarted while a stuck runtime still does. Does nothing without a watchdog.
+pub fn spawn_watchdog() {
+    let Some(interval) = watchdog_interval() else {
+        return;
+    };
+
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(interval / 2);
+
+        loop {
+            ticks.tick().await;
+            notify("WATCHDOG=1");
+        }
+    });
+}
+
+/// The file of the credential `name`, in the `CREDENTIALS_DIRECTORY` systemd sets for a service with
+/// `LoadCredential=` or `SetCredential=`, if it's set.
+pub fn credential_path(name: &str) -> Option<PathBuf> {
+    std::env::var_os("CREDENTIALS_DIRECTORY").map(|directory| PathBuf::from(directory).join(name))
+}
@@ -0,0 +1,76 @@
+//! Exporting the spans of every cycle to an OpenTelemetry collector, with `otlp`.
+//!
+//! Without it, the spans are only as expensive as the log level makes them: they're disabled
+//! unless logging at the info level.
+
+use std::{fmt::Display, future::Future, sync::OnceLock};
+
+use anyhow::anyhow;
+use opentelemetry::trace::{Status, TracerProvider};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig, WithHttpConfig};
+use opentelemetry_sdk::{trace::SdkTracerProvider, Resource};
+use tracing::{level_filters::LevelFilter, Instrument, Span, Subscriber};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{filter::Targets, registry::LookupSpan, Layer};
+
+use crate::config::OtlpConfig;
+
+/// The provider exporting the spans, once set up.
+static PROVIDER: OnceLock<SdkTracerProvider> = OnceLock::new();
+
+/// The layer exporting the info spans of this crate to the collector of `otlp`, whatever is
+/// logged.
+pub fn otlp_layer<S>(otlp: &OtlpConfig) -> anyhow::Result<impl Layer<S>>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(&otlp.endpoint)
+        .with_headers(otlp.headers.clone().into_iter().collect())
+        .build()
+        .map_err(|err| anyhow!("Error setting up the export to {}: {err}", otlp.endpoint))?;
+
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(
+            Resource::builder()
+                .with_service_name(otlp.service_name.clone())
+                .build(),
+        )
+        .build();
+    let tracer = provider.tracer(env!("CARGO_CRATE_NAME"));
+    let _ = PROVIDER.set(provider);
+
+    Ok(tracing_opentelemetry::layer()
+        .with_tracer(tracer)
+        .with_filter(Targets::new().with_target(env!("CARGO_CRATE_NAME"), LevelFilter::INFO)))
+}
+
+/// Exports the spans which weren't yet, before the program exits.
+pub fn shutdown() {
+    if let Some(provider) = PROVIDER.get() {
+        if let Err(err) = provider.shutdown() {
+            eprintln!("Error exporting the last spans: {err}");
+        }
+    }
+}
+
+/// Marks `span` as failed with `err`.
+pub fn failed(span: &Span, err: &impl Display) {
+    span.set_status(Status::error(err.to_string()));
+}
+
+/// Runs `future` in `span`, marking it as failed if `future` fails.
+pub async fn traced<T, E: Display>(
+    span: Span,
+    future: impl Future<Output = Result<T, E>>,
+) -> Result<T, E> {
+    let result = future.instrument(span.clone()).await;
+
+    if let Err(err) = &result {
+        failed(&span, err);
+    }
+
+    result
+}
@@ -0,0 +1,121 @@
+//! Fetching right away on SIGUSR1 or `POST /fetch` when polling, instead of at the next interval.
+
+use std::sync::Mutex;
+
+use anyhow::anyhow;
+use axum::http::StatusCode;
+use futures::FutureExt;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::Notify,
+};
+use tracing::info;
+
+/// Wakes the polling loop up once a fetch is requested while it waits for the next interval.
+pub struct FetchTrigger {
+    /// Whether a fetch is running.
+    running: Mutex<bool>,
+    notify: Notify,
+}
+
+pub static FETCH_TRIGGER: FetchTrigger = FetchTrigger {
+    running: Mutex::new(false),
+    notify: Notify::const_new(),
+};
+
+/// What requesting a fetch did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Triggered {
+    Started,
+    /// A fetch was already running, the request is covered by it.
+    AlreadyRunning,
+}
+
+impl FetchTrigger {
+    /// Requests a fetch right away, unless one is running.
+    pub fn trigger(&self) -> Triggered {
+        let running = self.running.lock().unwrap();
+
+        if *running {
+            return Triggered::AlreadyRunning;
+        }
+
+        // Stored until the polling loop waits for it, if it isn't yet.
+        self.notify.notify_one();
+        Triggered::Started
+    }
+
+    /// Marks a fetch as running, which covers the requests made up to now.
+    pub fn start(&self) {
+        let mut running = self.running.lock().unwrap();
+        *running = true;
+        let _ = self.notify.notified().now_or_never();
+    }
+
+    /// Marks the running fetch as done.
+    pub fn finish(&self) {
+        *self.running.lock().unwrap() = false;
+    }
+
+    /// Completes once a fetch is requested.
+    pub async fn requested(&self) {
+        self.notify.notified().await;
+    }
+}
+
+/// Requests a fetch on every SIGUSR1.
+pub fn handle_sigusr1() -> anyhow::Result<()> {
+    let mut sigusr1 = signal(SignalKind::user_defined1())
+        .map_err(|err| anyhow!("Error installing the SIGUSR1 handler: {err}"))?;
+
+    tokio::spawn(async move {
+        while sigusr1.recv().await.is_some() {
+            log_triggered(FETCH_TRIGGER.trigger(), "SIGUSR1");
+        }
+    });
+
+    Ok(())
+}
+
+/// Requests a fetch for `POST /fetch`, answering whether one was started.
+pub async fn serve_fetch() -> (StatusCode, &'static str) {
+    let triggered = FETCH_TRIGGER.trigger();
+    log_triggered(triggered, "POST /fetch");
+
+    match triggered {
+        Triggered::Started => (StatusCode::ACCEPTED, "started a fetch\n"),
+        Triggered::AlreadyRunning => (StatusCode::OK, "a fetch is already running\n"),
+    }
+}
+
+fn log_triggered(triggered: Triggered, source: &str) {
+    match triggered {
+        Triggered::Started => info!("fetching right away after {source}"),
+        Triggered::AlreadyRunning => {
+            info!("not fetching after {source}, a fetch is already running")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn coalesces_the_requests_during_a_fetch() {
+        let trigger = FetchTrigger {
+            running: Mutex::new(false),
+            notify: Notify::new(),
+        };
+
+        assert_eq!(trigger.trigger(), Triggered::Started);
+        // The fetch started by the schedule covers the request made before it.
+        trigger.start();
+        assert_eq!(trigger.trigger(), Triggered::AlreadyRunning);
+        trigger.finish();
+
+        assert!(trigger.requested().now_or_never().is_none());
+        assert_eq!(trigger.trigger(), Triggered::Started);
+        assert!(trigger.requested().now_or_never().is_some());
+    }
+}
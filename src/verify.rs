@@ -0,0 +1,351 @@
+//! Comparing the measurements on the Picos with the stored ones, the `verify` subcommand.
+
+use std::{collections::BTreeMap, num::NonZeroU32, process::ExitCode};
+
+use anyhow::anyhow;
+use chrono::{DateTime, FixedOffset, Utc};
+use futures::{stream, FutureExt, StreamExt};
+use rust_decimal::Decimal;
+use tokio::sync::mpsc;
+use tracing::{error, info_span, Instrument};
+
+use crate::{
+    config::{Config, Station, Storage},
+    cycle::CycleSummary,
+    failure::{Categorize, Failure},
+    pico::{fetch_measurements, finish_session, DownloadInfo, Source, CHUNKS_IN_FLIGHT},
+    protocol,
+    report::StationReport,
+    serialize_decimal,
+    shutdown::{INTERRUPTED_EXIT_CODE, SHUTDOWN},
+    sink::{connect_database, Database},
+    Measurement,
+};
+
+/// How the measurements of a Pico compare to the stored ones.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+pub struct Verification {
+    /// How many are stored with the same values.
+    pub equal: usize,
+    pub different: Vec<Difference>,
+    /// The times of those which aren't stored.
+    pub missing: Vec<DateTime<FixedOffset>>,
+}
+
+impl Verification {
+    pub fn has_discrepancies(&self) -> bool {
+        !self.different.is_empty() || !self.missing.is_empty()
+    }
+}
+
+/// A measurement stored with other values than the Pico has.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct Difference {
+    pub time: DateTime<FixedOffset>,
+    pub pico: Values,
+    pub stored: Values,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+pub struct Values {
+    #[serde(serialize_with = "serialize_decimal")]
+    pub temp: Decimal,
+    #[serde(serialize_with = "serialize_decimal")]
+    pub humidity: Decimal,
+}
+
+/// Downloads the measurements of every station like a dry run, acknowledging none, and compares
+/// them to the stored ones, printing the discrepancies unless `quiet` is set.
+///
+/// The exit code of the summary is that of the first station which failed, or else tells whether
+/// there were any discrepancies.
+pub async fn verify_stations(
+    config: &Config,
+    stations: &[Station],
+    quiet: bool,
+) -> anyhow::Result<CycleSummary> {
+    if matches!(config.storage, Storage::Influxdb { .. }) {
+        return Err(Failure::Config.tag(anyhow!("verify isn't supported with InfluxDB storage")));
+    }
+    if config
+        .aggregate
+        .as_ref()
+        .is_some_and(|aggregate| !aggregate.keep_measurements)
+    {
+        return Err(Failure::Config.tag(anyhow!(
+            "verify needs the measurements, which aggregate.keep_measurements = false doesn't store"
+        )));
+    }
+
+    let db = connect_database(config).await.category(Failure::Database)?;
+
+    let downloads: Vec<_> = stream::iter(stations)
+        .map(|station| {
+            download(config, station)
+                .instrument(info_span!(
+                    "station",
+                    station_id = station.station_id,
+                    pico = %station.pico,
+                    pico_port = station.pico_port
+                ))
+                .map(move |(result, report)| (station, result, report))
+        })
+        .buffered(config.max_concurrent_fetches.get())
+        .collect()
+        .await;
+
+    let mut summary = CycleSummary {
+        fetched: 0,
+        inserted: 0,
+        buffered: 0,
+        succeeded: 0,
+        stations: stations.len(),
+        exit_code: ExitCode::SUCCESS,
+        reports: Vec::new(),
+        errors: Vec::new(),
+        db_connect: None,
+    };
+    let mut failure = None;
+    let mut discrepancies = false;
+
+    for (station, result, mut report) in downloads {
+        let result = match result {
+            Ok(measurements) => stored_values(config, &db, station, &measurements)
+                .await
+                .map(|stored| (measurements, stored)),
+            Err(err) => Err(err),
+        };
+
+        match result {
+            Ok((measurements, stored)) => {
+                let verification = compare(config, &measurements, &stored);
+
+                if !quiet {
+                    print_verification(station, measurements.len(), &verification);
+                }
+
+                discrepancies |= verification.has_discrepancies();
+                summary.fetched += measurements.len();
+                summary.succeeded += 1;
+                report.verification = Some(verification);
+            }
+            Err(err) => {
+                error!(
+                    station_id = station.station_id,
+                    pico = %station.pico,
+                    pico_port = station.pico_port,
+                    "failed: {err}"
+                );
+                failure = failure.or(Some(Failure::of(&err)));
+                report.errors.push(err.to_string());
+            }
+        }
+
+        summary.reports.push(report);
+    }
+
+    summary.exit_code = match failure {
+        _ if SHUTDOWN.is_requested() => ExitCode::from(INTERRUPTED_EXIT_CODE),
+        Some(failure) => failure.map_or(ExitCode::FAILURE, Failure::exit_code),
+        None if discrepancies => Failure::Discrepancy.exit_code(),
+        None => ExitCode::SUCCESS,
+    };
+
+    Ok(summary)
+}
+
+/// Downloads the measurements of `station` without acknowledging any.
+async fn download(
+    config: &Config,
+    station: &Station,
+) -> (anyhow::Result<Vec<Measurement>>, StationReport) {
+    let mut report = StationReport::new(station);
+    let mut download = DownloadInfo::default();
+    let (sender, mut receiver) = mpsc::channel::<Vec<Measurement>>(CHUNKS_IN_FLIGHT);
+
+    let collect = async {
+        let mut all = Vec::new();
+
+        while let Some(chunk) = receiver.recv().await {
+            report.observe(&chunk);
+            all.extend(chunk);
+        }
+
+        all
+    };
+
+    let (fetch, measurements) = tokio::join!(
+        fetch_measurements(
+            config,
+            station,
+            Source::Connect,
+            None,
+            sender,
+            &mut download
+        ),
+        collect
+    );
+
+    report.downloaded(config, station, download);
+
+    let result = match fetch {
+        Ok(session) => {
+            if let Err(err) = finish_session(config, station, session, 0).await {
+                error!("{err}");
+            }
+            Ok(measurements)
+        }
+        Err(err) => Err(err.into()),
+    };
+
+    (result, report)
+}
+
+/// The stored values of the measurements of `station` in the time range of `measurements`.
+async fn stored_values(
+    config: &Config,
+    db: &Database,
+    station: &Station,
+    measurements: &[Measurement],
+) -> anyhow::Result<BTreeMap<DateTime<Utc>, Values>> {
+    let times = measurements
+        .iter()
+        .map(|measurement| measurement.time.to_utc());
+    let (Some(from), Some(to)) = (times.clone().min(), times.max()) else {
+        return Ok(BTreeMap::new());
+    };
+
+    let stored = db
+        .stored_measurements(config, station, from, to)
+        .await
+        .category(Failure::Database)?;
+
+    Ok(stored
+        .into_iter()
+        .map(|(time, temp, humidity)| (time, Values { temp, humidity }))
+        .collect())
+}
+
+/// Compares `measurements` to the `stored` values, which are equal if they're closer than half the
+/// resolution of the Pico, so neither a different scale nor the rounding of the database count.
+fn compare(
+    config: &Config,
+    measurements: &[Measurement],
+    stored: &BTreeMap<DateTime<Utc>, Values>,
+) -> Verification {
+    let close = |a: Decimal, b: Decimal, scale: NonZeroU32| {
+        (a - b).abs() < protocol::scale(1, scale) / Decimal::TWO
+    };
+
+    let mut verification = Verification::default();
+
+    for measurement in measurements {
+        let pico = Values {
+            temp: measurement.temp,
+            humidity: measurement.humidity,
+        };
+
+        match stored.get(&measurement.time.to_utc()) {
+            None => verification.missing.push(measurement.time),
+            Some(stored)
+                if close(pico.temp, stored.temp, config.temp_scale)
+                    && close(pico.humidity, stored.humidity, config.humidity_scale) =>
+            {
+                verification.equal += 1
+            }
+            Some(stored) => verification.different.push(Difference {
+                time: measurement.time,
+                pico,
+                stored: *stored,
+            }),
+        }
+    }
+
+    verification
+}
+
+fn print_verification(station: &Station, fetched: usize, verification: &Verification) {
+    println!(
+        "station {}: {fetched} measurements, {} stored equally, {} differently, {} missing",
+        station.station_id,
+        verification.equal,
+        verification.different.len(),
+        verification.missing.len()
+    );
+
+    for difference in &verification.different {
+        println!(
+            "{}  differs: Pico {} °C {} %, stored {} °C {} %",
+            difference.time.to_rfc3339(),
+            difference.pico.temp,
+            difference.pico.humidity,
+            difference.stored.temp,
+            difference.stored.humidity
+        );
+    }
+
+    for time in &verification.missing {
+        println!("{}  missing", time.to_rfc3339());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn measurement(minute: i64, temp: i64, humidity: i64) -> Measurement {
+        Measurement {
+            time: DateTime::parse_from_rfc3339("2024-06-01T12:00:00+02:00").unwrap()
+                + chrono::Duration::minutes(minute),
+            temp: Decimal::new(temp, 1),
+            humidity: Decimal::new(humidity, 1),
+            uncalibrated: None,
+            out_of_range: false,
+            dew_point: None,
+            perceived_temp: None,
+            absolute_humidity: None,
+            packed: None,
+        }
+    }
+
+    #[test]
+    fn tells_equal_different_and_missing_measurements_apart() {
+        let measurements = [
+            measurement(0, 215, 480),
+            measurement(1, 216, 481),
+            measurement(2, 217, 482),
+        ];
+        let stored = |measurement: &Measurement, temp: Decimal, humidity: Decimal| {
+            (measurement.time.to_utc(), Values { temp, humidity })
+        };
+        let stored = BTreeMap::from([
+            // Rounded by SQLite or stored with more decimal places.
+            stored(
+                &measurements[0],
+                Decimal::new(2150000001, 8),
+                Decimal::new(4800, 2),
+            ),
+            stored(&measurements[1], Decimal::new(216, 1), Decimal::new(491, 1)),
+        ]);
+
+        let verification = compare(&Config::default(), &measurements, &stored);
+
+        assert_eq!(verification.equal, 1);
+        assert_eq!(
+            verification.different,
+            [Difference {
+                time: measurements[1].time,
+                pico: Values {
+                    temp: Decimal::new(216, 1),
+                    humidity: Decimal::new(481, 1),
+                },
+                stored: Values {
+                    temp: Decimal::new(216, 1),
+                    humidity: Decimal::new(491, 1),
+                },
+            }]
+        );
+        assert_eq!(verification.missing, [measurements[2].time]);
+        assert!(verification.has_discrepancies());
+    }
+}
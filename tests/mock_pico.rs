@@ -0,0 +1,1256 @@
+//! Runs the program against the mock Pico.
+
+use std::{
+    io::{BufRead, BufReader, Read},
+    path::{Path, PathBuf},
+    process::{Child, ChildStdout, Command, Output, Stdio},
+};
+
+/// A mock Pico serving a single connection.
+struct MockPico {
+    child: Child,
+    stdout: BufReader<ChildStdout>,
+    port: u16,
+}
+
+impl MockPico {
+    fn start(args: &[&str]) -> MockPico {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_mock_pico"))
+            .args(["--port", "0", "--once"])
+            .args(args)
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+
+        let mut stdout = BufReader::new(child.stdout.take().unwrap());
+        let mut line = String::new();
+        stdout.read_line(&mut line).unwrap();
+        let port = line.trim().rsplit(':').next().unwrap().parse().unwrap();
+
+        MockPico {
+            child,
+            stdout,
+            port,
+        }
+    }
+
+    /// Waits for the mock to exit and returns what it printed after starting.
+    fn finish(mut self) -> String {
+        let mut output = String::new();
+        self.stdout.read_to_string(&mut output).unwrap();
+        self.child.wait().unwrap();
+        output
+    }
+
+    /// Runs the program with `--dry-run` against the mock, configuring the station with
+    /// `station` merged into its defaults.
+    fn dry_run(&self, station: &str) -> Output {
+        dry_run(self.port, station)
+    }
+
+    /// Runs the program with `args` against the mock.
+    fn run(&self, args: &[&str]) -> Output {
+        run(self.port, "", args)
+    }
+}
+
+/// Runs the program with `--dry-run` against a Pico on `port` of localhost.
+fn dry_run(port: u16, station: &str) -> Output {
+    run(port, station, &["--dry-run"])
+}
+
+/// Runs the program with `args` against a Pico on `port` of localhost, configuring the station
+/// with `station` merged into its defaults.
+fn run(port: u16, station: &str, args: &[&str]) -> Output {
+    run_with(port, "", station, args)
+}
+
+/// Like [`run`], with `settings` merged into the defaults of the config as well.
+fn run_with(port: u16, settings: &str, station: &str, args: &[&str]) -> Output {
+    let config = format!(
+        r#"{{
+            {settings}
+            "connect_retries": 0,
+            "read_timeout_secs": 2,
+            "pico_timezone": "utc",
+            "stations": [{{ "pico": "127.0.0.1", "pico_port": {port}, "station_id": 7 {station} }}]
+        }}"#
+    );
+    let path = config_path(port);
+    std::fs::write(&path, config).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_pico_humidity_temp_read"))
+        .arg("--config")
+        .arg(&path)
+        .args(args)
+        .env_remove("RUST_LOG")
+        .output()
+        .unwrap();
+
+    std::fs::remove_file(path).unwrap();
+    output
+}
+
+fn config_path(port: u16) -> PathBuf {
+    std::env::temp_dir().join(format!("pico_humidity_temp_read_{port}.json"))
+}
+
+#[test]
+fn prints_the_measurements() {
+    let pico = MockPico::start(&["--count", "5", "--checksum"]);
+
+    let output = pico.dry_run(r#", "checksum": true"#);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+
+    assert!(output.status.success(), "{stdout}");
+    assert!(
+        stdout.starts_with("station 7: 5 measurements\n"),
+        "{stdout}"
+    );
+    assert_eq!(stdout.lines().count(), 7, "{stdout}");
+    assert!(
+        stdout.ends_with("5 measurements fetched from 1 of 1 stations\n"),
+        "{stdout}"
+    );
+
+    let mock_output = pico.finish();
+    assert!(mock_output.contains("received time"), "{mock_output}");
+    assert!(mock_output.contains("protocol version 1"), "{mock_output}");
+}
+
+#[test]
+fn replays_recorded_sessions() {
+    let pico = MockPico::start(&["--count", "5", "--checksum"]);
+    let port = pico.port;
+    let recording = std::env::temp_dir().join(format!("pico_session_{port}.bin"));
+    let recording_arg = recording.to_str().unwrap();
+
+    let fetched = run(
+        port,
+        r#", "checksum": true"#,
+        &["--dry-run", "--record", recording_arg],
+    );
+    pico.finish();
+    assert!(fetched.status.success(), "{fetched:?}");
+    assert_eq!(std::fs::metadata(&recording).unwrap().len(), 4 + 5 * 8);
+
+    // Nothing listens at the port anymore.
+    let replayed = run(
+        port,
+        r#", "checksum": true"#,
+        &["--dry-run", "replay", "--station", "7", recording_arg],
+    );
+    std::fs::remove_file(recording).unwrap();
+
+    assert!(replayed.status.success(), "{replayed:?}");
+    assert_eq!(replayed.stdout, fetched.stdout);
+}
+
+#[test]
+fn acknowledges_nothing_in_a_dry_run() {
+    let pico = MockPico::start(&["--count", "3", "--ack"]);
+
+    let output = pico.dry_run(r#", "ack": true"#);
+
+    assert!(output.status.success());
+    assert!(pico.finish().contains("acknowledged 0 measurements"));
+}
+
+/// Fetches the 5 measurements of a mock Pico expecting the acknowledgement into the SQLite
+/// database at `path`, returning the output and what the mock printed.
+fn store_acknowledged(path: &Path) -> (Output, String) {
+    let pico = MockPico::start(&["--count", "5", "--ack"]);
+    let settings = format!(r#""storage": {{ "sqlite": {{ "path": {path:?} }} }},"#);
+
+    let output = run_with(pico.port, &settings, r#", "ack": true"#, &[]);
+    (output, pico.finish())
+}
+
+#[test]
+fn acknowledges_the_persisted_measurements() {
+    let path = std::env::temp_dir().join("pico_humidity_temp_read_ack.db");
+    let _ = std::fs::remove_file(&path);
+
+    let (output, acknowledged) = store_acknowledged(&path);
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(output.status.success());
+    // The count as a little-endian u32.
+    assert!(
+        acknowledged.contains("acknowledged 5 measurements ([05, 00, 00, 00])"),
+        "{acknowledged}"
+    );
+}
+
+#[test]
+fn inserts_nothing_of_a_truncated_download() {
+    let path = std::env::temp_dir().join("pico_humidity_temp_read_truncated.db");
+    let _ = std::fs::remove_file(&path);
+    // Past the first chunks handed on to be inserted.
+    let pico = MockPico::start(&["--count", "3000", "--truncate-after", "2500"]);
+    let settings = format!(r#""storage": {{ "sqlite": {{ "path": {path:?} }} }},"#);
+
+    let output = run_with(pico.port, &settings, "", &[]);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    pico.finish();
+
+    let db = rusqlite::Connection::open(&path).unwrap();
+    let stored: i64 = db
+        .query_row("select count(*) from measurement", [], |row| row.get(0))
+        .unwrap();
+    drop(db);
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(output.status.code(), Some(20), "{stderr}");
+    assert!(
+        stderr.contains("Pico closed the connection after 2500 of 3000 measurements"),
+        "{stderr}"
+    );
+    assert_eq!(stored, 0);
+}
+
+#[test]
+fn acknowledges_nothing_after_a_failed_insert() {
+    let path = std::env::temp_dir().join("pico_humidity_temp_read_ack_failed.db");
+    let _ = std::fs::remove_file(&path);
+
+    // A table rejecting every measurement, which `insert or ignore` can't ignore.
+    rusqlite::Connection::open(&path)
+        .unwrap()
+        .execute_batch(
+            "create table measurement (
+                at text not null,
+                station_id integer not null,
+                temp real not null,
+                humidity real not null,
+                unique (at, station_id)
+            );
+            create trigger reject before insert on measurement
+            begin
+                select raise(abort, 'rejected');
+            end",
+        )
+        .unwrap();
+
+    let (output, acknowledged) = store_acknowledged(&path);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(output.status.code(), Some(40), "{stderr}");
+    assert!(
+        acknowledged.contains("acknowledged 0 measurements ([00, 00, 00, 00])"),
+        "{acknowledged}"
+    );
+}
+
+#[test]
+fn rejects_truncated_downloads() {
+    let pico = MockPico::start(&["--count", "5", "--truncate-after", "3"]);
+
+    let output = pico.dry_run("");
+
+    assert_eq!(output.status.code(), Some(20));
+    pico.finish();
+}
+
+#[test]
+fn allocates_nothing_for_announced_measurements() {
+    let pico = MockPico::start(&[
+        "--count",
+        "4294967295",
+        "--truncate-after",
+        "5",
+        "--interval-secs",
+        "0",
+    ]);
+
+    // A flash large enough to accept any count.
+    let output = pico.dry_run(r#", "sector_count": 16777216"#);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+
+    assert_eq!(output.status.code(), Some(20), "{stderr}");
+    assert!(
+        stderr.contains("Pico closed the connection after 5 of 4294967295 measurements"),
+        "{stderr}"
+    );
+    pico.finish();
+}
+
+#[test]
+fn rejects_invalid_dates() {
+    let pico = MockPico::start(&["--count", "5", "--invalid-date-at", "2"]);
+
+    let output = pico.dry_run("");
+    let stderr = String::from_utf8(output.stderr).unwrap();
+
+    assert_eq!(output.status.code(), Some(30), "{stderr}");
+    assert!(stderr.contains("Pico sent invalid date"), "{stderr}");
+    pico.finish();
+}
+
+#[test]
+fn times_out_on_stalling_picos() {
+    let pico = MockPico::start(&["--count", "5", "--stall-secs", "4"]);
+
+    let output = pico.dry_run("");
+    let stderr = String::from_utf8(output.stderr).unwrap();
+
+    assert!(!output.status.success());
+    assert!(stderr.contains("timed out"), "{stderr}");
+    pico.finish();
+}
+
+#[test]
+fn gives_up_on_sessions_taking_too_long() {
+    let pico = MockPico::start(&["--count", "5", "--stall-secs", "3"]);
+
+    let output = run_with(
+        pico.port,
+        r#""pico_socket": { "session_timeout_secs": 1 },"#,
+        "",
+        &["--dry-run"],
+    );
+    let stderr = String::from_utf8(output.stderr).unwrap();
+
+    assert_eq!(output.status.code(), Some(20), "{stderr}");
+    assert!(
+        stderr.contains("Gave up on the Pico after pico_socket.session_timeout_secs (1s)"),
+        "{stderr}"
+    );
+    pico.finish();
+}
+
+#[test]
+fn speaks_the_legacy_protocol() {
+    let pico = MockPico::start(&["--count", "2", "--legacy-protocol"]);
+
+    let output = pico.dry_run(r#", "legacy_protocol": true"#);
+
+    assert!(output.status.success());
+    assert!(!pico.finish().contains("protocol version"));
+}
+
+#[test]
+fn names_legacy_protocol_when_the_handshake_isnt_answered() {
+    // The count is taken for the version, which only 1 passes for.
+    for count in ["2", "1"] {
+        let pico = MockPico::start(&[
+            "--count",
+            count,
+            "--legacy-protocol",
+            "--last-time",
+            "2024-06-01T12:00:30",
+        ]);
+
+        let output = pico.dry_run("");
+        let stderr = String::from_utf8(output.stderr).unwrap();
+
+        assert_eq!(output.status.code(), Some(20), "{stderr}");
+        assert!(
+            stderr.contains("set legacy_protocol for the station"),
+            "{stderr}"
+        );
+        pico.finish();
+    }
+}
+
+#[test]
+fn sends_the_time_with_milliseconds() {
+    let pico = MockPico::start(&["--count", "2"]);
+
+    let output = pico.run(&["--dry-run", "-v"]);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+
+    assert!(output.status.success(), "{stderr}");
+    assert!(stderr.contains("after a round trip of"), "{stderr}");
+    assert!(pico.finish().contains("received time"));
+}
+
+#[test]
+fn sends_the_time_in_whole_seconds_to_legacy_firmware() {
+    let pico = MockPico::start(&["--count", "2", "--legacy-time-sync"]);
+
+    let output = run(
+        pico.port,
+        r#", "legacy_time_sync": true"#,
+        &["--dry-run", "-v"],
+    );
+    let stderr = String::from_utf8(output.stderr).unwrap();
+
+    assert!(output.status.success(), "{stderr}");
+    assert!(!stderr.contains("round trip"), "{stderr}");
+    assert!(pico.finish().contains("received time"));
+}
+
+#[test]
+fn names_legacy_time_sync_when_the_probe_isnt_echoed() {
+    let pico = MockPico::start(&["--count", "2", "--legacy-time-sync"]);
+
+    let output = pico.dry_run("");
+    let stderr = String::from_utf8(output.stderr).unwrap();
+
+    assert_eq!(output.status.code(), Some(20), "{stderr}");
+    assert!(
+        stderr.contains("set legacy_time_sync for the station"),
+        "{stderr}"
+    );
+    pico.finish();
+}
+
+#[test]
+fn leaves_the_clock_alone_while_the_host_clock_looks_wrong() {
+    let settings = r#""min_plausible_time": "9999-01-01","#;
+
+    let pico = MockPico::start(&["--count", "2"]);
+    let output = run_with(
+        pico.port,
+        settings,
+        "",
+        &["--dry-run", "--summary-json", "-"],
+    );
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+
+    assert!(output.status.success(), "{stderr}");
+    assert!(
+        stderr.contains("not setting the clock of the Pico"),
+        "{stderr}"
+    );
+    assert!(
+        stdout.contains(r#""clock_not_set": "the host's clock shows "#),
+        "{stdout}"
+    );
+    assert!(pico.finish().contains("client left the clock alone"));
+
+    let pico = MockPico::start(&["--count", "2"]);
+    let output = run_with(pico.port, settings, "", &["--dry-run", "--force-time-sync"]);
+    assert!(output.status.success());
+    assert!(pico.finish().contains("received time"));
+
+    // The legacy time sync can't leave the clock alone.
+    let pico = MockPico::start(&["--count", "2", "--legacy-time-sync"]);
+    let output = run_with(
+        pico.port,
+        settings,
+        r#", "legacy_time_sync": true"#,
+        &["--dry-run"],
+    );
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert_eq!(output.status.code(), Some(10), "{stderr}");
+    assert!(stderr.contains("--force-time-sync"), "{stderr}");
+    pico.finish();
+}
+
+#[test]
+fn authenticates_with_the_pre_shared_key() {
+    let pico = MockPico::start(&["--count", "5", "--psk", "secret"]);
+
+    let output = pico.dry_run(r#", "auth": "hmac", "psk": "secret""#);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+
+    assert!(output.status.success(), "{stdout}");
+    assert!(
+        stdout.starts_with("station 7: 5 measurements\n"),
+        "{stdout}"
+    );
+    assert!(pico.finish().contains("authenticated the client"));
+}
+
+#[test]
+fn reads_the_pre_shared_key_from_a_systemd_credential() {
+    let pico = MockPico::start(&["--count", "5", "--psk", "secret"]);
+    let credentials = std::env::temp_dir().join("pico_humidity_temp_read_credentials");
+    let _ = std::fs::remove_dir_all(&credentials);
+    std::fs::create_dir_all(&credentials).unwrap();
+
+    let path = config_path(pico.port);
+    let run = || {
+        std::fs::write(
+            &path,
+            format!(
+                r#"{{
+                    "connect_retries": 0,
+                    "read_timeout_secs": 2,
+                    "stations": [{{
+                        "pico": "127.0.0.1", "pico_port": {}, "station_id": 7,
+                        "auth": "hmac", "psk_credential": "pico-psk"
+                    }}]
+                }}"#,
+                pico.port
+            ),
+        )
+        .unwrap();
+
+        Command::new(env!("CARGO_BIN_EXE_pico_humidity_temp_read"))
+            .arg("--config")
+            .arg(&path)
+            .arg("--dry-run")
+            .env("CREDENTIALS_DIRECTORY", &credentials)
+            .env_remove("RUST_LOG")
+            .output()
+            .unwrap()
+    };
+
+    // The error names where the credential was expected.
+    let output = run();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert_eq!(output.status.code(), Some(10), "{stderr}");
+    assert!(
+        stderr.contains(&credentials.join("pico-psk").display().to_string()),
+        "{stderr}"
+    );
+
+    std::fs::write(credentials.join("pico-psk"), "secret\n").unwrap();
+    let output = run();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+
+    assert!(output.status.success(), "{stdout}");
+    assert!(
+        stdout.starts_with("station 7: 5 measurements\n"),
+        "{stdout}"
+    );
+    assert!(pico.finish().contains("authenticated the client"));
+    std::fs::remove_file(path).unwrap();
+    std::fs::remove_dir_all(credentials).unwrap();
+}
+
+#[test]
+fn exits_with_21_on_a_wrong_key() {
+    let pico = MockPico::start(&["--count", "5", "--psk", "secret"]);
+
+    let output = pico.dry_run(r#", "auth": "hmac", "psk": "guessed""#);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+
+    assert_eq!(output.status.code(), Some(21), "{stderr}");
+    assert!(
+        stderr.contains("Pico rejected the pre-shared key"),
+        "{stderr}"
+    );
+    assert!(pico.finish().contains("rejected the key of the client"));
+}
+
+#[test]
+fn tells_picos_without_authentication_apart() {
+    let pico = MockPico::start(&["--count", "5"]);
+
+    let output = pico.dry_run(r#", "auth": "hmac", "psk": "secret""#);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+
+    assert_eq!(output.status.code(), Some(20), "{stderr}");
+    assert!(
+        stderr.contains("Error reading the authentication challenge from the Pico"),
+        "{stderr}"
+    );
+    pico.finish();
+}
+
+#[test]
+fn exits_with_10_without_a_config() {
+    let path = std::env::temp_dir().join("pico_humidity_temp_read_missing.json");
+    let _ = std::fs::remove_file(&path);
+
+    let output = Command::new(env!("CARGO_BIN_EXE_pico_humidity_temp_read"))
+        .arg("--config")
+        .arg(&path)
+        .output()
+        .unwrap();
+
+    assert!(path.exists());
+    std::fs::remove_file(path).unwrap();
+    assert_eq!(output.status.code(), Some(10));
+}
+
+#[test]
+fn exits_with_20_on_unreachable_picos() {
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+
+    let output = dry_run(port, "");
+    let stderr = String::from_utf8(output.stderr).unwrap();
+
+    assert_eq!(output.status.code(), Some(20), "{stderr}");
+}
+
+#[test]
+fn exits_with_60_while_another_instance_runs() {
+    let pico = MockPico::start(&["--count", "5"]);
+    let mut lock_path = config_path(pico.port).into_os_string();
+    lock_path.push(".lock");
+    let lock = std::fs::File::create(&lock_path).unwrap();
+    lock.lock().unwrap();
+
+    let output = pico.dry_run("");
+    let stderr = String::from_utf8(output.stderr).unwrap();
+
+    assert_eq!(output.status.code(), Some(60), "{stderr}");
+    assert!(stderr.contains("Another instance is running"), "{stderr}");
+
+    let unlock = std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(300));
+        drop(lock);
+    });
+    let output = run(pico.port, "", &["--dry-run", "--wait-lock", "5"]);
+
+    unlock.join().unwrap();
+    std::fs::remove_file(lock_path).unwrap();
+    assert!(output.status.success(), "{output:?}");
+    pico.finish();
+}
+
+#[test]
+fn decodes_packed_measurements() {
+    // 2024-06-01 12:00:00 at 21.5 °C and 48.0 %.
+    let output = run(1, "", &["decode", "0xf035c1fa140c000"]);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+
+    assert!(output.status.success(), "{stdout}");
+    assert_eq!(
+        stdout,
+        "{\"time\":\"2024-06-01T12:00:00Z\",\"temp\":21.5,\"humidity\":48.0}\n"
+    );
+}
+
+/// Runs the program with `--dry-run` and `--output-parquet` against a mock sending `count`
+/// measurements and returns the metadata of the Parquet file.
+fn dry_run_to_parquet(count: &str, args: &[&str]) -> parquet::file::metadata::ParquetMetaData {
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+
+    let pico = MockPico::start(&["--count", count]);
+    let path = std::env::temp_dir().join(format!("pico_humidity_temp_read_{}.parquet", pico.port));
+
+    let output = pico.run(
+        &[
+            &["--dry-run", "--output-parquet", path.to_str().unwrap()],
+            args,
+        ]
+        .concat(),
+    );
+    pico.finish();
+
+    assert!(output.status.success(), "{output:?}");
+    let reader = SerializedFileReader::new(std::fs::File::open(&path).unwrap()).unwrap();
+    std::fs::remove_file(path).unwrap();
+    reader.metadata().clone()
+}
+
+#[test]
+fn writes_the_measurements_to_parquet() {
+    let metadata = dry_run_to_parquet("5", &["--parquet-row-group-size", "2"]);
+    let schema = metadata.file_metadata().schema_descr();
+
+    assert_eq!(metadata.file_metadata().num_rows(), 5);
+    assert_eq!(metadata.num_row_groups(), 3);
+    assert_eq!(
+        schema
+            .columns()
+            .iter()
+            .map(|column| column.name())
+            .collect::<Vec<_>>(),
+        ["time", "station_id", "temp_c", "humidity_pct"]
+    );
+    assert_eq!(
+        metadata.row_group(0).column(0).compression(),
+        parquet::basic::Compression::SNAPPY
+    );
+}
+
+#[test]
+fn writes_the_schema_to_parquet_without_measurements() {
+    let metadata = dry_run_to_parquet("0", &[]);
+
+    assert_eq!(metadata.file_metadata().num_rows(), 0);
+    assert_eq!(metadata.num_row_groups(), 0);
+    assert_eq!(metadata.file_metadata().schema_descr().num_columns(), 4);
+}
+
+#[test]
+fn presents_the_temperatures_in_fahrenheit() {
+    let pico = MockPico::start(&["--count", "5"]);
+    let csv = std::env::temp_dir().join(format!("pico_fahrenheit_{}.csv", pico.port));
+    let _ = std::fs::remove_file(&csv);
+
+    let output = run_with(
+        pico.port,
+        r#""output_units": { "temperature": "fahrenheit" },"#,
+        "",
+        &[
+            "--dry-run",
+            "--output-jsonl",
+            "-",
+            "--output-csv",
+            csv.to_str().unwrap(),
+        ],
+    );
+    pico.finish();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let records = std::fs::read_to_string(&csv).unwrap();
+    std::fs::remove_file(csv).unwrap();
+
+    assert!(output.status.success(), "{stdout}");
+    let lines: Vec<serde_json::Value> = stdout
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(lines.len(), 5, "{stdout}");
+    assert!(records.starts_with("time,station_id,temp_f,humidity_pct\n"));
+
+    for (line, record) in lines.iter().zip(records.lines().skip(1)) {
+        assert_eq!(line["temp_unit"], "fahrenheit");
+        // The same value in both, with a single decimal.
+        let temp = record.split(',').nth(2).unwrap();
+        assert_eq!(line["temp"].as_f64().unwrap(), temp.parse::<f64>().unwrap());
+        assert_eq!(temp.split('.').nth(1).map(str::len), Some(1), "{record}");
+    }
+}
+
+#[test]
+fn notifies_the_webhook_once_per_rule() {
+    use std::io::Write;
+
+    let webhook = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/hook", webhook.local_addr().unwrap());
+    let pico = MockPico::start(&["--count", "5"]);
+
+    let receiving = std::thread::spawn(move || {
+        let (stream, _) = webhook.accept().unwrap();
+        let mut stream = BufReader::new(stream);
+        let mut length = 0;
+        let mut line = String::new();
+
+        while stream.read_line(&mut line).unwrap() > 2 {
+            if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                length = value.trim().parse().unwrap();
+            }
+            line.clear();
+        }
+
+        let mut body = vec![0; length];
+        stream.read_exact(&mut body).unwrap();
+        stream
+            .get_mut()
+            .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+            .unwrap();
+
+        (String::from_utf8(body).unwrap(), webhook)
+    });
+
+    // The second webhook refuses the connection, which doesn't fail the run.
+    let output = run_with(
+        pico.port,
+        &format!(
+            r#""alerts": [
+                {{ "field": "humidity", "above": 0, "webhook": "{url}" }},
+                {{ "field": "temp", "below": 100, "webhook": "http://127.0.0.1:1/" }}
+            ],"#
+        ),
+        "",
+        &["--dry-run"],
+    );
+    pico.finish();
+    let (body, webhook) = receiving.join().unwrap();
+    let alert: serde_json::Value = serde_json::from_str(&body).unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+
+    assert!(output.status.success(), "{stderr}");
+    // Every measurement crosses the rule, which is still only notified once.
+    webhook.set_nonblocking(true).unwrap();
+    assert!(webhook.accept().is_err());
+    assert_eq!(alert["station_id"], 7);
+    assert_eq!(
+        alert["rule"],
+        serde_json::json!({ "index": 0, "field": "humidity", "below": null, "above": 0.0 })
+    );
+    assert!(alert["value"].as_f64().unwrap() > 0.0);
+    assert_eq!(alert["unit"], "%");
+    assert!(
+        stderr.contains("notifying the webhook of alerts[1] failed"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn only_syncs_the_time() {
+    let pico = MockPico::start(&["--count", "5"]);
+
+    let output = pico.run(&["sync-time"]);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+
+    assert!(output.status.success(), "{stdout}");
+    assert!(stdout.starts_with("station 7: clock set to "), "{stdout}");
+
+    let mock_output = pico.finish();
+    assert!(mock_output.contains("received time"), "{mock_output}");
+    assert!(
+        mock_output.contains("closed the connection after the time"),
+        "{mock_output}"
+    );
+}
+
+#[test]
+fn reports_the_stored_count() {
+    let pico = MockPico::start(&["--count", "42"]);
+
+    let output = pico.run(&["status"]);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+
+    assert!(output.status.success(), "{stdout}");
+    assert_eq!(
+        stdout,
+        "station 7: 42 measurements stored, 0.0% of the flash\n"
+    );
+    pico.finish();
+}
+
+#[test]
+fn pings_without_downloading_or_setting_the_clock() {
+    let pico = MockPico::start(&["--count", "42"]);
+
+    let output = pico.run(&["ping"]);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+
+    assert!(output.status.success(), "{stdout}");
+    assert!(
+        stdout.starts_with("station 7: reachable, 42 measurements pending, rtt "),
+        "{stdout}"
+    );
+    assert!(pico.finish().contains("client left the clock alone"));
+
+    // A Pico accepting the connection but never answering.
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let output = run(port, "", &["ping", "--timeout", "1"]);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+
+    assert_eq!(output.status.code(), Some(20), "{stderr}");
+    assert!(stderr.contains("after --timeout (1s)"), "{stderr}");
+}
+
+/// Fetches from a mock Pico with 5 measurements and stores them in a new SQLite database,
+/// returning what was logged.
+fn store_logging(args: &[&str]) -> (Output, String) {
+    let pico = MockPico::start(&["--count", "5"]);
+    let path =
+        std::env::temp_dir().join(format!("pico_humidity_temp_read_logging_{}.db", pico.port));
+    let _ = std::fs::remove_file(&path);
+
+    let settings = format!(r#""storage": {{ "sqlite": {{ "path": {path:?} }} }},"#);
+    let output = run_with(pico.port, &settings, "", args);
+    let stderr = String::from_utf8(output.stderr.clone()).unwrap();
+
+    std::fs::remove_file(path).unwrap();
+    pico.finish();
+    (output, stderr)
+}
+
+#[test]
+fn documents_the_config_flag_in_the_help() {
+    let output = Command::new(env!("CARGO_BIN_EXE_pico_humidity_temp_read"))
+        .arg("--help")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+
+    assert!(output.status.success());
+    assert!(stdout.contains("-c, --config <CONFIG>"), "{stdout}");
+    assert!(stdout.contains("PICOREAD_CONFIG"), "{stdout}");
+}
+
+#[test]
+fn reads_the_config_named_by_the_environment() {
+    let pico = MockPico::start(&["--count", "2"]);
+    let path = config_path(pico.port);
+    std::fs::write(
+        &path,
+        format!(
+            r#"{{ "connect_retries": 0, "stations": [{{ "pico": "127.0.0.1", "pico_port": {}, "station_id": 7 }}] }}"#,
+            pico.port
+        ),
+    )
+    .unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_pico_humidity_temp_read"))
+        .arg("--dry-run")
+        .env("PICOREAD_CONFIG", &path)
+        .env_remove("RUST_LOG")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+
+    std::fs::remove_file(path).unwrap();
+    assert!(output.status.success(), "{stdout}");
+    assert!(
+        stdout.contains("2 measurements fetched from 1 of 1 stations"),
+        "{stdout}"
+    );
+    pico.finish();
+}
+
+#[test]
+fn logs_only_the_summary_by_default() {
+    let (output, stderr) = store_logging(&[]);
+
+    assert!(output.status.success(), "{stderr}");
+    assert!(output.stdout.is_empty());
+    assert_eq!(stderr.lines().count(), 1, "{stderr}");
+    assert!(
+        stderr.contains("5 measurements fetched from 1 of 1 stations, 5 inserted"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn logs_the_timings_of_the_phases_at_debug_level() {
+    let (output, stderr) = store_logging(&["--log-level", "debug"]);
+
+    assert!(output.status.success(), "{stderr}");
+    assert!(stderr.contains("timings: database connect "), "{stderr}");
+    assert!(
+        stderr
+            .lines()
+            .any(|line| line.contains("timings of station 7: connect ")
+                && line.contains("(46 bytes, ")
+                && line.contains("(5 rows, ")),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn reports_the_timings_of_the_phases() {
+    let (output, stderr) = store_logging(&["--summary-json", "-"]);
+    assert!(output.status.success(), "{stderr}");
+
+    let summary: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let timings = &summary["stations"][0]["timings"];
+
+    assert!(summary["db_connect_secs"].is_f64(), "{summary}");
+    for phase in ["connect", "time_sync", "download", "decode", "insert"] {
+        assert!(timings[format!("{phase}_secs")].is_f64(), "{timings}");
+    }
+    assert_eq!(timings["download_bytes"], 46);
+    assert_eq!(timings["insert_rows"], 5);
+    assert!(timings["download_bytes_per_sec"].as_f64().unwrap() > 0.0);
+    assert!(timings["insert_rows_per_sec"].as_f64().unwrap() > 0.0);
+}
+
+#[test]
+fn logs_nothing_but_errors_when_quiet() {
+    let (output, stderr) = store_logging(&["--quiet"]);
+
+    assert!(output.status.success(), "{stderr}");
+    assert!(output.stdout.is_empty());
+    assert!(stderr.is_empty(), "{stderr}");
+}
+
+#[test]
+fn logs_the_progress_when_verbose() {
+    let (output, stderr) = store_logging(&["-v"]);
+
+    assert!(output.status.success(), "{stderr}");
+    assert!(stderr.contains("connected to the Pico in"), "{stderr}");
+    assert!(!stderr.contains("decoded measurement"), "{stderr}");
+
+    let pico = MockPico::start(&["--count", "5"]);
+    let output = run(pico.port, "", &["--dry-run", "-vv"]);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+
+    assert!(output.status.success(), "{stderr}");
+    assert!(stderr.contains("decoded measurement"), "{stderr}");
+    pico.finish();
+}
+
+/// Runs `verify` against a mock Pico with 4 measurements taken at a fixed time, storing in the
+/// SQLite database at `path`.
+fn verify(path: &std::path::Path, args: &[&str]) -> Output {
+    let pico = MockPico::start(&["--count", "4", "--last-time", "2024-06-01T12:00:00"]);
+    let settings = format!(r#""storage": {{ "sqlite": {{ "path": {path:?} }} }},"#);
+    let output = run_with(pico.port, &settings, "", args);
+    pico.finish();
+    output
+}
+
+#[test]
+fn verifies_the_stored_measurements() {
+    let path = std::env::temp_dir().join("pico_humidity_temp_read_verify.db");
+    let _ = std::fs::remove_file(&path);
+
+    let unstored = verify(&path, &["verify"]);
+    let stdout = String::from_utf8(unstored.stdout).unwrap();
+    assert_eq!(unstored.status.code(), Some(50), "{stdout}");
+    assert!(
+        stdout.starts_with("station 7: 4 measurements, 0 stored equally, 0 differently, 4 missing"),
+        "{stdout}"
+    );
+
+    let fetch = verify(&path, &[]);
+    assert!(fetch.status.success());
+    let stored = verify(&path, &["verify"]);
+    let stdout = String::from_utf8(stored.stdout).unwrap();
+    assert!(stored.status.success(), "{stdout}");
+    assert_eq!(
+        stdout,
+        "station 7: 4 measurements, 4 stored equally, 0 differently, 0 missing\n"
+    );
+
+    let db = rusqlite::Connection::open(&path).unwrap();
+    db.execute_batch(
+        "update measurement set temp = temp + 1 where at = (select max(at) from measurement);
+         delete from measurement where at = (select min(at) from measurement);",
+    )
+    .unwrap();
+    drop(db);
+
+    let changed = verify(&path, &["--summary-json", "-", "verify"]);
+    std::fs::remove_file(&path).unwrap();
+    let stdout = String::from_utf8(changed.stdout).unwrap();
+    assert_eq!(changed.status.code(), Some(50), "{stdout}");
+
+    let report: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    let verification = &report["stations"][0]["verification"];
+    assert_eq!(verification["equal"], 2, "{stdout}");
+    assert_eq!(verification["different"][0]["time"], "2024-06-01T12:00:00Z");
+    assert_eq!(
+        verification["missing"],
+        serde_json::json!(["2024-06-01T11:15:00Z"])
+    );
+}
+
+#[test]
+fn downloads_from_the_picos_connecting_in_listen_mode() {
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let path = std::env::temp_dir().join("pico_humidity_temp_read_listen.db");
+    let _ = std::fs::remove_file(&path);
+
+    let config = format!(
+        r#"{{
+            "storage": {{ "sqlite": {{ "path": {path:?} }} }},
+            "mode": "listen",
+            "listen_address": "127.0.0.1:{port}",
+            "identify_by": "station_byte",
+            "read_timeout_secs": 2,
+            "pico_timezone": "utc",
+            "stations": [
+                {{ "pico": "pico-1", "pico_port": 60438, "station_id": 1, "ack": true }},
+                {{ "pico": "pico-2", "pico_port": 60438, "station_id": 2, "ack": true }}
+            ]
+        }}"#
+    );
+    let config_path = config_path(port);
+    std::fs::write(&config_path, config).unwrap();
+
+    let mut program = Command::new(env!("CARGO_BIN_EXE_pico_humidity_temp_read"))
+        .arg("--config")
+        .arg(&config_path)
+        .env_remove("RUST_LOG")
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let address = format!("127.0.0.1:{port}");
+    let picos: Vec<_> = [("1", "3"), ("2", "5"), ("9", "1")]
+        .into_iter()
+        .map(|(station_id, count)| {
+            Command::new(env!("CARGO_BIN_EXE_mock_pico"))
+                .args(["--connect", &address, "--station-byte", station_id])
+                .args(["--count", count, "--ack"])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .unwrap()
+        })
+        .collect();
+    let picos: Vec<_> = picos
+        .into_iter()
+        .map(|pico| pico.wait_with_output().unwrap())
+        .collect();
+
+    Command::new("kill")
+        .arg("-TERM")
+        .arg(program.id().to_string())
+        .status()
+        .unwrap();
+    let status = program.wait().unwrap();
+    let mut stderr = String::new();
+    program
+        .stderr
+        .take()
+        .unwrap()
+        .read_to_string(&mut stderr)
+        .unwrap();
+    std::fs::remove_file(config_path).unwrap();
+
+    let db = rusqlite::Connection::open(&path).unwrap();
+    let counts: Vec<(i32, u32)> = db
+        .prepare("select station_id, count(*) from measurement group by station_id order by 1")
+        .unwrap()
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    drop(db);
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(status.success(), "{stderr}");
+    assert_eq!(counts, [(1, 3), (2, 5)], "{stderr}");
+    for (pico, acknowledged) in picos.iter().zip(["3", "5"]) {
+        let stdout = String::from_utf8_lossy(&pico.stdout);
+        assert!(
+            stdout.contains(&format!("acknowledged {acknowledged} measurements")),
+            "{stdout}"
+        );
+    }
+    assert!(
+        stderr.contains("the Pico sent the station id 9, which isn't configured"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn registers_named_stations_in_the_station_table() {
+    let pico = MockPico::start(&["--count", "3"]);
+    let path =
+        std::env::temp_dir().join(format!("pico_humidity_temp_read_stations_{}.db", pico.port));
+    let _ = std::fs::remove_file(&path);
+
+    let run_with_stations = |stations: &str, args: &[&str]| {
+        let config = format!(
+            r#"{{
+                "storage": {{ "sqlite": {{ "path": {path:?} }} }},
+                "station_table": true,
+                "connect_retries": 0,
+                "read_timeout_secs": 2,
+                "stations": [{stations}]
+            }}"#
+        );
+        let config_path = config_path(pico.port);
+        std::fs::write(&config_path, config).unwrap();
+
+        let output = Command::new(env!("CARGO_BIN_EXE_pico_humidity_temp_read"))
+            .arg("--config")
+            .arg(&config_path)
+            .args(args)
+            .env_remove("RUST_LOG")
+            .output()
+            .unwrap();
+
+        std::fs::remove_file(config_path).unwrap();
+        output
+    };
+
+    let init = run_with_stations(
+        r#"{ "pico": "127.0.0.1", "pico_port": 1, "station_id": 7 },
+           { "pico": "127.0.0.1", "pico_port": 1, "name": "attic" }"#,
+        &["--init-db"],
+    );
+    let fetch = run_with_stations(
+        &format!(
+            r#"{{ "pico": "127.0.0.1", "pico_port": {}, "name": "attic", "location": "roof" }}"#,
+            pico.port
+        ),
+        &[],
+    );
+    pico.finish();
+
+    let db = rusqlite::Connection::open(&path).unwrap();
+    let stations: Vec<(i32, Option<String>, Option<String>)> = db
+        .prepare("select id, name, location from station order by id")
+        .unwrap()
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    let stored: Vec<(i32, u32)> = db
+        .prepare("select station_id, count(*) from measurement group by station_id")
+        .unwrap()
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    drop(db);
+    std::fs::remove_file(&path).unwrap();
+
+    let stdout = String::from_utf8_lossy(&init.stdout);
+    assert!(init.status.success(), "{stdout}");
+    assert!(
+        stdout.contains("registered 2 stations in the station table"),
+        "{stdout}"
+    );
+    assert!(
+        fetch.status.success(),
+        "{}",
+        String::from_utf8_lossy(&fetch.stderr)
+    );
+    assert_eq!(
+        stations,
+        [
+            (7, None, None),
+            (8, Some("attic".to_string()), Some("roof".to_string()))
+        ]
+    );
+    assert_eq!(stored, [(8, 3)]);
+}
+
+#[test]
+fn stores_the_station_metadata() {
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let path = std::env::temp_dir().join("pico_humidity_temp_read_metadata.db");
+    let _ = std::fs::remove_file(&path);
+
+    // The Pico isn't reachable, but the metadata is stored before connecting to it.
+    let run_with_metadata = |metadata: &str| {
+        let output = run_with(
+            port,
+            &format!(r#""storage": {{ "sqlite": {{ "path": {path:?} }} }},"#),
+            &format!(r#", "metadata": {metadata}"#),
+            &[],
+        );
+        assert_eq!(output.status.code(), Some(20));
+    };
+    let stored = || -> (String, String) {
+        rusqlite::Connection::open(&path)
+            .unwrap()
+            .query_row(
+                "select metadata, updated_at from station_metadata where station_id = 7",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap()
+    };
+
+    run_with_metadata(r#"{ "sensor": "DHT22", "firmware": "1.3" }"#);
+    rusqlite::Connection::open(&path)
+        .unwrap()
+        .execute(
+            "update station_metadata set updated_at = '2000-01-01 00:00:00'",
+            [],
+        )
+        .unwrap();
+
+    // Unchanged, the row is left alone.
+    run_with_metadata(r#"{ "firmware": "1.3", "sensor": "DHT22" }"#);
+    let unchanged = stored();
+
+    run_with_metadata(r#"{ "sensor": "DHT22", "firmware": "1.4" }"#);
+    let changed = stored();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(
+        unchanged,
+        (
+            r#"{"firmware":"1.3","sensor":"DHT22"}"#.to_string(),
+            "2000-01-01 00:00:00".to_string()
+        )
+    );
+    assert_eq!(changed.0, r#"{"firmware":"1.4","sensor":"DHT22"}"#);
+    assert_ne!(changed.1, unchanged.1);
+}